@@ -1,34 +1,79 @@
 use std::{
-    net::SocketAddr,
+    net::{Ipv4Addr, SocketAddr},
     sync::{Arc, Mutex},
 };
 
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 
-use crate::tunnel::generate_digest_from_str;
+use crate::{
+    common::noise::decode_key, gateway::dnat::DnatRules, peers::acl_filter::AclFilter,
+    tunnel::generate_digest_from_str,
+};
+
+pub const DEFAULT_IPV4_NETWORK_LENGTH: u8 = 24;
+
+// keeps the private key out of the command line, where every user can see it with ps
+pub fn read_private_key_file(path: &str) -> Result<String, anyhow::Error> {
+    let key = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read private key file: {}", path))?;
+    let key = key.trim().to_string();
+    decode_key(&key).with_context(|| format!("failed to parse private key in file: {}", path))?;
+    Ok(key)
+}
 
 #[auto_impl::auto_impl(Box, &)]
 pub trait ConfigLoader: Send + Sync {
     fn get_id(&self) -> uuid::Uuid;
     fn set_id(&self, id: uuid::Uuid);
+    // get_id generates an id if it's missing, this only returns the one written in config
+    fn get_configured_id(&self) -> Option<uuid::Uuid>;
 
     fn get_inst_name(&self) -> String;
     fn set_inst_name(&self, name: String);
 
+    // advertised to peers instead of the system hostname if set
+    fn get_hostname(&self) -> Option<String>;
+    fn set_hostname(&self, hostname: Option<String>);
+
     fn get_netns(&self) -> Option<String>;
     fn set_netns(&self, ns: Option<String>);
 
     fn get_ipv4(&self) -> Option<std::net::Ipv4Addr>;
     fn set_ipv4(&self, addr: std::net::Ipv4Addr);
+    fn get_ipv4_inet(&self) -> Option<cidr::Ipv4Inet>;
+    fn set_ipv4_inet(&self, addr: cidr::Ipv4Inet);
+
+    fn get_ipv6(&self) -> Option<cidr::Ipv6Inet>;
+    fn set_ipv6(&self, addr: cidr::Ipv6Inet);
+
+    // network to pick an ipv4 address from if ipv4 is not set
+    fn get_dhcp_network(&self) -> Option<cidr::Ipv4Cidr>;
+    fn set_dhcp_network(&self, network: Option<cidr::Ipv4Cidr>);
 
     fn add_proxy_cidr(&self, cidr: cidr::IpCidr);
     fn remove_proxy_cidr(&self, cidr: cidr::IpCidr);
     fn get_proxy_cidrs(&self) -> Vec<cidr::IpCidr>;
+    // (cidr, mapped cidr) of proxy networks advertised under another cidr
+    fn get_proxy_cidr_mappings(&self) -> Vec<(cidr::IpCidr, cidr::IpCidr)>;
+    fn set_proxy_cidr_mapping(&self, cidr: cidr::IpCidr, mapped_cidr: Option<cidr::IpCidr>);
+
+    // virtual ipv4 of exit nodes to send internet traffic through, the first reachable one is used
+    fn get_exit_nodes(&self) -> Vec<Ipv4Addr>;
+    fn set_exit_nodes(&self, nodes: Vec<Ipv4Addr>);
 
     fn get_network_identity(&self) -> NetworkIdentity;
     fn set_network_identity(&self, identity: NetworkIdentity);
 
+    // the key in private_key_file wins over the inline one
+    fn get_private_key(&self) -> Option<String>;
+    fn set_private_key(&self, key: Option<String>);
+    fn get_private_key_file(&self) -> Option<String>;
+    fn set_private_key_file(&self, path: Option<String>);
+    // none means any public key is trusted, an empty list trusts no one
+    fn get_trusted_public_keys(&self) -> Option<Vec<String>>;
+    fn set_trusted_public_keys(&self, keys: Option<Vec<String>>);
+
     fn get_listener_uris(&self) -> Vec<url::Url>;
 
     fn get_file_logger_config(&self) -> FileLoggerConfig;
@@ -45,13 +90,80 @@ pub trait ConfigLoader: Send + Sync {
     fn get_rpc_portal(&self) -> Option<SocketAddr>;
     fn set_rpc_portal(&self, addr: SocketAddr);
 
+    // rpc portal accepts any client if not set
+    fn get_rpc_portal_auth(&self) -> Option<RpcPortalAuthConfig>;
+    fn set_rpc_portal_auth(&self, config: Option<RpcPortalAuthConfig>);
+
+    // http address to serve prometheus metrics
+    fn get_metrics_portal(&self) -> Option<SocketAddr>;
+    fn set_metrics_portal(&self, addr: Option<SocketAddr>);
+
+    // local address of socks5 server backed by the userspace stack of no tun mode
+    fn get_socks5_portal(&self) -> Option<SocketAddr>;
+    fn set_socks5_portal(&self, addr: Option<SocketAddr>);
+    fn get_socks5_auth(&self) -> Option<Socks5AuthConfig>;
+    fn set_socks5_auth(&self, config: Option<Socks5AuthConfig>);
+
+    fn get_port_forwards(&self) -> Vec<PortForwardConfig>;
+    fn set_port_forwards(&self, forwards: Vec<PortForwardConfig>);
+
     fn get_vpn_portal_config(&self) -> Option<VpnPortalConfig>;
     fn set_vpn_portal_config(&self, config: VpnPortalConfig);
 
+    fn get_dnat_rules(&self) -> Vec<DnatRuleConfig>;
+    fn set_dnat_rules(&self, rules: Vec<DnatRuleConfig>);
+
+    fn get_acl_rules(&self) -> Vec<AclRuleConfig>;
+    fn set_acl_rules(&self, rules: Vec<AclRuleConfig>);
+
+    fn get_dns_records(&self) -> Vec<DnsRecordConfig>;
+    fn set_dns_records(&self, records: Vec<DnsRecordConfig>);
+
+    // remote management through the mesh is disabled if not set
+    fn get_remote_management(&self) -> Option<RemoteManagementConfig>;
+    fn set_remote_management(&self, config: Option<RemoteManagementConfig>);
+
     fn get_flags(&self) -> Flags;
     fn set_flags(&self, flags: Flags);
 
+    // file the config is loaded from, used to reload the config
+    fn get_config_path(&self) -> Option<String> {
+        None
+    }
+
     fn dump(&self) -> String;
+
+    // apply update to the file the config is loaded from. the file is read again and only the
+    // update is applied to it, so command line overrides and runtime state are never written back.
+    fn save_with(&self, update: &dyn Fn(&dyn ConfigLoader)) -> Result<(), anyhow::Error> {
+        let Some(path) = self.get_config_path() else {
+            return Err(anyhow::anyhow!("config is not loaded from a file"));
+        };
+        let file_config = TomlConfigLoader::new(&path)?;
+        update(&file_config);
+        write_file_atomically(&path, &file_config.dump())
+    }
+}
+
+// write to a temp file next to path and rename it, so a crash never leaves a truncated config
+fn write_file_atomically(path: &str, content: &str) -> Result<(), anyhow::Error> {
+    use std::io::Write;
+    let tmp_path = format!("{}.tmp", path);
+    let write_tmp = || -> Result<(), std::io::Error> {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        // the config may hold secrets, keep the permissions of the original file
+        if let Ok(meta) = std::fs::metadata(path) {
+            file.set_permissions(meta.permissions())?;
+        }
+        file.write_all(content.as_bytes())?;
+        file.sync_all()
+    };
+    if let Err(e) = write_tmp() {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e).with_context(|| format!("failed to write config file: {}", tmp_path));
+    }
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("failed to replace config file: {}", path))
 }
 
 pub type NetworkSecretDigest = [u8; 32];
@@ -113,9 +225,86 @@ pub struct PeerConfig {
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct NetworkConfig {
     pub cidr: String,
+    // advertise the network under this cidr and translate addresses 1:1, so networks with the
+    // same cidr behind different nodes do not conflict
+    pub mapped_cidr: Option<String>,
     pub allow: Option<Vec<String>>,
 }
 
+pub fn check_proxy_cidr_mapping(
+    cidr: &cidr::IpCidr,
+    mapped_cidr: &cidr::IpCidr,
+) -> Result<(), anyhow::Error> {
+    match (cidr, mapped_cidr) {
+        (cidr::IpCidr::V4(c), cidr::IpCidr::V4(m)) if c.network_length() == m.network_length() => {
+            Ok(())
+        }
+        _ => Err(anyhow::anyhow!(
+            "mapped cidr {} must be ipv4 and have the same prefix length as {}",
+            mapped_cidr,
+            cidr
+        )),
+    }
+}
+
+fn cidr_overlaps(a: &cidr::IpCidr, b: &cidr::IpCidr) -> bool {
+    a.contains(&b.first_address()) || b.contains(&a.first_address())
+}
+
+// addresses in a mapped cidr are translated to the real network, so it must not overlap other
+// networks peers reach through this node or the virtual network
+pub fn check_proxy_cidr_mappings(
+    proxy_cidrs: &[cidr::IpCidr],
+    mappings: &[(cidr::IpCidr, cidr::IpCidr)],
+    virtual_network: Option<cidr::Ipv4Cidr>,
+) -> Result<(), anyhow::Error> {
+    for (cidr, mapped_cidr) in mappings {
+        check_proxy_cidr_mapping(cidr, mapped_cidr)?;
+        if let Some(other) = proxy_cidrs
+            .iter()
+            .find(|c| *c != cidr && cidr_overlaps(c, mapped_cidr))
+        {
+            return Err(anyhow::anyhow!(
+                "mapped cidr {} overlaps proxy network {}",
+                mapped_cidr,
+                other
+            ));
+        }
+        if let Some((other, _)) = mappings
+            .iter()
+            .find(|(c, m)| c != cidr && cidr_overlaps(m, mapped_cidr))
+        {
+            return Err(anyhow::anyhow!(
+                "mapped cidr {} overlaps the mapped cidr of proxy network {}",
+                mapped_cidr,
+                other
+            ));
+        }
+        if let Some(network) =
+            virtual_network.filter(|n| cidr_overlaps(&cidr::IpCidr::V4(*n), mapped_cidr))
+        {
+            return Err(anyhow::anyhow!(
+                "mapped cidr {} overlaps virtual network {}",
+                mapped_cidr,
+                network
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn check_proxy_networks(config: &dyn ConfigLoader) -> Result<(), anyhow::Error> {
+    let virtual_network = config
+        .get_ipv4_inet()
+        .map(|inet| inet.network())
+        .or(config.get_dhcp_network());
+    check_proxy_cidr_mappings(
+        &config.get_proxy_cidrs(),
+        &config.get_proxy_cidr_mappings(),
+        virtual_network,
+    )
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Default)]
 pub struct FileLoggerConfig {
     pub level: Option<String>,
@@ -134,9 +323,183 @@ pub struct VpnPortalConfig {
     pub wireguard_listen: SocketAddr,
 }
 
+// forward a local port to an address in the virtual network through the userspace stack
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct PortForwardConfig {
+    // tcp or udp
+    pub proto: String,
+    pub bind_addr: SocketAddr,
+    pub dst_addr: SocketAddr,
+}
+
+// tcp://127.0.0.1:8080/10.144.144.2:80
+impl std::str::FromStr for PortForwardConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "invalid port forward: {}, e.g. tcp://127.0.0.1:8080/10.144.144.2:80",
+                s
+            )
+        };
+        let (proto, rest) = s.split_once("://").ok_or_else(invalid)?;
+        let (bind_addr, dst_addr) = rest.split_once('/').ok_or_else(invalid)?;
+        if proto != "tcp" && proto != "udp" {
+            return Err(invalid());
+        }
+        Ok(PortForwardConfig {
+            proto: proto.to_string(),
+            bind_addr: bind_addr.parse().map_err(|_| invalid())?,
+            dst_addr: dst_addr.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+// forward a port of the virtual ip of this node to a host reachable by it, e.g. on its lan
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct DnatRuleConfig {
+    // tcp or udp
+    pub proto: String,
+    pub port: u16,
+    pub dst_addr: SocketAddr,
+}
+
+// tcp:8080/192.168.1.10:80
+impl std::str::FromStr for DnatRuleConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("invalid dnat rule: {}, e.g. tcp:8080/192.168.1.10:80", s);
+        let (proto, rest) = s.split_once(':').ok_or_else(invalid)?;
+        let (port, dst_addr) = rest.split_once('/').ok_or_else(invalid)?;
+        if proto != "tcp" && proto != "udp" {
+            return Err(invalid());
+        }
+        Ok(DnatRuleConfig {
+            proto: proto.to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+            dst_addr: dst_addr.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+// rules are evaluated in order on packets received from (or sent to) peers, the first matched
+// one wins. missing fields match anything.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Default)]
+pub struct AclRuleConfig {
+    // allow or drop
+    pub action: String,
+    // ip or cidr
+    pub src: Option<String>,
+    pub dst: Option<String>,
+    // tcp, udp, icmp or any
+    pub protocol: Option<String>,
+    // destination port or port range, e.g. 22 or 8000-9000
+    pub port: Option<String>,
+    // noise public key, hostname or instance id of the remote peer. a hostname or instance id
+    // is resolved to the public keys of the peers claiming it, an allow rule never matches a
+    // name claimed by more than one key.
+    pub peer: Option<String>,
+    // in (default) filters packets received from peers, out filters packets sent to peers,
+    // any filters both
+    pub direction: Option<String>,
+}
+
+// static record served by magic dns, a name without dot is placed in the network zone,
+// e.g. nas -> nas.<network_name>.et
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct DnsRecordConfig {
+    pub name: String,
+    pub addr: std::net::IpAddr,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Default)]
+pub struct RpcPortalAuthConfig {
+    // clients must send "authorization: Bearer <token>" if set
+    pub token: Option<String>,
+    // pem files of server certificate and key, tls is enabled if both are set
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    // pem file of ca to verify client certificates, clients must present a certificate if set
+    pub tls_client_ca: Option<String>,
+}
+
+// username and password clients of the socks5 portal must send (rfc 1929)
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Socks5AuthConfig {
+    pub username: String,
+    pub password: String,
+}
+
+impl Socks5AuthConfig {
+    // both are sent with a one byte length
+    pub fn check(&self) -> Result<(), anyhow::Error> {
+        if self.username.is_empty() || self.username.len() > 255 || self.password.len() > 255 {
+            return Err(anyhow::anyhow!(
+                "socks5 username must be 1 to 255 bytes and password at most 255 bytes"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// user:password
+impl std::str::FromStr for Socks5AuthConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (username, password) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("invalid socks5 auth, e.g. user:password"))?;
+        let config = Socks5AuthConfig {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        config.check()?;
+        Ok(config)
+    }
+}
+
+pub fn check_remote_management_allowed_peers(
+    allowed_peers: &[String],
+) -> Result<(), anyhow::Error> {
+    for peer in allowed_peers.iter().filter(|p| *p != "*") {
+        decode_key(peer).with_context(|| {
+            format!(
+                "remote management allowed peer is not a public key: {}",
+                peer
+            )
+        })?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Default)]
+pub struct RemoteManagementConfig {
+    // base64 encoded public keys of peers allowed to call management rpc of this node through
+    // the mesh, "*" allows every peer of the network
+    pub allowed_peers: Vec<String>,
+}
+
+// policies to choose which peer conn is used to send packets when we have multiple conns
+// (e.g. udp and tcp) to the same peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum ConnSelectPolicy {
+    // keep using the first conn until its loss rate exceeds the threshold.
+    Sticky,
+    #[default]
+    LowestLatency,
+    LowestLoss,
+    WeightedRoundRobin,
+}
+
 // Flags is used to control the behavior of the program
 #[derive(derivative::Derivative, Deserialize, Serialize)]
 #[derivative(Debug, Clone, PartialEq, Default)]
+#[serde(default)]
 pub struct Flags {
     #[derivative(Default(value = "\"tcp\".to_string()"))]
     pub default_protocol: String,
@@ -144,6 +507,50 @@ pub struct Flags {
     pub enable_encryption: bool,
     #[derivative(Default(value = "true"))]
     pub enable_ipv6: bool,
+    #[derivative(Default(value = "ConnSelectPolicy::LowestLatency"))]
+    pub conn_select_policy: ConnSelectPolicy,
+    #[derivative(Default(value = "0.2"))]
+    pub conn_failover_loss_rate: f32,
+    #[derivative(Default(value = "\"auto\".to_string()"))]
+    pub cipher_suite: String,
+    // session key of peer conn is rotated when any of these limits is reached
+    #[derivative(Default(value = "1 << 30"))]
+    pub rekey_after_bytes: u64,
+    #[derivative(Default(value = "1 << 20"))]
+    pub rekey_after_packets: u64,
+    #[derivative(Default(value = "120"))]
+    pub rekey_after_secs: u64,
+    #[derivative(Default(value = "10"))]
+    pub rekey_grace_secs: u64,
+    // action for received packets not matched by any acl rule
+    #[derivative(Default(value = "\"allow\".to_string()"))]
+    pub acl_default_action: String,
+    // answer dns queries for peer hostnames on the virtual ip
+    #[derivative(Default(value = "false"))]
+    pub enable_magic_dns: bool,
+    // advertise this node as an exit node, peers selecting it send internet traffic through it
+    #[derivative(Default(value = "false"))]
+    pub enable_exit_node: bool,
+    // carry ethernet frames with a tap device instead of ip packets with a tun device, all nodes
+    // of the network must use the same mode
+    #[derivative(Default(value = "false"))]
+    pub enable_tap: bool,
+    // linux bridge the tap device is attached to, e.g. a bridge of a physical lan
+    #[derivative(Default(value = "\"\".to_string()"))]
+    pub tap_bridge: String,
+    // terminate traffic of the virtual network with a userspace stack instead of a tun device,
+    // it is reachable through the socks5 portal and port forwards
+    #[derivative(Default(value = "false"))]
+    pub no_tun: bool,
+    // pem files of certificate chain and private key of wss listeners, a self signed certificate
+    // is generated if not set
+    #[derivative(Default(value = "\"\".to_string()"))]
+    pub ws_tls_cert: String,
+    #[derivative(Default(value = "\"\".to_string()"))]
+    pub ws_tls_key: String,
+    // do not verify certificates of wss servers, needed for servers with self signed certificates
+    #[derivative(Default(value = "false"))]
+    pub ws_tls_insecure: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -151,26 +558,48 @@ struct Config {
     netns: Option<String>,
     instance_name: Option<String>,
     instance_id: Option<uuid::Uuid>,
+    hostname: Option<String>,
     ipv4: Option<String>,
+    ipv6: Option<String>,
+    dhcp_network: Option<cidr::Ipv4Cidr>,
     network_identity: Option<NetworkIdentity>,
+    private_key: Option<String>,
+    private_key_file: Option<String>,
+    trusted_public_keys: Option<Vec<String>>,
     listeners: Option<Vec<url::Url>>,
 
     peer: Option<Vec<PeerConfig>>,
     proxy_network: Option<Vec<NetworkConfig>>,
+    exit_nodes: Option<Vec<Ipv4Addr>>,
 
     file_logger: Option<FileLoggerConfig>,
     console_logger: Option<ConsoleLoggerConfig>,
 
     rpc_portal: Option<SocketAddr>,
+    rpc_portal_auth: Option<RpcPortalAuthConfig>,
+    metrics_portal: Option<SocketAddr>,
+    socks5_portal: Option<SocketAddr>,
+    socks5_auth: Option<Socks5AuthConfig>,
+
+    port_forward: Option<Vec<PortForwardConfig>>,
 
     vpn_portal_config: Option<VpnPortalConfig>,
 
+    dnat: Option<Vec<DnatRuleConfig>>,
+
+    acl: Option<Vec<AclRuleConfig>>,
+
+    dns_record: Option<Vec<DnsRecordConfig>>,
+
+    remote_management: Option<RemoteManagementConfig>,
+
     flags: Option<Flags>,
 }
 
 #[derive(Debug, Clone)]
 pub struct TomlConfigLoader {
     config: Arc<Mutex<Config>>,
+    config_path: Option<String>,
 }
 
 impl Default for TomlConfigLoader {
@@ -187,15 +616,73 @@ impl TomlConfigLoader {
                 config_str, config_str
             )
         })?;
-        Ok(TomlConfigLoader {
+        // get_ipv4_inet and get_ipv6 assume the addresses are valid
+        if let Some(ipv4) = &config.ipv4 {
+            let valid = if ipv4.contains('/') {
+                ipv4.parse::<cidr::Ipv4Inet>().is_ok()
+            } else {
+                ipv4.parse::<std::net::Ipv4Addr>().is_ok()
+            };
+            if !valid {
+                return Err(anyhow::anyhow!("failed to parse ipv4 address: {}", ipv4));
+            }
+        }
+        if let Some(ipv6) = &config.ipv6 {
+            ipv6.parse::<cidr::Ipv6Inet>()
+                .with_context(|| format!("failed to parse ipv6 address: {}", ipv6))?;
+        }
+        // a node with an invalid key can not start, reject it here instead of panicking later
+        if let Some(path) = &config.private_key_file {
+            read_private_key_file(path)?;
+        } else if let Some(key) = &config.private_key {
+            decode_key(key).with_context(|| "failed to parse private key")?;
+        }
+        for key in config.trusted_public_keys.iter().flatten() {
+            decode_key(key)
+                .with_context(|| format!("failed to parse trusted public key: {}", key))?;
+        }
+        if let Some(remote_management) = &config.remote_management {
+            check_remote_management_allowed_peers(&remote_management.allowed_peers)?;
+        }
+        if let Some(socks5_auth) = &config.socks5_auth {
+            socks5_auth.check()?;
+        }
+        // acl rules are installed when the instance starts, an invalid one would stop it
+        AclFilter::check_rules(
+            config.acl.as_deref().unwrap_or_default(),
+            &config.flags.clone().unwrap_or_default().acl_default_action,
+        )?;
+        DnatRules::check_rules(config.dnat.as_deref().unwrap_or_default())?;
+        // get_proxy_cidrs assumes all cidrs are valid
+        for network in config.proxy_network.iter().flatten() {
+            let cidr = network
+                .cidr
+                .parse::<cidr::IpCidr>()
+                .with_context(|| format!("failed to parse proxy network: {}", network.cidr))?;
+            if let Some(mapped_cidr) = &network.mapped_cidr {
+                let mapped_cidr = mapped_cidr.parse::<cidr::IpCidr>().with_context(|| {
+                    format!(
+                        "failed to parse mapped cidr of proxy network: {}",
+                        mapped_cidr
+                    )
+                })?;
+                check_proxy_cidr_mapping(&cidr, &mapped_cidr)?;
+            }
+        }
+        let ret = TomlConfigLoader {
             config: Arc::new(Mutex::new(config)),
-        })
+            config_path: None,
+        };
+        check_proxy_networks(&ret)?;
+        Ok(ret)
     }
 
     pub fn new(config_path: &str) -> Result<Self, anyhow::Error> {
         let config_str = std::fs::read_to_string(config_path)
             .with_context(|| format!("failed to read config file: {}", config_path))?;
-        Self::new_from_str(&config_str)
+        let mut ret = Self::new_from_str(&config_str)?;
+        ret.config_path = Some(config_path.to_string());
+        Ok(ret)
     }
 }
 
@@ -213,6 +700,14 @@ impl ConfigLoader for TomlConfigLoader {
         self.config.lock().unwrap().instance_name = Some(name);
     }
 
+    fn get_hostname(&self) -> Option<String> {
+        self.config.lock().unwrap().hostname.clone()
+    }
+
+    fn set_hostname(&self, hostname: Option<String>) {
+        self.config.lock().unwrap().hostname = hostname;
+    }
+
     fn get_netns(&self) -> Option<String> {
         self.config.lock().unwrap().netns.clone()
     }
@@ -222,16 +717,65 @@ impl ConfigLoader for TomlConfigLoader {
     }
 
     fn get_ipv4(&self) -> Option<std::net::Ipv4Addr> {
+        self.get_ipv4_inet().map(|inet| inet.address())
+    }
+
+    fn set_ipv4(&self, addr: std::net::Ipv4Addr) {
+        // keep the configured prefix length
+        let network_length = self
+            .get_ipv4_inet()
+            .map(|inet| inet.network_length())
+            .unwrap_or(DEFAULT_IPV4_NETWORK_LENGTH);
+        self.set_ipv4_inet(cidr::Ipv4Inet::new(addr, network_length).unwrap());
+    }
+
+    fn get_ipv4_inet(&self) -> Option<cidr::Ipv4Inet> {
+        let locked_config = self.config.lock().unwrap();
+        let s = locked_config.ipv4.as_ref()?;
+        // address without prefix length is treated as /24 for compatibility
+        if s.contains('/') {
+            s.parse().ok()
+        } else {
+            cidr::Ipv4Inet::new(s.parse().ok()?, DEFAULT_IPV4_NETWORK_LENGTH).ok()
+        }
+    }
+
+    fn set_ipv4_inet(&self, addr: cidr::Ipv4Inet) {
+        self.config.lock().unwrap().ipv4 = Some(addr.to_string());
+    }
+
+    fn get_dhcp_network(&self) -> Option<cidr::Ipv4Cidr> {
+        self.config.lock().unwrap().dhcp_network
+    }
+
+    fn set_dhcp_network(&self, network: Option<cidr::Ipv4Cidr>) {
+        self.config.lock().unwrap().dhcp_network = network;
+    }
+
+    fn get_exit_nodes(&self) -> Vec<Ipv4Addr> {
+        self.config
+            .lock()
+            .unwrap()
+            .exit_nodes
+            .clone()
+            .unwrap_or_default()
+    }
+
+    fn set_exit_nodes(&self, nodes: Vec<Ipv4Addr>) {
+        self.config.lock().unwrap().exit_nodes = Some(nodes);
+    }
+
+    fn get_ipv6(&self) -> Option<cidr::Ipv6Inet> {
         let locked_config = self.config.lock().unwrap();
         locked_config
-            .ipv4
+            .ipv6
             .as_ref()
             .map(|s| s.parse().ok())
             .flatten()
     }
 
-    fn set_ipv4(&self, addr: std::net::Ipv4Addr) {
-        self.config.lock().unwrap().ipv4 = Some(addr.to_string());
+    fn set_ipv6(&self, addr: cidr::Ipv6Inet) {
+        self.config.lock().unwrap().ipv6 = Some(addr.to_string());
     }
 
     fn add_proxy_cidr(&self, cidr: cidr::IpCidr) {
@@ -254,6 +798,7 @@ impl ConfigLoader for TomlConfigLoader {
                 .unwrap()
                 .push(NetworkConfig {
                     cidr: cidr_str,
+                    mapped_cidr: None,
                     allow: None,
                 });
         }
@@ -281,6 +826,32 @@ impl ConfigLoader for TomlConfigLoader {
             .unwrap_or_default()
     }
 
+    fn get_proxy_cidr_mappings(&self) -> Vec<(cidr::IpCidr, cidr::IpCidr)> {
+        self.config
+            .lock()
+            .unwrap()
+            .proxy_network
+            .iter()
+            .flatten()
+            .filter_map(|c| {
+                Some((
+                    c.cidr.parse().unwrap(),
+                    c.mapped_cidr.as_ref()?.parse().unwrap(),
+                ))
+            })
+            .collect()
+    }
+
+    fn set_proxy_cidr_mapping(&self, cidr: cidr::IpCidr, mapped_cidr: Option<cidr::IpCidr>) {
+        let mut locked_config = self.config.lock().unwrap();
+        let cidr_str = cidr.to_string();
+        for network in locked_config.proxy_network.iter_mut().flatten() {
+            if network.cidr == cidr_str {
+                network.mapped_cidr = mapped_cidr.map(|c| c.to_string());
+            }
+        }
+    }
+
     fn get_id(&self) -> uuid::Uuid {
         let mut locked_config = self.config.lock().unwrap();
         if locked_config.instance_id.is_none() {
@@ -296,6 +867,10 @@ impl ConfigLoader for TomlConfigLoader {
         self.config.lock().unwrap().instance_id = Some(id);
     }
 
+    fn get_configured_id(&self) -> Option<uuid::Uuid> {
+        self.config.lock().unwrap().instance_id
+    }
+
     fn get_network_identity(&self) -> NetworkIdentity {
         self.config
             .lock()
@@ -309,6 +884,36 @@ impl ConfigLoader for TomlConfigLoader {
         self.config.lock().unwrap().network_identity = Some(identity);
     }
 
+    fn get_private_key(&self) -> Option<String> {
+        let config = self.config.lock().unwrap();
+        if let Some(path) = &config.private_key_file {
+            return read_private_key_file(path)
+                .map_err(|e| tracing::error!(?e, "failed to read private key file"))
+                .ok();
+        }
+        config.private_key.clone()
+    }
+
+    fn set_private_key(&self, key: Option<String>) {
+        self.config.lock().unwrap().private_key = key;
+    }
+
+    fn get_private_key_file(&self) -> Option<String> {
+        self.config.lock().unwrap().private_key_file.clone()
+    }
+
+    fn set_private_key_file(&self, path: Option<String>) {
+        self.config.lock().unwrap().private_key_file = path;
+    }
+
+    fn get_trusted_public_keys(&self) -> Option<Vec<String>> {
+        self.config.lock().unwrap().trusted_public_keys.clone()
+    }
+
+    fn set_trusted_public_keys(&self, keys: Option<Vec<String>>) {
+        self.config.lock().unwrap().trusted_public_keys = keys;
+    }
+
     fn get_listener_uris(&self) -> Vec<url::Url> {
         self.config
             .lock()
@@ -373,6 +978,51 @@ impl ConfigLoader for TomlConfigLoader {
         self.config.lock().unwrap().rpc_portal = Some(addr);
     }
 
+    fn get_rpc_portal_auth(&self) -> Option<RpcPortalAuthConfig> {
+        self.config.lock().unwrap().rpc_portal_auth.clone()
+    }
+
+    fn set_rpc_portal_auth(&self, config: Option<RpcPortalAuthConfig>) {
+        self.config.lock().unwrap().rpc_portal_auth = config;
+    }
+
+    fn get_metrics_portal(&self) -> Option<SocketAddr> {
+        self.config.lock().unwrap().metrics_portal
+    }
+
+    fn set_metrics_portal(&self, addr: Option<SocketAddr>) {
+        self.config.lock().unwrap().metrics_portal = addr;
+    }
+
+    fn get_socks5_portal(&self) -> Option<SocketAddr> {
+        self.config.lock().unwrap().socks5_portal
+    }
+
+    fn set_socks5_portal(&self, addr: Option<SocketAddr>) {
+        self.config.lock().unwrap().socks5_portal = addr;
+    }
+
+    fn get_socks5_auth(&self) -> Option<Socks5AuthConfig> {
+        self.config.lock().unwrap().socks5_auth.clone()
+    }
+
+    fn set_socks5_auth(&self, config: Option<Socks5AuthConfig>) {
+        self.config.lock().unwrap().socks5_auth = config;
+    }
+
+    fn get_port_forwards(&self) -> Vec<PortForwardConfig> {
+        self.config
+            .lock()
+            .unwrap()
+            .port_forward
+            .clone()
+            .unwrap_or_default()
+    }
+
+    fn set_port_forwards(&self, forwards: Vec<PortForwardConfig>) {
+        self.config.lock().unwrap().port_forward = Some(forwards);
+    }
+
     fn get_vpn_portal_config(&self) -> Option<VpnPortalConfig> {
         self.config.lock().unwrap().vpn_portal_config.clone()
     }
@@ -380,6 +1030,43 @@ impl ConfigLoader for TomlConfigLoader {
         self.config.lock().unwrap().vpn_portal_config = Some(config);
     }
 
+    fn get_dnat_rules(&self) -> Vec<DnatRuleConfig> {
+        self.config.lock().unwrap().dnat.clone().unwrap_or_default()
+    }
+
+    fn set_dnat_rules(&self, rules: Vec<DnatRuleConfig>) {
+        self.config.lock().unwrap().dnat = Some(rules);
+    }
+
+    fn get_acl_rules(&self) -> Vec<AclRuleConfig> {
+        self.config.lock().unwrap().acl.clone().unwrap_or_default()
+    }
+
+    fn set_acl_rules(&self, rules: Vec<AclRuleConfig>) {
+        self.config.lock().unwrap().acl = Some(rules);
+    }
+
+    fn get_dns_records(&self) -> Vec<DnsRecordConfig> {
+        self.config
+            .lock()
+            .unwrap()
+            .dns_record
+            .clone()
+            .unwrap_or_default()
+    }
+
+    fn set_dns_records(&self, records: Vec<DnsRecordConfig>) {
+        self.config.lock().unwrap().dns_record = Some(records);
+    }
+
+    fn get_remote_management(&self) -> Option<RemoteManagementConfig> {
+        self.config.lock().unwrap().remote_management.clone()
+    }
+
+    fn set_remote_management(&self, config: Option<RemoteManagementConfig>) {
+        self.config.lock().unwrap().remote_management = config;
+    }
+
     fn get_flags(&self) -> Flags {
         self.config
             .lock()
@@ -393,6 +1080,10 @@ impl ConfigLoader for TomlConfigLoader {
         self.config.lock().unwrap().flags = Some(flags);
     }
 
+    fn get_config_path(&self) -> Option<String> {
+        self.config_path.clone()
+    }
+
     fn dump(&self) -> String {
         toml::to_string_pretty(&*self.config.lock().unwrap()).unwrap()
     }
@@ -402,13 +1093,73 @@ impl ConfigLoader for TomlConfigLoader {
 pub mod tests {
     use super::*;
 
+    #[test]
+    fn parse_port_forward() {
+        let forward: PortForwardConfig = "udp://0.0.0.0:5353/10.144.144.2:53".parse().unwrap();
+        assert_eq!("udp", forward.proto);
+        assert_eq!("0.0.0.0:5353", forward.bind_addr.to_string());
+        assert_eq!("10.144.144.2:53", forward.dst_addr.to_string());
+
+        for s in [
+            "icmp://0.0.0.0:5353/10.144.144.2:53",
+            "tcp://0.0.0.0:5353",
+            "tcp://0.0.0.0/10.144.144.2:53",
+        ] {
+            assert!(s.parse::<PortForwardConfig>().is_err());
+        }
+    }
+
+    #[test]
+    fn parse_dnat_rule() {
+        let rule: DnatRuleConfig = "udp:5353/192.168.1.10:53".parse().unwrap();
+        assert_eq!("udp", rule.proto);
+        assert_eq!(5353, rule.port);
+        assert_eq!("192.168.1.10:53", rule.dst_addr.to_string());
+
+        for s in [
+            "icmp:8080/192.168.1.10:80",
+            "tcp:8080",
+            "tcp:http/192.168.1.10:80",
+            "tcp:8080/192.168.1.10",
+        ] {
+            assert!(s.parse::<DnatRuleConfig>().is_err());
+        }
+    }
+
+    #[test]
+    fn parse_socks5_auth() {
+        let auth: Socks5AuthConfig = "alice:p:ss".parse().unwrap();
+        assert_eq!("alice", auth.username);
+        assert_eq!("p:ss", auth.password);
+
+        let long = "x".repeat(256);
+        for s in [
+            "alice",
+            ":pass",
+            &format!("{}:pass", long),
+            &format!("alice:{}", long),
+        ] {
+            assert!(s.parse::<Socks5AuthConfig>().is_err());
+        }
+        assert!(TomlConfigLoader::new_from_str(
+            "[socks5_auth]\nusername = \"\"\npassword = \"pass\"\n"
+        )
+        .is_err());
+    }
+
     #[tokio::test]
     async fn full_example_test() {
         let config_str = r#"
 instance_name = "default"
 instance_id = "87ede5a2-9c3d-492d-9bbe-989b9d07e742"
+hostname = "build-server"
 ipv4 = "10.144.144.10"
+ipv6 = "fd00::1/64"
+dhcp_network = "10.144.144.0/24"
 listeners = [ "tcp://0.0.0.0:11010", "udp://0.0.0.0:11010" ]
+private_key = "2KQyyCFv5n4qZmtKU3h8GZu4+0ACSYdgFsSgW8IX0lI="
+trusted_public_keys = [ "0w1pS8dS4tTQlhEcRrCgvdKDIkvYXdwJDWBKt9SmmTg=" ]
+exit_nodes = [ "10.144.144.1", "10.144.144.2" ]
 
 [network_identity]
 network_name = "default"
@@ -428,6 +1179,42 @@ allow = ["tcp", "udp", "icmp"]
 cidr = "10.1.1.0/24"
 allow = ["tcp", "icmp"]
 
+[[proxy_network]]
+cidr = "192.168.1.0/24"
+mapped_cidr = "10.200.1.0/24"
+
+[[acl]]
+action = "drop"
+src = "10.144.144.0/24"
+protocol = "tcp"
+port = "22"
+
+[[acl]]
+action = "allow"
+peer = "build-server"
+
+[[dns_record]]
+name = "nas"
+addr = "10.144.144.100"
+
+[remote_management]
+allowed_peers = ["2KQyyCFv5n4qZmtKU3h8GZu4+0ACSYdgFsSgW8IX0lI="]
+
+[[port_forward]]
+proto = "tcp"
+bind_addr = "127.0.0.1:8080"
+dst_addr = "10.144.144.2:80"
+
+[[dnat]]
+proto = "tcp"
+port = 8080
+dst_addr = "192.168.1.10:80"
+
+[rpc_portal_auth]
+token = "secret-token"
+tls_cert = "/etc/easytier/rpc.pem"
+tls_key = "/etc/easytier/rpc.key"
+
 [file_logger]
 level = "info"
 file = "easytier"
@@ -446,6 +1233,22 @@ level = "warn"
 
         let ret = ret.unwrap();
         assert_eq!("10.144.144.10", ret.get_ipv4().unwrap().to_string());
+        assert_eq!("10.144.144.10/24", ret.get_ipv4_inet().unwrap().to_string());
+        assert_eq!("fd00::1/64", ret.get_ipv6().unwrap().to_string());
+        assert_eq!(
+            "10.144.144.0/24",
+            ret.get_dhcp_network().unwrap().to_string()
+        );
+        assert_eq!(
+            Some("2KQyyCFv5n4qZmtKU3h8GZu4+0ACSYdgFsSgW8IX0lI=".to_string()),
+            ret.get_private_key()
+        );
+        assert_eq!(
+            Some(vec![
+                "0w1pS8dS4tTQlhEcRrCgvdKDIkvYXdwJDWBKt9SmmTg=".to_string()
+            ]),
+            ret.get_trusted_public_keys()
+        );
 
         assert_eq!(
             vec!["tcp://0.0.0.0:11010", "udp://0.0.0.0:11010"],
@@ -455,6 +1258,289 @@ level = "warn"
                 .collect::<Vec<String>>()
         );
 
+        assert_eq!(
+            vec!["10.144.144.1", "10.144.144.2"],
+            ret.get_exit_nodes()
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect::<Vec<String>>()
+        );
+
+        assert_eq!(Some("build-server".to_string()), ret.get_hostname());
+
+        let acl_rules = ret.get_acl_rules();
+        assert_eq!(2, acl_rules.len());
+        assert_eq!("drop", acl_rules[0].action);
+        assert_eq!(Some("22".to_string()), acl_rules[0].port);
+        assert_eq!(None, acl_rules[0].peer);
+        assert_eq!(Some("build-server".to_string()), acl_rules[1].peer);
+
+        let dns_records = ret.get_dns_records();
+        assert_eq!(1, dns_records.len());
+        assert_eq!("nas", dns_records[0].name);
+        assert_eq!("10.144.144.100", dns_records[0].addr.to_string());
+
+        assert_eq!(
+            vec!["2KQyyCFv5n4qZmtKU3h8GZu4+0ACSYdgFsSgW8IX0lI="],
+            ret.get_remote_management().unwrap().allowed_peers
+        );
+
+        let port_forwards = ret.get_port_forwards();
+        assert_eq!(1, port_forwards.len());
+        assert_eq!("tcp", port_forwards[0].proto);
+        assert_eq!("10.144.144.2:80", port_forwards[0].dst_addr.to_string());
+
+        let dnat_rules = ret.get_dnat_rules();
+        assert_eq!(1, dnat_rules.len());
+        assert_eq!(8080, dnat_rules[0].port);
+        assert_eq!("192.168.1.10:80", dnat_rules[0].dst_addr.to_string());
+
+        let rpc_portal_auth = ret.get_rpc_portal_auth().unwrap();
+        assert_eq!(Some("secret-token".to_string()), rpc_portal_auth.token);
+        assert_eq!(None, rpc_portal_auth.tls_client_ca);
+
         println!("{}", ret.dump());
     }
+
+    #[tokio::test]
+    async fn ipv4_prefix_test() {
+        let ret = TomlConfigLoader::new_from_str(r#"ipv4 = "10.144.0.10/16""#).unwrap();
+        assert_eq!("10.144.0.10", ret.get_ipv4().unwrap().to_string());
+        assert_eq!(16, ret.get_ipv4_inet().unwrap().network_length());
+
+        ret.set_ipv4("10.144.144.1".parse().unwrap());
+        assert_eq!("10.144.144.1/16", ret.get_ipv4_inet().unwrap().to_string());
+
+        ret.set_ipv4_inet("10.1.2.3/20".parse().unwrap());
+        assert_eq!("10.1.2.3/20", ret.get_ipv4_inet().unwrap().to_string());
+        assert_eq!("10.1.2.3", ret.get_ipv4().unwrap().to_string());
+
+        let ret = TomlConfigLoader::default();
+        ret.set_ipv4("10.144.144.1".parse().unwrap());
+        assert_eq!("10.144.144.1/24", ret.get_ipv4_inet().unwrap().to_string());
+
+        assert!(TomlConfigLoader::new_from_str(r#"ipv4 = "10.144.0.10/33""#).is_err());
+        assert!(TomlConfigLoader::new_from_str(r#"ipv4 = "10.144.0""#).is_err());
+    }
+
+    #[test]
+    fn invalid_proxy_network_test() {
+        let ret = TomlConfigLoader::new_from_str(
+            r#"
+[[proxy_network]]
+cidr = "10.1.1.0/33"
+"#,
+        );
+        assert!(ret.is_err());
+
+        // mapped cidr must have the same size
+        let ret = TomlConfigLoader::new_from_str(
+            r#"
+[[proxy_network]]
+cidr = "192.168.1.0/24"
+mapped_cidr = "10.200.0.0/16"
+"#,
+        );
+        assert!(ret.is_err());
+
+        // mapped cidr must not overlap other proxy networks or the virtual network
+        for (mapped_cidr, ipv4) in [
+            ("192.168.2.0/24", "10.144.144.1"),
+            ("10.200.1.0/24", "10.144.144.1"),
+            ("10.144.144.0/24", "10.144.144.1/16"),
+        ] {
+            let ret = TomlConfigLoader::new_from_str(&format!(
+                r#"
+ipv4 = "{}"
+
+[[proxy_network]]
+cidr = "192.168.1.0/24"
+mapped_cidr = "{}"
+
+[[proxy_network]]
+cidr = "192.168.2.0/24"
+mapped_cidr = "10.200.1.0/24"
+"#,
+                ipv4, mapped_cidr
+            ));
+            assert!(ret.is_err(), "{}", mapped_cidr);
+        }
+    }
+
+    #[test]
+    fn check_proxy_cidr_mappings_test() {
+        let cidr = |s: &str| s.parse::<cidr::IpCidr>().unwrap();
+        let proxy_cidrs = [cidr("192.168.1.0/24"), cidr("192.168.2.0/24")];
+        let virtual_network = Some("10.144.144.0/24".parse().unwrap());
+        let check = |mappings: &[(&str, &str)]| {
+            let mappings = mappings
+                .iter()
+                .map(|(c, m)| (cidr(c), cidr(m)))
+                .collect::<Vec<_>>();
+            check_proxy_cidr_mappings(&proxy_cidrs, &mappings, virtual_network)
+        };
+
+        assert!(check(&[
+            ("192.168.1.0/24", "10.200.1.0/24"),
+            ("192.168.2.0/24", "10.200.2.0/24")
+        ])
+        .is_ok());
+        // a network may be mapped to itself
+        assert!(check(&[("192.168.1.0/24", "192.168.1.0/24")]).is_ok());
+
+        assert!(check(&[("192.168.1.0/24", "192.168.2.0/24")]).is_err());
+        assert!(check(&[
+            ("192.168.1.0/24", "10.200.1.0/24"),
+            ("192.168.2.0/24", "10.200.1.0/24")
+        ])
+        .is_err());
+        assert!(check(&[("192.168.1.0/24", "10.144.144.0/24")]).is_err());
+        let proxy_cidrs = [cidr("10.0.0.0/8"), cidr("192.168.1.0/24")];
+        assert!(check_proxy_cidr_mappings(
+            &proxy_cidrs,
+            &[(cidr("192.168.1.0/24"), cidr("10.200.1.0/24"))],
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn conn_select_policy_test() {
+        let config = TomlConfigLoader::new_from_str(
+            r#"
+[flags]
+conn_select_policy = "weighted_round_robin"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            ConnSelectPolicy::WeightedRoundRobin,
+            config.get_flags().conn_select_policy
+        );
+
+        // a typo is an error instead of falling back to the default
+        let ret = TomlConfigLoader::new_from_str(
+            r#"
+[flags]
+conn_select_policy = "lowest_lag"
+"#,
+        );
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn invalid_ipv6_test() {
+        let ret = TomlConfigLoader::new_from_str(r#"ipv6 = "fd00::1/129""#);
+        assert!(ret.is_err());
+        let ret = TomlConfigLoader::new_from_str(r#"ipv6 = "10.0.0.1/24""#);
+        assert!(ret.is_err());
+
+        let config = TomlConfigLoader::new_from_str(r#"ipv6 = "fd00::1/64""#).unwrap();
+        assert_eq!("fd00::1/64", config.get_ipv6().unwrap().to_string());
+    }
+
+    #[test]
+    fn invalid_acl_test() {
+        let ret = TomlConfigLoader::new_from_str("[[acl]]\naction = \"drop\"\nport = \"ssh\"");
+        assert!(ret.is_err());
+        let ret = TomlConfigLoader::new_from_str("[flags]\nacl_default_action = \"reject\"");
+        assert!(ret.is_err());
+
+        let config =
+            TomlConfigLoader::new_from_str("[[acl]]\naction = \"drop\"\nport = \"22\"").unwrap();
+        assert_eq!(1, config.get_acl_rules().len());
+    }
+
+    #[test]
+    fn invalid_dnat_test() {
+        let rule = "[[dnat]]\nproto = \"tcp\"\nport = 8080\ndst_addr = \"192.168.1.10:80\"\n";
+        let ret = TomlConfigLoader::new_from_str(&format!("{}{}", rule, rule));
+        assert!(ret.is_err());
+        let ret = TomlConfigLoader::new_from_str(
+            "[[dnat]]\nproto = \"tcp\"\nport = 8080\ndst_addr = \"[fd00::1]:80\"",
+        );
+        assert!(ret.is_err());
+
+        let config = TomlConfigLoader::new_from_str(rule).unwrap();
+        assert_eq!(1, config.get_dnat_rules().len());
+    }
+
+    #[test]
+    fn private_key_test() {
+        let ret = TomlConfigLoader::new_from_str(r#"private_key = "not a key""#);
+        assert!(ret.is_err());
+        let ret = TomlConfigLoader::new_from_str(r#"trusted_public_keys = [ "not a key" ]"#);
+        assert!(ret.is_err());
+        let ret =
+            TomlConfigLoader::new_from_str("[remote_management]\nallowed_peers = [ \"laptop\" ]");
+        assert!(ret.is_err());
+        let ret = TomlConfigLoader::new_from_str("[remote_management]\nallowed_peers = [ \"*\" ]");
+        assert!(ret.is_ok());
+
+        let config = TomlConfigLoader::new_from_str("").unwrap();
+        assert_eq!(None, config.get_trusted_public_keys());
+        let config = TomlConfigLoader::new_from_str("trusted_public_keys = []").unwrap();
+        assert_eq!(Some(vec![]), config.get_trusted_public_keys());
+
+        let key = "2KQyyCFv5n4qZmtKU3h8GZu4+0ACSYdgFsSgW8IX0lI=";
+        let path = std::env::temp_dir().join(format!("easytier-key-{}", std::process::id()));
+        std::fs::write(&path, format!("{}\n", key)).unwrap();
+        let config = TomlConfigLoader::new_from_str(&format!(
+            "private_key_file = {:?}",
+            path.to_str().unwrap()
+        ))
+        .unwrap();
+        assert_eq!(Some(key.to_string()), config.get_private_key());
+
+        std::fs::write(&path, "not a key").unwrap();
+        let ret = TomlConfigLoader::new_from_str(&format!(
+            "private_key_file = {:?}",
+            path.to_str().unwrap()
+        ));
+        assert!(ret.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn save_with_test() {
+        let path = std::env::temp_dir().join(format!("easytier-save-{}.toml", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "instance_name = \"saved\"\n").unwrap();
+
+        let config = TomlConfigLoader::new(path).unwrap();
+        // runtime state, e.g. a command line override, must not end up in the file
+        config.set_ipv4("10.144.144.1".parse().unwrap());
+        let peer = PeerConfig {
+            uri: "tcp://1.2.3.4:11010".parse().unwrap(),
+        };
+        config
+            .save_with(&|c: &dyn ConfigLoader| c.set_peers(vec![peer.clone()]))
+            .unwrap();
+
+        let saved = TomlConfigLoader::new(path).unwrap();
+        assert_eq!("saved", saved.get_inst_name());
+        assert_eq!(vec![peer], saved.get_peers());
+        assert_eq!(None, saved.get_ipv4());
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+        std::fs::remove_file(path).unwrap();
+
+        let config = TomlConfigLoader::default();
+        assert!(config.save_with(&|_: &dyn ConfigLoader| {}).is_err());
+    }
+
+    #[test]
+    fn proxy_cidr_mapping_test() {
+        let config = TomlConfigLoader::default();
+        let cidr: cidr::IpCidr = "192.168.1.0/24".parse().unwrap();
+        let mapped_cidr: cidr::IpCidr = "10.200.1.0/24".parse().unwrap();
+        config.add_proxy_cidr(cidr);
+        assert!(config.get_proxy_cidr_mappings().is_empty());
+
+        config.set_proxy_cidr_mapping(cidr, Some(mapped_cidr));
+        assert_eq!(vec![(cidr, mapped_cidr)], config.get_proxy_cidr_mappings());
+        assert_eq!(vec![cidr], config.get_proxy_cidrs());
+
+        config.set_proxy_cidr_mapping(cidr, None);
+        assert!(config.get_proxy_cidr_mappings().is_empty());
+    }
 }
@@ -47,6 +47,15 @@ pub enum Error {
 
     #[error("secret key error: {0}")]
     SecretKeyError(String),
+
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+
+    #[error("noise handshake error: {0}")]
+    NoiseError(#[from] snow::Error),
+
+    #[error("untrusted public key: {0}")]
+    UntrustedPublicKey(String),
 }
 
 pub type Result<T> = result::Result<T, Error>;
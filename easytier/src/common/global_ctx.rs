@@ -5,18 +5,24 @@ use std::{
 };
 
 use crate::rpc::PeerConnInfo;
+use blake2::{Blake2b512, Blake2s256, Digest};
 use crossbeam::atomic::AtomicCell;
 
 use super::{
-    config::{ConfigLoader, Flags},
+    config::{check_proxy_cidr_mappings, ConfigLoader, Flags},
+    error::Error,
     netns::NetNS,
     network::IPCollector,
+    noise::{decode_key, PeerPublicKeys, PublicKey, StaticKeypair},
     stun::{StunInfoCollector, StunInfoCollectorTrait},
     PeerId,
 };
 
 pub type NetworkIdentity = crate::common::config::NetworkIdentity;
 
+// advertised in proxy cidrs by exit nodes, only peers selecting the node as exit node route to it
+pub const EXIT_NODE_CIDR: &str = "0.0.0.0/0";
+
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum GlobalCtxEvent {
     TunDeviceReady(String),
@@ -27,6 +33,7 @@ pub enum GlobalCtxEvent {
     PeerConnRemoved(PeerConnInfo),
 
     ListenerAdded(url::Url),
+    ListenerRemoved(url::Url),
     ConnectionAccepted(String, String), // (local url, remote url)
     ConnectionError(String, String, String), // (local url, remote url, error message)
 
@@ -35,6 +42,14 @@ pub enum GlobalCtxEvent {
 
     VpnPortalClientConnected(String, String), // (portal, client ip)
     VpnPortalClientDisconnected(String, String), // (portal, client ip)
+
+    DhcpIpv4Changed(Option<cidr::Ipv4Inet>, Option<cidr::Ipv4Inet>), // (old, new)
+    // (ipv4, peers claiming it as "hostname(inst_id)"), the first peer owns the address
+    DuplicateIpv4(String, Vec<String>),
+
+    ExitNodeChanged(Option<std::net::Ipv4Addr>),
+
+    ConfigReloaded,
 }
 
 type EventBus = tokio::sync::broadcast::Sender<GlobalCtxEvent>;
@@ -49,7 +64,10 @@ pub struct GlobalCtx {
 
     event_bus: EventBus,
 
-    cached_ipv4: AtomicCell<Option<std::net::Ipv4Addr>>,
+    cached_ipv4: AtomicCell<Option<cidr::Ipv4Inet>>,
+    // kept out of config so it's not written to the config file
+    dhcp_ipv4: AtomicCell<Option<cidr::Ipv4Inet>>,
+    cached_ipv6: AtomicCell<Option<cidr::Ipv6Inet>>,
     cached_proxy_cidrs: AtomicCell<Option<Vec<cidr::IpCidr>>>,
 
     ip_collector: Arc<IPCollector>,
@@ -59,6 +77,9 @@ pub struct GlobalCtx {
     stun_info_collection: Box<dyn StunInfoCollectorTrait>,
 
     running_listeners: Mutex<Vec<url::Url>>,
+
+    static_keypair: StaticKeypair,
+    ephemeral_keypair: StaticKeypair,
 }
 
 impl std::fmt::Debug for GlobalCtx {
@@ -69,6 +90,7 @@ impl std::fmt::Debug for GlobalCtx {
             .field("net_ns", &self.net_ns.name())
             .field("event_bus", &"EventBus")
             .field("ipv4", &self.cached_ipv4)
+            .field("ipv6", &self.cached_ipv6)
             .finish()
     }
 }
@@ -83,6 +105,18 @@ impl GlobalCtx {
 
         let (event_bus, _) = tokio::sync::broadcast::channel(100);
 
+        // the key is validated when config is loaded
+        let static_keypair = match config_fs.get_private_key() {
+            Some(key) => StaticKeypair::from_private_key_str(&key).unwrap_or_else(|e| {
+                panic!("invalid private key in config: {:?}", e);
+            }),
+            None => StaticKeypair::generate(),
+        };
+        tracing::info!(public_key = %static_keypair.public_key_str(), "node static key");
+        if config_fs.get_trusted_public_keys().is_none() {
+            tracing::warn!("no trusted public keys configured, peers with any key are accepted");
+        }
+
         GlobalCtx {
             inst_name: config_fs.get_inst_name(),
             id,
@@ -92,6 +126,8 @@ impl GlobalCtx {
 
             event_bus,
             cached_ipv4: AtomicCell::new(None),
+            dhcp_ipv4: AtomicCell::new(None),
+            cached_ipv6: AtomicCell::new(None),
             cached_proxy_cidrs: AtomicCell::new(None),
 
             ip_collector: Arc::new(IPCollector::new(net_ns)),
@@ -101,6 +137,9 @@ impl GlobalCtx {
             stun_info_collection: Box::new(StunInfoCollector::new_with_default_servers()),
 
             running_listeners: Mutex::new(Vec::new()),
+
+            static_keypair,
+            ephemeral_keypair: StaticKeypair::generate(),
         }
     }
 
@@ -117,20 +156,57 @@ impl GlobalCtx {
     }
 
     pub fn get_ipv4(&self) -> Option<std::net::Ipv4Addr> {
+        self.get_ipv4_inet().map(|inet| inet.address())
+    }
+
+    pub fn set_ipv4(&mut self, addr: std::net::Ipv4Addr) {
+        self.config.set_ipv4(addr);
+        self.cached_ipv4.store(None);
+    }
+
+    pub fn get_ipv4_inet(&self) -> Option<cidr::Ipv4Inet> {
         if let Some(ret) = self.cached_ipv4.load() {
             return Some(ret);
         }
-        let addr = self.config.get_ipv4();
+        let addr = self.config.get_ipv4_inet().or(self.dhcp_ipv4.load());
         self.cached_ipv4.store(addr.clone());
         return addr;
     }
 
-    pub fn set_ipv4(&mut self, addr: std::net::Ipv4Addr) {
-        self.config.set_ipv4(addr);
+    pub fn set_ipv4_inet(&self, addr: cidr::Ipv4Inet) {
+        self.config.set_ipv4_inet(addr);
         self.cached_ipv4.store(None);
     }
 
+    // a configured address always takes precedence
+    pub fn set_dhcp_ipv4_inet(&self, addr: cidr::Ipv4Inet) {
+        self.dhcp_ipv4.store(Some(addr));
+        self.cached_ipv4.store(None);
+    }
+
+    pub fn is_ipv4_from_dhcp(&self) -> bool {
+        self.config.get_ipv4_inet().is_none() && self.dhcp_ipv4.load().is_some()
+    }
+
+    pub fn get_ipv6(&self) -> Option<cidr::Ipv6Inet> {
+        if let Some(ret) = self.cached_ipv6.load() {
+            return Some(ret);
+        }
+        let addr = self.config.get_ipv6();
+        self.cached_ipv6.store(addr.clone());
+        return addr;
+    }
+
+    pub fn set_ipv6(&mut self, addr: cidr::Ipv6Inet) {
+        self.config.set_ipv6(addr);
+        self.cached_ipv6.store(None);
+    }
+
     pub fn add_proxy_cidr(&self, cidr: cidr::IpCidr) -> Result<(), std::io::Error> {
+        let mut proxy_cidrs = self.get_proxy_cidrs();
+        proxy_cidrs.push(cidr);
+        self.check_proxy_cidr_mappings(&proxy_cidrs, &self.get_proxy_cidr_mappings())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
         self.config.add_proxy_cidr(cidr);
         self.cached_proxy_cidrs.store(None);
         Ok(())
@@ -153,6 +229,53 @@ impl GlobalCtx {
         ret
     }
 
+    fn check_proxy_cidr_mappings(
+        &self,
+        proxy_cidrs: &[cidr::IpCidr],
+        mappings: &[(cidr::IpCidr, cidr::IpCidr)],
+    ) -> Result<(), anyhow::Error> {
+        let virtual_network = self
+            .get_ipv4_inet()
+            .map(|inet| inet.network())
+            .or(self.config.get_dhcp_network());
+        check_proxy_cidr_mappings(proxy_cidrs, mappings, virtual_network)
+    }
+
+    pub fn set_proxy_cidr_mapping(
+        &self,
+        cidr: cidr::IpCidr,
+        mapped_cidr: Option<cidr::IpCidr>,
+    ) -> Result<(), Error> {
+        let proxy_cidrs = self.get_proxy_cidrs();
+        if !proxy_cidrs.contains(&cidr) {
+            return Err(Error::NotFound);
+        }
+        let mut mappings = self.get_proxy_cidr_mappings();
+        mappings.retain(|(c, _)| *c != cidr);
+        mappings.extend(mapped_cidr.map(|m| (cidr, m)));
+        self.check_proxy_cidr_mappings(&proxy_cidrs, &mappings)?;
+        self.config.set_proxy_cidr_mapping(cidr, mapped_cidr);
+        Ok(())
+    }
+
+    pub fn get_proxy_cidr_mappings(&self) -> Vec<(cidr::IpCidr, cidr::IpCidr)> {
+        self.config.get_proxy_cidr_mappings()
+    }
+
+    // proxy cidrs seen by peers, mapped networks are advertised under their mapped cidr
+    pub fn get_advertised_proxy_cidrs(&self) -> Vec<cidr::IpCidr> {
+        let mappings = self.get_proxy_cidr_mappings();
+        self.get_proxy_cidrs()
+            .into_iter()
+            .map(|cidr| {
+                mappings
+                    .iter()
+                    .find(|(c, _)| *c == cidr)
+                    .map_or(cidr, |(_, mapped_cidr)| *mapped_cidr)
+            })
+            .collect()
+    }
+
     pub fn get_id(&self) -> uuid::Uuid {
         self.config.get_id()
     }
@@ -166,6 +289,9 @@ impl GlobalCtx {
     }
 
     pub fn get_hostname(&self) -> Option<String> {
+        if let Some(hostname) = self.config.get_hostname() {
+            return Some(hostname);
+        }
         if let Some(hostname) = self.hotname.take() {
             self.hotname.store(Some(hostname.clone()));
             return Some(hostname);
@@ -200,14 +326,74 @@ impl GlobalCtx {
         self.running_listeners.lock().unwrap().push(url);
     }
 
+    pub fn remove_running_listener(&self, url: &url::Url) {
+        self.running_listeners.lock().unwrap().retain(|x| x != url);
+    }
+
     pub fn get_vpn_portal_cidr(&self) -> Option<cidr::Ipv4Cidr> {
         self.config.get_vpn_portal_config().map(|x| x.client_cidr)
     }
 
+    pub fn get_exit_node_cidr(&self) -> Option<cidr::Ipv4Cidr> {
+        self.get_flags()
+            .enable_exit_node
+            .then(|| EXIT_NODE_CIDR.parse().unwrap())
+    }
+
     pub fn get_flags(&self) -> Flags {
         self.config.get_flags()
     }
 
+    pub fn get_static_keypair(&self) -> &StaticKeypair {
+        &self.static_keypair
+    }
+
+    // all keys are trusted if trusted public keys are not configured
+    pub fn is_public_key_trusted(&self, key: &PublicKey) -> bool {
+        let Some(trusted_keys) = self.config.get_trusted_public_keys() else {
+            return true;
+        };
+
+        trusted_keys.iter().any(|k| match decode_key(k) {
+            Ok(k) => k == *key,
+            Err(e) => {
+                tracing::warn!(?e, "invalid trusted public key in config");
+                false
+            }
+        })
+    }
+
+    pub fn is_trust_enforced(&self) -> bool {
+        self.config.get_trusted_public_keys().is_some()
+    }
+
+    pub fn get_ephemeral_keypair(&self) -> &StaticKeypair {
+        &self.ephemeral_keypair
+    }
+
+    pub fn get_my_public_keys(&self) -> PeerPublicKeys {
+        PeerPublicKeys {
+            static_key: *self.static_keypair.public_key(),
+            ephemeral_key: *self.ephemeral_keypair.public_key(),
+            trust_enforced: self.is_trust_enforced(),
+        }
+    }
+
+    // key of traffic relayed between us and a peer. the static dh proves both ends own
+    // their advertised keys, the ephemeral dh keeps it secret after static keys leak.
+    pub fn get_pairwise_key(&self, peer_keys: &PeerPublicKeys) -> Result<[u8; 64], Error> {
+        let ss = self.static_keypair.dh(&peer_keys.static_key)?;
+        let ee = self.ephemeral_keypair.dh(&peer_keys.ephemeral_key)?;
+        let mut hasher = Blake2b512::new();
+        hasher.update(b"easytier-pairwise-v1");
+        hasher.update(ss);
+        hasher.update(ee);
+        hasher.update(self.get_256_key());
+        let mut key = [0u8; 64];
+        key.copy_from_slice(&hasher.finalize());
+        Ok(key)
+    }
+
     pub fn get_128_key(&self) -> [u8; 16] {
         let mut key = [0u8; 16];
         let secret = self
@@ -224,6 +410,18 @@ impl GlobalCtx {
         hasher.write(&key[0..16]);
         key
     }
+
+    pub fn get_256_key(&self) -> [u8; 32] {
+        let identity = self.config.get_network_identity();
+        let secret = identity.network_secret.unwrap_or_default();
+        // DefaultHasher is not a kdf, its output is not stable across rust versions either
+        let mut hasher = Blake2s256::new();
+        hasher.update(b"easytier-network-key-v1");
+        hasher.update(identity.network_name.as_bytes());
+        hasher.update([0u8]);
+        hasher.update(secret.as_bytes());
+        hasher.finalize().into()
+    }
 }
 
 #[cfg(test)]
@@ -262,6 +460,53 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn dhcp_ipv4_not_in_config() {
+        let global_ctx = GlobalCtx::new(TomlConfigLoader::default());
+        let addr: cidr::Ipv4Inet = "10.144.144.3/24".parse().unwrap();
+        global_ctx.set_dhcp_ipv4_inet(addr);
+        assert_eq!(Some(addr), global_ctx.get_ipv4_inet());
+        assert!(global_ctx.is_ipv4_from_dhcp());
+        assert_eq!(None, global_ctx.config.get_ipv4_inet());
+
+        let addr: cidr::Ipv4Inet = "10.144.144.1/24".parse().unwrap();
+        global_ctx.set_ipv4_inet(addr);
+        assert_eq!(Some(addr), global_ctx.get_ipv4_inet());
+        assert!(!global_ctx.is_ipv4_from_dhcp());
+    }
+
+    #[test]
+    fn network_key_from_secret() {
+        let new_ctx = |name: &str, secret: &str| {
+            let config = TomlConfigLoader::default();
+            config.set_network_identity(NetworkIdentity::new(name.to_owned(), secret.to_owned()));
+            GlobalCtx::new(config)
+        };
+        let key = new_ctx("net", "secret").get_256_key();
+        assert_eq!(key, new_ctx("net", "secret").get_256_key());
+        assert_ne!(key, new_ctx("net", "other").get_256_key());
+        assert_ne!(key, new_ctx("other", "secret").get_256_key());
+    }
+
+    #[test]
+    fn pairwise_key_is_symmetric() {
+        let a = GlobalCtx::new(TomlConfigLoader::default());
+        let b = GlobalCtx::new(TomlConfigLoader::default());
+        assert_eq!(
+            a.get_pairwise_key(&b.get_my_public_keys()).unwrap(),
+            b.get_pairwise_key(&a.get_my_public_keys()).unwrap()
+        );
+
+        // a peer lying about its static key can not derive the key
+        let c = GlobalCtx::new(TomlConfigLoader::default());
+        let mut fake = c.get_my_public_keys();
+        fake.static_key = b.get_my_public_keys().static_key;
+        assert_ne!(
+            a.get_pairwise_key(&fake).unwrap(),
+            c.get_pairwise_key(&a.get_my_public_keys()).unwrap()
+        );
+    }
+
     pub fn get_mock_global_ctx_with_network(
         network_identy: Option<NetworkIdentity>,
     ) -> ArcGlobalCtx {
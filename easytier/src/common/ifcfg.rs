@@ -1,4 +1,4 @@
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 use async_trait::async_trait;
 use tokio::process::Command;
@@ -25,11 +25,38 @@ pub trait IfConfiguerTrait: Send + Sync {
         address: Ipv4Addr,
         cidr_prefix: u8,
     ) -> Result<(), Error>;
+    async fn add_ipv6_ip(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error>;
+    async fn add_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error>;
+    async fn remove_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error>;
     async fn set_link_status(&self, name: &str, up: bool) -> Result<(), Error>;
     async fn remove_ip(&self, name: &str, ip: Option<Ipv4Addr>) -> Result<(), Error>;
+    // gateway of the default route, None if there is no default route or it is on-link
+    async fn get_default_ipv4_gateway(&self) -> Result<Option<Ipv4Addr>, Error>;
+    // route a single host through a gateway, keeps underlay traffic off the tun device
+    async fn add_ipv4_host_route(&self, address: Ipv4Addr, gateway: Ipv4Addr) -> Result<(), Error>;
+    async fn remove_ipv4_host_route(&self, address: Ipv4Addr) -> Result<(), Error>;
     async fn wait_interface_show(&self, _name: &str) -> Result<(), Error> {
         return Ok(());
     }
+    // attach the interface to a bridge, used to bridge tap device to a lan
+    async fn set_bridge(&self, _name: &str, _bridge: &str) -> Result<(), Error> {
+        Err(anyhow::anyhow!("bridging is not supported on this platform").into())
+    }
 }
 
 fn cidr_to_subnet_mask(prefix_length: u8) -> Ipv4Addr {
@@ -48,7 +75,18 @@ fn cidr_to_subnet_mask(prefix_length: u8) -> Ipv4Addr {
     )
 }
 
+// the word after key in output, e.g. gateway in "default via 192.168.1.1 dev eth0"
+fn parse_ipv4_after(output: &str, key: &str) -> Option<Ipv4Addr> {
+    let mut words = output.split_whitespace();
+    words.find(|w| *w == key)?;
+    words.next()?.parse().ok()
+}
+
 async fn run_shell_cmd(cmd: &str) -> Result<(), Error> {
+    run_shell_cmd_output(cmd).await.map(|_| ())
+}
+
+async fn run_shell_cmd_output(cmd: &str) -> Result<String, Error> {
     let cmd_out: std::process::Output;
     #[cfg(target_os = "windows")]
     {
@@ -76,7 +114,7 @@ async fn run_shell_cmd(cmd: &str) -> Result<(), Error> {
             stdout.to_string() + &stderr.to_string(),
         ));
     }
-    Ok(())
+    Ok(stdout.to_string())
 }
 
 pub struct MacIfConfiger {}
@@ -134,6 +172,54 @@ impl IfConfiguerTrait for MacIfConfiger {
         .await
     }
 
+    async fn add_ipv6_ip(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(
+            format!(
+                "ifconfig {} inet6 {} prefixlen {} alias",
+                name, address, cidr_prefix,
+            )
+            .as_str(),
+        )
+        .await
+    }
+
+    async fn add_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(
+            format!(
+                "route -n add -inet6 {}/{} -interface {}",
+                address, cidr_prefix, name
+            )
+            .as_str(),
+        )
+        .await
+    }
+
+    async fn remove_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(
+            format!(
+                "route -n delete -inet6 {}/{} -interface {}",
+                address, cidr_prefix, name
+            )
+            .as_str(),
+        )
+        .await
+    }
+
     async fn set_link_status(&self, name: &str, up: bool) -> Result<(), Error> {
         run_shell_cmd(format!("ifconfig {} {}", name, if up { "up" } else { "down" }).as_str())
             .await
@@ -149,6 +235,21 @@ impl IfConfiguerTrait for MacIfConfiger {
             .await
         }
     }
+
+    async fn get_default_ipv4_gateway(&self) -> Result<Option<Ipv4Addr>, Error> {
+        let Ok(output) = run_shell_cmd_output("route -n get default").await else {
+            return Ok(None);
+        };
+        Ok(parse_ipv4_after(&output, "gateway:"))
+    }
+
+    async fn add_ipv4_host_route(&self, address: Ipv4Addr, gateway: Ipv4Addr) -> Result<(), Error> {
+        run_shell_cmd(format!("route -n add -host {} {}", address, gateway).as_str()).await
+    }
+
+    async fn remove_ipv4_host_route(&self, address: Ipv4Addr) -> Result<(), Error> {
+        run_shell_cmd(format!("route -n delete -host {}", address).as_str()).await
+    }
 }
 
 pub struct LinuxIfConfiger {}
@@ -190,6 +291,42 @@ impl IfConfiguerTrait for LinuxIfConfiger {
             .await
     }
 
+    async fn add_ipv6_ip(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(format!("ip -6 addr add {}/{} dev {}", address, cidr_prefix, name).as_str())
+            .await
+    }
+
+    async fn add_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(
+            format!(
+                "ip -6 route add {}/{} dev {} metric 65535",
+                address, cidr_prefix, name
+            )
+            .as_str(),
+        )
+        .await
+    }
+
+    async fn remove_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(format!("ip -6 route del {}/{} dev {}", address, cidr_prefix, name).as_str())
+            .await
+    }
+
     async fn set_link_status(&self, name: &str, up: bool) -> Result<(), Error> {
         run_shell_cmd(format!("ip link set {} {}", name, if up { "up" } else { "down" }).as_str())
             .await
@@ -205,6 +342,23 @@ impl IfConfiguerTrait for LinuxIfConfiger {
             .await
         }
     }
+
+    async fn get_default_ipv4_gateway(&self) -> Result<Option<Ipv4Addr>, Error> {
+        let output = run_shell_cmd_output("ip -4 route show default").await?;
+        Ok(parse_ipv4_after(&output, "via"))
+    }
+
+    async fn add_ipv4_host_route(&self, address: Ipv4Addr, gateway: Ipv4Addr) -> Result<(), Error> {
+        run_shell_cmd(format!("ip route add {}/32 via {}", address, gateway).as_str()).await
+    }
+
+    async fn remove_ipv4_host_route(&self, address: Ipv4Addr) -> Result<(), Error> {
+        run_shell_cmd(format!("ip route del {}/32", address).as_str()).await
+    }
+
+    async fn set_bridge(&self, name: &str, bridge: &str) -> Result<(), Error> {
+        run_shell_cmd(format!("ip link set {} master {}", name, bridge).as_str()).await
+    }
 }
 
 #[cfg(target_os = "windows")]
@@ -319,6 +473,60 @@ impl IfConfiguerTrait for WindowsIfConfiger {
         .await
     }
 
+    async fn add_ipv6_ip(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(
+            format!(
+                "netsh interface ipv6 add address {} address={}/{}",
+                name, address, cidr_prefix
+            )
+            .as_str(),
+        )
+        .await
+    }
+
+    async fn add_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        let Some(idx) = Self::get_interface_index(name) else {
+            return Err(Error::NotFound);
+        };
+        run_shell_cmd(
+            format!(
+                "netsh interface ipv6 add route {}/{} {} metric=255 store=active",
+                address, cidr_prefix, idx
+            )
+            .as_str(),
+        )
+        .await
+    }
+
+    async fn remove_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        let Some(idx) = Self::get_interface_index(name) else {
+            return Err(Error::NotFound);
+        };
+        run_shell_cmd(
+            format!(
+                "netsh interface ipv6 delete route {}/{} {} store=active",
+                address, cidr_prefix, idx
+            )
+            .as_str(),
+        )
+        .await
+    }
+
     async fn set_link_status(&self, name: &str, up: bool) -> Result<(), Error> {
         run_shell_cmd(
             format!(
@@ -342,6 +550,27 @@ impl IfConfiguerTrait for WindowsIfConfiger {
         }
     }
 
+    async fn get_default_ipv4_gateway(&self) -> Result<Option<Ipv4Addr>, Error> {
+        // rows of the route table are "destination netmask gateway interface metric"
+        let output = run_shell_cmd_output("route print -4 0.0.0.0").await?;
+        Ok(output.lines().find_map(|line| {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            match words.as_slice() {
+                ["0.0.0.0", "0.0.0.0", gateway, ..] => gateway.parse().ok(),
+                _ => None,
+            }
+        }))
+    }
+
+    async fn add_ipv4_host_route(&self, address: Ipv4Addr, gateway: Ipv4Addr) -> Result<(), Error> {
+        run_shell_cmd(format!("route ADD {} MASK 255.255.255.255 {}", address, gateway).as_str())
+            .await
+    }
+
+    async fn remove_ipv4_host_route(&self, address: Ipv4Addr) -> Result<(), Error> {
+        run_shell_cmd(format!("route DELETE {} MASK 255.255.255.255", address).as_str()).await
+    }
+
     async fn wait_interface_show(&self, name: &str) -> Result<(), Error> {
         Ok(
             tokio::time::timeout(std::time::Duration::from_secs(10), async move {
@@ -367,3 +596,30 @@ pub type IfConfiger = LinuxIfConfiger;
 
 #[cfg(target_os = "windows")]
 pub type IfConfiger = WindowsIfConfiger;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_default_gateway() {
+        assert_eq!(
+            Some(Ipv4Addr::new(192, 168, 1, 1)),
+            parse_ipv4_after(
+                "default via 192.168.1.1 dev wlan0 proto dhcp metric 600\n",
+                "via"
+            )
+        );
+        assert_eq!(
+            Some(Ipv4Addr::new(10, 0, 0, 1)),
+            parse_ipv4_after(
+                "   route to: default\ndestination: default\n    gateway: 10.0.0.1\n  interface: en0\n",
+                "gateway:"
+            )
+        );
+        assert_eq!(
+            None,
+            parse_ipv4_after("default dev ppp0 scope link\n", "via")
+        );
+    }
+}
@@ -13,6 +13,7 @@ pub mod global_ctx;
 pub mod ifcfg;
 pub mod netns;
 pub mod network;
+pub mod noise;
 pub mod stun;
 pub mod stun_codec_ext;
 
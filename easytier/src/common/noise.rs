@@ -0,0 +1,152 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
+use snow::{params::DHChoice, resolvers::CryptoResolver, HandshakeState};
+
+use super::error::Error;
+
+// XX lets both sides learn the static key of each other without knowing it beforehand,
+// the ephemeral keys give forward secrecy even if static keys are leaked later.
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const NOISE_PROLOGUE: &[u8] = b"easytier-noise-v1";
+
+pub const NOISE_MAX_MSG_LEN: usize = 65535;
+
+pub type PublicKey = [u8; 32];
+
+// keys a peer advertises in route info, used to key traffic relayed by other peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PeerPublicKeys {
+    pub static_key: PublicKey,
+    // generated on every start, gives forward secrecy to relayed traffic
+    pub ephemeral_key: PublicKey,
+    // the peer only accepts relayed traffic proving the static key of the sender
+    pub trust_enforced: bool,
+}
+
+#[derive(Clone)]
+pub struct StaticKeypair {
+    private_key: [u8; 32],
+    public_key: PublicKey,
+}
+
+impl std::fmt::Debug for StaticKeypair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StaticKeypair")
+            .field("public_key", &encode_key(&self.public_key))
+            .finish()
+    }
+}
+
+impl StaticKeypair {
+    pub fn generate() -> Self {
+        let keypair = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+            .generate_keypair()
+            .unwrap();
+        let mut ret = StaticKeypair {
+            private_key: [0u8; 32],
+            public_key: [0u8; 32],
+        };
+        ret.private_key.copy_from_slice(&keypair.private);
+        ret.public_key.copy_from_slice(&keypair.public);
+        ret
+    }
+
+    pub fn from_private_key(private_key: [u8; 32]) -> Self {
+        let mut dh = snow::resolvers::DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .unwrap();
+        dh.set(&private_key);
+        let mut public_key = [0u8; 32];
+        public_key.copy_from_slice(dh.pubkey());
+        StaticKeypair {
+            private_key,
+            public_key,
+        }
+    }
+
+    pub fn from_private_key_str(private_key: &str) -> Result<Self, Error> {
+        Ok(Self::from_private_key(decode_key(private_key)?))
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    pub fn private_key_str(&self) -> String {
+        encode_key(&self.private_key)
+    }
+
+    pub fn public_key_str(&self) -> String {
+        encode_key(&self.public_key)
+    }
+
+    pub fn dh(&self, their_public_key: &PublicKey) -> Result<[u8; 32], Error> {
+        let mut dh = snow::resolvers::DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .unwrap();
+        dh.set(&self.private_key);
+        let mut ret = [0u8; 32];
+        dh.dh(their_public_key, &mut ret)?;
+        // low order points give an all zero secret
+        if ret == [0u8; 32] {
+            return Err(Error::InvalidKey("low order public key".to_owned()));
+        }
+        Ok(ret)
+    }
+
+    fn builder(&self) -> snow::Builder<'_> {
+        snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(&self.private_key)
+            .prologue(NOISE_PROLOGUE)
+    }
+
+    pub fn build_initiator(&self) -> Result<HandshakeState, Error> {
+        Ok(self.builder().build_initiator()?)
+    }
+
+    pub fn build_responder(&self) -> Result<HandshakeState, Error> {
+        Ok(self.builder().build_responder()?)
+    }
+}
+
+pub fn encode_key(key: &[u8; 32]) -> String {
+    BASE64_STANDARD.encode(key)
+}
+
+pub fn decode_key(key: &str) -> Result<[u8; 32], Error> {
+    let decoded = BASE64_STANDARD
+        .decode(key.trim())
+        .map_err(|e| Error::InvalidKey(format!("{:?}", e)))?;
+    if decoded.len() != 32 {
+        return Err(Error::InvalidKey(format!(
+            "key length should be 32, got {}",
+            decoded.len()
+        )));
+    }
+    let mut ret = [0u8; 32];
+    ret.copy_from_slice(&decoded);
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keypair_from_private_key() {
+        let keypair = StaticKeypair::generate();
+        let restored = StaticKeypair::from_private_key_str(&keypair.private_key_str()).unwrap();
+        assert_eq!(keypair.public_key(), restored.public_key());
+        assert_eq!(keypair.public_key_str(), restored.public_key_str());
+
+        assert!(StaticKeypair::from_private_key_str("not a key").is_err());
+        assert!(decode_key(&BASE64_STANDARD.encode([1u8; 16])).is_err());
+    }
+
+    #[test]
+    fn dh_is_symmetric() {
+        let a = StaticKeypair::generate();
+        let b = StaticKeypair::generate();
+        assert_eq!(a.dh(b.public_key()).unwrap(), b.dh(a.public_key()).unwrap());
+        assert!(a.dh(&[0u8; 32]).is_err());
+    }
+}
@@ -373,8 +373,7 @@ mod tests {
         },
         instance::listeners::ListenerManager,
         peers::tests::{
-            connect_peer_manager, create_mock_peer_manager, wait_route_appear,
-            wait_route_appear_with_cost,
+            connect_peer_manager, create_mock_peer_manager, wait_direct_peer, wait_route_appear,
         },
         rpc::peer::GetIpListResponse,
     };
@@ -420,7 +419,7 @@ mod tests {
 
         lis_c.run().await.unwrap();
 
-        wait_route_appear_with_cost(p_a.clone(), p_c.my_peer_id(), Some(1))
+        wait_direct_peer(p_a.clone(), p_c.my_peer_id())
             .await
             .unwrap();
     }
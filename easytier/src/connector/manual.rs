@@ -17,6 +17,7 @@ use crate::{
 
 use crate::{
     common::{
+        config::{ConfigLoader, PeerConfig},
         error::Error,
         global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
         netns::NetNS,
@@ -42,11 +43,20 @@ struct ReconnResult {
     conn_id: PeerConnId,
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct ReconnectStats {
+    pub attempts: u64,
+    pub failures: u64,
+}
+
 struct ConnectorManagerData {
     connectors: ConnectorMap,
     reconnecting: DashSet<String>,
+    reconnect_stats: DashMap<String, ReconnectStats>,
     peer_manager: Arc<PeerManager>,
     alive_conn_urls: Arc<Mutex<BTreeSet<String>>>,
+    // peer each connector connected to last time, so it can be removed by peer id
+    connector_peer_ids: DashMap<String, PeerId>,
     // user removed connector urls
     removed_conn_urls: Arc<DashSet<String>>,
     net_ns: NetNS,
@@ -70,8 +80,10 @@ impl ManualConnectorManager {
             data: Arc::new(ConnectorManagerData {
                 connectors,
                 reconnecting: DashSet::new(),
+                reconnect_stats: DashMap::new(),
                 peer_manager,
                 alive_conn_urls: Arc::new(Mutex::new(BTreeSet::new())),
+                connector_peer_ids: DashMap::new(),
                 removed_conn_urls: Arc::new(DashSet::new()),
                 net_ns: global_ctx.net_ns.clone(),
                 global_ctx,
@@ -110,6 +122,44 @@ impl ManualConnectorManager {
         Ok(())
     }
 
+    // keep peers in config in sync with connectors changed at runtime, so a later reload sees
+    // them. the change is also applied to the config file if persist is set.
+    pub fn update_peers_in_config(&self, url: &str, add: bool, persist: bool) -> Result<(), Error> {
+        let added = if add {
+            Some(PeerConfig {
+                uri: url
+                    .parse()
+                    .with_context(|| format!("invalid peer url: {}", url))?,
+            })
+        } else {
+            None
+        };
+        let update = |config: &dyn ConfigLoader| {
+            let mut peers = config.get_peers();
+            peers.retain(|p| p.uri.as_str() != url);
+            peers.extend(added.clone());
+            config.set_peers(peers);
+        };
+        let config = &self.global_ctx.config;
+        if persist {
+            config.save_with(&update)?;
+        }
+        update(config.as_ref());
+        Ok(())
+    }
+
+    pub fn get_reconnect_stats(&self) -> Vec<(String, ReconnectStats)> {
+        self.data
+            .reconnect_stats
+            .iter()
+            .map(|x| (x.key().clone(), x.value().clone()))
+            .collect()
+    }
+
+    pub fn get_connector_peer_id(&self, url: &str) -> Option<PeerId> {
+        self.data.connector_peer_ids.get(url).map(|x| *x.value())
+    }
+
     pub async fn list_connectors(&self) -> Vec<Connector> {
         let conn_urls: BTreeSet<String> = self
             .data
@@ -133,6 +183,7 @@ impl ManualConnectorManager {
             ret.insert(
                 0,
                 Connector {
+                    peer_id: self.get_connector_peer_id(&conn_url).unwrap_or_default(),
                     url: conn_url,
                     status: status.into(),
                 },
@@ -150,6 +201,7 @@ impl ManualConnectorManager {
             ret.insert(
                 0,
                 Connector {
+                    peer_id: self.get_connector_peer_id(&conn_url).unwrap_or_default(),
                     url: conn_url,
                     status: ConnectorStatus::Connecting.into(),
                 },
@@ -195,6 +247,13 @@ impl ManualConnectorManager {
 
                         reconn_tasks.spawn(async move {
                             let reconn_ret = Self::conn_reconnect(data_clone.clone(), dead_url.clone(), connector.clone()).await;
+                            {
+                                let mut stats = data_clone.reconnect_stats.entry(dead_url.clone()).or_default();
+                                stats.attempts += 1;
+                                if reconn_ret.is_err() {
+                                    stats.failures += 1;
+                                }
+                            }
                             sender.send(reconn_ret).await.unwrap();
 
                             data_clone.reconnecting.remove(&dead_url).unwrap();
@@ -206,6 +265,9 @@ impl ManualConnectorManager {
 
                 ret = reconn_result_recv.recv() => {
                     log::warn!("reconn_tasks done, out: {:?}", ret);
+                    if let Some(Ok(ret)) = &ret {
+                        data.connector_peer_ids.insert(ret.dead_url.clone(), ret.peer_id);
+                    }
                     let _ = reconn_tasks.join_next().await.unwrap();
                 }
             }
@@ -235,6 +297,7 @@ impl ManualConnectorManager {
         for it in data.removed_conn_urls.iter() {
             let url = it.key();
             if let Some(_) = data.connectors.remove(url) {
+                data.connector_peer_ids.remove(url);
                 log::warn!("connector: {}, removed", url);
                 continue;
             } else if data.reconnecting.contains(url) {
@@ -393,21 +456,29 @@ impl ConnectorManageRpc for ConnectorManagerRpcService {
         let req = request.into_inner();
         let url = url::Url::parse(&req.url)
             .map_err(|_| tonic::Status::invalid_argument("invalid url"))?;
-        if req.action == easytier_rpc::ConnectorManageAction::Remove as i32 {
-            self.0.remove_connector(url.path()).await.map_err(|e| {
-                tonic::Status::invalid_argument(format!("remove connector failed: {:?}", e))
-            })?;
-            return Ok(tonic::Response::new(
-                easytier_rpc::ManageConnectorResponse::default(),
+        if req.persist && self.0.global_ctx.config.get_config_path().is_none() {
+            return Err(tonic::Status::failed_precondition(
+                "instance is not started with a config file",
             ));
-        } else {
+        }
+        let add = req.action != easytier_rpc::ConnectorManageAction::Remove as i32;
+        if add {
             self.0
                 .add_connector_by_url(url.as_str())
                 .await
                 .map_err(|e| {
                     tonic::Status::invalid_argument(format!("add connector failed: {:?}", e))
                 })?;
+        } else {
+            self.0.remove_connector(url.as_str()).await.map_err(|e| {
+                tonic::Status::invalid_argument(format!("remove connector failed: {:?}", e))
+            })?;
         }
+        self.0
+            .update_peers_in_config(url.as_str(), add, req.persist)
+            .map_err(|e| {
+                tonic::Status::failed_precondition(format!("update config failed: {:?}", e))
+            })?;
         Ok(tonic::Response::new(
             easytier_rpc::ManageConnectorResponse::default(),
         ))
@@ -447,4 +518,42 @@ mod tests {
 
         tokio::time::sleep(std::time::Duration::from_secs(5)).await;
     }
+
+    #[tokio::test]
+    async fn manage_connector_rpc() {
+        let peer_mgr = create_mock_peer_manager().await;
+        let global_ctx = peer_mgr.get_global_ctx();
+        let mgr = Arc::new(ManualConnectorManager::new(global_ctx.clone(), peer_mgr));
+        let service = ConnectorManagerRpcService(mgr.clone());
+
+        let request = |action: easytier_rpc::ConnectorManageAction, persist| {
+            tonic::Request::new(ManageConnectorRequest {
+                action: action.into(),
+                url: "tcp://127.0.0.1:31019".to_string(),
+                persist,
+            })
+        };
+
+        service
+            .manage_connector(request(easytier_rpc::ConnectorManageAction::Add, false))
+            .await
+            .unwrap();
+        assert_eq!(1, mgr.list_connectors().await.len());
+        assert_eq!(
+            "tcp://127.0.0.1:31019",
+            global_ctx.config.get_peers()[0].uri.as_str()
+        );
+
+        service
+            .manage_connector(request(easytier_rpc::ConnectorManageAction::Remove, false))
+            .await
+            .unwrap();
+        assert!(global_ctx.config.get_peers().is_empty());
+
+        // the mock config is not loaded from a file
+        assert!(service
+            .manage_connector(request(easytier_rpc::ConnectorManageAction::Add, true))
+            .await
+            .is_err());
+    }
 }
@@ -5,6 +5,8 @@ use std::{
 
 #[cfg(feature = "quic")]
 use crate::tunnel::quic::QUICTunnelConnector;
+#[cfg(feature = "websocket")]
+use crate::tunnel::websocket::WsTunnelConnector;
 #[cfg(feature = "wireguard")]
 use crate::tunnel::wireguard::{WgConfig, WgTunnelConnector};
 use crate::{
@@ -105,6 +107,13 @@ pub async fn create_connector_by_url(
             .await;
             return Ok(Box::new(connector));
         }
+        #[cfg(feature = "websocket")]
+        "ws" | "wss" => {
+            check_scheme_and_get_socket_addr::<SocketAddr>(&url, url.scheme())?;
+            let mut connector = WsTunnelConnector::new(url);
+            connector.set_tls_insecure(global_ctx.get_flags().ws_tls_insecure);
+            return Ok(Box::new(connector));
+        }
         _ => {
             return Err(Error::InvalidUrl(url.into()));
         }
@@ -474,8 +474,7 @@ pub mod tests {
         peers::{
             peer_manager::PeerManager,
             tests::{
-                connect_peer_manager, create_mock_peer_manager, wait_route_appear,
-                wait_route_appear_with_cost,
+                connect_peer_manager, create_mock_peer_manager, wait_direct_peer, wait_route_appear,
             },
         },
     };
@@ -532,7 +531,7 @@ pub mod tests {
         hole_punching_a.run().await.unwrap();
         hole_punching_c.run().await.unwrap();
 
-        wait_route_appear_with_cost(p_a.clone(), p_c.my_peer_id(), Some(1))
+        wait_direct_peer(p_a.clone(), p_c.my_peer_id())
             .await
             .unwrap();
         println!("{:?}", p_a.list_routes().await);
@@ -2,8 +2,7 @@
 
 use std::{net::SocketAddr, vec};
 
-use clap::{command, Args, Parser, Subcommand};
-use rpc::vpn_portal_rpc_client::VpnPortalRpcClient;
+use clap::{command, Args, Parser, Subcommand, ValueEnum};
 use utils::{list_peer_route_pair, PeerRoutePair};
 
 mod arch;
@@ -13,16 +12,26 @@ mod tunnel;
 mod utils;
 
 use crate::{
-    common::stun::{StunInfoCollector, UdpNatTypeDetector},
+    common::{
+        config::DnatRuleConfig,
+        stun::{StunInfoCollector, UdpNatTypeDetector},
+    },
     rpc::{
+        acl_manage_rpc_client::AclManageRpcClient, config_manage_rpc_client::ConfigManageRpcClient,
         connector_manage_rpc_client::ConnectorManageRpcClient,
+        dnat_manage_rpc_client::DnatManageRpcClient, event_rpc_client::EventRpcClient,
         peer_center_rpc_client::PeerCenterRpcClient, peer_manage_rpc_client::PeerManageRpcClient,
+        remote_manage_rpc_client::RemoteManageRpcClient, vpn_portal_rpc_client::VpnPortalRpcClient,
         *,
     },
     utils::{cost_to_str, float_to_str},
 };
 use humansize::format_size;
 use tabled::settings::Style;
+use tonic::{
+    body::BoxBody,
+    codegen::{http, Body, BoxFuture, Context, Poll, Service},
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -31,10 +40,47 @@ struct Cli {
     #[arg(short = 'p', long, default_value = "127.0.0.1:15888")]
     rpc_portal: SocketAddr,
 
+    /// output format, json and yaml print the rpc responses as is
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Table, global = true)]
+    output: OutputFormat,
+
+    /// run the command on another node of the network through the local node, by peer id,
+    /// hostname or instance id. the node must allow the public key of the local node in its
+    /// remote_management config
+    #[arg(short, long, global = true)]
+    target_peer: Option<String>,
+
+    /// token of the rpc portal, as set by --rpc-portal-token of easytier-core
+    #[arg(long, global = true)]
+    rpc_token: Option<String>,
+
+    /// connect the rpc portal with tls, verifying its certificate by this ca pem file
+    #[arg(long, global = true)]
+    tls_ca: Option<String>,
+
+    /// client certificate pem file, for portals verifying client certificates
+    #[arg(long, global = true, requires = "tls_key")]
+    tls_cert: Option<String>,
+
+    /// client private key pem file
+    #[arg(long, global = true, requires = "tls_cert")]
+    tls_key: Option<String>,
+
+    /// domain name to verify the portal certificate against, the portal ip is used by default
+    #[arg(long, global = true)]
+    tls_domain: Option<String>,
+
     #[command(subcommand)]
     sub_command: SubCommand,
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Table,
+    Json,
+    Yaml,
+}
+
 #[derive(Subcommand, Debug)]
 enum SubCommand {
     Peer(PeerArgs),
@@ -43,6 +89,24 @@ enum SubCommand {
     Route,
     PeerCenter,
     VpnPortal,
+    Acl,
+    Dnat(DnatArgs),
+    Reload(ReloadArgs),
+    Events(EventsArgs),
+}
+
+#[derive(Args, Debug)]
+struct ReloadArgs {
+    /// config file to apply, reload the instance's own config file if not set
+    #[arg(short, long)]
+    config_file: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct EventsArgs {
+    /// keep running and print new events, otherwise only recent events are printed
+    #[arg(short, long)]
+    follow: bool,
 }
 
 #[derive(Args, Debug)]
@@ -63,10 +127,22 @@ struct PeerListArgs {
     verbose: bool,
 }
 
+#[derive(Args, Debug)]
+struct PeerRemoveArgs {
+    /// id of a directly connected peer
+    peer_id: u32,
+
+    /// also remove the peer from the config file of the instance
+    #[arg(long)]
+    persist: bool,
+}
+
 #[derive(Subcommand, Debug)]
 enum PeerSubCommand {
-    Add,
-    Remove,
+    /// connect to a peer, same as connector add
+    Add(ConnectorManageArgs),
+    /// disconnect a peer and remove connectors to it
+    Remove(PeerRemoveArgs),
     List(PeerListArgs),
 }
 
@@ -82,48 +158,241 @@ struct ConnectorArgs {
     sub_command: Option<ConnectorSubCommand>,
 }
 
+#[derive(Args, Debug)]
+struct ConnectorManageArgs {
+    /// url of the peer, e.g. tcp://1.2.3.4:11010
+    url: String,
+
+    /// also write the change to the config file of the instance
+    #[arg(long)]
+    persist: bool,
+}
+
 #[derive(Subcommand, Debug)]
 enum ConnectorSubCommand {
-    Add,
-    Remove,
+    Add(ConnectorManageArgs),
+    Remove(ConnectorManageArgs),
+    List,
+}
+
+#[derive(Args, Debug)]
+struct DnatArgs {
+    #[command(subcommand)]
+    sub_command: Option<DnatSubCommand>,
+}
+
+#[derive(Args, Debug)]
+struct DnatAddArgs {
+    /// port of the virtual ip and its destination, e.g. tcp:8080/192.168.1.10:80
+    rule: DnatRuleConfig,
+
+    /// also write the change to the config file of the instance
+    #[arg(long)]
+    persist: bool,
+}
+
+#[derive(Args, Debug)]
+struct DnatRemoveArgs {
+    /// port of the virtual ip, e.g. tcp:8080
+    #[arg(value_parser = parse_dnat_port)]
+    port: (String, u16),
+
+    /// also write the change to the config file of the instance
+    #[arg(long)]
+    persist: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum DnatSubCommand {
+    Add(DnatAddArgs),
+    Remove(DnatRemoveArgs),
     List,
 }
 
+fn parse_dnat_port(s: &str) -> Result<(String, u16), String> {
+    let invalid = || format!("invalid dnat port: {}, e.g. tcp:8080", s);
+    let (proto, port) = s.split_once(':').ok_or_else(invalid)?;
+    if proto != "tcp" && proto != "udp" {
+        return Err(invalid());
+    }
+    Ok((proto.to_string(), port.parse().map_err(|_| invalid())?))
+}
+
 #[derive(thiserror::Error, Debug)]
 enum Error {
     #[error("tonic transport error")]
     TonicTransportError(#[from] tonic::transport::Error),
     #[error("tonic rpc error")]
     TonicRpcError(#[from] tonic::Status),
+    #[error("io error")]
+    IoError(#[from] std::io::Error),
+    #[error("json error")]
+    JsonError(#[from] serde_json::Error),
+    #[error("yaml error")]
+    YamlError(#[from] serde_yaml::Error),
+    #[error("peer {0} not found")]
+    PeerNotFound(u32),
+    #[error("invalid rpc token")]
+    InvalidToken(#[from] http::header::InvalidHeaderValue),
+}
+
+// transport of all rpc clients. it attaches the rpc token to every request, and runs the
+// request on the node given by --target-peer through ForwardRpc of the local node if set.
+#[derive(Clone)]
+struct RpcChannel {
+    channel: tonic::transport::Channel,
+    authorization: Option<http::HeaderValue>,
+    target_peer: Option<String>,
+}
+
+impl RpcChannel {
+    // a unary request is a single grpc frame: compressed flag, message length and message
+    async fn forward(
+        self,
+        target_peer: String,
+        request: http::Request<BoxBody>,
+    ) -> Result<Vec<u8>, tonic::Status> {
+        let method = request.uri().path().to_string();
+        let mut body = request.into_body();
+        let mut frame = Vec::new();
+        while let Some(data) = body.data().await {
+            frame.extend_from_slice(&data?);
+        }
+        if frame.len() < 5 || frame[0] != 0 {
+            return Err(tonic::Status::internal("unexpected grpc request frame"));
+        }
+
+        let mut client = RemoteManageRpcClient::new(RpcChannel {
+            target_peer: None,
+            ..self
+        });
+        let request = ForwardRpcRequest {
+            target_peer,
+            method,
+            body: frame.split_off(5),
+        };
+        Ok(client.forward_rpc(request).await?.into_inner().body)
+    }
+}
+
+impl Service<http::Request<BoxBody>> for RpcChannel {
+    type Response = http::Response<tonic::transport::Body>;
+    type Error = tonic::transport::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.channel.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<BoxBody>) -> Self::Future {
+        if let Some(authorization) = &self.authorization {
+            request
+                .headers_mut()
+                .insert("authorization", authorization.clone());
+        }
+        let Some(target_peer) = self.target_peer.clone() else {
+            return Box::pin(self.channel.call(request));
+        };
+
+        let channel = self.clone();
+        Box::pin(async move {
+            // reply like a grpc server, with the status in the headers of the response
+            let (status, body) = match channel.forward(target_peer, request).await {
+                Ok(message) => {
+                    let mut frame = vec![0];
+                    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+                    frame.extend(message);
+                    (tonic::Status::ok(""), tonic::transport::Body::from(frame))
+                }
+                Err(status) => (status, tonic::transport::Body::empty()),
+            };
+            let mut response = http::Response::new(body);
+            response.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("application/grpc"),
+            );
+            let _ = status.add_header(response.headers_mut());
+            Ok(response)
+        })
+    }
 }
 
 struct CommandHandler {
     addr: String,
+    output: OutputFormat,
+    target_peer: Option<String>,
+    rpc_token: Option<String>,
+    tls: Option<tonic::transport::ClientTlsConfig>,
 }
 
 impl CommandHandler {
-    async fn get_peer_manager_client(
-        &self,
-    ) -> Result<PeerManageRpcClient<tonic::transport::Channel>, Error> {
-        Ok(PeerManageRpcClient::connect(self.addr.clone()).await?)
+    fn is_table_output(&self) -> bool {
+        self.output == OutputFormat::Table
+    }
+
+    // for json and yaml output
+    fn print_output<T: serde::Serialize>(&self, data: &T) -> Result<(), Error> {
+        match self.output {
+            OutputFormat::Yaml => print!("{}", serde_yaml::to_string(data)?),
+            _ => println!("{}", serde_json::to_string_pretty(data)?),
+        }
+        Ok(())
+    }
+
+    // all clients share this transport, see RpcChannel
+    async fn channel(&self) -> Result<RpcChannel, Error> {
+        let mut endpoint = tonic::transport::Endpoint::new(self.addr.clone())?;
+        if let Some(tls) = &self.tls {
+            endpoint = endpoint.tls_config(tls.clone())?;
+        }
+        let authorization = match &self.rpc_token {
+            Some(token) => Some(format!("Bearer {}", token).parse()?),
+            None => None,
+        };
+        Ok(RpcChannel {
+            channel: endpoint.connect().await?,
+            authorization,
+            target_peer: self.target_peer.clone(),
+        })
+    }
+
+    async fn get_peer_manager_client(&self) -> Result<PeerManageRpcClient<RpcChannel>, Error> {
+        Ok(PeerManageRpcClient::new(self.channel().await?))
     }
 
     async fn get_connector_manager_client(
         &self,
-    ) -> Result<ConnectorManageRpcClient<tonic::transport::Channel>, Error> {
-        Ok(ConnectorManageRpcClient::connect(self.addr.clone()).await?)
+    ) -> Result<ConnectorManageRpcClient<RpcChannel>, Error> {
+        Ok(ConnectorManageRpcClient::new(self.channel().await?))
     }
 
-    async fn get_peer_center_client(
-        &self,
-    ) -> Result<PeerCenterRpcClient<tonic::transport::Channel>, Error> {
-        Ok(PeerCenterRpcClient::connect(self.addr.clone()).await?)
+    async fn get_peer_center_client(&self) -> Result<PeerCenterRpcClient<RpcChannel>, Error> {
+        Ok(PeerCenterRpcClient::new(self.channel().await?))
     }
 
-    async fn get_vpn_portal_client(
-        &self,
-    ) -> Result<VpnPortalRpcClient<tonic::transport::Channel>, Error> {
-        Ok(VpnPortalRpcClient::connect(self.addr.clone()).await?)
+    async fn get_vpn_portal_client(&self) -> Result<VpnPortalRpcClient<RpcChannel>, Error> {
+        Ok(VpnPortalRpcClient::new(self.channel().await?))
+    }
+
+    async fn get_acl_manager_client(&self) -> Result<AclManageRpcClient<RpcChannel>, Error> {
+        Ok(AclManageRpcClient::new(self.channel().await?))
+    }
+
+    async fn get_dnat_manager_client(&self) -> Result<DnatManageRpcClient<RpcChannel>, Error> {
+        Ok(DnatManageRpcClient::new(self.channel().await?))
+    }
+
+    async fn get_config_manager_client(&self) -> Result<ConfigManageRpcClient<RpcChannel>, Error> {
+        Ok(ConfigManageRpcClient::new(self.channel().await?))
+    }
+
+    async fn get_event_client(&self) -> Result<EventRpcClient<RpcChannel>, Error> {
+        if self.target_peer.is_some() {
+            return Err(
+                tonic::Status::unimplemented("events of remote peers can not be streamed").into(),
+            );
+        }
+        Ok(EventRpcClient::new(self.channel().await?))
     }
 
     async fn list_peers(&self) -> Result<ListPeerResponse, Error> {
@@ -140,23 +409,86 @@ impl CommandHandler {
         Ok(response.into_inner())
     }
 
+    async fn list_connectors(&self) -> Result<ListConnectorResponse, Error> {
+        let mut client = self.get_connector_manager_client().await?;
+        let request = tonic::Request::new(ListConnectorRequest::default());
+        let response = client.list_connector(request).await?;
+        Ok(response.into_inner())
+    }
+
     async fn list_peer_route_pair(&self) -> Result<Vec<PeerRoutePair>, Error> {
         let peers = self.list_peers().await?.peer_infos;
         let routes = self.list_routes().await?.routes;
         Ok(list_peer_route_pair(peers, routes))
     }
 
-    #[allow(dead_code)]
-    fn handle_peer_add(&self, _args: PeerArgs) {
-        println!("add peer");
+    async fn manage_connector(
+        &self,
+        action: ConnectorManageAction,
+        url: &str,
+        persist: bool,
+    ) -> Result<(), Error> {
+        let request = ManageConnectorRequest {
+            action: action.into(),
+            url: url.to_string(),
+            persist,
+        };
+        let mut client = self.get_connector_manager_client().await?;
+        client.manage_connector(request).await?;
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    fn handle_peer_remove(&self, _args: PeerArgs) {
-        println!("remove peer");
+    async fn handle_connector_add(&self, args: &ConnectorManageArgs) -> Result<(), Error> {
+        self.manage_connector(ConnectorManageAction::Add, &args.url, args.persist)
+            .await?;
+        println!("connector added: {}", args.url);
+        Ok(())
+    }
+
+    async fn handle_connector_remove(&self, args: &ConnectorManageArgs) -> Result<(), Error> {
+        self.manage_connector(ConnectorManageAction::Remove, &args.url, args.persist)
+            .await?;
+        println!("connector removed: {}", args.url);
+        Ok(())
+    }
+
+    // connectors to the peer are removed first, otherwise they reconnect it right after it's
+    // closed.
+    async fn handle_peer_remove(&self, args: &PeerRemoveArgs) -> Result<(), Error> {
+        // remove connectors first, otherwise they connect to the peer again
+        let mut removed = false;
+        let connectors = self.list_connectors().await?.connectors;
+        for connector in connectors {
+            if connector.peer_id == args.peer_id {
+                self.manage_connector(ConnectorManageAction::Remove, &connector.url, args.persist)
+                    .await?;
+                println!("connector removed: {}", connector.url);
+                removed = true;
+            }
+        }
+
+        let peers = self.list_peers().await?.peer_infos;
+        if peers.iter().any(|p| p.peer_id == args.peer_id) {
+            let request = ClosePeerRequest {
+                peer_id: args.peer_id,
+            };
+            let mut client = self.get_peer_manager_client().await?;
+            client.close_peer(request).await?;
+            println!("peer removed: {}", args.peer_id);
+            removed = true;
+        }
+
+        if !removed {
+            return Err(Error::PeerNotFound(args.peer_id));
+        }
+        Ok(())
     }
 
     async fn handle_peer_list(&self, _args: &PeerArgs) -> Result<(), Error> {
+        if !self.is_table_output() {
+            return self.print_output(&self.list_peers().await?);
+        }
+
         #[derive(tabled::Tabled)]
         struct PeerTableItem {
             ipv4: String,
@@ -176,7 +508,7 @@ impl CommandHandler {
                 PeerTableItem {
                     ipv4: p.route.ipv4_addr.clone(),
                     hostname: p.route.hostname.clone(),
-                    cost: cost_to_str(p.route.cost),
+                    cost: cost_to_str(p.route.cost, p.route.next_hop_peer_id == p.route.peer_id),
                     lat_ms: float_to_str(p.get_latency_ms().unwrap_or(0.0), 3),
                     loss_rate: float_to_str(p.get_loss_rate().unwrap_or(0.0), 3),
                     rx_bytes: format_size(p.get_rx_bytes().unwrap_or(0), humansize::DECIMAL),
@@ -203,9 +535,14 @@ impl CommandHandler {
     }
 
     async fn handle_route_list(&self) -> Result<(), Error> {
+        if !self.is_table_output() {
+            return self.print_output(&self.list_routes().await?);
+        }
+
         #[derive(tabled::Tabled)]
         struct RouteTableItem {
             ipv4: String,
+            ipv6: String,
             hostname: String,
             proxy_cidrs: String,
             next_hop_ipv4: String,
@@ -214,6 +551,17 @@ impl CommandHandler {
             cost: i32,
         }
 
+        // mark nodes claiming the same ipv4, only the owner receives traffic to the address
+        let ipv4_to_str = |route: &Route| {
+            if !route.ipv4_conflict {
+                route.ipv4_addr.clone()
+            } else if route.ipv4_owner {
+                format!("{} (duplicated)", route.ipv4_addr)
+            } else {
+                format!("{} (duplicated, unreachable)", route.ipv4_addr)
+            }
+        };
+
         let mut items: Vec<RouteTableItem> = vec![];
         let peer_routes = self.list_peer_route_pair().await?;
         for p in peer_routes.iter() {
@@ -224,9 +572,10 @@ impl CommandHandler {
                 continue;
             };
 
-            if p.route.cost == 1 {
+            if p.route.next_hop_peer_id == p.route.peer_id {
                 items.push(RouteTableItem {
-                    ipv4: p.route.ipv4_addr.clone(),
+                    ipv4: ipv4_to_str(&p.route),
+                    ipv6: p.route.ipv6_addr.clone(),
                     hostname: p.route.hostname.clone(),
                     proxy_cidrs: p.route.proxy_cidrs.clone().join(",").to_string(),
                     next_hop_ipv4: "DIRECT".to_string(),
@@ -236,7 +585,8 @@ impl CommandHandler {
                 });
             } else {
                 items.push(RouteTableItem {
-                    ipv4: p.route.ipv4_addr.clone(),
+                    ipv4: ipv4_to_str(&p.route),
+                    ipv6: p.route.ipv6_addr.clone(),
                     hostname: p.route.hostname.clone(),
                     proxy_cidrs: p.route.proxy_cidrs.clone().join(",").to_string(),
                     next_hop_ipv4: next_hop_pair.route.ipv4_addr.clone(),
@@ -255,11 +605,202 @@ impl CommandHandler {
         Ok(())
     }
 
+    async fn handle_acl_list(&self) -> Result<(), Error> {
+        #[derive(tabled::Tabled)]
+        struct AclTableItem {
+            index: String,
+            direction: String,
+            action: String,
+            src: String,
+            dst: String,
+            protocol: String,
+            port: String,
+            peer: String,
+            hits: u64,
+        }
+
+        let mut client = self.get_acl_manager_client().await?;
+        let request = tonic::Request::new(ListAclRuleRequest::default());
+        let response = client.list_acl_rule(request).await?.into_inner();
+        if !self.is_table_output() {
+            return self.print_output(&response);
+        }
+
+        let or_any = |s: String| if s.is_empty() { "*".to_string() } else { s };
+        let mut items: Vec<AclTableItem> = response
+            .rules
+            .into_iter()
+            .map(|r| AclTableItem {
+                index: r.index.to_string(),
+                direction: r.direction,
+                action: r.action,
+                src: or_any(r.src),
+                dst: or_any(r.dst),
+                protocol: or_any(r.protocol),
+                port: or_any(r.port),
+                peer: or_any(r.peer),
+                hits: r.hit_count,
+            })
+            .collect();
+        items.push(AclTableItem {
+            index: "default".to_string(),
+            direction: "in".to_string(),
+            action: response.default_action,
+            src: "*".to_string(),
+            dst: "*".to_string(),
+            protocol: "*".to_string(),
+            port: "*".to_string(),
+            peer: "*".to_string(),
+            hits: response.default_hit_count,
+        });
+
+        println!(
+            "{}",
+            tabled::Table::new(items).with(Style::modern()).to_string()
+        );
+
+        Ok(())
+    }
+
+    async fn handle_dnat_list(&self) -> Result<(), Error> {
+        #[derive(tabled::Tabled)]
+        struct DnatTableItem {
+            proto: String,
+            port: u32,
+            dst_addr: String,
+            hits: u64,
+        }
+
+        let mut client = self.get_dnat_manager_client().await?;
+        let request = tonic::Request::new(ListDnatRuleRequest::default());
+        let response = client.list_dnat_rule(request).await?.into_inner();
+        if !self.is_table_output() {
+            return self.print_output(&response);
+        }
+
+        let items: Vec<DnatTableItem> = response
+            .rules
+            .into_iter()
+            .map(|r| DnatTableItem {
+                proto: r.proto,
+                port: r.port,
+                dst_addr: r.dst_addr,
+                hits: r.hit_count,
+            })
+            .collect();
+
+        println!(
+            "{}",
+            tabled::Table::new(items).with(Style::modern()).to_string()
+        );
+
+        Ok(())
+    }
+
+    async fn manage_dnat_rule(
+        &self,
+        action: DnatManageAction,
+        rule: DnatRuleInfo,
+        persist: bool,
+    ) -> Result<(), Error> {
+        let request = ManageDnatRuleRequest {
+            action: action.into(),
+            rule: Some(rule),
+            persist,
+        };
+        let mut client = self.get_dnat_manager_client().await?;
+        client.manage_dnat_rule(request).await?;
+        Ok(())
+    }
+
+    async fn handle_dnat_add(&self, args: &DnatAddArgs) -> Result<(), Error> {
+        let rule = DnatRuleInfo {
+            proto: args.rule.proto.clone(),
+            port: args.rule.port as u32,
+            dst_addr: args.rule.dst_addr.to_string(),
+            ..Default::default()
+        };
+        self.manage_dnat_rule(DnatManageAction::DnatAdd, rule, args.persist)
+            .await?;
+        println!(
+            "dnat rule added: {}:{} -> {}",
+            args.rule.proto, args.rule.port, args.rule.dst_addr
+        );
+        Ok(())
+    }
+
+    async fn handle_dnat_remove(&self, args: &DnatRemoveArgs) -> Result<(), Error> {
+        let (proto, port) = &args.port;
+        let rule = DnatRuleInfo {
+            proto: proto.clone(),
+            port: *port as u32,
+            ..Default::default()
+        };
+        self.manage_dnat_rule(DnatManageAction::DnatRemove, rule, args.persist)
+            .await?;
+        println!("dnat rule removed: {}:{}", proto, port);
+        Ok(())
+    }
+
+    async fn handle_reload(&self, args: &ReloadArgs) -> Result<(), Error> {
+        let config = match &args.config_file {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => String::new(),
+        };
+
+        let mut client = self.get_config_manager_client().await?;
+        let request = tonic::Request::new(ReloadConfigRequest { config });
+        let response = client.reload_config(request).await?.into_inner();
+        if !self.is_table_output() {
+            return self.print_output(&response);
+        }
+        if response.changes.is_empty() {
+            println!("config reloaded, nothing changed");
+        } else {
+            println!("config reloaded, changes:");
+            for change in response.changes {
+                println!("  {}", change);
+            }
+        }
+        Ok(())
+    }
+
+    // one json object per line, so the output can be fed to log pipelines directly
+    async fn handle_events(&self, args: &EventsArgs) -> Result<(), Error> {
+        let mut client = self.get_event_client().await?;
+        let request = tonic::Request::new(SubscribeEventRequest {
+            follow: args.follow,
+        });
+        let mut stream = client.subscribe_event(request).await?.into_inner();
+        while let Some(event) = stream.message().await? {
+            if event.event_type == "Lagged" {
+                eprintln!(
+                    "events are dropped because the client is too slow: {}",
+                    event.payload
+                );
+            }
+            let time = chrono::DateTime::from_timestamp_millis(event.timestamp_ms)
+                .map(|t| t.with_timezone(&chrono::Local).to_rfc3339())
+                .unwrap_or_default();
+            let payload = serde_json::from_str::<serde_json::Value>(&event.payload)
+                .unwrap_or(serde_json::Value::String(event.payload));
+            let line = serde_json::json!({
+                "time": time,
+                "timestamp_ms": event.timestamp_ms,
+                "event_type": event.event_type,
+                "event": payload,
+            });
+            println!("{}", line);
+        }
+        Ok(())
+    }
+
     async fn handle_connector_list(&self) -> Result<(), Error> {
-        let mut client = self.get_connector_manager_client().await?;
-        let request = tonic::Request::new(ListConnectorRequest::default());
-        let response = client.list_connector(request).await?;
-        println!("response: {:#?}", response.into_inner());
+        let response = self.list_connectors().await?;
+        if !self.is_table_output() {
+            return self.print_output(&response);
+        }
+        println!("response: {:#?}", response);
         Ok(())
     }
 }
@@ -268,17 +809,44 @@ impl CommandHandler {
 #[tracing::instrument]
 async fn main() -> Result<(), Error> {
     let cli = Cli::parse();
+    let tls = if cli.tls_ca.is_some() || cli.tls_cert.is_some() || cli.tls_domain.is_some() {
+        let mut tls = tonic::transport::ClientTlsConfig::new();
+        if let Some(ca) = &cli.tls_ca {
+            tls = tls.ca_certificate(tonic::transport::Certificate::from_pem(std::fs::read(ca)?));
+        }
+        if let (Some(cert), Some(key)) = (&cli.tls_cert, &cli.tls_key) {
+            tls = tls.identity(tonic::transport::Identity::from_pem(
+                std::fs::read(cert)?,
+                std::fs::read(key)?,
+            ));
+        }
+        if let Some(domain) = &cli.tls_domain {
+            tls = tls.domain_name(domain.clone());
+        }
+        Some(tls)
+    } else {
+        None
+    };
     let handler = CommandHandler {
-        addr: format!("http://{}:{}", cli.rpc_portal.ip(), cli.rpc_portal.port()),
+        addr: format!(
+            "{}://{}:{}",
+            if tls.is_some() { "https" } else { "http" },
+            cli.rpc_portal.ip(),
+            cli.rpc_portal.port()
+        ),
+        output: cli.output,
+        target_peer: cli.target_peer,
+        rpc_token: cli.rpc_token,
+        tls,
     };
 
     match cli.sub_command {
         SubCommand::Peer(peer_args) => match &peer_args.sub_command {
-            Some(PeerSubCommand::Add) => {
-                println!("add peer");
+            Some(PeerSubCommand::Add(args)) => {
+                handler.handle_connector_add(args).await?;
             }
-            Some(PeerSubCommand::Remove) => {
-                println!("remove peer");
+            Some(PeerSubCommand::Remove(args)) => {
+                handler.handle_peer_remove(args).await?;
             }
             Some(PeerSubCommand::List(arg)) => {
                 if arg.verbose {
@@ -292,11 +860,11 @@ async fn main() -> Result<(), Error> {
             }
         },
         SubCommand::Connector(conn_args) => match conn_args.sub_command {
-            Some(ConnectorSubCommand::Add) => {
-                println!("add connector");
+            Some(ConnectorSubCommand::Add(args)) => {
+                handler.handle_connector_add(&args).await?;
             }
-            Some(ConnectorSubCommand::Remove) => {
-                println!("remove connector");
+            Some(ConnectorSubCommand::Remove(args)) => {
+                handler.handle_connector_remove(&args).await?;
             }
             Some(ConnectorSubCommand::List) => {
                 handler.handle_connector_list().await?;
@@ -310,7 +878,14 @@ async fn main() -> Result<(), Error> {
         }
         SubCommand::Stun => {
             let stun = UdpNatTypeDetector::new(StunInfoCollector::get_default_servers());
-            println!("udp type: {:?}", stun.get_udp_nat_type(0).await);
+            let udp_nat_type = stun.get_udp_nat_type(0).await;
+            if handler.is_table_output() {
+                println!("udp type: {:?}", udp_nat_type);
+            } else {
+                handler.print_output(&serde_json::json!({
+                    "udp_nat_type": format!("{:?}", udp_nat_type),
+                }))?;
+            }
         }
         SubCommand::PeerCenter => {
             let mut peer_center_client = handler.get_peer_center_client().await?;
@@ -318,6 +893,10 @@ async fn main() -> Result<(), Error> {
                 .get_global_peer_map(GetGlobalPeerMapRequest::default())
                 .await?
                 .into_inner();
+            if !handler.is_table_output() {
+                handler.print_output(&resp)?;
+                return Ok(());
+            }
 
             #[derive(tabled::Tabled)]
             struct PeerCenterTableItem {
@@ -357,13 +936,36 @@ async fn main() -> Result<(), Error> {
             let resp = vpn_portal_client
                 .get_vpn_portal_info(GetVpnPortalInfoRequest::default())
                 .await?
-                .into_inner()
-                .vpn_portal_info
-                .unwrap_or_default();
+                .into_inner();
+            let resp = resp.vpn_portal_info.unwrap_or_default();
+            if !handler.is_table_output() {
+                handler.print_output(&resp)?;
+                return Ok(());
+            }
             println!("portal_name: {}\n", resp.vpn_type);
             println!("client_config:{}", resp.client_config);
             println!("connected_clients:\n{:#?}", resp.connected_clients);
         }
+        SubCommand::Acl => {
+            handler.handle_acl_list().await?;
+        }
+        SubCommand::Dnat(dnat_args) => match &dnat_args.sub_command {
+            Some(DnatSubCommand::Add(args)) => {
+                handler.handle_dnat_add(args).await?;
+            }
+            Some(DnatSubCommand::Remove(args)) => {
+                handler.handle_dnat_remove(args).await?;
+            }
+            Some(DnatSubCommand::List) | None => {
+                handler.handle_dnat_list().await?;
+            }
+        },
+        SubCommand::Reload(reload_args) => {
+            handler.handle_reload(&reload_args).await?;
+        }
+        SubCommand::Events(events_args) => {
+            handler.handle_events(&events_args).await?;
+        }
     }
 
     Ok(())
@@ -3,16 +3,18 @@
 #[cfg(test)]
 mod tests;
 
-use std::{backtrace, io::Write as _, net::SocketAddr};
+use std::{backtrace, collections::HashSet, io::Write as _, net::SocketAddr};
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser};
 
 mod arch;
 mod common;
 mod connector;
 mod gateway;
 mod instance;
+#[cfg(feature = "smoltcp")]
+mod netstack;
 mod peer_center;
 mod peers;
 mod rpc;
@@ -20,9 +22,16 @@ mod tunnel;
 mod vpn_portal;
 
 use common::{
-    config::{ConsoleLoggerConfig, FileLoggerConfig, NetworkIdentity, PeerConfig, VpnPortalConfig},
+    config::{
+        check_proxy_cidr_mapping, check_proxy_networks, check_remote_management_allowed_peers,
+        read_private_key_file, ConnSelectPolicy, ConsoleLoggerConfig, DnatRuleConfig,
+        FileLoggerConfig, NetworkIdentity, PeerConfig, PortForwardConfig, RemoteManagementConfig,
+        RpcPortalAuthConfig, VpnPortalConfig,
+    },
     get_logger_timer_rfc3339,
+    noise::decode_key,
 };
+use gateway::dnat::DnatRules;
 use instance::instance::Instance;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
@@ -39,9 +48,23 @@ use mimalloc_rust::*;
 #[global_allocator]
 static GLOBAL_MIMALLOC: GlobalMiMalloc = GlobalMiMalloc;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[arg(
+        short = 'c',
+        long,
+        help = "path of a toml config file. flags given on command line take precedence over values in the file, also when the file is reloaded"
+    )]
+    config_file: Option<String>,
+
+    #[arg(
+        long,
+        help = "print the effective config in toml format and exit",
+        default_value = "false"
+    )]
+    dump_config: bool,
+
     #[arg(
         long,
         help = "network name to identify this vpn network",
@@ -55,13 +78,73 @@ struct Cli {
     )]
     network_secret: String,
 
+    #[arg(
+        long,
+        help = "file containing the base64 encoded x25519 private key of this node, a random one is generated if not set"
+    )]
+    private_key_file: Option<String>,
+
+    #[arg(
+        long,
+        help = "base64 encoded public keys of peers allowed to join this network, also checked for traffic relayed by other peers. all peers with correct network secret are allowed if not set"
+    )]
+    trusted_public_keys: Vec<String>,
+
+    #[arg(
+        long,
+        help = "base64 encoded public keys of peers allowed to manage this node through the network with easytier-cli --target-peer, * allows all peers. remote management is disabled if empty"
+    )]
+    remote_management_allowed_peers: Vec<String>,
+
     #[arg(
         short,
         long,
-        help = "ipv4 address of this vpn node, if empty, this node will only forward packets and no TUN device will be created"
+        help = "ipv4 address of this vpn node with optional prefix length, e.g. 10.144.144.1/16. the prefix length of the config file or /24 is used if not given. if empty, this node will only forward packets and no TUN device will be created"
     )]
     ipv4: Option<String>,
 
+    #[arg(
+        long,
+        help = "ipv6 address with prefix length of this vpn node, e.g. fd00::1/64"
+    )]
+    ipv6: Option<String>,
+
+    #[arg(
+        long,
+        help = "pick a free ipv4 address from this network automatically when ipv4 is not set, e.g. 10.144.144.0/24"
+    )]
+    dhcp_network: Option<String>,
+
+    #[arg(
+        long,
+        help = "answer dns queries for <hostname>.<network_name>.et on the virtual ip, other queries are forwarded to the system resolver",
+        default_value = "false"
+    )]
+    enable_magic_dns: bool,
+
+    #[arg(
+        long,
+        help = "allow peers to send their internet traffic through this node, the traffic is forwarded by the subnet proxy",
+        default_value = "false"
+    )]
+    enable_exit_node: bool,
+
+    #[arg(
+        long,
+        help = "virtual ipv4 of exit node to send internet traffic through, can be given multiple times and the first reachable one is used"
+    )]
+    exit_nodes: Vec<String>,
+
+    #[arg(
+        long,
+        help = "use a tap device and carry ethernet frames between nodes, only supported on linux. all nodes of the network must enable it",
+        default_value = "false"
+    )]
+    enable_tap: bool,
+
+    #[arg(long, help = "attach the tap device to this linux bridge")]
+    tap_bridge: Option<String>,
+
     #[arg(short, long, help = "peers to connect initially")]
     peers: Vec<String>,
 
@@ -71,7 +154,7 @@ struct Cli {
     #[arg(
         short = 'n',
         long,
-        help = "export local networks to other peers in the vpn"
+        help = "export local networks to other peers in the vpn, a network can be advertised under another cidr of the same size with 1:1 address mapping, e.g. 192.168.1.0/24=10.200.1.0/24"
     )]
     proxy_networks: Vec<String>,
 
@@ -83,6 +166,27 @@ struct Cli {
     )]
     rpc_portal: SocketAddr,
 
+    #[arg(
+        long,
+        help = "token required by the rpc portal, clients must pass it with easytier-cli --rpc-token"
+    )]
+    rpc_portal_token: Option<String>,
+
+    #[arg(
+        long,
+        help = "pem file of the rpc portal certificate, tls is enabled when set with --rpc-portal-tls-key"
+    )]
+    rpc_portal_tls_cert: Option<String>,
+
+    #[arg(long, help = "pem file of the rpc portal private key")]
+    rpc_portal_tls_key: Option<String>,
+
+    #[arg(
+        long,
+        help = "pem file of ca to verify client certificates, clients without a certificate signed by it are rejected"
+    )]
+    rpc_portal_tls_client_ca: Option<String>,
+
     #[arg(short, long, help = "listeners to accept connections, pass '' to avoid listening.",
             default_values_t = ["tcp://0.0.0.0:11010".to_string(),
                                 "udp://0.0.0.0:11010".to_string(),
@@ -118,6 +222,12 @@ struct Cli {
     )]
     instance_id: Option<String>,
 
+    #[arg(
+        long,
+        help = "hostname advertised to peers, the system hostname is used if not set"
+    )]
+    hostname: Option<String>,
+
     #[arg(
         long,
         help = "url that defines the vpn portal, allow other vpn clients to connect.
@@ -126,6 +236,59 @@ and the vpn client is in network of 10.14.14.0/24"
     )]
     vpn_portal: Option<String>,
 
+    #[arg(
+        long,
+        help = "serve prometheus metrics at http://<addr>/metrics, e.g. 127.0.0.1:9100"
+    )]
+    metrics_portal: Option<String>,
+
+    #[arg(
+        long,
+        help = "do not create a tun device, traffic of the virtual network is handled by a userspace stack and reachable with --socks5 and --port-forward. no root is needed",
+        default_value = "false"
+    )]
+    no_tun: bool,
+
+    #[arg(
+        long,
+        help = "serve a socks5 proxy into the virtual network in no tun mode, e.g. 127.0.0.1:1080"
+    )]
+    socks5: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        help = "username and password clients of the socks5 proxy must send, e.g. alice:secret"
+    )]
+    socks5_auth: Option<String>,
+
+    #[arg(
+        long,
+        help = "forward a local port to the virtual network in no tun mode, can be given multiple times, e.g. tcp://127.0.0.1:8080/10.144.144.2:80"
+    )]
+    port_forward: Vec<String>,
+
+    #[arg(
+        long,
+        help = "forward a port of the virtual ip to a host reachable by this node, can be given multiple times, e.g. tcp:8080/192.168.1.10:80"
+    )]
+    dnat: Vec<String>,
+
+    #[arg(
+        long,
+        help = "pem file of the certificate chain for wss listeners, a self signed certificate is used if not set"
+    )]
+    ws_tls_cert: Option<String>,
+
+    #[arg(long, help = "pem file of the private key for wss listeners")]
+    ws_tls_key: Option<String>,
+
+    #[arg(
+        long,
+        help = "do not verify the certificate of wss peers, required to connect to listeners with a self signed certificate. insecure, traffic can be intercepted",
+        default_value = "false"
+    )]
+    ws_tls_insecure: bool,
+
     #[arg(long, help = "default protocol to use when connecting to peers")]
     default_protocol: Option<String>,
 
@@ -146,80 +309,260 @@ and the vpn client is in network of 10.14.14.0/24"
 
     #[arg(long, help = "do not use ipv6", default_value = "false")]
     disable_ipv6: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "policy to select conn when there are multiple conns to one peer"
+    )]
+    conn_select_policy: Option<ConnSelectPolicy>,
+
+    #[arg(
+        long,
+        help = "switch to another conn when loss rate of current conn exceeds this value, 0.0 ~ 1.0"
+    )]
+    conn_failover_loss_rate: Option<f32>,
+
+    #[arg(
+        long,
+        help = "preferred cipher suite for encryption, can be auto, aes-gcm or chacha20-poly1305. auto prefers aes-gcm only if cpu has aes instructions"
+    )]
+    cipher_suite: Option<String>,
+
+    #[arg(
+        long,
+        help = "rotate session key after this many bytes are sent on a conn"
+    )]
+    rekey_after_bytes: Option<u64>,
+
+    #[arg(
+        long,
+        help = "rotate session key after this many packets are sent on a conn"
+    )]
+    rekey_after_packets: Option<u64>,
+
+    #[arg(long, help = "rotate session key after this many seconds")]
+    rekey_after_secs: Option<u64>,
+
+    #[arg(
+        long,
+        help = "seconds to keep the previous session key for packets still in flight"
+    )]
+    rekey_grace_secs: Option<u64>,
+
+    // ids of args given on command line, filled by parse_with_sources
+    #[arg(skip)]
+    explicit_args: HashSet<String>,
 }
 
-impl From<Cli> for TomlConfigLoader {
-    fn from(cli: Cli) -> Self {
-        let cfg = TomlConfigLoader::default();
-        cfg.set_inst_name(cli.instance_name.clone());
-        cfg.set_network_identity(NetworkIdentity::new(
-            cli.network_name.clone(),
-            cli.network_secret.clone(),
-        ));
+impl Cli {
+    fn parse_with_sources() -> Self {
+        Self::from_matches(&Cli::command().get_matches())
+    }
+
+    fn from_matches(matches: &clap::ArgMatches) -> Self {
+        let mut cli = Cli::from_arg_matches(matches).unwrap_or_else(|e| e.exit());
+        cli.explicit_args = matches
+            .ids()
+            .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+            .map(|id| id.to_string())
+            .collect();
+        cli
+    }
+
+    // without a config file all flags (including default values) are used, otherwise only
+    // flags given on command line override the file.
+    fn should_apply(&self, arg_id: &str) -> bool {
+        self.config_file.is_none() || self.explicit_args.contains(arg_id)
+    }
+
+    fn load_config(&self) -> Result<TomlConfigLoader, anyhow::Error> {
+        let cfg = match &self.config_file {
+            Some(path) => TomlConfigLoader::new(path)?,
+            None => TomlConfigLoader::default(),
+        };
+        self.apply_to_config(&cfg)?;
+        Ok(cfg)
+    }
+
+    fn apply_to_config(&self, cfg: &TomlConfigLoader) -> Result<(), anyhow::Error> {
+        let cli = self;
+        if cli.should_apply("instance_name") {
+            cfg.set_inst_name(cli.instance_name.clone());
+        }
 
-        cfg.set_netns(cli.net_ns.clone());
+        if let Some(instance_id) = &cli.instance_id {
+            cfg.set_id(
+                instance_id
+                    .parse()
+                    .with_context(|| format!("failed to parse instance id: {}", instance_id))?,
+            );
+        }
+
+        if cli.hostname.is_some() {
+            cfg.set_hostname(cli.hostname.clone());
+        }
+
+        if cli.should_apply("network_name") || cli.should_apply("network_secret") {
+            let old_identity = cfg.get_network_identity();
+            cfg.set_network_identity(NetworkIdentity::new(
+                if cli.should_apply("network_name") {
+                    cli.network_name.clone()
+                } else {
+                    old_identity.network_name
+                },
+                if cli.should_apply("network_secret") {
+                    cli.network_secret.clone()
+                } else {
+                    old_identity.network_secret.unwrap_or_default()
+                },
+            ));
+        }
+
+        if let Some(private_key_file) = &cli.private_key_file {
+            read_private_key_file(private_key_file)?;
+            cfg.set_private_key_file(Some(private_key_file.clone()));
+        }
+
+        if cli.should_apply("trusted_public_keys") {
+            for key in cli.trusted_public_keys.iter() {
+                decode_key(key)
+                    .with_context(|| format!("failed to parse trusted public key: {}", key))?;
+            }
+            cfg.set_trusted_public_keys(Some(cli.trusted_public_keys.clone()));
+        }
+
+        if cli.should_apply("remote_management_allowed_peers") {
+            let allowed_peers = cli.remote_management_allowed_peers.clone();
+            check_remote_management_allowed_peers(&allowed_peers)?;
+            cfg.set_remote_management(if allowed_peers.is_empty() {
+                None
+            } else {
+                Some(RemoteManagementConfig { allowed_peers })
+            });
+        }
+
+        if cli.should_apply("net_ns") {
+            cfg.set_netns(cli.net_ns.clone());
+        }
         if let Some(ipv4) = &cli.ipv4 {
-            cfg.set_ipv4(
-                ipv4.parse()
-                    .with_context(|| format!("failed to parse ipv4 address: {}", ipv4))
-                    .unwrap(),
-            )
+            if ipv4.contains('/') {
+                cfg.set_ipv4_inet(
+                    ipv4.parse()
+                        .with_context(|| format!("failed to parse ipv4 address: {}", ipv4))?,
+                )
+            } else {
+                cfg.set_ipv4(
+                    ipv4.parse()
+                        .with_context(|| format!("failed to parse ipv4 address: {}", ipv4))?,
+                )
+            }
         }
 
-        cfg.set_peers(
-            cli.peers
-                .iter()
-                .map(|s| PeerConfig {
-                    uri: s
-                        .parse()
-                        .with_context(|| format!("failed to parse peer uri: {}", s))
-                        .unwrap(),
-                })
-                .collect(),
-        );
+        if let Some(dhcp_network) = &cli.dhcp_network {
+            cfg.set_dhcp_network(Some(dhcp_network.parse().with_context(|| {
+                format!("failed to parse dhcp network: {}", dhcp_network)
+            })?));
+        }
 
-        cfg.set_listeners(
-            cli.listeners
-                .iter()
-                .filter_map(|s| {
-                    if s.is_empty() {
-                        return None;
-                    }
+        if cli.should_apply("exit_nodes") {
+            cfg.set_exit_nodes(
+                cli.exit_nodes
+                    .iter()
+                    .map(|ip| {
+                        ip.parse()
+                            .with_context(|| format!("failed to parse exit node: {}", ip))
+                    })
+                    .collect::<Result<_, _>>()?,
+            );
+        }
 
-                    Some(
+        if let Some(ipv6) = &cli.ipv6 {
+            cfg.set_ipv6(
+                ipv6.parse()
+                    .with_context(|| format!("failed to parse ipv6 address: {}", ipv6))?,
+            )
+        }
+
+        if cli.should_apply("peers") {
+            cfg.set_peers(
+                cli.peers
+                    .iter()
+                    .map(|s| {
+                        Ok(PeerConfig {
+                            uri: s
+                                .parse()
+                                .with_context(|| format!("failed to parse peer uri: {}", s))?,
+                        })
+                    })
+                    .collect::<Result<_, anyhow::Error>>()?,
+            );
+        }
+
+        if cli.should_apply("listeners") {
+            cfg.set_listeners(
+                cli.listeners
+                    .iter()
+                    .filter(|s| !s.is_empty())
+                    .map(|s| {
                         s.parse()
                             .with_context(|| format!("failed to parse listener uri: {}", s))
-                            .unwrap(),
-                    )
-                })
-                .collect(),
-        );
-
-        for n in cli.proxy_networks.iter() {
-            cfg.add_proxy_cidr(
-                n.parse()
-                    .with_context(|| format!("failed to parse proxy network: {}", n))
-                    .unwrap(),
+                    })
+                    .collect::<Result<_, _>>()?,
             );
         }
 
-        cfg.set_rpc_portal(cli.rpc_portal);
+        if cli.should_apply("proxy_networks") {
+            for cidr in cfg.get_proxy_cidrs() {
+                cfg.remove_proxy_cidr(cidr);
+            }
+            for n in cli.proxy_networks.iter() {
+                // 192.168.1.0/24=10.200.1.0/24
+                let (cidr_str, mapped_str) = match n.split_once('=') {
+                    Some((c, m)) => (c, Some(m)),
+                    None => (n.as_str(), None),
+                };
+                let cidr: cidr::IpCidr = cidr_str
+                    .parse()
+                    .with_context(|| format!("failed to parse proxy network: {}", n))?;
+                cfg.add_proxy_cidr(cidr);
+                if let Some(mapped_str) = mapped_str {
+                    let mapped_cidr = mapped_str
+                        .parse()
+                        .with_context(|| format!("failed to parse proxy network: {}", n))?;
+                    check_proxy_cidr_mapping(&cidr, &mapped_cidr)?;
+                    cfg.set_proxy_cidr_mapping(cidr, Some(mapped_cidr));
+                }
+            }
+        }
 
-        if cli.external_node.is_some() {
+        if cli.should_apply("rpc_portal") {
+            cfg.set_rpc_portal(cli.rpc_portal);
+        }
+
+        if cli.rpc_portal_token.is_some()
+            || cli.rpc_portal_tls_cert.is_some()
+            || cli.rpc_portal_tls_key.is_some()
+            || cli.rpc_portal_tls_client_ca.is_some()
+        {
+            let old_auth = cfg.get_rpc_portal_auth().unwrap_or_default();
+            cfg.set_rpc_portal_auth(Some(RpcPortalAuthConfig {
+                token: cli.rpc_portal_token.clone().or(old_auth.token),
+                tls_cert: cli.rpc_portal_tls_cert.clone().or(old_auth.tls_cert),
+                tls_key: cli.rpc_portal_tls_key.clone().or(old_auth.tls_key),
+                tls_client_ca: cli
+                    .rpc_portal_tls_client_ca
+                    .clone()
+                    .or(old_auth.tls_client_ca),
+            }));
+        }
+
+        if let Some(external_node) = &cli.external_node {
             let mut old_peers = cfg.get_peers();
             old_peers.push(PeerConfig {
-                uri: cli
-                    .external_node
-                    .clone()
-                    .unwrap()
-                    .parse()
-                    .with_context(|| {
-                        format!(
-                            "failed to parse external node uri: {}",
-                            cli.external_node.unwrap()
-                        )
-                    })
-                    .unwrap(),
+                uri: external_node.parse().with_context(|| {
+                    format!("failed to parse external node uri: {}", external_node)
+                })?,
             });
             cfg.set_peers(old_peers);
         }
@@ -231,42 +574,73 @@ impl From<Cli> for TomlConfigLoader {
         }
 
         if cli.file_log_dir.is_some() || cli.file_log_level.is_some() {
+            let old_config = cfg.get_file_logger_config();
             cfg.set_file_logger_config(FileLoggerConfig {
-                level: cli.file_log_level.clone(),
-                dir: cli.file_log_dir.clone(),
-                file: Some(format!("easytier-{}", cli.instance_name)),
+                level: cli.file_log_level.clone().or(old_config.level),
+                dir: cli.file_log_dir.clone().or(old_config.dir),
+                file: old_config
+                    .file
+                    .or_else(|| Some(format!("easytier-{}", cfg.get_inst_name()))),
             });
         }
 
-        if cli.vpn_portal.is_some() {
-            let url: url::Url = cli
-                .vpn_portal
-                .clone()
-                .unwrap()
+        if let Some(metrics_portal) = &cli.metrics_portal {
+            cfg.set_metrics_portal(Some(metrics_portal.parse().with_context(|| {
+                format!("failed to parse metrics portal address: {}", metrics_portal)
+            })?));
+        }
+
+        if cli.socks5.is_some() {
+            cfg.set_socks5_portal(cli.socks5);
+        }
+
+        if let Some(socks5_auth) = &cli.socks5_auth {
+            cfg.set_socks5_auth(Some(socks5_auth.parse()?));
+        }
+
+        if cli.should_apply("port_forward") {
+            cfg.set_port_forwards(
+                cli.port_forward
+                    .iter()
+                    .map(|s| s.parse::<PortForwardConfig>())
+                    .collect::<Result<_, _>>()?,
+            );
+        }
+
+        if cli.should_apply("dnat") {
+            cfg.set_dnat_rules(
+                cli.dnat
+                    .iter()
+                    .map(|s| s.parse::<DnatRuleConfig>())
+                    .collect::<Result<_, _>>()?,
+            );
+            DnatRules::check_rules(&cfg.get_dnat_rules())?;
+        }
+
+        if let Some(vpn_portal) = &cli.vpn_portal {
+            let url: url::Url = vpn_portal
                 .parse()
-                .with_context(|| {
-                    format!(
-                        "failed to parse vpn portal url: {}",
-                        cli.vpn_portal.unwrap()
-                    )
-                })
-                .unwrap();
+                .with_context(|| format!("failed to parse vpn portal url: {}", vpn_portal))?;
+            let (Some(host), Some(port)) = (url.host_str(), url.port()) else {
+                return Err(anyhow::anyhow!(
+                    "vpn portal url requires host and port: {}",
+                    vpn_portal
+                ));
+            };
             cfg.set_vpn_portal_config(VpnPortalConfig {
-                client_cidr: url.path()[1..]
+                client_cidr: url
+                    .path()
+                    .trim_start_matches('/')
                     .parse()
                     .with_context(|| {
                         format!("failed to parse vpn portal client cidr: {}", url.path())
-                    })
-                    .unwrap(),
-                wireguard_listen: format!("{}:{}", url.host_str().unwrap(), url.port().unwrap())
-                    .parse()
-                    .with_context(|| {
-                        format!(
-                            "failed to parse vpn portal wireguard listen address: {}",
-                            url.host_str().unwrap()
-                        )
-                    })
-                    .unwrap(),
+                    })?,
+                wireguard_listen: format!("{}:{}", host, port).parse().with_context(|| {
+                    format!(
+                        "failed to parse vpn portal wireguard listen address: {}",
+                        host
+                    )
+                })?,
             });
         }
 
@@ -274,30 +648,118 @@ impl From<Cli> for TomlConfigLoader {
         if cli.default_protocol.is_some() {
             f.default_protocol = cli.default_protocol.as_ref().unwrap().clone();
         }
-        f.enable_encryption = !cli.disable_encryption;
-        f.enable_ipv6 = !cli.disable_ipv6;
+        if cli.should_apply("disable_encryption") {
+            f.enable_encryption = !cli.disable_encryption;
+        }
+        if cli.should_apply("disable_ipv6") {
+            f.enable_ipv6 = !cli.disable_ipv6;
+        }
+        if cli.should_apply("enable_magic_dns") {
+            f.enable_magic_dns = cli.enable_magic_dns;
+        }
+        if cli.should_apply("enable_exit_node") {
+            f.enable_exit_node = cli.enable_exit_node;
+        }
+        if cli.should_apply("no_tun") {
+            f.no_tun = cli.no_tun;
+        }
+        if cli.should_apply("enable_tap") {
+            f.enable_tap = cli.enable_tap;
+        }
+        if let Some(bridge) = &cli.tap_bridge {
+            f.tap_bridge = bridge.clone();
+        }
+        if let Some(cert) = &cli.ws_tls_cert {
+            f.ws_tls_cert = cert.clone();
+        }
+        if let Some(key) = &cli.ws_tls_key {
+            f.ws_tls_key = key.clone();
+        }
+        if cli.should_apply("ws_tls_insecure") {
+            f.ws_tls_insecure = cli.ws_tls_insecure;
+        }
+        if let Some(policy) = cli.conn_select_policy {
+            f.conn_select_policy = policy;
+        }
+        if let Some(loss_rate) = cli.conn_failover_loss_rate {
+            f.conn_failover_loss_rate = loss_rate;
+        }
+        if let Some(cipher_suite) = &cli.cipher_suite {
+            f.cipher_suite = cipher_suite.clone();
+        }
+        if let Some(bytes) = cli.rekey_after_bytes {
+            f.rekey_after_bytes = bytes;
+        }
+        if let Some(packets) = cli.rekey_after_packets {
+            f.rekey_after_packets = packets;
+        }
+        if let Some(secs) = cli.rekey_after_secs {
+            f.rekey_after_secs = secs;
+        }
+        if let Some(secs) = cli.rekey_grace_secs {
+            f.rekey_grace_secs = secs;
+        }
         cfg.set_flags(f);
 
-        cfg
+        // proxy networks and the virtual ip may come from both the file and the command line
+        check_proxy_networks(cfg)?;
+        Ok(())
     }
 }
 
-fn init_logger(config: impl ConfigLoader) {
-    let file_config = config.get_file_logger_config();
-    let file_level = file_config
-        .level
+fn parse_log_level(level: Option<String>) -> LevelFilter {
+    level
         .map(|s| s.parse().unwrap())
-        .unwrap_or(LevelFilter::OFF);
+        .unwrap_or(LevelFilter::OFF)
+}
+
+fn build_log_filter(level: LevelFilter) -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env()
+        .unwrap()
+}
+
+type LogLevelReloader = Box<dyn Fn(LevelFilter) + Send + Sync>;
+
+// log levels can be changed by config reload, but the file logger can only be adjusted
+// if it is enabled at startup.
+struct LoggerReloader {
+    console: LogLevelReloader,
+    file: Option<LogLevelReloader>,
+}
+
+impl LoggerReloader {
+    fn reload(&self, config: &dyn ConfigLoader) {
+        (self.console)(parse_log_level(config.get_console_logger_config().level));
+        let file_level = parse_log_level(config.get_file_logger_config().level);
+        match &self.file {
+            Some(file) => file(file_level),
+            None if file_level != LevelFilter::OFF => {
+                tracing::warn!("file logger is not enabled at startup, restart to enable it")
+            }
+            None => {}
+        }
+    }
+}
+
+fn init_logger(config: impl ConfigLoader) -> LoggerReloader {
+    let file_config = config.get_file_logger_config();
+    let file_level = parse_log_level(file_config.level);
 
     // logger to rolling file
     let mut file_layer = None;
+    let mut file_reloader: Option<LogLevelReloader> = None;
     if file_level != LevelFilter::OFF {
         let mut l = tracing_subscriber::fmt::layer();
         l.set_ansi(false);
-        let file_filter = EnvFilter::builder()
-            .with_default_directive(file_level.into())
-            .from_env()
-            .unwrap();
+        let (file_filter, file_filter_handle) =
+            tracing_subscriber::reload::Layer::new(build_log_filter(file_level));
+        file_reloader = Some(Box::new(move |level| {
+            if let Err(e) = file_filter_handle.reload(build_log_filter(level)) {
+                tracing::error!(?e, "reload file log level failed");
+            }
+        }));
         let file_appender = tracing_appender::rolling::Builder::new()
             .rotation(tracing_appender::rolling::Rotation::DAILY)
             .max_log_files(5)
@@ -312,16 +774,9 @@ fn init_logger(config: impl ConfigLoader) {
     }
 
     // logger to console
-    let console_config = config.get_console_logger_config();
-    let console_level = console_config
-        .level
-        .map(|s| s.parse().unwrap())
-        .unwrap_or(LevelFilter::OFF);
-
-    let console_filter = EnvFilter::builder()
-        .with_default_directive(console_level.into())
-        .from_env()
-        .unwrap();
+    let console_level = parse_log_level(config.get_console_logger_config().level);
+    let (console_filter, console_filter_handle) =
+        tracing_subscriber::reload::Layer::new(build_log_filter(console_level));
     let console_layer = tracing_subscriber::fmt::layer()
         .pretty()
         .with_timer(get_logger_timer_rfc3339())
@@ -332,6 +787,15 @@ fn init_logger(config: impl ConfigLoader) {
         .with(console_layer)
         .with(file_layer)
         .init();
+
+    LoggerReloader {
+        console: Box::new(move |level| {
+            if let Err(e) = console_filter_handle.reload(build_log_filter(level)) {
+                tracing::error!(?e, "reload console log level failed");
+            }
+        }),
+        file: file_reloader,
+    }
 }
 
 fn print_event(msg: String) {
@@ -360,13 +824,12 @@ fn setup_panic_handler() {
 }
 
 #[tracing::instrument]
-pub async fn async_main(cli: Cli) {
-    let cfg: TomlConfigLoader = cli.into();
-
-    init_logger(&cfg);
+pub async fn async_main(cli: Cli, cfg: TomlConfigLoader) {
+    let logger_reloader = init_logger(&cfg);
     let mut inst = Instance::new(cfg.clone());
 
-    let mut events = inst.get_global_ctx().subscribe();
+    let global_ctx = inst.get_global_ctx();
+    let mut events = global_ctx.subscribe();
     tokio::spawn(async move {
         while let Ok(e) = events.recv().await {
             match e {
@@ -399,6 +862,10 @@ pub async fn async_main(cli: Cli) {
                     print_event(format!("new listener added. listener: {}", p));
                 }
 
+                GlobalCtxEvent::ListenerRemoved(p) => {
+                    print_event(format!("listener removed. listener: {}", p));
+                }
+
                 GlobalCtxEvent::ConnectionAccepted(local, remote) => {
                     print_event(format!(
                         "new connection accepted. local: {}, remote: {}",
@@ -441,6 +908,29 @@ pub async fn async_main(cli: Cli) {
                         portal, client_addr
                     ));
                 }
+
+                GlobalCtxEvent::DhcpIpv4Changed(old, new) => {
+                    print_event(format!("dhcp ip changed. old: {:?}, new: {:?}", old, new));
+                }
+
+                GlobalCtxEvent::DuplicateIpv4(ipv4, peers) => {
+                    print_event(format!(
+                        "duplicate ipv4 detected, traffic goes to the first peer. ipv4: {}, peers: {:?}",
+                        ipv4, peers
+                    ));
+                }
+
+                GlobalCtxEvent::ExitNodeChanged(exit_node) => match exit_node {
+                    Some(exit_node) => {
+                        print_event(format!("exit node changed. exit node: {}", exit_node))
+                    }
+                    None => print_event("no exit node is reachable".to_string()),
+                },
+
+                GlobalCtxEvent::ConfigReloaded => {
+                    logger_reloader.reload(global_ctx.config.as_ref());
+                    print_event("config reloaded".to_string());
+                }
             }
         }
     });
@@ -450,7 +940,30 @@ pub async fn async_main(cli: Cli) {
     println!("{}", cfg.dump());
     println!("-----------------------------------");
 
-    inst.run().await.unwrap();
+    // keep flags given on command line when reloading the config file
+    let config_reloader = inst.get_config_reloader();
+    config_reloader.set_config_override(Box::new(move |cfg| cli.apply_to_config(cfg)));
+
+    #[cfg(unix)]
+    {
+        let mut sighup =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+        tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                match config_reloader.reload_from_file().await {
+                    Ok(changes) => {
+                        print_event(format!("reload config on SIGHUP. changes: {:?}", changes))
+                    }
+                    Err(e) => print_event(format!("reload config on SIGHUP failed. err: {}", e)),
+                }
+            }
+        });
+    }
+
+    if let Err(e) = inst.run().await {
+        eprintln!("failed to start easytier: {:?}", e);
+        std::process::exit(1);
+    }
 
     inst.wait().await;
 }
@@ -458,21 +971,34 @@ pub async fn async_main(cli: Cli) {
 fn main() {
     setup_panic_handler();
 
-    let cli = Cli::parse();
+    let cli = Cli::parse_with_sources();
     tracing::info!(cli = ?cli, "cli args parsed");
 
+    let cfg = match cli.load_config() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("failed to load config: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if cli.dump_config {
+        println!("{}", cfg.dump());
+        return;
+    }
+
     if cli.multi_thread {
         tokio::runtime::Builder::new_multi_thread()
             .worker_threads(2)
             .enable_all()
             .build()
             .unwrap()
-            .block_on(async move { async_main(cli).await })
+            .block_on(async move { async_main(cli, cfg).await })
     } else {
         tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .unwrap()
-            .block_on(async move { async_main(cli).await })
+            .block_on(async move { async_main(cli, cfg).await })
     }
 }
@@ -0,0 +1,413 @@
+// port forwards (dnat) from the virtual ip of this node to hosts it can reach, e.g. a service on
+// its lan. tcp and udp packets from peers to a port with a rule are handled by TcpProxy and
+// UdpProxy and sent to the destination of the rule, so only that service is exposed instead of
+// the whole proxy network.
+
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, SocketAddrV4},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use dashmap::DashMap;
+
+use crate::{
+    common::{
+        config::{ConfigLoader, DnatRuleConfig},
+        error::Error,
+        global_ctx::ArcGlobalCtx,
+    },
+    rpc::{
+        dnat_manage_rpc_server::DnatManageRpc, DnatManageAction, DnatRuleInfo, ListDnatRuleRequest,
+        ListDnatRuleResponse, ManageDnatRuleRequest, ManageDnatRuleResponse,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DnatProto {
+    Tcp,
+    Udp,
+}
+
+impl FromStr for DnatProto {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "tcp" => Ok(DnatProto::Tcp),
+            "udp" => Ok(DnatProto::Udp),
+            _ => Err(anyhow::anyhow!("invalid dnat proto: {}", s)),
+        }
+    }
+}
+
+impl DnatProto {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DnatProto::Tcp => "tcp",
+            DnatProto::Udp => "udp",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DnatRule {
+    dst_addr: SocketAddrV4,
+    hit_count: AtomicU64,
+}
+
+fn parse_rule(rule: &DnatRuleConfig) -> Result<((DnatProto, u16), SocketAddrV4), Error> {
+    let proto = rule.proto.parse::<DnatProto>()?;
+    if rule.port == 0 {
+        return Err(anyhow::anyhow!("invalid dnat port: {}", rule.port).into());
+    }
+    let SocketAddr::V4(dst_addr) = rule.dst_addr else {
+        return Err(anyhow::anyhow!("dnat destination must be ipv4: {}", rule.dst_addr).into());
+    };
+    Ok(((proto, rule.port), dst_addr))
+}
+
+fn parse_rules(rules: &[DnatRuleConfig]) -> Result<HashMap<(DnatProto, u16), SocketAddrV4>, Error> {
+    let mut ret = HashMap::new();
+    for rule in rules {
+        let (key, dst_addr) = parse_rule(rule)?;
+        if ret.insert(key, dst_addr).is_some() {
+            return Err(
+                anyhow::anyhow!("duplicated dnat rule: {}:{}", key.0.as_str(), key.1).into(),
+            );
+        }
+    }
+    Ok(ret)
+}
+
+#[derive(Debug, Default)]
+pub struct DnatRules {
+    rules: DashMap<(DnatProto, u16), DnatRule>,
+}
+
+impl DnatRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn check_rules(rules: &[DnatRuleConfig]) -> Result<(), Error> {
+        parse_rules(rules).map(|_| ())
+    }
+
+    // replace all rules, hit counters of unchanged rules are kept. nothing is changed if any rule
+    // is invalid or two rules use the same port.
+    pub fn set_rules(&self, rules: &[DnatRuleConfig]) -> Result<(), Error> {
+        let new_rules = parse_rules(rules)?;
+        tracing::info!(?new_rules, "dnat rules updated");
+
+        self.rules
+            .retain(|k, v| new_rules.get(k) == Some(&v.dst_addr));
+        for (key, dst_addr) in new_rules {
+            self.rules.entry(key).or_insert_with(|| DnatRule {
+                dst_addr,
+                hit_count: AtomicU64::new(0),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn add_rule(&self, rule: &DnatRuleConfig) -> Result<(), Error> {
+        let (key, dst_addr) = parse_rule(rule)?;
+        match self.rules.entry(key) {
+            dashmap::mapref::entry::Entry::Occupied(_) => {
+                Err(
+                    anyhow::anyhow!("dnat rule already exists: {}:{}", key.0.as_str(), key.1)
+                        .into(),
+                )
+            }
+            dashmap::mapref::entry::Entry::Vacant(e) => {
+                tracing::info!(?key, ?dst_addr, "dnat rule added");
+                e.insert(DnatRule {
+                    dst_addr,
+                    hit_count: AtomicU64::new(0),
+                });
+                Ok(())
+            }
+        }
+    }
+
+    pub fn remove_rule(&self, proto: DnatProto, port: u16) -> Result<(), Error> {
+        let Some(_) = self.rules.remove(&(proto, port)) else {
+            return Err(Error::NotFound);
+        };
+        tracing::info!(?proto, ?port, "dnat rule removed");
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn lookup(&self, proto: DnatProto, port: u16) -> Option<SocketAddrV4> {
+        self.rules.get(&(proto, port)).map(|r| r.dst_addr)
+    }
+
+    // called for each new connection or udp flow
+    pub fn count_hit(&self, proto: DnatProto, port: u16) {
+        if let Some(r) = self.rules.get(&(proto, port)) {
+            r.hit_count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn list_rules(&self) -> ListDnatRuleResponse {
+        let mut rules = self
+            .rules
+            .iter()
+            .map(|r| (*r.key(), r.dst_addr, r.hit_count.load(Ordering::Relaxed)))
+            .collect::<Vec<_>>();
+        rules.sort_by_key(|r| r.0);
+        ListDnatRuleResponse {
+            rules: rules
+                .into_iter()
+                .map(|((proto, port), dst_addr, hit_count)| DnatRuleInfo {
+                    proto: proto.as_str().to_string(),
+                    port: port as u32,
+                    dst_addr: dst_addr.to_string(),
+                    hit_count,
+                })
+                .collect(),
+        }
+    }
+}
+
+pub struct DnatManagerRpcService {
+    global_ctx: ArcGlobalCtx,
+    rules: Arc<DnatRules>,
+}
+
+impl DnatManagerRpcService {
+    pub fn new(global_ctx: ArcGlobalCtx, rules: Arc<DnatRules>) -> Self {
+        DnatManagerRpcService { global_ctx, rules }
+    }
+
+    // keep rules in config in sync, so a later reload sees them. the change is also applied
+    // to the config file if persist is set.
+    fn update_rules_in_config(
+        &self,
+        proto: DnatProto,
+        port: u16,
+        added: Option<DnatRuleConfig>,
+        persist: bool,
+    ) -> Result<(), Error> {
+        let update = |config: &dyn ConfigLoader| {
+            let mut rules = config.get_dnat_rules();
+            rules.retain(|r| {
+                r.port != port || r.proto.parse::<DnatProto>().map_or(true, |p| p != proto)
+            });
+            rules.extend(added.clone());
+            config.set_dnat_rules(rules);
+        };
+        let config = &self.global_ctx.config;
+        if persist {
+            config.save_with(&update)?;
+        }
+        update(config.as_ref());
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl DnatManageRpc for DnatManagerRpcService {
+    async fn list_dnat_rule(
+        &self,
+        _request: tonic::Request<ListDnatRuleRequest>,
+    ) -> Result<tonic::Response<ListDnatRuleResponse>, tonic::Status> {
+        Ok(tonic::Response::new(self.rules.list_rules()))
+    }
+
+    async fn manage_dnat_rule(
+        &self,
+        request: tonic::Request<ManageDnatRuleRequest>,
+    ) -> Result<tonic::Response<ManageDnatRuleResponse>, tonic::Status> {
+        let req = request.into_inner();
+        let rule = req
+            .rule
+            .ok_or_else(|| tonic::Status::invalid_argument("dnat rule is required"))?;
+        let proto = rule
+            .proto
+            .parse::<DnatProto>()
+            .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+        let port = u16::try_from(rule.port)
+            .map_err(|_| tonic::Status::invalid_argument("invalid dnat port"))?;
+        if req.persist && self.global_ctx.config.get_config_path().is_none() {
+            return Err(tonic::Status::failed_precondition(
+                "instance is not started with a config file",
+            ));
+        }
+
+        // the change is checked and saved first, so running rules never differ from the file
+        let remove = req.action == DnatManageAction::DnatRemove as i32;
+        let exists = self.rules.lookup(proto, port).is_some();
+        let added = if remove {
+            if !exists {
+                return Err(tonic::Status::invalid_argument(format!(
+                    "remove dnat rule failed: {:?}",
+                    Error::NotFound
+                )));
+            }
+            None
+        } else {
+            let config = DnatRuleConfig {
+                proto: proto.as_str().to_string(),
+                port,
+                dst_addr: rule
+                    .dst_addr
+                    .parse()
+                    .map_err(|_| tonic::Status::invalid_argument("invalid dnat dst addr"))?,
+            };
+            parse_rule(&config).map_err(|e| {
+                tonic::Status::invalid_argument(format!("add dnat rule failed: {:?}", e))
+            })?;
+            if exists {
+                return Err(tonic::Status::invalid_argument(format!(
+                    "dnat rule already exists: {}:{}",
+                    proto.as_str(),
+                    port
+                )));
+            }
+            Some(config)
+        };
+        self.update_rules_in_config(proto, port, added.clone(), req.persist)
+            .map_err(|e| {
+                tonic::Status::failed_precondition(format!("update config failed: {:?}", e))
+            })?;
+
+        let ret = match &added {
+            Some(config) => self.rules.add_rule(config),
+            None => self.rules.remove_rule(proto, port),
+        };
+        ret.map_err(|e| tonic::Status::aborted(format!("update dnat rules failed: {:?}", e)))?;
+        Ok(tonic::Response::new(ManageDnatRuleResponse::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{
+        config::TomlConfigLoader,
+        global_ctx::{tests::get_mock_global_ctx, GlobalCtx},
+    };
+
+    use super::*;
+
+    #[test]
+    fn set_and_edit_rules() {
+        let rules = DnatRules::new();
+        let http: DnatRuleConfig = "tcp:8080/192.168.1.10:80".parse().unwrap();
+        let dns: DnatRuleConfig = "udp:53/192.168.1.1:53".parse().unwrap();
+        rules.set_rules(&[http.clone(), dns.clone()]).unwrap();
+        assert_eq!(
+            Some("192.168.1.10:80".parse().unwrap()),
+            rules.lookup(DnatProto::Tcp, 8080)
+        );
+        assert_eq!(None, rules.lookup(DnatProto::Udp, 8080));
+
+        // invalid rules change nothing
+        let mut ipv6 = http.clone();
+        ipv6.dst_addr = "[fd00::1]:80".parse().unwrap();
+        assert!(rules.set_rules(&[ipv6]).is_err());
+        assert!(rules.set_rules(&[http.clone(), http.clone()]).is_err());
+        assert!(rules.add_rule(&http).is_err());
+        assert_eq!(2, rules.list_rules().rules.len());
+
+        // hit counter of unchanged rule is kept
+        rules.count_hit(DnatProto::Tcp, 8080);
+        rules.set_rules(&[http]).unwrap();
+        let list = rules.list_rules().rules;
+        assert_eq!(1, list.len());
+        assert_eq!(1, list[0].hit_count);
+
+        rules.add_rule(&dns).unwrap();
+        rules.remove_rule(DnatProto::Tcp, 8080).unwrap();
+        assert!(matches!(
+            rules.remove_rule(DnatProto::Tcp, 8080),
+            Err(Error::NotFound)
+        ));
+        assert_eq!("udp", rules.list_rules().rules[0].proto);
+    }
+
+    #[tokio::test]
+    async fn manage_dnat_rule_rpc() {
+        let global_ctx = get_mock_global_ctx();
+        let rules = Arc::new(DnatRules::new());
+        let service = DnatManagerRpcService::new(global_ctx.clone(), rules.clone());
+
+        let request = |action: DnatManageAction, persist| {
+            tonic::Request::new(ManageDnatRuleRequest {
+                action: action.into(),
+                rule: Some(DnatRuleInfo {
+                    proto: "tcp".to_string(),
+                    port: 8080,
+                    dst_addr: "192.168.1.10:80".to_string(),
+                    hit_count: 0,
+                }),
+                persist,
+            })
+        };
+
+        service
+            .manage_dnat_rule(request(DnatManageAction::DnatAdd, false))
+            .await
+            .unwrap();
+        assert_eq!(1, rules.list_rules().rules.len());
+        assert_eq!(
+            "192.168.1.10:80",
+            global_ctx.config.get_dnat_rules()[0].dst_addr.to_string()
+        );
+
+        // mock instance has no config file
+        assert!(service
+            .manage_dnat_rule(request(DnatManageAction::DnatRemove, true))
+            .await
+            .is_err());
+
+        service
+            .manage_dnat_rule(request(DnatManageAction::DnatRemove, false))
+            .await
+            .unwrap();
+        assert!(rules.is_empty());
+        assert!(global_ctx.config.get_dnat_rules().is_empty());
+        assert!(service
+            .manage_dnat_rule(request(DnatManageAction::DnatRemove, false))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn manage_dnat_rule_save_failed() {
+        let path = std::env::temp_dir().join(format!("easytier-dnat-{}.toml", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "instance_name = \"dnat\"\n").unwrap();
+        let global_ctx = Arc::new(GlobalCtx::new(TomlConfigLoader::new(path).unwrap()));
+        std::fs::remove_file(path).unwrap();
+
+        let rules = Arc::new(DnatRules::new());
+        let service = DnatManagerRpcService::new(global_ctx.clone(), rules.clone());
+        let ret = service
+            .manage_dnat_rule(tonic::Request::new(ManageDnatRuleRequest {
+                action: DnatManageAction::DnatAdd.into(),
+                rule: Some(DnatRuleInfo {
+                    proto: "tcp".to_string(),
+                    port: 8080,
+                    dst_addr: "192.168.1.10:80".to_string(),
+                    hit_count: 0,
+                }),
+                persist: true,
+            }))
+            .await;
+
+        // the file can not be read, nothing is changed
+        assert!(ret.is_err());
+        assert!(rules.is_empty());
+        assert!(global_ctx.config.get_dnat_rules().is_empty());
+    }
+}
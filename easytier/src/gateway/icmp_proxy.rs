@@ -39,15 +39,23 @@ struct IcmpNatEntry {
     src_peer_id: PeerId,
     my_peer_id: PeerId,
     src_ip: IpAddr,
+    // destination the peer sent to, it differs from the real one for mapped proxy networks
+    dst_ip: Ipv4Addr,
     start_time: std::time::Instant,
 }
 
 impl IcmpNatEntry {
-    fn new(src_peer_id: PeerId, my_peer_id: PeerId, src_ip: IpAddr) -> Result<Self, Error> {
+    fn new(
+        src_peer_id: PeerId,
+        my_peer_id: PeerId,
+        src_ip: IpAddr,
+        dst_ip: Ipv4Addr,
+    ) -> Result<Self, Error> {
         Ok(Self {
             src_peer_id,
             my_peer_id,
             src_ip,
+            dst_ip,
             start_time: std::time::Instant::now(),
         })
     }
@@ -120,6 +128,7 @@ fn socket_recv_loop(socket: Socket, nat_table: IcmpNatTable, sender: UnboundedSe
             continue;
         };
 
+        ipv4_packet.set_source(v.dst_ip);
         ipv4_packet.set_destination(dest_ip);
         ipv4_packet.set_checksum(ipv4::checksum(&ipv4_packet.to_immutable()));
 
@@ -179,6 +188,10 @@ impl IcmpProxy {
         Ok(Arc::new(ret))
     }
 
+    pub fn nat_table_size(&self) -> usize {
+        self.nat_table.len()
+    }
+
     pub async fn start(self: &Arc<Self>) -> Result<(), Error> {
         self.start_icmp_proxy().await?;
         self.start_nat_table_cleaner().await?;
@@ -271,8 +284,9 @@ impl IcmpProxy {
         let icmp_id = icmp_packet.get_identifier();
         let icmp_seq = icmp_packet.get_sequence_number();
 
+        let real_dst_ip = self.cidr_set.to_real_v4(ipv4.get_destination());
         let key = IcmpNatKey {
-            dst_ip: ipv4.get_destination().into(),
+            dst_ip: real_dst_ip.into(),
             icmp_id,
             icmp_seq,
         };
@@ -281,6 +295,7 @@ impl IcmpProxy {
             hdr.from_peer_id.into(),
             hdr.to_peer_id.into(),
             ipv4.get_source().into(),
+            ipv4.get_destination(),
         )
         .ok()?;
 
@@ -288,7 +303,7 @@ impl IcmpProxy {
             tracing::info!("icmp nat table entry replaced: {:?}", old);
         }
 
-        if let Err(e) = self.send_icmp_packet(ipv4.get_destination(), &icmp_packet) {
+        if let Err(e) = self.send_icmp_packet(real_dst_ip, &icmp_packet) {
             tracing::error!("send icmp packet failed: {:?}", e);
         }
 
@@ -3,6 +3,7 @@ use tokio::task::JoinSet;
 
 use crate::common::global_ctx::ArcGlobalCtx;
 
+pub mod dnat;
 pub mod icmp_proxy;
 pub mod tcp_proxy;
 pub mod udp_proxy;
@@ -11,14 +12,21 @@ pub mod udp_proxy;
 struct CidrSet {
     global_ctx: ArcGlobalCtx,
     cidr_set: Arc<Mutex<Vec<cidr::IpCidr>>>,
+    // (mapped cidr, real cidr) of mapped proxy networks, cidr_set contains the mapped ones
+    mapped_cidrs: Arc<Mutex<Vec<(cidr::Ipv4Cidr, cidr::Ipv4Cidr)>>>,
+    // also contains every unicast address outside the virtual network
+    exit_node: bool,
     tasks: JoinSet<()>,
 }
 
 impl CidrSet {
     pub fn new(global_ctx: ArcGlobalCtx) -> Self {
+        let exit_node = global_ctx.get_flags().enable_exit_node;
         let mut ret = Self {
             global_ctx,
             cidr_set: Arc::new(Mutex::new(vec![])),
+            mapped_cidrs: Arc::new(Mutex::new(vec![])),
+            exit_node,
             tasks: JoinSet::new(),
         };
         ret.run_cidr_updater();
@@ -28,10 +36,12 @@ impl CidrSet {
     fn run_cidr_updater(&mut self) {
         let global_ctx = self.global_ctx.clone();
         let cidr_set = self.cidr_set.clone();
+        let mapped_cidrs = self.mapped_cidrs.clone();
         self.tasks.spawn(async move {
             let mut last_cidrs = vec![];
+            let mut last_mappings = vec![];
             loop {
-                let cidrs = global_ctx.get_proxy_cidrs();
+                let cidrs = global_ctx.get_advertised_proxy_cidrs();
                 if cidrs != last_cidrs {
                     last_cidrs = cidrs.clone();
                     cidr_set.lock().unwrap().clear();
@@ -39,23 +49,146 @@ impl CidrSet {
                         cidr_set.lock().unwrap().push(cidr.clone());
                     }
                 }
+                let mappings = global_ctx.get_proxy_cidr_mappings();
+                if mappings != last_mappings {
+                    last_mappings = mappings.clone();
+                    *mapped_cidrs.lock().unwrap() = mappings
+                        .iter()
+                        .filter_map(|m| match m {
+                            (cidr::IpCidr::V4(real), cidr::IpCidr::V4(mapped)) => {
+                                Some((*mapped, *real))
+                            }
+                            _ => None,
+                        })
+                        .collect();
+                }
                 tokio::time::sleep(std::time::Duration::from_secs(1)).await;
             }
         });
     }
 
     pub fn contains_v4(&self, ip: std::net::Ipv4Addr) -> bool {
-        let ip = ip.into();
+        let addr = ip.into();
         let s = self.cidr_set.lock().unwrap();
         for cidr in s.iter() {
-            if cidr.contains(&ip) {
+            if cidr.contains(&addr) {
                 return true;
             }
         }
-        false
+        self.exit_node && self.is_exit_traffic(ip)
+    }
+
+    // translate an address of a mapped proxy network to the real address, others are unchanged
+    pub fn to_real_v4(&self, ip: std::net::Ipv4Addr) -> std::net::Ipv4Addr {
+        let s = self.mapped_cidrs.lock().unwrap();
+        for (mapped, real) in s.iter() {
+            if mapped.contains(&ip) {
+                let host_bits = u32::from(ip) & !u32::from(mapped.mask());
+                return (u32::from(real.first_address()) | host_bits).into();
+            }
+        }
+        ip
+    }
+
+    // lan and link local addresses of the exit node are only reachable when proxied explicitly
+    fn is_exit_traffic(&self, ip: std::net::Ipv4Addr) -> bool {
+        if ip.is_unspecified()
+            || ip.is_loopback()
+            || ip.is_multicast()
+            || ip.is_broadcast()
+            || ip.is_private()
+            || ip.is_link_local()
+        {
+            return false;
+        }
+        !self
+            .global_ctx
+            .get_ipv4_inet()
+            .map(|inet| inet.network().contains(&ip))
+            .unwrap_or(false)
     }
 
     pub fn is_empty(&self) -> bool {
-        self.cidr_set.lock().unwrap().is_empty()
+        !self.exit_node && self.cidr_set.lock().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{error::Error, global_ctx::tests::get_mock_global_ctx};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn exit_node_cidr_set() {
+        let global_ctx = get_mock_global_ctx();
+        global_ctx.set_ipv4_inet("10.144.144.1/24".parse().unwrap());
+        let cidr_set = CidrSet::new(global_ctx.clone());
+        assert!(cidr_set.is_empty());
+        assert!(!cidr_set.contains_v4("1.1.1.1".parse().unwrap()));
+
+        let mut flags = global_ctx.get_flags();
+        flags.enable_exit_node = true;
+        global_ctx.config.set_flags(flags);
+        let cidr_set = CidrSet::new(global_ctx.clone());
+        assert!(!cidr_set.is_empty());
+        assert!(cidr_set.contains_v4("1.1.1.1".parse().unwrap()));
+        assert!(!cidr_set.contains_v4("192.168.1.1".parse().unwrap()));
+        assert!(!cidr_set.contains_v4("172.16.0.1".parse().unwrap()));
+        assert!(!cidr_set.contains_v4("169.254.169.254".parse().unwrap()));
+        assert!(!cidr_set.contains_v4("10.144.144.2".parse().unwrap()));
+        assert!(!cidr_set.contains_v4("224.0.0.1".parse().unwrap()));
+
+        // the lan of the exit node is reachable when it is proxied explicitly
+        global_ctx
+            .add_proxy_cidr("192.168.1.0/24".parse().unwrap())
+            .unwrap();
+        let cidr_set = CidrSet::new(global_ctx);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(cidr_set.contains_v4("192.168.1.1".parse().unwrap()));
+        assert!(!cidr_set.contains_v4("192.168.2.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn mapped_cidr_set() {
+        let global_ctx = get_mock_global_ctx();
+        let cidr = "192.168.1.0/24".parse().unwrap();
+        global_ctx.add_proxy_cidr(cidr).unwrap();
+        global_ctx
+            .set_proxy_cidr_mapping(cidr, Some("10.200.1.0/24".parse().unwrap()))
+            .unwrap();
+        assert!(global_ctx
+            .set_proxy_cidr_mapping(cidr, Some("10.200.0.0/16".parse().unwrap()))
+            .is_err());
+        // only proxy networks can be mapped
+        assert!(matches!(
+            global_ctx.set_proxy_cidr_mapping(
+                "192.168.2.0/24".parse().unwrap(),
+                Some("10.200.2.0/24".parse().unwrap())
+            ),
+            Err(Error::NotFound)
+        ));
+        // a new proxy network may not overlap the mapped cidr
+        assert!(global_ctx
+            .add_proxy_cidr("10.200.0.0/16".parse().unwrap())
+            .is_err());
+        assert_eq!(
+            vec!["10.200.1.0/24".parse::<cidr::IpCidr>().unwrap()],
+            global_ctx.get_advertised_proxy_cidrs()
+        );
+
+        let cidr_set = CidrSet::new(global_ctx);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        // only the mapped cidr is reachable from peers
+        assert!(cidr_set.contains_v4("10.200.1.20".parse().unwrap()));
+        assert!(!cidr_set.contains_v4("192.168.1.20".parse().unwrap()));
+        assert_eq!(
+            "192.168.1.20".parse::<std::net::Ipv4Addr>().unwrap(),
+            cidr_set.to_real_v4("10.200.1.20".parse().unwrap())
+        );
+        assert_eq!(
+            "10.1.1.1".parse::<std::net::Ipv4Addr>().unwrap(),
+            cidr_set.to_real_v4("10.1.1.1".parse().unwrap())
+        );
     }
 }
@@ -24,6 +24,7 @@ use crate::peers::peer_manager::PeerManager;
 use crate::peers::{NicPacketFilter, PeerPacketFilter};
 use crate::tunnel::packet_def::{PacketType, ZCPacket};
 
+use super::dnat::{DnatProto, DnatRules};
 use super::CidrSet;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -43,17 +44,20 @@ pub struct NatDstEntry {
     id: uuid::Uuid,
     src: SocketAddr,
     dst: SocketAddr,
+    // address connected to, it differs from dst for dnat and mapped proxy networks
+    real_dst: SocketAddr,
     start_time: Instant,
     tasks: Mutex<JoinSet<()>>,
     state: AtomicCell<NatDstEntryState>,
 }
 
 impl NatDstEntry {
-    pub fn new(src: SocketAddr, dst: SocketAddr) -> Self {
+    pub fn new(src: SocketAddr, dst: SocketAddr, real_dst: SocketAddr) -> Self {
         Self {
             id: uuid::Uuid::new_v4(),
             src,
             dst,
+            real_dst,
             start_time: Instant::now(),
             tasks: Mutex::new(JoinSet::new()),
             state: AtomicCell::new(NatDstEntryState::SynReceived),
@@ -81,6 +85,7 @@ pub struct TcpProxy {
     addr_conn_map: AddrConnSockMap,
 
     cidr_set: CidrSet,
+    dnat_rules: Arc<DnatRules>,
 }
 
 #[async_trait::async_trait]
@@ -156,7 +161,11 @@ impl NicPacketFilter for TcpProxy {
 }
 
 impl TcpProxy {
-    pub fn new(global_ctx: Arc<GlobalCtx>, peer_manager: Arc<PeerManager>) -> Arc<Self> {
+    pub fn new(
+        global_ctx: Arc<GlobalCtx>,
+        peer_manager: Arc<PeerManager>,
+        dnat_rules: Arc<DnatRules>,
+    ) -> Arc<Self> {
         Arc::new(Self {
             global_ctx: global_ctx.clone(),
             peer_manager,
@@ -169,6 +178,7 @@ impl TcpProxy {
             addr_conn_map: Arc::new(DashMap::new()),
 
             cidr_set: CidrSet::new(global_ctx),
+            dnat_rules,
         })
     }
 
@@ -310,7 +320,7 @@ impl TcpProxy {
         }
         let Ok(Ok(dst_tcp_stream)) = tokio::time::timeout(
             Duration::from_secs(10),
-            TcpSocket::new_v4().unwrap().connect(nat_entry.dst),
+            TcpSocket::new_v4().unwrap().connect(nat_entry.real_dst),
         )
         .await
         else {
@@ -355,6 +365,11 @@ impl TcpProxy {
         self.local_port.load(std::sync::atomic::Ordering::Relaxed)
     }
 
+    // connecting and established connections
+    pub fn nat_table_size(&self) -> usize {
+        self.syn_map.len() + self.conn_map.len()
+    }
+
     async fn try_handle_peer_packet(&self, packet: &mut ZCPacket) -> Option<()> {
         let ipv4_addr = self.global_ctx.get_ipv4()?;
         let hdr = packet.peer_manager_header().unwrap();
@@ -370,11 +385,19 @@ impl TcpProxy {
             return None;
         }
 
-        if !self.cidr_set.contains_v4(ipv4.get_destination()) {
+        // connections to a dnat port of the virtual ip go to the destination of the rule
+        let dnat_dst = if ipv4.get_destination() == ipv4_addr {
+            let tcp_packet = TcpPacket::new(ipv4.payload())?;
+            self.dnat_rules
+                .lookup(DnatProto::Tcp, tcp_packet.get_destination())
+        } else {
+            None
+        };
+        if dnat_dst.is_none() && !self.cidr_set.contains_v4(ipv4.get_destination()) {
             return None;
         }
 
-        tracing::info!(ipv4 = ?ipv4, cidr_set = ?self.cidr_set, "proxy tcp packet received");
+        tracing::info!(ipv4 = ?ipv4, cidr_set = ?self.cidr_set, ?dnat_dst, "proxy tcp packet received");
 
         let ip_packet = Ipv4Packet::new(payload_bytes).unwrap();
         let tcp_packet = TcpPacket::new(ip_packet.payload()).unwrap();
@@ -389,10 +412,18 @@ impl TcpProxy {
             let dest_port = tcp_packet.get_destination();
             let dst = SocketAddr::V4(SocketAddrV4::new(dest_ip, dest_port));
 
+            let real_dst = dnat_dst
+                .unwrap_or_else(|| SocketAddrV4::new(self.cidr_set.to_real_v4(dest_ip), dest_port));
+            let real_dst = SocketAddr::V4(real_dst);
+
             let old_val = self
                 .syn_map
-                .insert(src, Arc::new(NatDstEntry::new(src, dst)));
-            tracing::trace!(src = ?src, dst = ?dst, old_entry = ?old_val, "tcp syn received");
+                .insert(src, Arc::new(NatDstEntry::new(src, dst, real_dst)));
+            // syn may be retransmitted
+            if dnat_dst.is_some() && old_val.is_none() {
+                self.dnat_rules.count_hit(DnatProto::Tcp, dest_port);
+            }
+            tracing::trace!(src = ?src, dst = ?dst, ?real_dst, old_entry = ?old_val, "tcp syn received");
         }
 
         let mut ip_packet = MutableIpv4Packet::new(payload_bytes).unwrap();
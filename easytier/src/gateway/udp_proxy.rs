@@ -32,11 +32,16 @@ use crate::{
     },
 };
 
+use super::dnat::{DnatProto, DnatRules};
 use super::CidrSet;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 struct UdpNatKey {
     src_socket: SocketAddr,
+    // set if the destination is translated by dnat or a mapped proxy network. such flows get their
+    // own socket, so responses of a real destination reached under several addresses go back
+    // from the right one
+    virtual_dst: Option<SocketAddrV4>,
 }
 
 #[derive(Debug)]
@@ -45,6 +50,9 @@ struct UdpNatEntry {
     my_peer_id: PeerId,
     src_socket: SocketAddr,
     socket: UdpSocket,
+    // real destination of dnat or mapped proxy networks and the address the peer sent to, used as
+    // source of responses
+    virtual_addr: Option<(SocketAddrV4, SocketAddrV4)>,
     forward_task: Mutex<Option<JoinHandle<()>>>,
     stopped: AtomicBool,
     start_time: std::time::Instant,
@@ -52,7 +60,12 @@ struct UdpNatEntry {
 
 impl UdpNatEntry {
     #[tracing::instrument(err(level = Level::WARN))]
-    fn new(src_peer_id: PeerId, my_peer_id: PeerId, src_socket: SocketAddr) -> Result<Self, Error> {
+    fn new(
+        src_peer_id: PeerId,
+        my_peer_id: PeerId,
+        src_socket: SocketAddr,
+        virtual_addr: Option<(SocketAddrV4, SocketAddrV4)>,
+    ) -> Result<Self, Error> {
         // TODO: try use src port, so we will be ip restricted nat type
         let socket2_socket = socket2::Socket::new(
             socket2::Domain::IPV4,
@@ -68,6 +81,7 @@ impl UdpNatEntry {
             my_peer_id,
             src_socket,
             socket,
+            virtual_addr,
             forward_task: Mutex::new(None),
             stopped: AtomicBool::new(false),
             start_time: std::time::Instant::now(),
@@ -189,6 +203,10 @@ impl UdpNatEntry {
             let SocketAddr::V4(src_v4) = src_socket else {
                 continue;
             };
+            let src_v4 = match self.virtual_addr {
+                Some((real_addr, virtual_addr)) if real_addr == src_v4 => virtual_addr,
+                _ => src_v4,
+            };
 
             let Ok(_) = Self::compose_ipv4_packet(
                 &self,
@@ -216,6 +234,7 @@ pub struct UdpProxy {
     peer_manager: Arc<PeerManager>,
 
     cidr_set: CidrSet,
+    dnat_rules: Arc<DnatRules>,
 
     nat_table: Arc<DashMap<UdpNatKey, Arc<UdpNatEntry>>>,
 
@@ -227,11 +246,11 @@ pub struct UdpProxy {
 
 impl UdpProxy {
     async fn try_handle_packet(&self, packet: &ZCPacket) -> Option<()> {
-        if self.cidr_set.is_empty() {
+        if self.cidr_set.is_empty() && self.dnat_rules.is_empty() {
             return None;
         }
 
-        let _ = self.global_ctx.get_ipv4()?;
+        let my_ipv4 = self.global_ctx.get_ipv4()?;
         let hdr = packet.peer_manager_header().unwrap();
         if hdr.packet_type != PacketType::Data as u8 {
             return None;
@@ -242,12 +261,19 @@ impl UdpProxy {
             return None;
         }
 
-        if !self.cidr_set.contains_v4(ipv4.get_destination()) {
+        let udp_packet = udp::UdpPacket::new(ipv4.payload())?;
+
+        // packets to a dnat port of the virtual ip go to the destination of the rule
+        let dnat_dst = if ipv4.get_destination() == my_ipv4 {
+            self.dnat_rules
+                .lookup(DnatProto::Udp, udp_packet.get_destination())
+        } else {
+            None
+        };
+        if dnat_dst.is_none() && !self.cidr_set.contains_v4(ipv4.get_destination()) {
             return None;
         }
 
-        let udp_packet = udp::UdpPacket::new(ipv4.payload())?;
-
         tracing::trace!(
             ?packet,
             ?ipv4,
@@ -255,19 +281,34 @@ impl UdpProxy {
             "udp nat packet request received"
         );
 
+        let virtual_addr = SocketAddrV4::new(ipv4.get_destination(), udp_packet.get_destination());
+        let real_addr = dnat_dst.unwrap_or_else(|| {
+            SocketAddrV4::new(
+                self.cidr_set.to_real_v4(*virtual_addr.ip()),
+                virtual_addr.port(),
+            )
+        });
+        let translated = (real_addr != virtual_addr).then_some((real_addr, virtual_addr));
+
         let nat_key = UdpNatKey {
             src_socket: SocketAddr::new(ipv4.get_source().into(), udp_packet.get_source()),
+            virtual_dst: translated.map(|(_, virtual_addr)| virtual_addr),
         };
         let nat_entry = self
             .nat_table
             .entry(nat_key)
             .or_try_insert_with::<Error>(|| {
                 tracing::info!(?packet, ?ipv4, ?udp_packet, "udp nat table entry created");
+                if dnat_dst.is_some() {
+                    self.dnat_rules
+                        .count_hit(DnatProto::Udp, virtual_addr.port());
+                }
                 let _g = self.global_ctx.net_ns.guard();
                 Ok(Arc::new(UdpNatEntry::new(
                     hdr.from_peer_id.get(),
                     hdr.to_peer_id.get(),
                     nat_key.src_socket,
+                    translated,
                 )?))
             })
             .ok()?
@@ -285,8 +326,7 @@ impl UdpProxy {
         }
 
         // TODO: should it be async.
-        let dst_socket =
-            SocketAddr::new(ipv4.get_destination().into(), udp_packet.get_destination());
+        let dst_socket = SocketAddr::V4(real_addr);
         let send_ret = {
             let _g = self.global_ctx.net_ns.guard();
             nat_entry
@@ -324,6 +364,7 @@ impl UdpProxy {
     pub fn new(
         global_ctx: ArcGlobalCtx,
         peer_manager: Arc<PeerManager>,
+        dnat_rules: Arc<DnatRules>,
     ) -> Result<Arc<Self>, Error> {
         let cidr_set = CidrSet::new(global_ctx.clone());
         let (sender, receiver) = unbounded_channel();
@@ -331,6 +372,7 @@ impl UdpProxy {
             global_ctx,
             peer_manager,
             cidr_set,
+            dnat_rules,
             nat_table: Arc::new(DashMap::new()),
             sender,
             receiver: Mutex::new(Some(receiver)),
@@ -339,6 +381,10 @@ impl UdpProxy {
         Ok(Arc::new(ret))
     }
 
+    pub fn nat_table_size(&self) -> usize {
+        self.nat_table.len()
+    }
+
     pub async fn start(self: &Arc<Self>) -> Result<(), Error> {
         self.peer_manager
             .add_packet_process_pipeline(Box::new(self.clone()))
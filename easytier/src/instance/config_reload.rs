@@ -0,0 +1,498 @@
+// apply a new config to a running instance without dropping tunnels. only connectors,
+// listeners, proxy cidrs, exit nodes, trusted keys, acl rules, dnat rules, dns records, remote
+// management and log levels can be changed, other changes are rejected because they need a restart.
+// connections to peers whose key is removed from the trusted keys are closed.
+
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use tracing::level_filters::LevelFilter;
+
+use crate::{
+    common::{
+        config::{ConfigLoader, PeerConfig, TomlConfigLoader},
+        error::Error,
+        global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
+        noise::decode_key,
+    },
+    connector::manual::ManualConnectorManager,
+    gateway::dnat::DnatRules,
+    peers::{acl_filter::AclFilter, peer_manager::PeerManager},
+    rpc::{config_manage_rpc_server::ConfigManageRpc, ReloadConfigRequest, ReloadConfigResponse},
+};
+
+use super::listeners::{get_listener_by_url, ListenerManager};
+
+fn check_restart_required(old: &dyn ConfigLoader, new: &dyn ConfigLoader) -> Vec<&'static str> {
+    let mut ret = vec![];
+
+    let (old_nid, new_nid) = (old.get_network_identity(), new.get_network_identity());
+    if old_nid.network_name != new_nid.network_name
+        || old_nid.network_secret != new_nid.network_secret
+    {
+        ret.push("network_identity");
+    }
+    if old.get_inst_name() != new.get_inst_name() {
+        ret.push("instance_name");
+    }
+    // a config without instance id keeps the generated one
+    if new
+        .get_configured_id()
+        .map(|id| id != old.get_id())
+        .unwrap_or(false)
+    {
+        ret.push("instance_id");
+    }
+    if old.get_hostname() != new.get_hostname() {
+        ret.push("hostname");
+    }
+    if old.get_netns() != new.get_netns() {
+        ret.push("netns");
+    }
+    // address assigned by dhcp is not in config
+    if old.get_ipv4_inet() != new.get_ipv4_inet() {
+        ret.push("ipv4");
+    }
+    if old.get_dhcp_network() != new.get_dhcp_network() {
+        ret.push("dhcp_network");
+    }
+    if old.get_ipv6() != new.get_ipv6() {
+        ret.push("ipv6");
+    }
+    if old.get_private_key() != new.get_private_key() {
+        ret.push("private_key");
+    }
+    if old.get_rpc_portal() != new.get_rpc_portal() {
+        ret.push("rpc_portal");
+    }
+    if old.get_rpc_portal_auth() != new.get_rpc_portal_auth() {
+        ret.push("rpc_portal_auth");
+    }
+    if old.get_metrics_portal() != new.get_metrics_portal() {
+        ret.push("metrics_portal");
+    }
+    if old.get_socks5_portal() != new.get_socks5_portal() {
+        ret.push("socks5_portal");
+    }
+    if old.get_socks5_auth() != new.get_socks5_auth() {
+        ret.push("socks5_auth");
+    }
+    if old.get_port_forwards() != new.get_port_forwards() {
+        ret.push("port_forward");
+    }
+    if old.get_vpn_portal_config() != new.get_vpn_portal_config() {
+        ret.push("vpn_portal_config");
+    }
+
+    // default acl action is applied like acl rules
+    let mut new_flags = new.get_flags();
+    new_flags.acl_default_action = old.get_flags().acl_default_action;
+    if old.get_flags() != new_flags {
+        ret.push("flags");
+    }
+
+    ret
+}
+
+fn check_log_level(level: &Option<String>) -> Result<(), Error> {
+    if let Some(level) = level {
+        level
+            .parse::<LevelFilter>()
+            .map_err(|_| anyhow::anyhow!("invalid log level: {}", level))?;
+    }
+    Ok(())
+}
+
+// returns (added, removed)
+fn diff<T: PartialEq + Clone>(old: &[T], new: &[T]) -> (Vec<T>, Vec<T>) {
+    (
+        new.iter().filter(|x| !old.contains(x)).cloned().collect(),
+        old.iter().filter(|x| !new.contains(x)).cloned().collect(),
+    )
+}
+
+pub type ConfigOverride = Box<dyn Fn(&TomlConfigLoader) -> Result<(), anyhow::Error> + Send + Sync>;
+
+pub struct ConfigReloader {
+    global_ctx: ArcGlobalCtx,
+    conn_manager: Arc<ManualConnectorManager>,
+    listener_manager: Arc<Mutex<ListenerManager<PeerManager>>>,
+    peer_manager: Arc<PeerManager>,
+    acl_filter: Arc<AclFilter>,
+    dnat_rules: Arc<DnatRules>,
+    // applied to the config loaded from file, e.g. flags given on command line
+    config_override: std::sync::Mutex<Option<ConfigOverride>>,
+
+    // only one reload at a time
+    lock: Mutex<()>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        global_ctx: ArcGlobalCtx,
+        conn_manager: Arc<ManualConnectorManager>,
+        listener_manager: Arc<Mutex<ListenerManager<PeerManager>>>,
+        peer_manager: Arc<PeerManager>,
+        acl_filter: Arc<AclFilter>,
+        dnat_rules: Arc<DnatRules>,
+    ) -> Self {
+        ConfigReloader {
+            global_ctx,
+            conn_manager,
+            listener_manager,
+            peer_manager,
+            acl_filter,
+            dnat_rules,
+            config_override: std::sync::Mutex::new(None),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn set_config_override(&self, f: ConfigOverride) {
+        *self.config_override.lock().unwrap() = Some(f);
+    }
+
+    pub async fn reload_from_file(&self) -> Result<Vec<String>, Error> {
+        let Some(path) = self.global_ctx.config.get_config_path() else {
+            return Err(anyhow::anyhow!("instance is not started with a config file").into());
+        };
+        let new_config = TomlConfigLoader::new(&path)?;
+        if let Some(f) = self.config_override.lock().unwrap().as_ref() {
+            f(&new_config)?;
+        }
+        self.reload(&new_config).await
+    }
+
+    // returns description of applied changes. nothing is applied if the new config is invalid or
+    // needs a restart, errors of starting listeners or connectors are returned after other
+    // changes are applied.
+    pub async fn reload(&self, new: &dyn ConfigLoader) -> Result<Vec<String>, Error> {
+        let _g = self.lock.lock().await;
+        let cur = self.global_ctx.config.as_ref();
+
+        let restart_required = check_restart_required(cur, new);
+        if !restart_required.is_empty() {
+            return Err(anyhow::anyhow!(
+                "changes of {} require a restart",
+                restart_required.join(", ")
+            )
+            .into());
+        }
+
+        let new_console_logger = new.get_console_logger_config();
+        let new_file_logger = new.get_file_logger_config();
+        check_log_level(&new_console_logger.level)?;
+        check_log_level(&new_file_logger.level)?;
+
+        let (added_listeners, removed_listeners) =
+            diff(&cur.get_listener_uris(), &new.get_listener_uris());
+        for l in added_listeners.iter() {
+            get_listener_by_url(l, self.global_ctx.clone())?;
+        }
+
+        let new_trusted_keys = new.get_trusted_public_keys();
+        for key in new_trusted_keys.iter().flatten() {
+            decode_key(key)?;
+        }
+
+        let new_dnat_rules = new.get_dnat_rules();
+        DnatRules::check_rules(&new_dnat_rules)?;
+
+        let mut changes = vec![];
+        let mut errs = vec![];
+
+        // acl rules are validated when set, so an invalid rule rejects the whole reload
+        let new_acl_rules = new.get_acl_rules();
+        let new_acl_default_action = new.get_flags().acl_default_action;
+        if new_acl_rules != cur.get_acl_rules()
+            || new_acl_default_action != cur.get_flags().acl_default_action
+        {
+            self.acl_filter
+                .set_rules(&new_acl_rules, &new_acl_default_action)?;
+            cur.set_acl_rules(new_acl_rules);
+            let mut flags = cur.get_flags();
+            flags.acl_default_action = new_acl_default_action;
+            cur.set_flags(flags);
+            changes.push("acl rules updated".to_string());
+        }
+
+        if new_dnat_rules != cur.get_dnat_rules() {
+            self.dnat_rules.set_rules(&new_dnat_rules)?;
+            cur.set_dnat_rules(new_dnat_rules);
+            changes.push("dnat rules updated".to_string());
+        }
+
+        if new_console_logger != cur.get_console_logger_config() {
+            changes.push(format!(
+                "console log level: {}",
+                new_console_logger.level.as_deref().unwrap_or("off")
+            ));
+            cur.set_console_logger_config(new_console_logger);
+        }
+        if new_file_logger != cur.get_file_logger_config() {
+            changes.push(format!(
+                "file log level: {}",
+                new_file_logger.level.as_deref().unwrap_or("off")
+            ));
+            cur.set_file_logger_config(new_file_logger);
+        }
+
+        if new_trusted_keys != cur.get_trusted_public_keys() {
+            cur.set_trusted_public_keys(new_trusted_keys);
+            changes.push("trusted public keys updated".to_string());
+            // keys of existing conns are only checked in handshake
+            let closed = self.peer_manager.close_untrusted_peer_conns().await;
+            if closed > 0 {
+                changes.push(format!("{} untrusted peer connections closed", closed));
+            }
+        }
+
+        // magic dns reads records from config on every query
+        let new_dns_records = new.get_dns_records();
+        if new_dns_records != cur.get_dns_records() {
+            cur.set_dns_records(new_dns_records);
+            changes.push("dns records updated".to_string());
+        }
+
+        // checked on every remote management request
+        let new_remote_management = new.get_remote_management();
+        if new_remote_management != cur.get_remote_management() {
+            cur.set_remote_management(new_remote_management);
+            changes.push("remote management updated".to_string());
+        }
+
+        // exit node manager picks up the new list in a second
+        let new_exit_nodes = new.get_exit_nodes();
+        if new_exit_nodes != cur.get_exit_nodes() {
+            changes.push(format!("exit nodes: {:?}", new_exit_nodes));
+            cur.set_exit_nodes(new_exit_nodes);
+        }
+
+        // mappings of the new config are already validated when it's loaded. old ones are cleared
+        // first, so a network or mapping may move to the cidr of another one
+        let new_mappings = new.get_proxy_cidr_mappings();
+        let mappings_changed = new_mappings != cur.get_proxy_cidr_mappings();
+        if mappings_changed {
+            for (cidr, _) in cur.get_proxy_cidr_mappings() {
+                self.global_ctx.set_proxy_cidr_mapping(cidr, None)?;
+            }
+        }
+
+        let (added_cidrs, removed_cidrs) = diff(&cur.get_proxy_cidrs(), &new.get_proxy_cidrs());
+        for cidr in removed_cidrs {
+            self.global_ctx.remove_proxy_cidr(cidr)?;
+            changes.push(format!("proxy cidr removed: {}", cidr));
+        }
+        for cidr in added_cidrs {
+            self.global_ctx.add_proxy_cidr(cidr)?;
+            changes.push(format!("proxy cidr added: {}", cidr));
+        }
+        if mappings_changed {
+            for (cidr, mapped_cidr) in new_mappings.iter() {
+                self.global_ctx
+                    .set_proxy_cidr_mapping(*cidr, Some(*mapped_cidr))?;
+            }
+            changes.push(format!("proxy cidr mappings: {:?}", new_mappings));
+        }
+
+        let peer_uris = |c: &dyn ConfigLoader| c.get_peers().into_iter().map(|p| p.uri);
+        let (added_peers, removed_peers) = diff(
+            &peer_uris(cur).collect::<Vec<_>>(),
+            &peer_uris(new).collect::<Vec<_>>(),
+        );
+        let mut peers = cur.get_peers();
+        for uri in removed_peers {
+            // the connector may be dead and being reconnected, it is removed later in that case
+            if let Err(e) = self.conn_manager.remove_connector(uri.as_str()).await {
+                tracing::warn!(?e, %uri, "remove connector failed");
+            }
+            peers.retain(|p| p.uri != uri);
+            changes.push(format!("peer removed: {}", uri));
+        }
+        for uri in added_peers {
+            match self.conn_manager.add_connector_by_url(uri.as_str()).await {
+                Ok(_) => {
+                    changes.push(format!("peer added: {}", uri));
+                    peers.push(PeerConfig { uri });
+                }
+                Err(e) => errs.push(format!("add peer {} failed: {}", uri, e)),
+            }
+        }
+        cur.set_peers(peers);
+
+        let mut listeners = cur.get_listeners();
+        let mut listener_manager = self.listener_manager.lock().await;
+        for l in removed_listeners {
+            match listener_manager.remove_listener_by_url(&l).await {
+                Ok(_) => {
+                    listeners.retain(|x| *x != l);
+                    changes.push(format!("listener removed: {}", l));
+                }
+                Err(e) => errs.push(format!("remove listener {} failed: {}", l, e)),
+            }
+        }
+        for l in added_listeners {
+            match listener_manager.add_running_listener_by_url(&l).await {
+                Ok(_) => {
+                    changes.push(format!("listener added: {}", l));
+                    listeners.push(l);
+                }
+                Err(e) => errs.push(format!("add listener {} failed: {:?}", l, e)),
+            }
+        }
+        drop(listener_manager);
+        cur.set_listeners(listeners);
+
+        tracing::info!(?changes, ?errs, "config reloaded");
+        if !changes.is_empty() {
+            self.global_ctx.issue_event(GlobalCtxEvent::ConfigReloaded);
+        }
+
+        if errs.is_empty() {
+            Ok(changes)
+        } else {
+            Err(anyhow::anyhow!(
+                "config partially reloaded, applied: {:?}, errors: {:?}",
+                changes,
+                errs
+            )
+            .into())
+        }
+    }
+}
+
+pub struct ConfigManagerRpcService(pub Arc<ConfigReloader>);
+
+#[tonic::async_trait]
+impl ConfigManageRpc for ConfigManagerRpcService {
+    async fn reload_config(
+        &self,
+        request: tonic::Request<ReloadConfigRequest>,
+    ) -> Result<tonic::Response<ReloadConfigResponse>, tonic::Status> {
+        let req = request.into_inner();
+        let ret = if req.config.is_empty() {
+            self.0.reload_from_file().await
+        } else {
+            let new_config = TomlConfigLoader::new_from_str(&req.config)
+                .map_err(|e| tonic::Status::invalid_argument(format!("{:?}", e)))?;
+            self.0.reload(&new_config).await
+        };
+        let changes = ret.map_err(|e| tonic::Status::failed_precondition(format!("{}", e)))?;
+        Ok(tonic::Response::new(ReloadConfigResponse { changes }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instance::instance::Instance;
+
+    use super::*;
+
+    const BASE_CONFIG: &str = r#"
+instance_name = "reload"
+listeners = []
+
+[network_identity]
+network_name = "reload"
+network_secret = "secret"
+"#;
+
+    #[tokio::test]
+    async fn reload_config_incrementally() {
+        let inst = Instance::new(TomlConfigLoader::new_from_str(BASE_CONFIG).unwrap());
+        let global_ctx = inst.get_global_ctx();
+        let reloader = inst.get_config_reloader();
+
+        let new_config = TomlConfigLoader::new_from_str(
+            r#"
+instance_name = "reload"
+listeners = [ "tcp://127.0.0.1:31017" ]
+
+[network_identity]
+network_name = "reload"
+network_secret = "secret"
+
+[[peer]]
+uri = "tcp://127.0.0.1:31018"
+
+[[proxy_network]]
+cidr = "10.147.223.0/24"
+
+[[acl]]
+action = "drop"
+protocol = "tcp"
+port = "22"
+
+[[dnat]]
+proto = "tcp"
+port = 8080
+dst_addr = "192.168.1.10:80"
+
+[console_logger]
+level = "debug"
+"#,
+        )
+        .unwrap();
+        let changes = reloader.reload(&new_config).await.unwrap();
+        assert_eq!(changes.len(), 6, "{:?}", changes);
+        assert_eq!(
+            global_ctx.get_proxy_cidrs(),
+            vec!["10.147.223.0/24".parse::<cidr::IpCidr>().unwrap()]
+        );
+        assert_eq!(global_ctx.config.get_listeners().len(), 1);
+        assert_eq!(global_ctx.config.get_peers().len(), 1);
+        assert_eq!(inst.get_acl_filter().list_rules().rules.len(), 1);
+        assert_eq!(inst.get_dnat_rules().list_rules().rules.len(), 1);
+        assert!(inst
+            .get_conn_manager()
+            .list_connectors()
+            .await
+            .iter()
+            .any(|c| c.url == "tcp://127.0.0.1:31018"));
+
+        // reload the same config again changes nothing
+        assert!(reloader.reload(&new_config).await.unwrap().is_empty());
+
+        // back to the base config
+        let changes = reloader
+            .reload(&TomlConfigLoader::new_from_str(BASE_CONFIG).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(changes.len(), 6, "{:?}", changes);
+        assert!(global_ctx.get_proxy_cidrs().is_empty());
+        assert!(global_ctx.config.get_listeners().is_empty());
+        assert!(global_ctx.config.get_peers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reload_config_reject_restart_required() {
+        let inst = Instance::new(TomlConfigLoader::new_from_str(BASE_CONFIG).unwrap());
+        let reloader = inst.get_config_reloader();
+
+        let new_config = TomlConfigLoader::new_from_str(
+            &BASE_CONFIG.replace("network_secret = \"secret\"", "network_secret = \"other\""),
+        )
+        .unwrap();
+        let err = reloader.reload(&new_config).await.unwrap_err();
+        assert!(err.to_string().contains("network_identity"), "{}", err);
+
+        let new_config = TomlConfigLoader::new_from_str(&format!(
+            "hostname = \"other\"\ninstance_id = \"{}\"\n{}",
+            uuid::Uuid::new_v4(),
+            BASE_CONFIG
+        ))
+        .unwrap();
+        let err = reloader.reload(&new_config).await.unwrap_err();
+        assert!(err.to_string().contains("instance_id"), "{}", err);
+        assert!(err.to_string().contains("hostname"), "{}", err);
+
+        let new_config = TomlConfigLoader::new_from_str(&format!(
+            "{}\n[[acl]]\naction = \"reject\"\n\n[[peer]]\nuri = \"tcp://127.0.0.1:31019\"\n",
+            BASE_CONFIG
+        ))
+        .unwrap();
+        assert!(reloader.reload(&new_config).await.is_err());
+        // nothing is applied if acl rules are invalid
+        assert!(inst.get_global_ctx().config.get_peers().is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+// automatic ipv4 assignment for nodes started without an address. a free address in the dhcp
+// network is picked according to the route table, and if two nodes still end up with the same
+// address (e.g. both picked it before their routes were synced), the one with the smaller
+// instance id keeps it and the other one picks again. a node with a static address always keeps
+// it. the assigned address is not written to config, so it's never saved to the config file.
+
+use std::{collections::HashSet, net::Ipv4Addr, sync::Arc, time::Duration};
+
+use crate::{
+    common::global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
+    peers::peer_manager::PeerManager,
+    rpc::Route,
+};
+
+use super::virtual_nic::VirtualNic;
+
+// give the route table some time to sync before picking the first address
+const DHCP_INITIAL_WAIT: Duration = Duration::from_secs(5);
+const DHCP_CHECK_INTERVAL: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, PartialEq)]
+struct PeerAddr {
+    inst_id: uuid::Uuid,
+    addr: Ipv4Addr,
+    dhcp: bool,
+}
+
+// addresses of other nodes
+fn collect_peer_addrs(my_inst_id: &uuid::Uuid, routes: &[Route]) -> Vec<PeerAddr> {
+    routes
+        .iter()
+        .filter_map(|r| {
+            let inst_id = r.inst_id.parse::<uuid::Uuid>().ok()?;
+            let addr = r.ipv4_addr.parse::<Ipv4Addr>().ok()?;
+            if inst_id == *my_inst_id {
+                return None;
+            }
+            Some(PeerAddr {
+                inst_id,
+                addr,
+                dhcp: r.ipv4_dhcp,
+            })
+        })
+        .collect()
+}
+
+// called for dhcp addresses only, a static address of a peer always wins
+fn has_conflict(my_inst_id: &uuid::Uuid, addr: Ipv4Addr, peers: &[PeerAddr]) -> bool {
+    peers
+        .iter()
+        .any(|p| p.addr == addr && (!p.dhcp || p.inst_id < *my_inst_id))
+}
+
+// network and broadcast addresses are skipped unless the network is too small to have them.
+fn host_range(network: &cidr::Ipv4Cidr) -> (u32, u32) {
+    let first = u32::from(network.first_address());
+    let last = u32::from(network.last_address());
+    if network.network_length() >= 31 {
+        (first, last - first + 1)
+    } else {
+        (first + 1, last - first - 1)
+    }
+}
+
+// every node starts probing at a position derived from its instance id, so nodes joining at
+// the same time are unlikely to pick the same address.
+fn select_ipv4(
+    network: &cidr::Ipv4Cidr,
+    my_inst_id: &uuid::Uuid,
+    used: &HashSet<Ipv4Addr>,
+) -> Option<cidr::Ipv4Inet> {
+    let (base, count) = host_range(network);
+    let start = (my_inst_id.as_u128() % count as u128) as u32;
+    (0..count)
+        .map(|i| Ipv4Addr::from(base + (start + i) % count))
+        .find(|addr| !used.contains(addr))
+        .map(|addr| cidr::Ipv4Inet::new(addr, network.network_length()).unwrap())
+}
+
+pub struct DhcpIpAllocator {
+    network: cidr::Ipv4Cidr,
+    global_ctx: ArcGlobalCtx,
+    peer_mgr: Arc<PeerManager>,
+    nic: Arc<VirtualNic>,
+}
+
+impl DhcpIpAllocator {
+    pub fn new(
+        network: cidr::Ipv4Cidr,
+        global_ctx: ArcGlobalCtx,
+        peer_mgr: Arc<PeerManager>,
+        nic: Arc<VirtualNic>,
+    ) -> Self {
+        DhcpIpAllocator {
+            network,
+            global_ctx,
+            peer_mgr,
+            nic,
+        }
+    }
+
+    async fn check_once(&self) {
+        let my_inst_id = self.global_ctx.get_id();
+        let peers = collect_peer_addrs(&my_inst_id, &self.peer_mgr.list_routes().await);
+        let cur = self.global_ctx.get_ipv4_inet();
+        if let Some(cur) = cur {
+            if !has_conflict(&my_inst_id, cur.address(), &peers) {
+                return;
+            }
+            tracing::warn!(
+                ?cur,
+                "dhcp address conflicts with another node, pick a new one"
+            );
+        }
+
+        let used = peers.iter().map(|p| p.addr).collect();
+        let Some(new) = select_ipv4(&self.network, &my_inst_id, &used) else {
+            tracing::warn!(network = ?self.network, "no free address in dhcp network");
+            return;
+        };
+
+        if let Err(e) = self.nic.assign_ipv4(new).await {
+            tracing::error!(?e, ?new, "assign dhcp address to tun device failed");
+            return;
+        }
+        self.global_ctx.set_dhcp_ipv4_inet(new);
+        tracing::info!(?cur, ?new, "dhcp address assigned");
+        self.global_ctx
+            .issue_event(GlobalCtxEvent::DhcpIpv4Changed(cur, Some(new)));
+    }
+
+    pub async fn run(self) {
+        tokio::time::sleep(DHCP_INITIAL_WAIT).await;
+        loop {
+            self.check_once().await;
+            tokio::time::sleep(DHCP_CHECK_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(inst_id: &uuid::Uuid, ipv4_addr: &str) -> Route {
+        Route {
+            inst_id: inst_id.to_string(),
+            ipv4_addr: ipv4_addr.to_string(),
+            ipv4_dhcp: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn select_skips_used_and_reserved_addresses() {
+        let network: cidr::Ipv4Cidr = "10.144.144.0/30".parse().unwrap();
+        let id = uuid::Uuid::new_v4();
+
+        let mut used = HashSet::new();
+        let first = select_ipv4(&network, &id, &used).unwrap();
+        assert!(["10.144.144.1/30", "10.144.144.2/30"].contains(&first.to_string().as_str()));
+        // selection is deterministic
+        assert_eq!(first, select_ipv4(&network, &id, &used).unwrap());
+
+        used.insert(first.address());
+        let second = select_ipv4(&network, &id, &used).unwrap();
+        assert_ne!(first, second);
+
+        used.insert(second.address());
+        assert_eq!(None, select_ipv4(&network, &id, &used));
+    }
+
+    #[test]
+    fn conflict_resolved_by_instance_id() {
+        let small = uuid::Uuid::from_u128(1);
+        let large = uuid::Uuid::from_u128(2);
+        let addr: Ipv4Addr = "10.144.144.5".parse().unwrap();
+
+        let routes = vec![route(&small, "10.144.144.5"), route(&large, "10.144.144.5")];
+        assert!(!has_conflict(
+            &small,
+            addr,
+            &collect_peer_addrs(&small, &routes)
+        ));
+        assert!(has_conflict(
+            &large,
+            addr,
+            &collect_peer_addrs(&large, &routes)
+        ));
+
+        let routes = vec![route(&small, "10.144.144.6")];
+        assert!(!has_conflict(
+            &large,
+            addr,
+            &collect_peer_addrs(&large, &routes)
+        ));
+
+        // static address wins regardless of instance id
+        let mut routes = vec![route(&large, "10.144.144.5")];
+        routes[0].ipv4_dhcp = false;
+        assert!(has_conflict(
+            &small,
+            addr,
+            &collect_peer_addrs(&small, &routes)
+        ));
+    }
+}
@@ -0,0 +1,195 @@
+// keeps recent events of the global ctx and streams them to rpc clients. if events are dropped
+// because a subscriber is too slow, a Lagged event with the number of dropped events is sent in
+// their place, so clients know their view is incomplete and can resync.
+
+use std::{collections::VecDeque, pin::Pin, sync::Arc};
+
+use futures::Stream;
+use tokio::sync::broadcast;
+
+use crate::{
+    common::global_ctx::GlobalCtxEvent,
+    rpc::{event_rpc_server::EventRpc, InstanceEvent, SubscribeEventRequest},
+};
+
+const MAX_RECENT_EVENTS: usize = 256;
+const LAGGED_EVENT_TYPE: &str = "Lagged";
+
+fn lagged_event(n: u64) -> InstanceEvent {
+    InstanceEvent {
+        timestamp_ms: chrono::Local::now().timestamp_millis(),
+        event_type: LAGGED_EVENT_TYPE.to_string(),
+        payload: serde_json::json!({ LAGGED_EVENT_TYPE: n }).to_string(),
+    }
+}
+
+fn to_instance_event(event: &GlobalCtxEvent) -> InstanceEvent {
+    let payload = serde_json::to_value(event).unwrap_or_default();
+    // unit variants are serialized as a string, others as {"Variant": ...}
+    let event_type = match &payload {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Object(m) => m.keys().next().cloned().unwrap_or_default(),
+        _ => String::new(),
+    };
+    InstanceEvent {
+        timestamp_ms: chrono::Local::now().timestamp_millis(),
+        event_type,
+        payload: payload.to_string(),
+    }
+}
+
+pub struct EventRecorder {
+    recent: std::sync::Mutex<VecDeque<InstanceEvent>>,
+    sender: broadcast::Sender<InstanceEvent>,
+}
+
+impl EventRecorder {
+    pub fn new() -> Self {
+        EventRecorder {
+            recent: std::sync::Mutex::new(VecDeque::new()),
+            sender: broadcast::channel(100).0,
+        }
+    }
+
+    fn record(&self, event: &GlobalCtxEvent) {
+        self.record_instance_event(to_instance_event(event));
+    }
+
+    fn record_instance_event(&self, event: InstanceEvent) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= MAX_RECENT_EVENTS {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        // sent while holding the lock, so subscribers never miss or repeat an event
+        let _ = self.sender.send(event);
+    }
+
+    // recent events, and a receiver of events after them
+    fn subscribe(&self) -> (Vec<InstanceEvent>, broadcast::Receiver<InstanceEvent>) {
+        let recent = self.recent.lock().unwrap();
+        (recent.iter().cloned().collect(), self.sender.subscribe())
+    }
+
+    pub async fn run(self: Arc<Self>, mut receiver: broadcast::Receiver<GlobalCtxEvent>) {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => self.record(&event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!(n, "event recorder lagged, some events are dropped");
+                    self.record_instance_event(lagged_event(n));
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+impl Default for EventRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct EventRpcService(pub Arc<EventRecorder>);
+
+#[tonic::async_trait]
+impl EventRpc for EventRpcService {
+    type SubscribeEventStream =
+        Pin<Box<dyn Stream<Item = Result<InstanceEvent, tonic::Status>> + Send>>;
+
+    async fn subscribe_event(
+        &self,
+        request: tonic::Request<SubscribeEventRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeEventStream>, tonic::Status> {
+        let follow = request.into_inner().follow;
+        let (recent, mut receiver) = self.0.subscribe();
+        let stream = async_stream::stream! {
+            for event in recent {
+                yield Ok(event);
+            }
+            while follow {
+                match receiver.recv().await {
+                    Ok(event) => yield Ok(event),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        tracing::warn!(n, "event stream lagged, some events are dropped");
+                        yield Ok(lagged_event(n));
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        };
+        Ok(tonic::Response::new(Box::pin(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use crate::common::global_ctx::tests::get_mock_global_ctx;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn subscribe_recent_and_follow() {
+        let global_ctx = get_mock_global_ctx();
+        let recorder = Arc::new(EventRecorder::new());
+        tokio::spawn(recorder.clone().run(global_ctx.subscribe()));
+
+        global_ctx.issue_event(GlobalCtxEvent::PeerAdded(1));
+        global_ctx.issue_event(GlobalCtxEvent::ConfigReloaded);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let service = EventRpcService(recorder);
+        let recent = service
+            .subscribe_event(tonic::Request::new(SubscribeEventRequest { follow: false }))
+            .await
+            .unwrap()
+            .into_inner()
+            .collect::<Vec<_>>()
+            .await;
+        let recent = recent.into_iter().map(|e| e.unwrap()).collect::<Vec<_>>();
+        assert_eq!(2, recent.len());
+        assert_eq!("PeerAdded", recent[0].event_type);
+        assert_eq!(r#"{"PeerAdded":1}"#, recent[0].payload);
+        assert_eq!("ConfigReloaded", recent[1].event_type);
+
+        let mut stream = service
+            .subscribe_event(tonic::Request::new(SubscribeEventRequest { follow: true }))
+            .await
+            .unwrap()
+            .into_inner();
+        global_ctx.issue_event(GlobalCtxEvent::PeerRemoved(1));
+        let mut types = vec![];
+        for _ in 0..3 {
+            let event = tokio::time::timeout(std::time::Duration::from_secs(1), stream.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            types.push(event.event_type);
+        }
+        assert_eq!(vec!["PeerAdded", "ConfigReloaded", "PeerRemoved"], types);
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_gets_lagged_event() {
+        let recorder = Arc::new(EventRecorder::new());
+        let service = EventRpcService(recorder.clone());
+        let mut stream = service
+            .subscribe_event(tonic::Request::new(SubscribeEventRequest { follow: true }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        for i in 0..150 {
+            recorder.record(&GlobalCtxEvent::PeerAdded(i));
+        }
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(LAGGED_EVENT_TYPE, event.event_type);
+        assert_eq!(r#"{"Lagged":50}"#, event.payload);
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(r#"{"PeerAdded":50}"#, event.payload);
+    }
+}
@@ -0,0 +1,349 @@
+// route internet traffic of this node through an exit node. the first reachable node of the
+// exit_nodes config is selected, so traffic fails over to the next one when the selected node
+// leaves the route table.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
+
+use crate::{
+    common::{
+        config::ConfigLoader,
+        global_ctx::{ArcGlobalCtx, GlobalCtxEvent, EXIT_NODE_CIDR},
+        ifcfg::IfConfiguerTrait,
+        PeerId,
+    },
+    peers::{peer_manager::PeerManager, rpc_service::PeerManagerRpcService},
+    rpc::Route,
+};
+
+// more specific than the default route of the underlay, so it does not need to be replaced
+const SPLIT_DEFAULT_ROUTES: [(Ipv4Addr, u8); 2] = [
+    (Ipv4Addr::new(0, 0, 0, 0), 1),
+    (Ipv4Addr::new(128, 0, 0, 0), 1),
+];
+
+// exit nodes only forward ipv4, ipv6 internet traffic is sent into the tun device and dropped
+// there, instead of leaking through the underlay
+const SPLIT_DEFAULT_IPV6_ROUTES: [(Ipv6Addr, u8); 2] = [
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 1),
+    (Ipv6Addr::new(0x8000, 0, 0, 0, 0, 0, 0, 0), 1),
+];
+
+fn select_exit_node(exit_nodes: &[Ipv4Addr], routes: &[Route]) -> Option<(Ipv4Addr, PeerId)> {
+    exit_nodes.iter().find_map(|ip| {
+        routes
+            .iter()
+            .find(|r| {
+                r.ipv4_addr == ip.to_string() && r.proxy_cidrs.iter().any(|c| c == EXIT_NODE_CIDR)
+            })
+            .map(|r| (*ip, r.peer_id))
+    })
+}
+
+fn parse_ip_host(url: &str) -> Option<IpAddr> {
+    match url::Url::parse(url).ok()?.host()? {
+        url::Host::Ipv4(ip) => Some(ip.into()),
+        url::Host::Ipv6(ip) => Some(ip.into()),
+        _ => None,
+    }
+}
+
+// reached through routes more specific than the split routes, e.g. on the lan
+fn is_local_host(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        // link local fe80::/10 and unique local fc00::/7
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || (ip.segments()[0] & 0xffc0) == 0xfe80
+                || (ip.segments()[0] & 0xfe00) == 0xfc00
+        }
+    }
+}
+
+pub struct ExitNodeManager {
+    global_ctx: ArcGlobalCtx,
+    peer_mgr: Arc<PeerManager>,
+    ifcfg: Box<dyn IfConfiguerTrait>,
+    ifname: String,
+
+    cur_exit_node: Option<(Ipv4Addr, PeerId)>,
+    routes_installed: bool,
+    ipv6_routes_installed: bool,
+    // gateway of the underlay default route, the bypass routes go through it
+    underlay_gateway: Option<Ipv4Addr>,
+    bypass_addrs: Vec<Ipv4Addr>,
+    // avoid logging the same problem every update
+    warned: Option<&'static str>,
+}
+
+impl ExitNodeManager {
+    pub fn new(
+        global_ctx: ArcGlobalCtx,
+        peer_mgr: Arc<PeerManager>,
+        ifcfg: Box<dyn IfConfiguerTrait>,
+        ifname: String,
+    ) -> Self {
+        ExitNodeManager {
+            global_ctx,
+            peer_mgr,
+            ifcfg,
+            ifname,
+            cur_exit_node: None,
+            routes_installed: false,
+            ipv6_routes_installed: false,
+            underlay_gateway: None,
+            bypass_addrs: vec![],
+            warned: None,
+        }
+    }
+
+    fn warn_once(&mut self, msg: &'static str) {
+        if self.warned != Some(msg) {
+            tracing::warn!("{}", msg);
+            self.warned = Some(msg);
+        }
+    }
+
+    // addresses of underlay connections and configured peers. local addresses are skipped,
+    // they are reached through routes more specific than the split routes.
+    async fn list_underlay_addrs(&self) -> Vec<IpAddr> {
+        let peers = PeerManagerRpcService::new(self.peer_mgr.clone())
+            .list_peers()
+            .await;
+        let conn_addrs = peers
+            .iter()
+            .flat_map(|p| p.conns.iter())
+            .filter_map(|c| c.tunnel.as_ref())
+            .filter_map(|t| parse_ip_host(&t.remote_addr));
+        let peer_addrs = self
+            .global_ctx
+            .config
+            .get_peers()
+            .into_iter()
+            .filter_map(|p| parse_ip_host(p.uri.as_str()));
+
+        let mut ret = conn_addrs
+            .chain(peer_addrs)
+            .filter(|ip| !is_local_host(ip))
+            .collect::<Vec<_>>();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+
+    async fn remove_bypass_routes(&mut self) {
+        for addr in self.bypass_addrs.drain(..) {
+            if let Err(e) = self.ifcfg.remove_ipv4_host_route(addr).await {
+                tracing::trace!(?e, ?addr, "remove underlay bypass route failed");
+            }
+        }
+    }
+
+    // the split routes are more specific than the default route, so the default gateway is
+    // still the one of the underlay after they are installed. it changes when the node moves to
+    // another network, the bypass routes are installed again then.
+    async fn refresh_underlay_gateway(&mut self) {
+        let gateway = match self.ifcfg.get_default_ipv4_gateway().await {
+            Ok(gateway) => gateway,
+            Err(e) => {
+                tracing::trace!(?e, "get default gateway failed");
+                None
+            }
+        };
+        // keep the old one while the underlay has no default route, e.g. during a reconnect
+        let Some(gateway) = gateway else {
+            return;
+        };
+        if self.underlay_gateway != Some(gateway) {
+            tracing::info!(?gateway, old = ?self.underlay_gateway, "underlay gateway changed");
+            self.remove_bypass_routes().await;
+            self.underlay_gateway = Some(gateway);
+        }
+    }
+
+    async fn install_routes(&mut self) {
+        self.refresh_underlay_gateway().await;
+        // without bypass routes the underlay traffic would loop into the tun device
+        let Some(gateway) = self.underlay_gateway else {
+            self.warn_once("underlay has no default gateway, exit node routes are not installed");
+            return;
+        };
+
+        let underlay_addrs = self.list_underlay_addrs().await;
+        for addr in underlay_addrs.iter() {
+            let IpAddr::V4(addr) = *addr else {
+                continue;
+            };
+            if self.bypass_addrs.contains(&addr) {
+                continue;
+            }
+            // not recorded on failure, so it is tried again on the next update
+            if let Err(e) = self.ifcfg.add_ipv4_host_route(addr, gateway).await {
+                tracing::warn!(?e, ?addr, ?gateway, "add underlay bypass route failed");
+                continue;
+            }
+            self.bypass_addrs.push(addr);
+        }
+
+        if !self.routes_installed {
+            for (addr, prefix) in SPLIT_DEFAULT_ROUTES {
+                if let Err(e) = self.ifcfg.add_ipv4_route(&self.ifname, addr, prefix).await {
+                    tracing::warn!(?e, ?addr, prefix, "add exit node route failed");
+                }
+            }
+            self.routes_installed = true;
+        }
+
+        // ipv6 underlay connections would be sent into the tun device as well
+        if underlay_addrs.iter().any(|a| a.is_ipv6()) {
+            if self.ipv6_routes_installed {
+                self.remove_ipv6_routes().await;
+            }
+            self.warn_once(
+                "peers are connected over ipv6, ipv6 traffic is not blocked while using an exit node",
+            );
+        } else if !self.ipv6_routes_installed {
+            for (addr, prefix) in SPLIT_DEFAULT_IPV6_ROUTES {
+                if let Err(e) = self.ifcfg.add_ipv6_route(&self.ifname, addr, prefix).await {
+                    tracing::warn!(?e, ?addr, prefix, "add exit node ipv6 route failed");
+                }
+            }
+            self.ipv6_routes_installed = true;
+        }
+    }
+
+    async fn remove_ipv6_routes(&mut self) {
+        for (addr, prefix) in SPLIT_DEFAULT_IPV6_ROUTES {
+            if let Err(e) = self
+                .ifcfg
+                .remove_ipv6_route(&self.ifname, addr, prefix)
+                .await
+            {
+                tracing::trace!(?e, ?addr, prefix, "remove exit node ipv6 route failed");
+            }
+        }
+        self.ipv6_routes_installed = false;
+    }
+
+    async fn remove_routes(&mut self) {
+        for (addr, prefix) in SPLIT_DEFAULT_ROUTES {
+            if let Err(e) = self
+                .ifcfg
+                .remove_ipv4_route(&self.ifname, addr, prefix)
+                .await
+            {
+                tracing::trace!(?e, ?addr, prefix, "remove exit node route failed");
+            }
+        }
+        self.remove_ipv6_routes().await;
+        self.remove_bypass_routes().await;
+        self.routes_installed = false;
+        self.underlay_gateway = None;
+        self.warned = None;
+    }
+
+    async fn update(&mut self) {
+        let routes = self.peer_mgr.list_routes().await;
+        let selected = select_exit_node(&self.global_ctx.config.get_exit_nodes(), &routes);
+        if selected != self.cur_exit_node {
+            tracing::info!(?selected, old = ?self.cur_exit_node, "exit node changed");
+            self.peer_mgr.set_exit_node(selected.map(|x| x.1));
+            self.global_ctx
+                .issue_event(GlobalCtxEvent::ExitNodeChanged(selected.map(|x| x.0)));
+            self.cur_exit_node = selected;
+        }
+
+        let _g = self.global_ctx.net_ns.guard();
+        if self.cur_exit_node.is_some() {
+            self.install_routes().await;
+        } else if self.routes_installed {
+            self.remove_routes().await;
+        }
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            self.update().await;
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(peer_id: PeerId, ipv4_addr: &str, exit_node: bool) -> Route {
+        Route {
+            peer_id,
+            ipv4_addr: ipv4_addr.to_string(),
+            proxy_cidrs: if exit_node {
+                vec![EXIT_NODE_CIDR.to_string()]
+            } else {
+                vec![]
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn select_exit_node_in_order() {
+        let exit_nodes = vec![
+            "10.144.144.1".parse().unwrap(),
+            "10.144.144.2".parse().unwrap(),
+        ];
+        let mut routes = vec![
+            route(2, "10.144.144.2", true),
+            route(1, "10.144.144.1", true),
+            route(3, "10.144.144.3", true),
+        ];
+        assert_eq!(
+            Some(("10.144.144.1".parse().unwrap(), 1)),
+            select_exit_node(&exit_nodes, &routes)
+        );
+
+        // fail over when the first node is gone
+        routes.retain(|r| r.peer_id != 1);
+        assert_eq!(
+            Some(("10.144.144.2".parse().unwrap(), 2)),
+            select_exit_node(&exit_nodes, &routes)
+        );
+
+        // nodes without opt in are never used
+        let routes = vec![route(1, "10.144.144.1", false)];
+        assert_eq!(None, select_exit_node(&exit_nodes, &routes));
+    }
+
+    #[test]
+    fn parse_underlay_host() {
+        assert_eq!(
+            Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))),
+            parse_ip_host("udp://1.2.3.4:11010")
+        );
+        assert_eq!(None, parse_ip_host("tcp://example.com:11010"));
+        assert_eq!(
+            Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            parse_ip_host("tcp://[::1]:11010")
+        );
+    }
+
+    #[test]
+    fn local_underlay_host() {
+        for ip in [
+            "192.168.1.1",
+            "169.254.1.1",
+            "127.0.0.1",
+            "fe80::1",
+            "fd00::1",
+            "::1",
+        ] {
+            assert!(is_local_host(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["1.2.3.4", "2001:db8::1"] {
+            assert!(!is_local_host(&ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
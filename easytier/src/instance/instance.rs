@@ -1,5 +1,4 @@
 use std::borrow::BorrowMut;
-use std::net::Ipv4Addr;
 use std::pin::Pin;
 use std::sync::{Arc, Weak};
 
@@ -7,9 +6,11 @@ use anyhow::Context;
 use futures::{SinkExt, StreamExt};
 
 use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
 
 use tokio::{sync::Mutex, task::JoinSet};
-use tonic::transport::server::TcpIncoming;
+use tonic::service::interceptor;
+use tonic::transport::server::{Router, TcpIncoming};
 use tonic::transport::Server;
 
 use crate::common::config::ConfigLoader;
@@ -19,10 +20,13 @@ use crate::common::PeerId;
 use crate::connector::direct::DirectConnectorManager;
 use crate::connector::manual::{ConnectorManagerRpcService, ManualConnectorManager};
 use crate::connector::udp_hole_punch::UdpHolePunchConnector;
+use crate::gateway::dnat::{DnatManagerRpcService, DnatRules};
 use crate::gateway::icmp_proxy::IcmpProxy;
 use crate::gateway::tcp_proxy::TcpProxy;
 use crate::gateway::udp_proxy::UdpProxy;
 use crate::peer_center::instance::PeerCenterInstance;
+use crate::peers::acl_filter::{AclFilter, AclManagerRpcService};
+use crate::peers::ethernet_switch::EthernetSwitch;
 use crate::peers::peer_conn::PeerConnId;
 use crate::peers::peer_manager::{PeerManager, RouteAlgoType};
 use crate::peers::rpc_service::PeerManagerRpcService;
@@ -34,7 +38,15 @@ use crate::tunnel::packet_def::ZCPacket;
 use crate::tunnel::{ZCPacketSink, ZCPacketStream};
 use crate::vpn_portal::{self, VpnPortal};
 
+use super::config_reload::{ConfigManagerRpcService, ConfigReloader};
+use super::dhcp::DhcpIpAllocator;
+use super::events::{EventRecorder, EventRpcService};
+use super::exit_node::ExitNodeManager;
 use super::listeners::ListenerManager;
+use super::magic_dns::MagicDnsServer;
+use super::metrics::MetricsServer;
+use super::remote_manage::{local_rpc_channel, RemoteManageRpcService, RemoteManager};
+use super::rpc_auth::{load_server_tls_config, TokenInterceptor};
 use super::virtual_nic;
 
 use crate::common::ifcfg::IfConfiguerTrait;
@@ -47,11 +59,15 @@ struct IpProxy {
 }
 
 impl IpProxy {
-    fn new(global_ctx: ArcGlobalCtx, peer_manager: Arc<PeerManager>) -> Result<Self, Error> {
-        let tcp_proxy = TcpProxy::new(global_ctx.clone(), peer_manager.clone());
+    fn new(
+        global_ctx: ArcGlobalCtx,
+        peer_manager: Arc<PeerManager>,
+        dnat_rules: Arc<DnatRules>,
+    ) -> Result<Self, Error> {
+        let tcp_proxy = TcpProxy::new(global_ctx.clone(), peer_manager.clone(), dnat_rules.clone());
         let icmp_proxy = IcmpProxy::new(global_ctx.clone(), peer_manager.clone())
             .with_context(|| "create icmp proxy failed")?;
-        let udp_proxy = UdpProxy::new(global_ctx.clone(), peer_manager.clone())
+        let udp_proxy = UdpProxy::new(global_ctx.clone(), peer_manager.clone(), dnat_rules)
             .with_context(|| "create udp proxy failed")?;
         Ok(IpProxy {
             tcp_proxy,
@@ -85,11 +101,20 @@ pub struct Instance {
     udp_hole_puncher: Arc<Mutex<UdpHolePunchConnector>>,
 
     ip_proxy: Option<IpProxy>,
+    dnat_rules: Arc<DnatRules>,
 
     peer_center: Arc<PeerCenterInstance>,
 
     vpn_portal: Arc<Mutex<Box<dyn VpnPortal>>>,
 
+    acl_filter: Arc<AclFilter>,
+
+    ethernet_switch: Option<Arc<EthernetSwitch>>,
+
+    config_reloader: Arc<ConfigReloader>,
+    event_recorder: Arc<EventRecorder>,
+    remote_manager: Arc<RemoteManager>,
+
     global_ctx: ArcGlobalCtx,
 }
 
@@ -130,6 +155,20 @@ impl Instance {
 
         let peer_center = Arc::new(PeerCenterInstance::new(peer_manager.clone()));
 
+        let acl_filter = Arc::new(AclFilter::new(Arc::downgrade(&peer_manager)));
+        let dnat_rules = Arc::new(DnatRules::new());
+
+        let config_reloader = Arc::new(ConfigReloader::new(
+            global_ctx.clone(),
+            conn_manager.clone(),
+            listener_manager.clone(),
+            peer_manager.clone(),
+            acl_filter.clone(),
+            dnat_rules.clone(),
+        ));
+
+        let remote_manager = Arc::new(RemoteManager::new(global_ctx.clone(), peer_manager.clone()));
+
         #[cfg(feature = "wireguard")]
         let vpn_portal_inst = vpn_portal::wireguard::WireGuard::default();
         #[cfg(not(feature = "wireguard"))]
@@ -150,11 +189,20 @@ impl Instance {
             udp_hole_puncher: Arc::new(Mutex::new(udp_hole_puncher)),
 
             ip_proxy: None,
+            dnat_rules,
 
             peer_center,
 
             vpn_portal: Arc::new(Mutex::new(Box::new(vpn_portal_inst))),
 
+            acl_filter,
+
+            ethernet_switch: None,
+
+            config_reloader,
+            event_recorder: Arc::new(EventRecorder::new()),
+            remote_manager,
+
             global_ctx,
         }
     }
@@ -185,14 +233,44 @@ impl Instance {
         }
     }
 
-    // async fn do_forward_nic_to_peers_ethernet(mut ret: BytesMut, mgr: &PeerManager) {
-    //     if let Some(eth) = EthernetPacket::new(&ret) {
-    //         log::warn!("begin to forward: {:?}, type: {}", eth, eth.get_ethertype());
-    //         Self::do_forward_nic_to_peers_ipv4(ret.split_off(14), mgr).await;
-    //     } else {
-    //         log::warn!("not ipv4 packet: {:?}", ret);
-    //     }
-    // }
+    async fn do_forward_nic_to_peers_ipv6(ret: ZCPacket, mgr: &PeerManager) {
+        if let Some(ipv6) = Ipv6Packet::new(ret.payload()) {
+            if ipv6.get_version() != 6 {
+                tracing::info!("[USER_PACKET] not ipv6 packet: {:?}", ipv6);
+                return;
+            }
+            let dst_ipv6 = ipv6.get_destination();
+            tracing::trace!(
+                ?ret,
+                "[USER_PACKET] recv new packet from tun device and forward to peers."
+            );
+
+            let send_ret = mgr.send_msg_ipv6(ret, dst_ipv6).await;
+            if send_ret.is_err() {
+                tracing::trace!(?send_ret, "[USER_PACKET] send_msg_ipv6 failed")
+            }
+        } else {
+            tracing::warn!(?ret, "[USER_PACKET] not ipv6 packet");
+        }
+    }
+
+    async fn do_forward_nic_to_peers_ip(ret: ZCPacket, mgr: &PeerManager) {
+        let Some(first_byte) = ret.payload().first() else {
+            return;
+        };
+        match first_byte >> 4 {
+            4 => Self::do_forward_nic_to_peers_ipv4(ret, mgr).await,
+            6 => Self::do_forward_nic_to_peers_ipv6(ret, mgr).await,
+            v => tracing::trace!(?v, "[USER_PACKET] unknown ip version, drop it"),
+        }
+    }
+
+    async fn do_forward_nic_to_peers_ethernet(ret: ZCPacket, switch: &EthernetSwitch) {
+        let send_ret = switch.forward_frame(ret).await;
+        if send_ret.is_err() {
+            tracing::trace!(?send_ret, "[USER_PACKET] forward ethernet frame failed")
+        }
+    }
 
     fn do_forward_nic_to_peers(
         &mut self,
@@ -200,6 +278,7 @@ impl Instance {
     ) -> Result<(), Error> {
         // read from nic and write to corresponding tunnel
         let mgr = self.peer_manager.clone();
+        let switch = self.ethernet_switch.clone();
 
         self.tasks.spawn(async move {
             while let Some(ret) = stream.next().await {
@@ -207,8 +286,12 @@ impl Instance {
                     log::error!("read from nic failed: {:?}", ret);
                     break;
                 }
-                Self::do_forward_nic_to_peers_ipv4(ret.unwrap(), mgr.as_ref()).await;
-                // Self::do_forward_nic_to_peers_ethernet(ret.into(), mgr.as_ref()).await;
+                match switch.as_ref() {
+                    Some(switch) => {
+                        Self::do_forward_nic_to_peers_ethernet(ret.unwrap(), switch).await
+                    }
+                    None => Self::do_forward_nic_to_peers_ip(ret.unwrap(), mgr.as_ref()).await,
+                }
             }
         });
 
@@ -264,18 +347,27 @@ impl Instance {
         Ok(())
     }
 
-    async fn assign_ipv4_to_tun_device(&mut self, ipv4_addr: Ipv4Addr) -> Result<(), Error> {
+    async fn assign_ipv4_to_tun_device(&mut self, ipv4_addr: cidr::Ipv4Inet) -> Result<(), Error> {
+        let nic = self.virtual_nic.as_ref().unwrap().clone();
+        nic.assign_ipv4(ipv4_addr).await?;
+        Ok(())
+    }
+
+    async fn assign_ipv6_to_tun_device(&mut self, ipv6_addr: cidr::Ipv6Inet) -> Result<(), Error> {
         let nic = self.virtual_nic.as_ref().unwrap().clone();
         nic.link_up().await?;
-        nic.remove_ip(None).await?;
-        nic.add_ip(ipv4_addr, 24).await?;
-        if cfg!(target_os = "macos") {
-            nic.add_route(ipv4_addr, 24).await?;
-        }
+        nic.add_ipv6(ipv6_addr.address(), ipv6_addr.network_length())
+            .await?;
         Ok(())
     }
 
     pub async fn run(&mut self) -> Result<(), Error> {
+        self.tasks
+            .spawn(self.event_recorder.clone().run(self.global_ctx.subscribe()));
+
+        // install acl before any tunnel is accepted so no packet bypasses it during startup
+        self.run_acl_filter().await?;
+
         self.listener_manager
             .lock()
             .await
@@ -284,18 +376,64 @@ impl Instance {
         self.listener_manager.lock().await.run().await?;
         self.peer_manager.run().await?;
 
+        let enable_tap = self.global_ctx.get_flags().enable_tap;
+        if enable_tap {
+            self.run_ethernet_switch().await;
+        }
+
         self.run_rpc_server()?;
 
+        self.dnat_rules
+            .set_rules(&self.global_ctx.config.get_dnat_rules())?;
         self.ip_proxy = Some(IpProxy::new(
             self.get_global_ctx(),
             self.get_peer_manager(),
+            self.dnat_rules.clone(),
         )?);
         self.ip_proxy.as_ref().unwrap().start().await?;
 
-        if let Some(ipv4_addr) = self.global_ctx.get_ipv4() {
-            self.prepare_tun_device().await?;
-            self.assign_ipv4_to_tun_device(ipv4_addr).await?;
-            self.run_proxy_cidrs_route_updater();
+        if let Some(addr) = self.global_ctx.config.get_metrics_portal() {
+            self.run_metrics_server(addr).await?;
+        }
+
+        let ipv4_addr = self.global_ctx.get_ipv4_inet();
+        let ipv6_addr = self.global_ctx.get_ipv6();
+        // dhcp is only used when no address is configured
+        let dhcp_network = match ipv4_addr {
+            Some(_) => None,
+            None => self.global_ctx.config.get_dhcp_network(),
+        };
+        if self.global_ctx.get_flags().no_tun {
+            self.run_netstack().await?;
+        } else {
+            if self.global_ctx.config.get_socks5_portal().is_some()
+                || !self.global_ctx.config.get_port_forwards().is_empty()
+            {
+                tracing::warn!("socks5 portal and port forwards are only used in no tun mode");
+            }
+
+            // a tap device is useful without an address, e.g. bridged to a lan
+            if enable_tap || ipv4_addr.is_some() || ipv6_addr.is_some() || dhcp_network.is_some() {
+                self.prepare_tun_device().await?;
+            }
+
+            if let Some(ipv4_addr) = ipv4_addr {
+                self.assign_ipv4_to_tun_device(ipv4_addr).await?;
+                self.run_proxy_cidrs_route_updater();
+                self.run_exit_node_manager();
+            } else if let Some(dhcp_network) = dhcp_network {
+                self.run_dhcp_ip_allocator(dhcp_network);
+                self.run_proxy_cidrs_route_updater();
+                self.run_exit_node_manager();
+            }
+
+            if let Some(ipv6_addr) = ipv6_addr {
+                self.assign_ipv6_to_tun_device(ipv6_addr).await?;
+            }
+        }
+
+        if self.global_ctx.get_flags().enable_magic_dns {
+            self.run_magic_dns();
         }
 
         self.udp_hole_puncher.lock().await.run().await?;
@@ -311,6 +449,100 @@ impl Instance {
         Ok(())
     }
 
+    #[cfg(feature = "smoltcp")]
+    async fn run_netstack(&mut self) -> Result<(), Error> {
+        use crate::netstack::{port_forward::PortForwarder, socks5::Socks5Server, Netstack};
+
+        let Some(ipv4_addr) = self.global_ctx.get_ipv4_inet() else {
+            return Err(anyhow::anyhow!("no tun mode needs a static virtual ipv4").into());
+        };
+        if self.global_ctx.get_flags().enable_tap {
+            return Err(anyhow::anyhow!("no tun mode can not be used with tap mode").into());
+        }
+
+        let netstack = Netstack::new(ipv4_addr);
+        self.tasks.spawn(netstack.clone().run(
+            self.get_peer_manager(),
+            self.peer_packet_receiver.take().unwrap(),
+        ));
+
+        if let Some(addr) = self.global_ctx.config.get_socks5_portal() {
+            let auth = self.global_ctx.config.get_socks5_auth();
+            if auth.is_none() && !addr.ip().is_loopback() {
+                tracing::warn!(
+                    ?addr,
+                    "socks5 server without auth is reachable from other hosts, set socks5 auth"
+                );
+            }
+            let listener = Socks5Server::bind(addr).await?;
+            let server = Arc::new(Socks5Server::new(
+                netstack.clone(),
+                self.get_peer_manager(),
+                auth,
+            ));
+            tracing::info!(?addr, "socks5 server started");
+            self.tasks.spawn(server.serve(listener));
+        }
+
+        for config in self.global_ctx.config.get_port_forwards() {
+            let forwarder = Arc::new(PortForwarder::new(netstack.clone(), config)?);
+            let listener = forwarder.bind().await?;
+            self.tasks.spawn(forwarder.serve(listener));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "smoltcp"))]
+    async fn run_netstack(&mut self) -> Result<(), Error> {
+        Err(anyhow::anyhow!("no tun mode needs the smoltcp feature").into())
+    }
+
+    async fn run_ethernet_switch(&mut self) {
+        let switch = Arc::new(EthernetSwitch::new(self.get_peer_manager()));
+        self.peer_manager
+            .add_packet_process_pipeline(Box::new(switch.clone()))
+            .await;
+        self.ethernet_switch = Some(switch);
+    }
+
+    async fn run_metrics_server(&mut self, addr: std::net::SocketAddr) -> Result<(), Error> {
+        let mut server = MetricsServer::new(self.get_peer_manager(), self.get_conn_manager());
+        if let Some(ip_proxy) = self.ip_proxy.as_ref() {
+            server.set_ip_proxy(
+                ip_proxy.tcp_proxy.clone(),
+                ip_proxy.udp_proxy.clone(),
+                ip_proxy.icmp_proxy.clone(),
+            );
+        }
+        let listener = MetricsServer::bind(addr).await?;
+        tracing::info!(?addr, "metrics server started");
+        self.tasks.spawn(Arc::new(server).serve(listener));
+        Ok(())
+    }
+
+    fn run_magic_dns(&mut self) {
+        if self.virtual_nic.is_none() {
+            tracing::warn!("magic dns needs a virtual ip, it is not started");
+            return;
+        }
+        let server = Arc::new(MagicDnsServer::new(
+            self.get_global_ctx(),
+            self.get_peer_manager(),
+        ));
+        self.tasks.spawn(server.run());
+    }
+
+    fn run_dhcp_ip_allocator(&mut self, network: cidr::Ipv4Cidr) {
+        let allocator = DhcpIpAllocator::new(
+            network,
+            self.get_global_ctx(),
+            self.get_peer_manager(),
+            self.virtual_nic.as_ref().unwrap().clone(),
+        );
+        self.tasks.spawn(allocator.run());
+    }
+
     pub async fn run_vpn_portal(&mut self) -> Result<(), Error> {
         if self.global_ctx.get_vpn_portal_cidr().is_none() {
             return Err(anyhow::anyhow!("vpn portal cidr not set.").into());
@@ -323,6 +555,33 @@ impl Instance {
         Ok(())
     }
 
+    async fn run_acl_filter(&mut self) -> Result<(), Error> {
+        self.acl_filter.set_rules(
+            &self.global_ctx.config.get_acl_rules(),
+            &self.global_ctx.get_flags().acl_default_action,
+        )?;
+        // priority pipelines run before proxies and vpn portal added later
+        self.peer_manager
+            .add_priority_packet_process_pipeline(Box::new(self.acl_filter.clone()))
+            .await;
+        self.peer_manager
+            .add_priority_nic_packet_process_pipeline(Box::new(self.acl_filter.clone()))
+            .await;
+        Ok(())
+    }
+
+    pub fn get_acl_filter(&self) -> Arc<AclFilter> {
+        self.acl_filter.clone()
+    }
+
+    pub fn get_dnat_rules(&self) -> Arc<DnatRules> {
+        self.dnat_rules.clone()
+    }
+
+    pub fn get_config_reloader(&self) -> Arc<ConfigReloader> {
+        self.config_reloader.clone()
+    }
+
     pub fn get_peer_manager(&self) -> Arc<PeerManager> {
         self.peer_manager.clone()
     }
@@ -393,40 +652,90 @@ impl Instance {
         }
     }
 
+    fn add_rpc_services<L: Clone>(&self, server: &mut Server<L>) -> Router<L> {
+        server
+            .add_service(
+                crate::rpc::peer_manage_rpc_server::PeerManageRpcServer::new(
+                    PeerManagerRpcService::new(self.peer_manager.clone()),
+                ),
+            )
+            .add_service(
+                crate::rpc::connector_manage_rpc_server::ConnectorManageRpcServer::new(
+                    ConnectorManagerRpcService(self.conn_manager.clone()),
+                ),
+            )
+            .add_service(
+                crate::rpc::peer_center_rpc_server::PeerCenterRpcServer::new(
+                    self.peer_center.get_rpc_service(),
+                ),
+            )
+            .add_service(crate::rpc::vpn_portal_rpc_server::VpnPortalRpcServer::new(
+                self.get_vpn_portal_rpc_service(),
+            ))
+            .add_service(crate::rpc::acl_manage_rpc_server::AclManageRpcServer::new(
+                AclManagerRpcService(self.acl_filter.clone()),
+            ))
+            .add_service(
+                crate::rpc::dnat_manage_rpc_server::DnatManageRpcServer::new(
+                    DnatManagerRpcService::new(self.global_ctx.clone(), self.dnat_rules.clone()),
+                ),
+            )
+            .add_service(
+                crate::rpc::config_manage_rpc_server::ConfigManageRpcServer::new(
+                    ConfigManagerRpcService(self.config_reloader.clone()),
+                ),
+            )
+            .add_service(crate::rpc::event_rpc_server::EventRpcServer::new(
+                EventRpcService(self.event_recorder.clone()),
+            ))
+            .add_service(
+                crate::rpc::remote_manage_rpc_server::RemoteManageRpcServer::new(
+                    RemoteManageRpcService(self.remote_manager.clone()),
+                ),
+            )
+    }
+
     fn run_rpc_server(&mut self) -> Result<(), Error> {
+        // in-memory server for rpc forwarded from other peers, it skips the portal's auth and the
+        // remote_management config is checked instead.
+        let (local_channel, local_incoming) = local_rpc_channel();
+        let local_router = self.add_rpc_services(&mut Server::builder());
+        self.tasks.spawn(async move {
+            if let Err(e) = local_router.serve_with_incoming(local_incoming).await {
+                tracing::error!(?e, "local rpc server failed");
+            }
+        });
+        self.remote_manager.run(local_channel);
+
         let Some(addr) = self.global_ctx.config.get_rpc_portal() else {
             tracing::info!("rpc server not enabled, because rpc_portal is not set.");
             return Ok(());
         };
-        let peer_mgr = self.peer_manager.clone();
-        let conn_manager = self.conn_manager.clone();
-        let net_ns = self.global_ctx.net_ns.clone();
-        let peer_center = self.peer_center.clone();
-        let vpn_portal_rpc = self.get_vpn_portal_rpc_service();
+        let auth = self
+            .global_ctx
+            .config
+            .get_rpc_portal_auth()
+            .unwrap_or_default();
+        let mut server = Server::builder();
+        if let Some(tls) = load_server_tls_config(&auth)? {
+            server = server
+                .tls_config(tls)
+                .with_context(|| "invalid rpc portal tls config")?;
+        } else if !addr.ip().is_loopback() && auth.token.is_none() {
+            tracing::warn!(
+                ?addr,
+                "rpc portal is exposed without token or tls, anyone can manage this node"
+            );
+        }
+        let router = self
+            .add_rpc_services(&mut server.layer(interceptor(TokenInterceptor::new(auth.token))));
 
+        let net_ns = self.global_ctx.net_ns.clone();
         let incoming = TcpIncoming::new(addr, true, None)
             .map_err(|e| anyhow::anyhow!("create rpc server failed. addr: {}, err: {}", addr, e))?;
         self.tasks.spawn(async move {
             let _g = net_ns.guard();
-            Server::builder()
-                .add_service(
-                    crate::rpc::peer_manage_rpc_server::PeerManageRpcServer::new(
-                        PeerManagerRpcService::new(peer_mgr),
-                    ),
-                )
-                .add_service(
-                    crate::rpc::connector_manage_rpc_server::ConnectorManageRpcServer::new(
-                        ConnectorManagerRpcService(conn_manager.clone()),
-                    ),
-                )
-                .add_service(
-                    crate::rpc::peer_center_rpc_server::PeerCenterRpcServer::new(
-                        peer_center.get_rpc_service(),
-                    ),
-                )
-                .add_service(crate::rpc::vpn_portal_rpc_server::VpnPortalRpcServer::new(
-                    vpn_portal_rpc,
-                ))
+            router
                 .serve_with_incoming(incoming)
                 .await
                 .with_context(|| format!("rpc server failed. addr: {}", addr))
@@ -453,6 +762,10 @@ impl Instance {
                         let Ok(cidr) = cidr.parse::<cidr::Ipv4Cidr>() else {
                             continue;
                         };
+                        // routes of exit nodes are set by exit node manager
+                        if cidr.network_length() == 0 {
+                            continue;
+                        }
                         proxy_cidrs.push(cidr);
                     }
                 }
@@ -513,6 +826,17 @@ impl Instance {
         });
     }
 
+    fn run_exit_node_manager(&mut self) {
+        let nic = self.virtual_nic.as_ref().unwrap().clone();
+        let manager = ExitNodeManager::new(
+            self.global_ctx.clone(),
+            self.peer_manager.clone(),
+            Box::new(nic.get_ifcfg()),
+            nic.ifname().to_owned(),
+        );
+        self.tasks.spawn(manager.run());
+    }
+
     pub fn get_global_ctx(&self) -> ArcGlobalCtx {
         self.global_ctx.clone()
     }
@@ -2,10 +2,15 @@ use std::{fmt::Debug, sync::Arc};
 
 use anyhow::Context;
 use async_trait::async_trait;
-use tokio::{sync::Mutex, task::JoinSet};
+use tokio::{
+    sync::Mutex,
+    task::{AbortHandle, JoinSet},
+};
 
 #[cfg(feature = "quic")]
 use crate::tunnel::quic::QUICTunnelListener;
+#[cfg(feature = "websocket")]
+use crate::tunnel::websocket::WsTunnelListener;
 #[cfg(feature = "wireguard")]
 use crate::tunnel::wireguard::{WgConfig, WgTunnelListener};
 use crate::{
@@ -39,8 +44,17 @@ pub fn get_listener_by_url(
         }
         #[cfg(feature = "quic")]
         "quic" => Box::new(QUICTunnelListener::new(l.clone())),
+        #[cfg(feature = "websocket")]
+        "ws" | "wss" => {
+            let mut listener = WsTunnelListener::new(l.clone());
+            let flags = _ctx.get_flags();
+            if !flags.ws_tls_cert.is_empty() || !flags.ws_tls_key.is_empty() {
+                listener.set_tls_cert(flags.ws_tls_cert, flags.ws_tls_key);
+            }
+            Box::new(listener)
+        }
         _ => {
-            unreachable!("unsupported listener uri");
+            return Err(Error::InvalidUrl(l.to_string()));
         }
     })
 }
@@ -58,10 +72,12 @@ impl TunnelHandlerForListener for PeerManager {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct Listener {
     inner: Arc<Mutex<dyn TunnelListener>>,
     must_succ: bool,
+    url: url::Url,
+    task: Option<AbortHandle>,
 }
 
 pub struct ListenerManager<H> {
@@ -116,14 +132,47 @@ impl<H: TunnelHandlerForListener + Send + Sync + 'static + Debug> ListenerManage
     where
         L: TunnelListener + 'static,
     {
+        let url = listener.local_url();
         let listener = Arc::new(Mutex::new(listener));
         self.listeners.push(Listener {
             inner: listener,
             must_succ,
+            url,
+            task: None,
         });
         Ok(())
     }
 
+    // listeners added after run() are started immediately.
+    pub async fn add_running_listener_by_url(&mut self, url: &url::Url) -> Result<(), Error> {
+        if self.listeners.iter().any(|l| l.url == *url) {
+            return Err(anyhow::anyhow!("listener already exists: {}", url).into());
+        }
+        let lis = get_listener_by_url(url, self.global_ctx.clone())?;
+        self.add_listener(lis, true).await?;
+        let idx = self.listeners.len() - 1;
+        if let Err(e) = self.run_listener_at(idx).await {
+            self.listeners.pop();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub async fn remove_listener_by_url(&mut self, url: &url::Url) -> Result<(), Error> {
+        let Some(idx) = self.listeners.iter().position(|l| l.url == *url) else {
+            return Err(Error::NotFound);
+        };
+        let listener = self.listeners.remove(idx);
+        // the socket is closed when the accept task is dropped
+        if let Some(task) = listener.task {
+            task.abort();
+        }
+        self.global_ctx.remove_running_listener(url);
+        self.global_ctx
+            .issue_event(GlobalCtxEvent::ListenerRemoved(url.clone()));
+        Ok(())
+    }
+
     #[tracing::instrument]
     async fn run_listener(
         listener: Arc<Mutex<dyn TunnelListener>>,
@@ -156,23 +205,30 @@ impl<H: TunnelHandlerForListener + Send + Sync + 'static + Debug> ListenerManage
         }
     }
 
+    async fn run_listener_at(&mut self, idx: usize) -> Result<(), Error> {
+        let listener = &self.listeners[idx];
+        let _guard = self.net_ns.guard();
+        let addr = listener.inner.lock().await.local_url();
+        log::warn!("run listener: {:?}", listener);
+        listener
+            .inner
+            .lock()
+            .await
+            .listen()
+            .await
+            .with_context(|| format!("failed to add listener {}", addr))?;
+        let task = self.tasks.spawn(Self::run_listener(
+            listener.inner.clone(),
+            self.peer_manager.clone(),
+            self.global_ctx.clone(),
+        ));
+        self.listeners[idx].task = Some(task);
+        Ok(())
+    }
+
     pub async fn run(&mut self) -> Result<(), Error> {
-        for listener in &self.listeners {
-            let _guard = self.net_ns.guard();
-            let addr = listener.inner.lock().await.local_url();
-            log::warn!("run listener: {:?}", listener);
-            listener
-                .inner
-                .lock()
-                .await
-                .listen()
-                .await
-                .with_context(|| format!("failed to add listener {}", addr))?;
-            self.tasks.spawn(Self::run_listener(
-                listener.inner.clone(),
-                self.peer_manager.clone(),
-                self.global_ctx.clone(),
-            ));
+        for idx in 0..self.listeners.len() {
+            self.run_listener_at(idx).await?;
         }
 
         Ok(())
@@ -186,7 +242,10 @@ mod tests {
 
     use crate::{
         common::global_ctx::tests::get_mock_global_ctx,
-        tunnel::{packet_def::ZCPacket, ring::RingTunnelConnector, TunnelConnector},
+        tunnel::{
+            packet_def::ZCPacket, ring::RingTunnelConnector, tcp::TcpTunnelConnector,
+            TunnelConnector,
+        },
     };
 
     use super::*;
@@ -237,4 +296,48 @@ mod tests {
         .await
         .unwrap();
     }
+
+    #[tokio::test]
+    async fn add_and_remove_running_listener() {
+        let handler = Arc::new(MockListenerHandler {});
+        let global_ctx = get_mock_global_ctx();
+        let mut listener_mgr = ListenerManager::new(global_ctx.clone(), handler.clone());
+        listener_mgr.run().await.unwrap();
+
+        let url: url::Url = "tcp://127.0.0.1:31016".parse().unwrap();
+        listener_mgr
+            .add_running_listener_by_url(&url)
+            .await
+            .unwrap();
+        assert!(listener_mgr
+            .add_running_listener_by_url(&url)
+            .await
+            .is_err());
+
+        let tunnel = TcpTunnelConnector::new(url.clone())
+            .connect()
+            .await
+            .unwrap();
+        let (mut recv, _send) = tunnel.split();
+        assert_eq!(
+            recv.next().await.unwrap().unwrap().payload(),
+            "abc".as_bytes()
+        );
+        assert!(global_ctx.get_running_listeners().contains(&url));
+
+        listener_mgr.remove_listener_by_url(&url).await.unwrap();
+        assert!(!global_ctx.get_running_listeners().contains(&url));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(TcpTunnelConnector::new(url.clone())
+            .connect()
+            .await
+            .is_err());
+
+        assert!(listener_mgr.remove_listener_by_url(&url).await.is_err());
+        let unknown: url::Url = "unknown://127.0.0.1:31016".parse().unwrap();
+        assert!(listener_mgr
+            .add_running_listener_by_url(&unknown)
+            .await
+            .is_err());
+    }
 }
@@ -0,0 +1,701 @@
+// dns server on the virtual ip. answers A/AAAA/PTR queries for <hostname>.<network_name>.et
+// from the route table and static records in config, other queries are forwarded to the
+// system resolver. a hostname claimed by more than one node is not answered, and queries are
+// never forwarded to a local or virtual address, which may be this server itself.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::net::UdpSocket;
+
+use crate::{
+    common::{config::ConfigLoader, error::Error, global_ctx::ArcGlobalCtx},
+    peers::peer_manager::PeerManager,
+};
+
+const DNS_PORT: u16 = 53;
+const ZONE_SUFFIX: &str = "et";
+const RECORD_TTL: u32 = 60;
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+// interval to retry binding and to check whether the virtual ip is changed
+const CHECK_INTERVAL: Duration = Duration::from_secs(3);
+// records are built from the route table at most once in this interval
+const RECORDS_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NOERROR: u16 = 0;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+
+#[derive(Debug)]
+struct DnsQuestion {
+    id: u16,
+    flags: u16,
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    // question section of the query, copied to the response
+    raw: Vec<u8>,
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*buf.get(pos)?, *buf.get(pos + 1)?]))
+}
+
+// only standard queries with exactly one question are parsed.
+fn parse_query(buf: &[u8]) -> Option<DnsQuestion> {
+    let id = read_u16(buf, 0)?;
+    let flags = read_u16(buf, 2)?;
+    let qdcount = read_u16(buf, 4)?;
+    let is_response = flags & 0x8000 != 0;
+    let opcode = (flags >> 11) & 0xf;
+    if is_response || opcode != 0 || qdcount != 1 {
+        return None;
+    }
+
+    let mut pos = 12;
+    let mut labels = vec![];
+    loop {
+        let len = *buf.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // names in questions are never compressed
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = buf.get(pos..pos + len)?;
+        labels.push(String::from_utf8_lossy(label).to_lowercase());
+        pos += len;
+    }
+    let qtype = read_u16(buf, pos)?;
+    let qclass = read_u16(buf, pos + 2)?;
+
+    Some(DnsQuestion {
+        id,
+        flags,
+        name: labels.join("."),
+        qtype,
+        qclass,
+        raw: buf[12..pos + 4].to_vec(),
+    })
+}
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut buf = vec![];
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum DnsAnswer {
+    Addr(IpAddr),
+    Ptr(String),
+}
+
+fn build_response(q: &DnsQuestion, rcode: u16, answers: &[DnsAnswer]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(512);
+    buf.extend_from_slice(&q.id.to_be_bytes());
+    // QR, AA and RA are set, RD is copied from the query
+    let flags = 0x8000 | 0x0400 | (q.flags & 0x0100) | 0x0080 | rcode;
+    buf.extend_from_slice(&flags.to_be_bytes());
+    buf.extend_from_slice(&1u16.to_be_bytes());
+    buf.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&0u16.to_be_bytes());
+    buf.extend_from_slice(&0u16.to_be_bytes());
+    buf.extend_from_slice(&q.raw);
+
+    for answer in answers {
+        let (rtype, rdata) = match answer {
+            DnsAnswer::Addr(IpAddr::V4(addr)) => (TYPE_A, addr.octets().to_vec()),
+            DnsAnswer::Addr(IpAddr::V6(addr)) => (TYPE_AAAA, addr.octets().to_vec()),
+            DnsAnswer::Ptr(name) => (TYPE_PTR, encode_name(name)),
+        };
+        // pointer to the name in question
+        buf.extend_from_slice(&0xc00cu16.to_be_bytes());
+        buf.extend_from_slice(&rtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&RECORD_TTL.to_be_bytes());
+        buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&rdata);
+    }
+
+    buf
+}
+
+// hostnames may contain chars not allowed in dns labels
+fn sanitize_label(s: &str) -> String {
+    let label = s
+        .to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>();
+    let mut label = label.trim_matches('-').to_string();
+    label.truncate(63);
+    label
+}
+
+// 4.3.2.1.in-addr.arpa -> 1.2.3.4, ipv6 uses nibbles under ip6.arpa
+fn parse_reverse_name(name: &str) -> Option<IpAddr> {
+    if let Some(rest) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = rest
+            .split('.')
+            .map(|x| x.parse::<u8>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        if octets.len() != 4 {
+            return None;
+        }
+        octets.reverse();
+        return Some(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).into());
+    }
+
+    if let Some(rest) = name.strip_suffix(".ip6.arpa") {
+        let mut nibbles = rest
+            .split('.')
+            .map(|x| match x.len() {
+                1 => u8::from_str_radix(x, 16).ok(),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if nibbles.len() != 32 {
+            return None;
+        }
+        nibbles.reverse();
+        let mut octets = [0u8; 16];
+        for (i, octet) in octets.iter_mut().enumerate() {
+            *octet = (nibbles[i * 2] << 4) | nibbles[i * 2 + 1];
+        }
+        return Some(Ipv6Addr::from(octets).into());
+    }
+
+    None
+}
+
+#[derive(Debug, PartialEq)]
+enum Resolution {
+    // an empty list means the name exists but has no record of the queried type
+    Answers(Vec<DnsAnswer>),
+    NxDomain,
+    Forward,
+}
+
+#[derive(Debug)]
+struct DnsRecords {
+    zone: String,
+    addrs: HashMap<String, Vec<IpAddr>>,
+    names: HashMap<IpAddr, String>,
+    // name -> addresses of each node claiming it, see add_hosts
+    hosts: HashMap<String, Vec<Vec<IpAddr>>>,
+}
+
+impl DnsRecords {
+    fn new(network_name: &str) -> Self {
+        DnsRecords {
+            zone: format!("{}.{}", sanitize_label(network_name), ZONE_SUFFIX),
+            addrs: HashMap::new(),
+            names: HashMap::new(),
+            hosts: HashMap::new(),
+        }
+    }
+
+    fn fqdn(&self, name: &str) -> String {
+        let name = name.trim_end_matches('.').to_lowercase();
+        if name.contains('.') {
+            name
+        } else {
+            format!("{}.{}", name, self.zone)
+        }
+    }
+
+    fn add(&mut self, name: String, addr: IpAddr) {
+        let addrs = self.addrs.entry(name.clone()).or_default();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+        self.names.entry(addr).or_insert(name);
+    }
+
+    // one call for each node, hosts are added to records by add_hosts
+    fn add_host(&mut self, hostname: &str, addrs: impl Iterator<Item = IpAddr>) {
+        let label = sanitize_label(hostname);
+        if label.is_empty() {
+            return;
+        }
+        let name = self.fqdn(&label);
+        self.hosts.entry(name).or_default().push(addrs.collect());
+    }
+
+    // the result must not depend on the order of the route table, so a name claimed by more
+    // than one node is refused instead of answered with the first one.
+    fn add_hosts(&mut self) {
+        for (name, hosts) in std::mem::take(&mut self.hosts) {
+            if hosts.len() > 1 {
+                tracing::warn!(?name, ?hosts, "hostname claimed by multiple nodes, ignored");
+                continue;
+            }
+            for addr in hosts.into_iter().flatten() {
+                self.add(name.clone(), addr);
+            }
+        }
+    }
+
+    fn is_authoritative(&self, name: &str) -> bool {
+        name == self.zone
+            || name.ends_with(&format!(".{}", self.zone))
+            || self.addrs.contains_key(name)
+    }
+
+    fn resolve(&self, name: &str, qtype: u16) -> Resolution {
+        if qtype == TYPE_PTR {
+            return match parse_reverse_name(name).and_then(|addr| self.names.get(&addr)) {
+                Some(name) => Resolution::Answers(vec![DnsAnswer::Ptr(name.clone())]),
+                None => Resolution::Forward,
+            };
+        }
+
+        if !self.is_authoritative(name) {
+            return Resolution::Forward;
+        }
+
+        let Some(addrs) = self.addrs.get(name) else {
+            if name == self.zone {
+                return Resolution::Answers(vec![]);
+            }
+            return Resolution::NxDomain;
+        };
+
+        Resolution::Answers(
+            addrs
+                .iter()
+                .filter(|addr| match qtype {
+                    TYPE_A => addr.is_ipv4(),
+                    TYPE_AAAA => addr.is_ipv6(),
+                    _ => false,
+                })
+                .map(|addr| DnsAnswer::Addr(*addr))
+                .collect(),
+        )
+    }
+}
+
+// a resolver on this host (e.g. 127.0.0.53) may be configured to query magic dns itself
+fn is_local_addr(addr: &IpAddr) -> bool {
+    addr.is_loopback()
+        || addr.is_unspecified()
+        || std::net::UdpSocket::bind(SocketAddr::new(*addr, 0)).is_ok()
+}
+
+// nameservers in resolv.conf which are not on this host, queries are not forwarded if there is
+// none.
+fn system_nameservers() -> Vec<SocketAddr> {
+    let Ok(conf) = std::fs::read_to_string("/etc/resolv.conf") else {
+        return vec![];
+    };
+    conf.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            if fields.next() != Some("nameserver") {
+                return None;
+            }
+            fields.next()?.parse::<IpAddr>().ok()
+        })
+        .filter(|addr| {
+            let local = is_local_addr(addr);
+            if local {
+                tracing::info!(?addr, "local nameserver is not used by magic dns");
+            }
+            !local
+        })
+        .map(|addr| SocketAddr::new(addr, DNS_PORT))
+        .collect()
+}
+
+pub struct MagicDnsServer {
+    global_ctx: ArcGlobalCtx,
+    peer_mgr: Arc<PeerManager>,
+    upstreams: Vec<SocketAddr>,
+    port: u16,
+    records: Mutex<Option<(Instant, Arc<DnsRecords>)>>,
+}
+
+impl MagicDnsServer {
+    pub fn new(global_ctx: ArcGlobalCtx, peer_mgr: Arc<PeerManager>) -> Self {
+        let upstreams = system_nameservers();
+        if upstreams.is_empty() {
+            tracing::warn!("no system nameserver found, magic dns only answers local names");
+        }
+        MagicDnsServer {
+            global_ctx,
+            peer_mgr,
+            upstreams,
+            port: DNS_PORT,
+            records: Mutex::new(None),
+        }
+    }
+
+    async fn get_records(&self) -> Arc<DnsRecords> {
+        if let Some((built_at, records)) = self.records.lock().unwrap().as_ref() {
+            if built_at.elapsed() < RECORDS_REFRESH_INTERVAL {
+                return records.clone();
+            }
+        }
+        let records = Arc::new(self.build_records().await);
+        *self.records.lock().unwrap() = Some((Instant::now(), records.clone()));
+        records
+    }
+
+    // the virtual network is dhcp assigned or changed by reload, so it's checked on every query
+    fn select_upstream(&self, records: &DnsRecords) -> Option<SocketAddr> {
+        let ipv4 = self.global_ctx.get_ipv4_inet();
+        let ipv6 = self.global_ctx.get_ipv6();
+        self.upstreams.iter().copied().find(|upstream| {
+            let addr = upstream.ip();
+            let is_virtual = match addr {
+                IpAddr::V4(addr) => ipv4.map(|x| x.network().contains(&addr)),
+                IpAddr::V6(addr) => ipv6.map(|x| x.network().contains(&addr)),
+            }
+            .unwrap_or(false)
+                || records.names.contains_key(&addr);
+            !is_virtual
+        })
+    }
+
+    async fn build_records(&self) -> DnsRecords {
+        let global_ctx = &self.global_ctx;
+        let mut records = DnsRecords::new(&global_ctx.get_network_identity().network_name);
+
+        if let Some(hostname) = global_ctx.get_hostname() {
+            let addrs = global_ctx
+                .get_ipv4()
+                .map(IpAddr::V4)
+                .into_iter()
+                .chain(global_ctx.get_ipv6().map(|x| IpAddr::V6(x.address())));
+            records.add_host(&hostname, addrs);
+        }
+
+        for route in self.peer_mgr.list_routes().await {
+            // traffic to a duplicated ipv4 only goes to its owner
+            let ipv4 = if route.ipv4_conflict && !route.ipv4_owner {
+                None
+            } else {
+                route.ipv4_addr.parse::<Ipv4Addr>().ok()
+            };
+            let ipv6 = route.ipv6_addr.parse::<Ipv6Addr>().ok();
+            let addrs = ipv4.map(IpAddr::V4).into_iter().chain(ipv6.map(IpAddr::V6));
+            records.add_host(&route.hostname, addrs);
+        }
+        records.add_hosts();
+
+        for record in global_ctx.config.get_dns_records() {
+            let name = records.fqdn(&record.name);
+            records.add(name, record.addr);
+        }
+
+        records
+    }
+
+    async fn forward(&self, records: &DnsRecords, query: &[u8]) -> Result<Vec<u8>, Error> {
+        let upstream = self
+            .select_upstream(records)
+            .ok_or_else(|| anyhow::anyhow!("no upstream nameserver"))?;
+        let bind_addr: SocketAddr = match upstream {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(upstream).await?;
+        socket.send(query).await?;
+
+        let mut buf = vec![0u8; 4096];
+        let len = tokio::time::timeout(FORWARD_TIMEOUT, socket.recv(&mut buf)).await??;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    async fn handle_query(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let q = parse_query(query)?;
+        let records = self.get_records().await;
+        let resolution = match q.qclass {
+            CLASS_IN => records.resolve(&q.name, q.qtype),
+            _ => Resolution::Forward,
+        };
+        tracing::trace!(?q, ?resolution, "magic dns query");
+
+        match resolution {
+            Resolution::Answers(answers) => Some(build_response(&q, RCODE_NOERROR, &answers)),
+            Resolution::NxDomain => Some(build_response(&q, RCODE_NXDOMAIN, &[])),
+            Resolution::Forward => match self.forward(&records, query).await {
+                Ok(resp) => Some(resp),
+                Err(e) => {
+                    tracing::debug!(?e, name = ?q.name, "forward dns query failed");
+                    Some(build_response(&q, RCODE_SERVFAIL, &[]))
+                }
+            },
+        }
+    }
+
+    fn bind(&self, ipv4: Ipv4Addr) -> Result<UdpSocket, Error> {
+        let _g = self.global_ctx.net_ns.guard();
+        let socket = std::net::UdpSocket::bind((ipv4, self.port))?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpSocket::from_std(socket)?)
+    }
+
+    async fn serve(self: Arc<Self>, socket: Arc<UdpSocket>, ipv4: Ipv4Addr) {
+        let mut check_interval = tokio::time::interval(CHECK_INTERVAL);
+        let mut buf = vec![0u8; 4096];
+        loop {
+            tokio::select! {
+                ret = socket.recv_from(&mut buf) => {
+                    let (len, addr) = match ret {
+                        Ok(ret) => ret,
+                        Err(e) => {
+                            tracing::debug!(?e, "magic dns recv failed");
+                            continue;
+                        }
+                    };
+                    let query = buf[..len].to_vec();
+                    let server = self.clone();
+                    let socket = socket.clone();
+                    tokio::spawn(async move {
+                        if let Some(resp) = server.handle_query(&query).await {
+                            let _ = socket.send_to(&resp, addr).await;
+                        }
+                    });
+                }
+                _ = check_interval.tick() => {
+                    if self.global_ctx.get_ipv4() != Some(ipv4) {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    // listen on the virtual ipv4, rebind if it is changed (e.g. by dhcp).
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            let Some(ipv4) = self.global_ctx.get_ipv4() else {
+                tokio::time::sleep(CHECK_INTERVAL).await;
+                continue;
+            };
+            let socket = match self.bind(ipv4) {
+                Ok(socket) => Arc::new(socket),
+                Err(e) => {
+                    tracing::warn!(?e, ?ipv4, "bind magic dns server failed");
+                    tokio::time::sleep(CHECK_INTERVAL).await;
+                    continue;
+                }
+            };
+            tracing::info!(?ipv4, port = self.port, "magic dns server started");
+            self.clone().serve(socket, ipv4).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{common::config::DnsRecordConfig, peers::tests::create_mock_peer_manager};
+
+    use super::*;
+
+    fn build_query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&0x0100u16.to_be_bytes());
+        buf.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+        buf.extend_from_slice(&encode_name(name));
+        buf.extend_from_slice(&qtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf
+    }
+
+    async fn query(client: &UdpSocket, name: &str, qtype: u16) -> Vec<u8> {
+        client.send(&build_query(7, name, qtype)).await.unwrap();
+        let mut buf = vec![0u8; 512];
+        let len = tokio::time::timeout(Duration::from_secs(3), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    fn rcode(resp: &[u8]) -> u16 {
+        read_u16(resp, 2).unwrap() & 0xf
+    }
+
+    fn answer_count(resp: &[u8]) -> u16 {
+        read_u16(resp, 6).unwrap()
+    }
+
+    #[test]
+    fn resolve_records() {
+        let mut records = DnsRecords::new("My Net");
+        assert_eq!("my-net.et", records.zone);
+        records.add_host(
+            "Laptop_1",
+            ["10.144.144.1".parse().unwrap(), "fd00::1".parse().unwrap()].into_iter(),
+        );
+        records.add_hosts();
+
+        let v4 = DnsAnswer::Addr("10.144.144.1".parse().unwrap());
+        let v6 = DnsAnswer::Addr("fd00::1".parse().unwrap());
+        assert_eq!(
+            Resolution::Answers(vec![v4]),
+            records.resolve("laptop-1.my-net.et", TYPE_A)
+        );
+        assert_eq!(
+            Resolution::Answers(vec![v6]),
+            records.resolve("laptop-1.my-net.et", TYPE_AAAA)
+        );
+        assert_eq!(
+            Resolution::NxDomain,
+            records.resolve("other.my-net.et", TYPE_A)
+        );
+        assert_eq!(Resolution::Forward, records.resolve("example.com", TYPE_A));
+
+        let ptr = DnsAnswer::Ptr("laptop-1.my-net.et".to_string());
+        assert_eq!(
+            Resolution::Answers(vec![ptr.clone()]),
+            records.resolve("1.144.144.10.in-addr.arpa", TYPE_PTR)
+        );
+        let v6_reverse = format!("1{}.d.f.ip6.arpa", ".0".repeat(29));
+        assert_eq!(
+            Resolution::Answers(vec![ptr]),
+            records.resolve(&v6_reverse, TYPE_PTR)
+        );
+        assert_eq!(
+            Resolution::Forward,
+            records.resolve("2.144.144.10.in-addr.arpa", TYPE_PTR)
+        );
+    }
+
+    #[test]
+    fn refuse_duplicate_hostnames() {
+        let mut records = DnsRecords::new("net");
+        let a: IpAddr = "10.144.144.1".parse().unwrap();
+        let b: IpAddr = "10.144.144.2".parse().unwrap();
+        records.add_host("laptop", [a].into_iter());
+        records.add_host("Laptop", [b].into_iter());
+        records.add_host("nas", [b].into_iter());
+        records.add_hosts();
+
+        assert_eq!(
+            Resolution::NxDomain,
+            records.resolve("laptop.net.et", TYPE_A)
+        );
+        assert_eq!(
+            Resolution::Forward,
+            records.resolve("1.144.144.10.in-addr.arpa", TYPE_PTR)
+        );
+        assert_eq!(
+            Resolution::Answers(vec![DnsAnswer::Addr(b)]),
+            records.resolve("nas.net.et", TYPE_A)
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_local_and_virtual_upstreams() {
+        assert!(is_local_addr(&"127.0.0.53".parse().unwrap()));
+        assert!(is_local_addr(&"0.0.0.0".parse().unwrap()));
+        assert!(!is_local_addr(&"192.0.2.1".parse().unwrap()));
+
+        let peer_mgr = create_mock_peer_manager().await;
+        let global_ctx = peer_mgr.get_global_ctx();
+        global_ctx.set_ipv4_inet("10.144.144.1/24".parse().unwrap());
+        let mut server = MagicDnsServer::new(global_ctx, peer_mgr);
+        server.upstreams = vec![
+            "10.144.144.2:53".parse().unwrap(),
+            "192.0.2.1:53".parse().unwrap(),
+        ];
+        let records = server.build_records().await;
+        assert_eq!(
+            Some("192.0.2.1:53".parse().unwrap()),
+            server.select_upstream(&records)
+        );
+        server.upstreams.pop();
+        assert_eq!(None, server.select_upstream(&records));
+    }
+
+    #[tokio::test]
+    async fn magic_dns_server() {
+        // fake upstream echoes queries back
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, addr)) = upstream.recv_from(&mut buf).await {
+                let _ = upstream.send_to(&buf[..len], addr).await;
+            }
+        });
+
+        // the virtual network must not contain the upstream
+        let ipv4: Ipv4Addr = "127.0.0.2".parse().unwrap();
+        let peer_mgr = create_mock_peer_manager().await;
+        let global_ctx = peer_mgr.get_global_ctx();
+        global_ctx.set_ipv4_inet(cidr::Ipv4Inet::new(ipv4, 32).unwrap());
+        global_ctx.config.set_dns_records(vec![DnsRecordConfig {
+            name: "nas".to_string(),
+            addr: "10.144.144.100".parse().unwrap(),
+        }]);
+
+        let server = Arc::new(MagicDnsServer {
+            global_ctx: global_ctx.clone(),
+            peer_mgr,
+            upstreams: vec![upstream_addr],
+            port: 0,
+            records: Mutex::new(None),
+        });
+        let socket = Arc::new(server.bind(ipv4).unwrap());
+        let server_addr = socket.local_addr().unwrap();
+        tokio::spawn(server.serve(socket, ipv4));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server_addr).await.unwrap();
+
+        let hostname = sanitize_label(&global_ctx.get_hostname().unwrap());
+        let resp = query(&client, &format!("{}.default.et", hostname), TYPE_A).await;
+        assert_eq!(RCODE_NOERROR, rcode(&resp));
+        assert_eq!(1, answer_count(&resp));
+        assert!(resp.ends_with(&[127, 0, 0, 2]));
+
+        let resp = query(&client, "nas.default.et", TYPE_A).await;
+        assert_eq!(1, answer_count(&resp));
+        assert!(resp.ends_with(&[10, 144, 144, 100]));
+
+        let resp = query(&client, "nas.default.et", TYPE_AAAA).await;
+        assert_eq!(RCODE_NOERROR, rcode(&resp));
+        assert_eq!(0, answer_count(&resp));
+
+        let resp = query(&client, "100.144.144.10.in-addr.arpa", TYPE_PTR).await;
+        assert_eq!(1, answer_count(&resp));
+        assert!(resp.ends_with(&encode_name("nas.default.et")));
+
+        let resp = query(&client, "missing.default.et", TYPE_A).await;
+        assert_eq!(RCODE_NXDOMAIN, rcode(&resp));
+
+        let req = build_query(7, "example.com", TYPE_A);
+        let resp = query(&client, "example.com", TYPE_A).await;
+        assert_eq!(req, resp);
+    }
+}
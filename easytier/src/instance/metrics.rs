@@ -0,0 +1,357 @@
+// prometheus metrics served over http, in text exposition format.
+
+use std::{collections::HashMap, fmt::Display, fmt::Write as _, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    common::error::Error,
+    connector::manual::ManualConnectorManager,
+    gateway::{icmp_proxy::IcmpProxy, tcp_proxy::TcpProxy, udp_proxy::UdpProxy},
+    peers::{peer_manager::PeerManager, rpc_service::PeerManagerRpcService},
+};
+
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+fn escape_label_value(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[derive(Default)]
+struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+                .collect::<Vec<_>>();
+            let _ = write!(self.out, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+}
+
+pub struct MetricsServer {
+    peer_mgr: Arc<PeerManager>,
+    conn_manager: Arc<ManualConnectorManager>,
+
+    tcp_proxy: Option<Arc<TcpProxy>>,
+    udp_proxy: Option<Arc<UdpProxy>>,
+    icmp_proxy: Option<Arc<IcmpProxy>>,
+}
+
+impl MetricsServer {
+    pub fn new(peer_mgr: Arc<PeerManager>, conn_manager: Arc<ManualConnectorManager>) -> Self {
+        MetricsServer {
+            peer_mgr,
+            conn_manager,
+            tcp_proxy: None,
+            udp_proxy: None,
+            icmp_proxy: None,
+        }
+    }
+
+    pub fn set_ip_proxy(
+        &mut self,
+        tcp_proxy: Arc<TcpProxy>,
+        udp_proxy: Arc<UdpProxy>,
+        icmp_proxy: Arc<IcmpProxy>,
+    ) {
+        self.tcp_proxy = Some(tcp_proxy);
+        self.udp_proxy = Some(udp_proxy);
+        self.icmp_proxy = Some(icmp_proxy);
+    }
+
+    async fn collect(&self) -> String {
+        let mut w = MetricsWriter::default();
+
+        let routes = self.peer_mgr.list_routes().await;
+        let hostnames: HashMap<u32, String> = routes
+            .iter()
+            .map(|r| (r.peer_id, r.hostname.clone()))
+            .collect();
+        let peers = PeerManagerRpcService::new(self.peer_mgr.clone())
+            .list_peers()
+            .await;
+
+        // (peer_id, hostname, conn_id, tunnel type) and stats of every conn
+        let mut conns = vec![];
+        for peer in peers.iter() {
+            let peer_id = peer.peer_id.to_string();
+            let hostname = hostnames.get(&peer.peer_id).cloned().unwrap_or_default();
+            for conn in peer.conns.iter() {
+                let tunnel_type = conn
+                    .tunnel
+                    .as_ref()
+                    .map(|t| t.tunnel_type.clone())
+                    .unwrap_or_default();
+                let labels = [
+                    peer_id.clone(),
+                    hostname.clone(),
+                    conn.conn_id.clone(),
+                    tunnel_type,
+                ];
+                conns.push((
+                    labels,
+                    conn.stats.clone().unwrap_or_default(),
+                    conn.loss_rate,
+                ));
+            }
+        }
+        let conn_labels = |l: &[String; 4]| {
+            [
+                ("peer_id", l[0].as_str()),
+                ("hostname", l[1].as_str()),
+                ("conn_id", l[2].as_str()),
+                ("tunnel", l[3].as_str()),
+            ]
+        };
+
+        let counters = [
+            (
+                "easytier_peer_conn_rx_bytes_total",
+                "bytes received on conn",
+            ),
+            ("easytier_peer_conn_tx_bytes_total", "bytes sent on conn"),
+            (
+                "easytier_peer_conn_rx_packets_total",
+                "packets received on conn",
+            ),
+            (
+                "easytier_peer_conn_tx_packets_total",
+                "packets sent on conn",
+            ),
+        ];
+        for (i, (name, help)) in counters.iter().enumerate() {
+            w.family(name, "counter", help);
+            for (labels, stats, _) in conns.iter() {
+                let value = match i {
+                    0 => stats.rx_bytes,
+                    1 => stats.tx_bytes,
+                    2 => stats.rx_packets,
+                    _ => stats.tx_packets,
+                };
+                w.sample(name, &conn_labels(labels), value);
+            }
+        }
+
+        let name = "easytier_peer_conn_latency_seconds";
+        w.family(name, "gauge", "latency of conn");
+        for (labels, stats, _) in conns.iter() {
+            let latency = stats.latency_us as f64 / 1_000_000.0;
+            w.sample(name, &conn_labels(labels), latency);
+        }
+
+        let name = "easytier_peer_conn_loss_rate";
+        w.family(name, "gauge", "packet loss rate of conn, 0.0 ~ 1.0");
+        for (labels, _, loss_rate) in conns.iter() {
+            w.sample(name, &conn_labels(labels), loss_rate);
+        }
+
+        let name = "easytier_peer_count";
+        w.family(name, "gauge", "directly connected peers");
+        w.sample(name, &[], peers.len());
+
+        let name = "easytier_route_count";
+        w.family(name, "gauge", "reachable peers in route table");
+        w.sample(name, &[], routes.len());
+
+        let foreign_networks = self
+            .peer_mgr
+            .get_foreign_network_manager()
+            .list_foreign_networks()
+            .await;
+        let name = "easytier_foreign_network_count";
+        w.family(name, "gauge", "foreign networks relayed by this node");
+        w.sample(name, &[], foreign_networks.len());
+
+        let name = "easytier_foreign_network_peer_count";
+        w.family(
+            name,
+            "gauge",
+            "peers of foreign network relayed by this node",
+        );
+        for item in foreign_networks.iter() {
+            w.sample(
+                name,
+                &[("network_name", item.key().as_str())],
+                item.value().len(),
+            );
+        }
+
+        let name = "easytier_foreign_peer_count";
+        w.family(
+            name,
+            "gauge",
+            "peers of other networks used by this node to reach its own network",
+        );
+        w.sample(
+            name,
+            &[],
+            self.peer_mgr
+                .get_foreign_network_client()
+                .list_foreign_peers()
+                .len(),
+        );
+
+        let name = "easytier_proxy_nat_entries";
+        w.family(name, "gauge", "entries in nat table of subnet proxy");
+        if let Some(p) = &self.tcp_proxy {
+            w.sample(name, &[("proxy", "tcp")], p.nat_table_size());
+        }
+        if let Some(p) = &self.udp_proxy {
+            w.sample(name, &[("proxy", "udp")], p.nat_table_size());
+        }
+        if let Some(p) = &self.icmp_proxy {
+            w.sample(name, &[("proxy", "icmp")], p.nat_table_size());
+        }
+
+        let reconnect_stats = self.conn_manager.get_reconnect_stats();
+        let name = "easytier_connector_reconnect_total";
+        w.family(name, "counter", "reconnect attempts of connector");
+        for (url, stats) in reconnect_stats.iter() {
+            w.sample(name, &[("url", url.as_str())], stats.attempts);
+        }
+        let name = "easytier_connector_reconnect_failures_total";
+        w.family(name, "counter", "failed reconnect attempts of connector");
+        for (url, stats) in reconnect_stats.iter() {
+            w.sample(name, &[("url", url.as_str())], stats.failures);
+        }
+
+        w.out
+    }
+
+    // a minimal http/1.1 responder, only GET /metrics is served.
+    async fn handle_conn(&self, mut stream: TcpStream) -> Result<(), Error> {
+        let mut buf = vec![];
+        let mut chunk = [0u8; 1024];
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut chunk)).await??;
+            if n == 0 {
+                return Ok(());
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            if buf.len() > MAX_REQUEST_SIZE {
+                return Err(anyhow::anyhow!("http request too large").into());
+            }
+        }
+
+        let request = String::from_utf8_lossy(&buf);
+        let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+        let method = request_line.next().unwrap_or_default();
+        let path = request_line.next().unwrap_or_default();
+
+        let (status, body) = match (method, path) {
+            ("GET", "/metrics") => ("200 OK", self.collect().await),
+            ("GET", _) => ("404 Not Found", "not found\n".to_string()),
+            _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+        };
+        let resp = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        stream.write_all(resp.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    pub async fn bind(addr: SocketAddr) -> Result<TcpListener, Error> {
+        let listener = TcpListener::bind(addr).await.map_err(|e| {
+            anyhow::anyhow!("create metrics server failed. addr: {}, err: {}", addr, e)
+        })?;
+        Ok(listener)
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(ret) => ret,
+                Err(e) => {
+                    tracing::warn!(?e, "metrics server accept failed");
+                    continue;
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_conn(stream).await {
+                    tracing::debug!(?e, ?addr, "metrics request failed");
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::peers::tests::{connect_peer_manager, create_mock_peer_manager, wait_route_appear};
+
+    use super::*;
+
+    async fn http_get(addr: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        resp
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint() {
+        let peer_mgr_a = create_mock_peer_manager().await;
+        let peer_mgr_b = create_mock_peer_manager().await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_b.clone())
+            .await
+            .unwrap();
+
+        let conn_manager = Arc::new(ManualConnectorManager::new(
+            peer_mgr_a.get_global_ctx(),
+            peer_mgr_a.clone(),
+        ));
+        let server = Arc::new(MetricsServer::new(peer_mgr_a.clone(), conn_manager));
+        let listener = MetricsServer::bind("127.0.0.1:31090".parse().unwrap())
+            .await
+            .unwrap();
+        tokio::spawn(server.serve(listener));
+
+        let resp = http_get("127.0.0.1:31090", "/metrics").await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK"));
+        let peer_label = format!("peer_id=\"{}\"", peer_mgr_b.my_peer_id());
+        assert!(resp.lines().any(
+            |l| l.starts_with("easytier_peer_conn_rx_bytes_total{") && l.contains(&peer_label)
+        ));
+        assert!(resp.contains("\neasytier_route_count 1\n"));
+        assert!(resp.contains("\neasytier_peer_count 1\n"));
+
+        let resp = http_get("127.0.0.1:31090", "/other").await;
+        assert!(resp.starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn escape_label() {
+        let mut w = MetricsWriter::default();
+        w.sample("m", &[("url", "a\"b\\c")], 1);
+        assert_eq!("m{url=\"a\\\"b\\\\c\"} 1\n", w.out);
+    }
+}
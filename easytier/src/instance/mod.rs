@@ -1,4 +1,12 @@
+pub mod config_reload;
+pub mod dhcp;
+pub mod events;
+pub mod exit_node;
 pub mod instance;
 pub mod listeners;
+pub mod magic_dns;
+pub mod metrics;
+pub mod remote_manage;
+pub mod rpc_auth;
 pub mod tun_codec;
 pub mod virtual_nic;
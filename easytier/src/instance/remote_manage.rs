@@ -0,0 +1,508 @@
+// run management rpc on other nodes of the network. the request is sent over the mesh as raw
+// protobuf bytes, and the target node replays it on an in-memory rpc server if the public key
+// of the caller is allowed by its remote_management config.
+
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut};
+use tokio::{io::DuplexStream, sync::mpsc};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::{
+    codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder},
+    codegen::http::Uri,
+    transport::Channel,
+};
+
+use crate::{
+    common::{config::ConfigLoader, global_ctx::ArcGlobalCtx, noise::decode_key, PeerId},
+    peers::peer_manager::PeerManager,
+    rpc::{remote_manage_rpc_server::RemoteManageRpc, ForwardRpcRequest, ForwardRpcResponse},
+};
+
+static SERVICE_ID: u32 = 9;
+
+// streaming rpcs and forwarding itself can not be proxied
+const NOT_FORWARDABLE_METHODS: [&str; 2] = [
+    "/cli.EventRpc/SubscribeEvent",
+    "/cli.RemoteManageRpc/ForwardRpc",
+];
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RemoteRpcError {
+    code: i32,
+    message: String,
+}
+
+impl From<tonic::Status> for RemoteRpcError {
+    fn from(s: tonic::Status) -> Self {
+        RemoteRpcError {
+            code: s.code() as i32,
+            message: s.message().to_string(),
+        }
+    }
+}
+
+impl From<RemoteRpcError> for tonic::Status {
+    fn from(e: RemoteRpcError) -> Self {
+        tonic::Status::new(tonic::Code::from(e.code), e.message)
+    }
+}
+
+#[tarpc::service]
+pub trait RemoteManageService {
+    async fn call_rpc(method: String, body: Vec<u8>) -> Result<Vec<u8>, RemoteRpcError>;
+}
+
+// passes encoded messages through as is
+#[derive(Debug, Clone, Default)]
+struct RawCodec;
+
+impl Codec for RawCodec {
+    type Encode = Vec<u8>;
+    type Decode = Vec<u8>;
+    type Encoder = RawCodec;
+    type Decoder = RawCodec;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        RawCodec
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        RawCodec
+    }
+}
+
+impl Encoder for RawCodec {
+    type Item = Vec<u8>;
+    type Error = tonic::Status;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut EncodeBuf<'_>) -> Result<(), Self::Error> {
+        dst.put_slice(&item);
+        Ok(())
+    }
+}
+
+impl Decoder for RawCodec {
+    type Item = Vec<u8>;
+    type Error = tonic::Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<Self::Item>, Self::Error> {
+        let mut buf = vec![0; src.remaining()];
+        src.copy_to_slice(&mut buf);
+        Ok(Some(buf))
+    }
+}
+
+// every connection of the channel is a duplex stream handed to the server side incoming
+#[derive(Clone)]
+struct LocalRpcConnector(mpsc::UnboundedSender<Result<DuplexStream, std::io::Error>>);
+
+impl tonic::codegen::Service<Uri> for LocalRpcConnector {
+    type Response = DuplexStream;
+    type Error = std::io::Error;
+    type Future = std::future::Ready<Result<DuplexStream, std::io::Error>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: Uri) -> Self::Future {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let ret = self.0.send(Ok(server)).map(|_| client).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "local rpc server stopped")
+        });
+        std::future::ready(ret)
+    }
+}
+
+pub type LocalRpcIncoming = UnboundedReceiverStream<Result<DuplexStream, std::io::Error>>;
+
+// a channel to an in-memory rpc server, which serves the returned incoming. it does not go
+// through the rpc portal, so it works without the portal and its token or tls.
+pub fn local_rpc_channel() -> (Channel, LocalRpcIncoming) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let channel = tonic::transport::Endpoint::from_static("http://local.rpc")
+        .connect_with_connector_lazy(LocalRpcConnector(tx));
+    (channel, UnboundedReceiverStream::new(rx))
+}
+
+// one server per calling peer, from_peer is the sender of the rpc packets. only requests over
+// the direct session or with the pairwise key are served, so it can not be set by the caller
+#[derive(Clone)]
+struct RemoteManageServer {
+    global_ctx: ArcGlobalCtx,
+    peer_mgr: Arc<PeerManager>,
+    local_channel: Channel,
+    from_peer: PeerId,
+}
+
+impl RemoteManageServer {
+    async fn check_allowed(&self) -> Result<(), tonic::Status> {
+        let from_peer = self.from_peer;
+        let Some(config) = self.global_ctx.config.get_remote_management() else {
+            return Err(tonic::Status::permission_denied(
+                "remote management is not enabled on this node",
+            ));
+        };
+        // hostname and instance id are chosen by the peer itself, only its key is verified
+        let public_key = self
+            .peer_mgr
+            .get_peer_map()
+            .get_peer_public_keys(from_peer)
+            .await
+            .map(|k| k.static_key);
+        let allowed = config
+            .allowed_peers
+            .iter()
+            .any(|p| p == "*" || (public_key.is_some() && decode_key(p).ok() == public_key));
+        if !allowed {
+            return Err(tonic::Status::permission_denied(format!(
+                "peer {} is not allowed to manage this node",
+                from_peer
+            )));
+        }
+        Ok(())
+    }
+
+    // replay the request on the local rpc server
+    async fn call_local(&self, method: &str, body: Vec<u8>) -> Result<Vec<u8>, tonic::Status> {
+        let path = method
+            .parse::<tonic::codegen::http::uri::PathAndQuery>()
+            .map_err(|e| tonic::Status::invalid_argument(format!("invalid method: {}", e)))?;
+
+        let mut grpc = tonic::client::Grpc::new(self.local_channel.clone());
+        grpc.ready()
+            .await
+            .map_err(|e| tonic::Status::unavailable(format!("local rpc not ready: {}", e)))?;
+        let resp = grpc
+            .unary(tonic::Request::new(body), path, RawCodec)
+            .await?;
+        Ok(resp.into_inner())
+    }
+}
+
+#[tarpc::server]
+impl RemoteManageService for RemoteManageServer {
+    async fn call_rpc(
+        self,
+        _: tarpc::context::Context,
+        method: String,
+        body: Vec<u8>,
+    ) -> Result<Vec<u8>, RemoteRpcError> {
+        self.check_allowed().await?;
+        if NOT_FORWARDABLE_METHODS.contains(&method.as_str()) {
+            return Err(tonic::Status::unimplemented(format!(
+                "{} can not be called remotely",
+                method
+            ))
+            .into());
+        }
+        tracing::info!(from_peer = ?self.from_peer, ?method, "remote management rpc");
+        Ok(self.call_local(&method, body).await?)
+    }
+}
+
+pub struct RemoteManager {
+    global_ctx: ArcGlobalCtx,
+    peer_mgr: Arc<PeerManager>,
+}
+
+impl RemoteManager {
+    pub fn new(global_ctx: ArcGlobalCtx, peer_mgr: Arc<PeerManager>) -> Self {
+        RemoteManager {
+            global_ctx,
+            peer_mgr,
+        }
+    }
+
+    // local_channel serves the requests of allowed peers, see local_rpc_channel
+    pub fn run(&self, local_channel: Channel) {
+        let global_ctx = self.global_ctx.clone();
+        let peer_mgr = self.peer_mgr.clone();
+        self.peer_mgr
+            .get_peer_rpc_mgr()
+            .run_service_for_peer(SERVICE_ID, true, move |from_peer| {
+                RemoteManageServer {
+                    global_ctx: global_ctx.clone(),
+                    peer_mgr: peer_mgr.clone(),
+                    local_channel: local_channel.clone(),
+                    from_peer,
+                }
+                .serve()
+            });
+    }
+
+    // target is a peer id, hostname or instance id
+    async fn resolve_peer(&self, target: &str) -> Result<PeerId, tonic::Status> {
+        let routes = self.peer_mgr.list_routes().await;
+        if let Ok(peer_id) = target.parse::<PeerId>() {
+            if routes.iter().any(|r| r.peer_id == peer_id) {
+                return Ok(peer_id);
+            }
+        }
+        let matched = routes
+            .iter()
+            .filter(|r| r.hostname == target || r.inst_id == target)
+            .map(|r| r.peer_id)
+            .collect::<Vec<_>>();
+        match matched.as_slice() {
+            [peer_id] => Ok(*peer_id),
+            [] => Err(tonic::Status::not_found(format!(
+                "peer {} not found in route table",
+                target
+            ))),
+            _ => Err(tonic::Status::failed_precondition(format!(
+                "multiple peers named {}, use peer id instead",
+                target
+            ))),
+        }
+    }
+
+    pub async fn forward(
+        &self,
+        target: &str,
+        method: String,
+        body: Vec<u8>,
+    ) -> Result<Vec<u8>, tonic::Status> {
+        let dst_peer_id = self.resolve_peer(target).await?;
+        let ret = self
+            .peer_mgr
+            .get_peer_rpc_mgr()
+            .do_client_rpc_scoped(SERVICE_ID, dst_peer_id, |c| async {
+                let client =
+                    RemoteManageServiceClient::new(tarpc::client::Config::default(), c).spawn();
+                client
+                    .call_rpc(tarpc::context::current(), method, body)
+                    .await
+            })
+            .await;
+        match ret {
+            Ok(ret) => Ok(ret?),
+            Err(e) => Err(tonic::Status::unavailable(format!(
+                "call peer {} failed: {:?}",
+                dst_peer_id, e
+            ))),
+        }
+    }
+}
+
+pub struct RemoteManageRpcService(pub Arc<RemoteManager>);
+
+#[tonic::async_trait]
+impl RemoteManageRpc for RemoteManageRpcService {
+    async fn forward_rpc(
+        &self,
+        request: tonic::Request<ForwardRpcRequest>,
+    ) -> Result<tonic::Response<ForwardRpcResponse>, tonic::Status> {
+        let req = request.into_inner();
+        let body = self
+            .0
+            .forward(&req.target_peer, req.method, req.body)
+            .await?;
+        Ok(tonic::Response::new(ForwardRpcResponse { body }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use prost::Message;
+    use tonic::transport::Server;
+
+    use crate::{
+        common::{config::RemoteManagementConfig, noise::StaticKeypair},
+        peers::{
+            encrypt::{CipherSet, Encryptor},
+            rpc_service::PeerManagerRpcService,
+            tests::{
+                connect_peer_manager, create_mock_peer_manager, wait_for_condition,
+                wait_route_appear,
+            },
+        },
+        rpc::{
+            peer_manage_rpc_server::PeerManageRpcServer, ListRouteRequest, ListRouteResponse,
+            TaRpcPacket,
+        },
+        tunnel::packet_def::{PacketType, ZCPacket},
+    };
+
+    use super::*;
+
+    // the packet the rpc client of from_peer sends for a call_rpc request
+    async fn build_request(
+        from_peer: PeerId,
+        to_peer: PeerId,
+        transact_id: u32,
+        method: String,
+        body: Vec<u8>,
+    ) -> ZCPacket {
+        let (client_transport, mut server_transport) = tarpc::transport::channel::unbounded();
+        let client =
+            RemoteManageServiceClient::new(tarpc::client::Config::default(), client_transport)
+                .spawn();
+        tokio::spawn(async move {
+            let _ = client
+                .call_rpc(tarpc::context::current(), method, body)
+                .await;
+        });
+        let req: tarpc::ClientMessage<RemoteManageServiceRequest> =
+            server_transport.next().await.unwrap().unwrap();
+
+        let packet = TaRpcPacket {
+            from_peer,
+            to_peer,
+            service_id: SERVICE_ID,
+            transact_id,
+            is_req: true,
+            content: postcard::to_allocvec(&req).unwrap(),
+        };
+        let mut zc_packet = ZCPacket::new_with_payload(&packet.encode_to_vec());
+        zc_packet.fill_peer_manager_hdr(from_peer, to_peer, PacketType::TaRpc as u8);
+        zc_packet
+    }
+
+    #[tokio::test]
+    async fn relayed_request_with_forged_peer_id() {
+        // a - b - c - d, c allows d
+        let peer_mgr_a = create_mock_peer_manager().await;
+        let peer_mgr_b = create_mock_peer_manager().await;
+        let peer_mgr_c = create_mock_peer_manager().await;
+        let peer_mgr_d = create_mock_peer_manager().await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        connect_peer_manager(peer_mgr_b.clone(), peer_mgr_c.clone()).await;
+        connect_peer_manager(peer_mgr_c.clone(), peer_mgr_d.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_c.clone())
+            .await
+            .unwrap();
+        wait_route_appear(peer_mgr_c.clone(), peer_mgr_d.clone())
+            .await
+            .unwrap();
+
+        // requests replayed by c show up as connections to its local rpc server
+        let global_ctx_c = peer_mgr_c.get_global_ctx();
+        global_ctx_c
+            .config
+            .set_remote_management(Some(RemoteManagementConfig {
+                allowed_peers: vec![peer_mgr_d
+                    .get_global_ctx()
+                    .get_static_keypair()
+                    .public_key_str()],
+            }));
+        let (channel_c, mut incoming_c) = local_rpc_channel();
+        RemoteManager::new(global_ctx_c, peer_mgr_c.clone()).run(channel_c);
+
+        let (b, c, d) = (
+            peer_mgr_b.my_peer_id(),
+            peer_mgr_c.my_peer_id(),
+            peer_mgr_d.my_peer_id(),
+        );
+        let method = "/cli.PeerManageRpc/ListRoute".to_string();
+        let body = ListRouteRequest::default().encode_to_vec();
+
+        // a relays requests through b claiming to be d or b, encrypted with the network key
+        // known by all members
+        let global_ctx_a = peer_mgr_a.get_global_ctx();
+        let network_cipher = CipherSet::new(global_ctx_a.get_128_key(), global_ctx_a.get_256_key());
+        for (transact_id, from_peer) in [(1, d), (2, b)] {
+            let mut forged =
+                build_request(from_peer, c, transact_id, method.clone(), body.clone()).await;
+            network_cipher.encrypt(&mut forged).unwrap();
+            peer_mgr_a.get_peer_map().send_msg(forged, c).await.unwrap();
+        }
+        let ret = tokio::time::timeout(Duration::from_secs(3), incoming_c.next()).await;
+        assert!(ret.is_err());
+
+        // the same request over the direct session of d is served
+        let request = build_request(d, c, 3, method, body).await;
+        peer_mgr_d
+            .get_peer_map()
+            .send_msg(request, c)
+            .await
+            .unwrap();
+        let ret = tokio::time::timeout(Duration::from_secs(3), incoming_c.next()).await;
+        assert!(matches!(ret, Ok(Some(Ok(_)))));
+    }
+
+    #[tokio::test]
+    async fn forward_rpc_to_remote_peer() {
+        let peer_mgr_a = create_mock_peer_manager().await;
+        let peer_mgr_b = create_mock_peer_manager().await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_b.clone())
+            .await
+            .unwrap();
+
+        let global_ctx_b = peer_mgr_b.get_global_ctx();
+        let (channel_b, incoming_b) = local_rpc_channel();
+        let service = PeerManageRpcServer::new(PeerManagerRpcService::new(peer_mgr_b.clone()));
+        tokio::spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming_b),
+        );
+
+        let manager_a = RemoteManager::new(peer_mgr_a.get_global_ctx(), peer_mgr_a.clone());
+        manager_a.run(local_rpc_channel().0);
+        let manager_b = RemoteManager::new(global_ctx_b.clone(), peer_mgr_b.clone());
+        manager_b.run(channel_b);
+
+        let target = peer_mgr_b.my_peer_id().to_string();
+        let method = "/cli.PeerManageRpc/ListRoute".to_string();
+        let body = ListRouteRequest::default().encode_to_vec();
+
+        // disabled by default
+        let ret = manager_a
+            .forward(&target, method.clone(), body.clone())
+            .await;
+        assert_eq!(tonic::Code::PermissionDenied, ret.unwrap_err().code());
+
+        // peers are matched by public key, not by the names they claim
+        let allow = |allowed_peers: Vec<String>| {
+            global_ctx_b
+                .config
+                .set_remote_management(Some(RemoteManagementConfig { allowed_peers }))
+        };
+        allow(vec![
+            peer_mgr_a
+                .get_global_ctx()
+                .get_hostname()
+                .unwrap_or_default(),
+            peer_mgr_a.get_global_ctx().get_id().to_string(),
+            StaticKeypair::generate().public_key_str(),
+        ]);
+        let ret = manager_a
+            .forward(&target, method.clone(), body.clone())
+            .await;
+        assert_eq!(tonic::Code::PermissionDenied, ret.unwrap_err().code());
+
+        allow(vec![peer_mgr_a
+            .get_global_ctx()
+            .get_static_keypair()
+            .public_key_str()]);
+        wait_for_condition(
+            || async {
+                manager_a
+                    .forward(&target, method.clone(), body.clone())
+                    .await
+                    .is_ok()
+            },
+            std::time::Duration::from_secs(5),
+        )
+        .await;
+
+        allow(vec!["*".to_string()]);
+        let ret = manager_a.forward(&target, method, body).await.unwrap();
+        let routes = ListRouteResponse::decode(ret.as_slice()).unwrap().routes;
+        assert!(routes.iter().any(|r| r.peer_id == peer_mgr_a.my_peer_id()));
+
+        let ret = manager_a
+            .forward(&target, "/cli.EventRpc/SubscribeEvent".to_string(), vec![])
+            .await;
+        assert_eq!(tonic::Code::Unimplemented, ret.unwrap_err().code());
+    }
+}
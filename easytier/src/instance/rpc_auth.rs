@@ -0,0 +1,123 @@
+// token and tls settings of the management rpc portal.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use tonic::{
+    service::Interceptor,
+    transport::{Certificate, Identity, ServerTlsConfig},
+};
+
+use crate::common::{config::RpcPortalAuthConfig, error::Error};
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+// rejects requests without "authorization: Bearer <token>" if a token is set
+#[derive(Clone, Default)]
+pub struct TokenInterceptor {
+    token: Option<Arc<String>>,
+}
+
+impl TokenInterceptor {
+    pub fn new(token: Option<String>) -> Self {
+        TokenInterceptor {
+            token: token.filter(|t| !t.is_empty()).map(Arc::new),
+        }
+    }
+}
+
+impl Interceptor for TokenInterceptor {
+    fn call(&mut self, req: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
+        let Some(token) = &self.token else {
+            return Ok(req);
+        };
+        let provided = req
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if !constant_time_eq(provided.as_bytes(), token.as_bytes()) {
+            return Err(tonic::Status::unauthenticated(
+                "invalid or missing rpc token",
+            ));
+        }
+        Ok(req)
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, Error> {
+    Ok(std::fs::read(path).with_context(|| format!("read pem file failed: {}", path))?)
+}
+
+pub fn load_server_tls_config(
+    config: &RpcPortalAuthConfig,
+) -> Result<Option<ServerTlsConfig>, Error> {
+    let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) if config.tls_client_ca.is_none() => return Ok(None),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "tls_cert and tls_key must be set together to enable rpc portal tls"
+            )
+            .into())
+        }
+    };
+    let mut tls = ServerTlsConfig::new().identity(Identity::from_pem(
+        read_pem(cert.as_str())?,
+        read_pem(key.as_str())?,
+    ));
+    if let Some(ca) = &config.tls_client_ca {
+        tls = tls.client_ca_root(Certificate::from_pem(read_pem(ca.as_str())?));
+    }
+    Ok(Some(tls))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with_auth(value: Option<&str>) -> tonic::Request<()> {
+        let mut req = tonic::Request::new(());
+        if let Some(value) = value {
+            req.metadata_mut()
+                .insert("authorization", value.parse().unwrap());
+        }
+        req
+    }
+
+    #[test]
+    fn check_token() {
+        let mut interceptor = TokenInterceptor::new(Some("secret".to_string()));
+        assert!(interceptor
+            .call(request_with_auth(Some("Bearer secret")))
+            .is_ok());
+        for value in [None, Some("Bearer wrong"), Some("secret"), Some("Bearer ")] {
+            let ret = interceptor.call(request_with_auth(value));
+            assert_eq!(tonic::Code::Unauthenticated, ret.unwrap_err().code());
+        }
+
+        let mut interceptor = TokenInterceptor::new(None);
+        assert!(interceptor.call(request_with_auth(None)).is_ok());
+    }
+
+    #[test]
+    fn tls_config_requires_cert_and_key() {
+        assert!(load_server_tls_config(&RpcPortalAuthConfig::default())
+            .unwrap()
+            .is_none());
+        let config = RpcPortalAuthConfig {
+            tls_cert: Some("/tmp/cert.pem".to_string()),
+            ..Default::default()
+        };
+        assert!(load_server_tls_config(&config).is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     io,
-    net::Ipv4Addr,
+    net::{Ipv4Addr, Ipv6Addr},
     pin::Pin,
     task::{Context, Poll},
 };
@@ -263,7 +263,15 @@ impl VirtualNic {
     async fn create_dev_ret_err(&mut self) -> Result<Box<dyn Tunnel>, Error> {
         let mut config = Configuration::default();
         let has_packet_info = cfg!(target_os = "macos");
-        config.layer(Layer::L3);
+        let flags = self.global_ctx.get_flags();
+        if flags.enable_tap {
+            if !cfg!(target_os = "linux") {
+                return Err(anyhow::anyhow!("tap mode is only supported on linux").into());
+            }
+            config.layer(Layer::L2);
+        } else {
+            config.layer(Layer::L3);
+        }
 
         #[cfg(target_os = "linux")]
         {
@@ -296,6 +304,17 @@ impl VirtualNic {
         let ifname = dev.get_ref().name()?;
         self.ifcfg.wait_interface_show(ifname.as_str()).await?;
 
+        if flags.enable_tap {
+            let _g = self.global_ctx.net_ns.guard();
+            if !flags.tap_bridge.is_empty() {
+                self.ifcfg
+                    .set_bridge(ifname.as_str(), flags.tap_bridge.as_str())
+                    .await?;
+            }
+            // a tap device may have no address, so it is not brought up by assign_ipv4
+            self.ifcfg.set_link_status(ifname.as_str(), true).await?;
+        }
+
         let (a, b) = BiLock::new(dev);
 
         let ft = TunnelWrapper::new(
@@ -347,6 +366,25 @@ impl VirtualNic {
         Ok(())
     }
 
+    // replace all ipv4 addresses of the nic with the given one
+    pub async fn assign_ipv4(&self, addr: cidr::Ipv4Inet) -> Result<(), Error> {
+        self.link_up().await?;
+        self.remove_ip(None).await?;
+        self.add_ip(addr.address(), addr.network_length() as i32)
+            .await?;
+        if cfg!(target_os = "macos") {
+            self.add_route(addr.first_address(), addr.network_length())
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn add_ipv6(&self, ip: Ipv6Addr, cidr: u8) -> Result<(), Error> {
+        let _g = self.global_ctx.net_ns.guard();
+        self.ifcfg.add_ipv6_ip(self.ifname(), ip, cidr).await?;
+        Ok(())
+    }
+
     pub fn get_ifcfg(&self) -> impl IfConfiguerTrait {
         IfConfiger {}
     }
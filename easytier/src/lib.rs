@@ -5,6 +5,8 @@ pub mod common;
 pub mod connector;
 pub mod gateway;
 pub mod instance;
+#[cfg(feature = "smoltcp")]
+pub mod netstack;
 pub mod peer_center;
 pub mod peers;
 pub mod rpc;
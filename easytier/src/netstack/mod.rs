@@ -0,0 +1,603 @@
+// userspace tcp/ip stack of no tun mode. ip packets from peers are fed into smoltcp and packets
+// it sends are forwarded to peers, so tcp and udp of the virtual network can be used without a
+// tun device.
+
+pub mod port_forward;
+pub mod socks5;
+
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    io,
+    net::{Ipv4Addr, SocketAddrV4},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use pnet::packet::ipv4::Ipv4Packet;
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken},
+    socket::{tcp, udp, Socket},
+    time::{Duration as SmolDuration, Instant as SmolInstant},
+    wire::{HardwareAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Notify,
+};
+
+use crate::{
+    common::error::Error,
+    peers::{peer_manager::PeerManager, PacketRecvChanReceiver},
+    tunnel::packet_def::ZCPacket,
+};
+
+// smaller than the mtu of tun device, leaves room for headers of the underlay tunnel
+const NETSTACK_MTU: usize = 1380;
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_PACKET_NUM: usize = 64;
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// idle connections are kept by keepalive, dead ones are aborted after the timeout
+const TCP_KEEPALIVE: SmolDuration = SmolDuration::from_secs(30);
+const TCP_TIMEOUT: SmolDuration = SmolDuration::from_secs(120);
+const MAX_POLL_DELAY: Duration = Duration::from_millis(100);
+// packets from peers waiting for the stack, more are dropped like on a full nic queue
+const MAX_RX_QUEUE_LEN: usize = 1024;
+
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+fn to_endpoint(addr: SocketAddrV4) -> IpEndpoint {
+    IpEndpoint::new(
+        IpAddress::Ipv4(Ipv4Address(addr.ip().octets())),
+        addr.port(),
+    )
+}
+
+#[allow(unreachable_patterns)]
+fn from_endpoint(endpoint: IpEndpoint) -> Option<SocketAddrV4> {
+    match endpoint.addr {
+        IpAddress::Ipv4(addr) => Some(SocketAddrV4::new(Ipv4Addr::from(addr.0), endpoint.port)),
+        _ => None,
+    }
+}
+
+// packets are queued in memory, the stack is polled by Netstack::run
+#[derive(Default)]
+struct ChannelDevice {
+    rx: VecDeque<Vec<u8>>,
+    tx: Vec<Vec<u8>>,
+}
+
+impl ChannelDevice {
+    fn push_rx(&mut self, packet: Vec<u8>) {
+        if self.rx.len() >= MAX_RX_QUEUE_LEN {
+            tracing::trace!("[USER_PACKET] netstack rx queue full, packet dropped");
+            return;
+        }
+        self.rx.push_back(packet);
+    }
+}
+
+struct ChannelRxToken(Vec<u8>);
+
+impl RxToken for ChannelRxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+struct ChannelTxToken<'a>(&'a mut Vec<Vec<u8>>);
+
+impl TxToken for ChannelTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buf = vec![0; len];
+        let ret = f(&mut buf);
+        self.0.push(buf);
+        ret
+    }
+}
+
+impl Device for ChannelDevice {
+    type RxToken<'a>
+        = ChannelRxToken
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = ChannelTxToken<'a>
+    where
+        Self: 'a;
+
+    fn receive(
+        &mut self,
+        _timestamp: SmolInstant,
+    ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((ChannelRxToken(packet), ChannelTxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: SmolInstant) -> Option<Self::TxToken<'_>> {
+        Some(ChannelTxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = NETSTACK_MTU;
+        caps
+    }
+}
+
+struct NetstackInner {
+    iface: Interface,
+    device: ChannelDevice,
+    sockets: SocketSet<'static>,
+    next_port: u16,
+    // tcp sockets dropped by their owner, removed once the close handshake is done
+    closing: Vec<SocketHandle>,
+}
+
+impl NetstackInner {
+    #[allow(unreachable_patterns)]
+    fn is_port_in_use(&self, port: u16) -> bool {
+        self.sockets.iter().any(|(_, socket)| match socket {
+            Socket::Tcp(s) => s.local_endpoint().map(|e| e.port) == Some(port),
+            Socket::Udp(s) => s.endpoint().port == port,
+            _ => false,
+        })
+    }
+
+    // ports are handed out in turn, ports still used by a socket are skipped after wrapping
+    fn alloc_port(&mut self) -> Result<u16, Error> {
+        for _ in EPHEMERAL_PORT_START..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = port.checked_add(1).unwrap_or(EPHEMERAL_PORT_START);
+            if !self.is_port_in_use(port) {
+                return Ok(port);
+            }
+        }
+        Err(anyhow::anyhow!("no free port in netstack").into())
+    }
+}
+
+pub struct Netstack {
+    ipv4: Ipv4Addr,
+    inner: Mutex<NetstackInner>,
+    // wakes the poll loop after sockets are changed
+    notify: Notify,
+}
+
+impl Netstack {
+    pub fn new(ipv4: cidr::Ipv4Inet) -> Arc<Self> {
+        let mut device = ChannelDevice::default();
+        let config = Config::new(HardwareAddress::Ip);
+        let mut iface = Interface::new(config, &mut device, SmolInstant::now());
+        let addr = Ipv4Address(ipv4.address().octets());
+        iface.update_ip_addrs(|addrs| {
+            addrs
+                .push(IpCidr::new(IpAddress::Ipv4(addr), ipv4.network_length()))
+                .unwrap();
+        });
+        // everything not in the virtual network, e.g. proxy cidrs, is also sent to peers
+        iface.routes_mut().add_default_ipv4_route(addr).unwrap();
+
+        Arc::new(Netstack {
+            ipv4: ipv4.address(),
+            inner: Mutex::new(NetstackInner {
+                iface,
+                device,
+                sockets: SocketSet::new(vec![]),
+                next_port: EPHEMERAL_PORT_START,
+                closing: vec![],
+            }),
+            notify: Notify::new(),
+        })
+    }
+
+    pub fn ipv4(&self) -> Ipv4Addr {
+        self.ipv4
+    }
+
+    fn poll(&self) -> (Vec<Vec<u8>>, Duration) {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        let now = SmolInstant::now();
+        inner.iface.poll(now, &mut inner.device, &mut inner.sockets);
+
+        let sockets = &mut inner.sockets;
+        inner.closing.retain(|handle| {
+            if sockets.get::<tcp::Socket>(*handle).state() == tcp::State::Closed {
+                sockets.remove(*handle);
+                false
+            } else {
+                true
+            }
+        });
+
+        let delay = inner
+            .iface
+            .poll_delay(now, &inner.sockets)
+            .map(|d| Duration::from_millis(d.total_millis()))
+            .unwrap_or(MAX_POLL_DELAY)
+            .min(MAX_POLL_DELAY);
+        (std::mem::take(&mut inner.device.tx), delay)
+    }
+
+    async fn send_to_peers(&self, peer_mgr: &PeerManager, packet: Vec<u8>) {
+        let Some(ipv4) = Ipv4Packet::new(&packet) else {
+            return;
+        };
+        let dst = ipv4.get_destination();
+        if dst == self.ipv4 {
+            self.inner.lock().unwrap().device.push_rx(packet);
+            self.notify.notify_one();
+            return;
+        }
+        let ret = peer_mgr
+            .send_msg_ipv4(ZCPacket::new_with_payload(&packet), dst)
+            .await;
+        if ret.is_err() {
+            tracing::trace!(?ret, ?dst, "[USER_PACKET] netstack send to peers failed");
+        }
+    }
+
+    pub async fn run(
+        self: Arc<Self>,
+        peer_mgr: Arc<PeerManager>,
+        mut nic_recv: PacketRecvChanReceiver,
+    ) {
+        loop {
+            let (packets, delay) = self.poll();
+            for packet in packets {
+                self.send_to_peers(&peer_mgr, packet).await;
+            }
+
+            tokio::select! {
+                packet = nic_recv.recv() => {
+                    let Some(packet) = packet else {
+                        tracing::warn!("nic channel closed, netstack stopped");
+                        return;
+                    };
+                    self.inner
+                        .lock()
+                        .unwrap()
+                        .device
+                        .push_rx(packet.payload().to_vec());
+                }
+                _ = self.notify.notified() => {}
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    }
+
+    pub async fn tcp_connect(
+        self: &Arc<Self>,
+        dst: SocketAddrV4,
+    ) -> Result<NetstackTcpStream, Error> {
+        let handle = {
+            let mut guard = self.inner.lock().unwrap();
+            let inner = &mut *guard;
+            let mut socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+                tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            );
+            socket.set_keep_alive(Some(TCP_KEEPALIVE));
+            socket.set_timeout(Some(TCP_TIMEOUT));
+            let port = inner.alloc_port()?;
+            socket
+                .connect(inner.iface.context(), to_endpoint(dst), port)
+                .map_err(|e| anyhow::anyhow!("netstack connect to {} failed: {:?}", dst, e))?;
+            inner.sockets.add(socket)
+        };
+        self.notify.notify_one();
+
+        // the socket is released when the stream is dropped, also on timeout
+        let stream = NetstackTcpStream {
+            stack: self.clone(),
+            handle,
+        };
+        tokio::time::timeout(TCP_CONNECT_TIMEOUT, stream.wait_connected())
+            .await
+            .map_err(|_| anyhow::anyhow!("netstack connect to {} timeout", dst))??;
+        Ok(stream)
+    }
+
+    pub fn udp_bind(self: &Arc<Self>) -> Result<NetstackUdpSocket, Error> {
+        let handle = {
+            let mut inner = self.inner.lock().unwrap();
+            let mut socket = udp::Socket::new(
+                udp::PacketBuffer::new(
+                    vec![udp::PacketMetadata::EMPTY; UDP_PACKET_NUM],
+                    vec![0; UDP_BUFFER_SIZE],
+                ),
+                udp::PacketBuffer::new(
+                    vec![udp::PacketMetadata::EMPTY; UDP_PACKET_NUM],
+                    vec![0; UDP_BUFFER_SIZE],
+                ),
+            );
+            let port = inner.alloc_port()?;
+            socket
+                .bind(port)
+                .map_err(|e| anyhow::anyhow!("netstack bind udp port {} failed: {:?}", port, e))?;
+            inner.sockets.add(socket)
+        };
+        Ok(NetstackUdpSocket {
+            stack: self.clone(),
+            handle,
+        })
+    }
+}
+
+pub struct NetstackTcpStream {
+    stack: Arc<Netstack>,
+    handle: SocketHandle,
+}
+
+impl NetstackTcpStream {
+    fn with_socket<R>(&self, f: impl FnOnce(&mut tcp::Socket) -> R) -> R {
+        let mut inner = self.stack.inner.lock().unwrap();
+        f(inner.sockets.get_mut::<tcp::Socket>(self.handle))
+    }
+
+    async fn wait_connected(&self) -> Result<(), Error> {
+        poll_fn(|cx| {
+            self.with_socket(|s| match s.state() {
+                tcp::State::SynSent | tcp::State::SynReceived => {
+                    s.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+                _ if s.may_send() || s.may_recv() => Poll::Ready(Ok(())),
+                state => Poll::Ready(Err(anyhow::anyhow!(
+                    "netstack connect failed, state: {}",
+                    state
+                )
+                .into())),
+            })
+        })
+        .await
+    }
+}
+
+impl AsyncRead for NetstackTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let ret = self.with_socket(|s| {
+            if s.can_recv() {
+                match s.recv_slice(buf.initialize_unfilled()) {
+                    Ok(n) => {
+                        buf.advance(n);
+                        Poll::Ready(Ok(()))
+                    }
+                    Err(e) => Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("{:?}", e),
+                    ))),
+                }
+            } else if !s.may_recv() {
+                // eof
+                Poll::Ready(Ok(()))
+            } else {
+                s.register_recv_waker(cx.waker());
+                Poll::Pending
+            }
+        });
+        // window of the socket may be changed
+        if ret.is_ready() {
+            self.stack.notify.notify_one();
+        }
+        ret
+    }
+}
+
+impl AsyncWrite for NetstackTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let ret = self.with_socket(|s| {
+            if s.can_send() {
+                Poll::Ready(
+                    s.send_slice(buf)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e))),
+                )
+            } else if !s.may_send() {
+                Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+            } else {
+                s.register_send_waker(cx.waker());
+                Poll::Pending
+            }
+        });
+        if ret.is_ready() {
+            self.stack.notify.notify_one();
+        }
+        ret
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with_socket(|s| s.close());
+        self.stack.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for NetstackTcpStream {
+    fn drop(&mut self) {
+        let mut inner = self.stack.inner.lock().unwrap();
+        inner.sockets.get_mut::<tcp::Socket>(self.handle).close();
+        inner.closing.push(self.handle);
+        drop(inner);
+        self.stack.notify.notify_one();
+    }
+}
+
+pub struct NetstackUdpSocket {
+    stack: Arc<Netstack>,
+    handle: SocketHandle,
+}
+
+impl NetstackUdpSocket {
+    fn with_socket<R>(&self, f: impl FnOnce(&mut udp::Socket) -> R) -> R {
+        let mut inner = self.stack.inner.lock().unwrap();
+        f(inner.sockets.get_mut::<udp::Socket>(self.handle))
+    }
+
+    pub async fn send_to(&self, buf: &[u8], dst: SocketAddrV4) -> Result<(), Error> {
+        let ret = poll_fn(|cx| {
+            self.with_socket(|s| match s.send_slice(buf, to_endpoint(dst)) {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(udp::SendError::BufferFull) => {
+                    s.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+                Err(e) => Poll::Ready(Err(anyhow::anyhow!(
+                    "netstack udp send to {} failed: {:?}",
+                    dst,
+                    e
+                ))),
+            })
+        })
+        .await;
+        self.stack.notify.notify_one();
+        ret?;
+        Ok(())
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddrV4), Error> {
+        let (len, endpoint) = poll_fn(|cx| {
+            self.with_socket(|s| match s.recv_slice(buf) {
+                Ok((len, meta)) => Poll::Ready(Ok((len, meta.endpoint))),
+                Err(udp::RecvError::Exhausted) => {
+                    s.register_recv_waker(cx.waker());
+                    Poll::Pending
+                }
+                Err(e) => Poll::Ready(Err(anyhow::anyhow!("netstack udp recv failed: {:?}", e))),
+            })
+        })
+        .await?;
+        let from = from_endpoint(endpoint)
+            .ok_or_else(|| anyhow::anyhow!("unexpected udp source: {}", endpoint))?;
+        Ok((len, from))
+    }
+}
+
+impl Drop for NetstackUdpSocket {
+    fn drop(&mut self) {
+        self.stack.inner.lock().unwrap().sockets.remove(self.handle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use crate::{
+        common::global_ctx::tests::get_mock_global_ctx,
+        peers::{
+            peer_manager::RouteAlgoType,
+            tests::{connect_peer_manager, wait_route_appear},
+        },
+    };
+
+    use super::*;
+
+    pub(super) async fn create_netstack_peer_manager(
+        ipv4: &str,
+    ) -> (Arc<PeerManager>, Arc<Netstack>) {
+        let global_ctx = get_mock_global_ctx();
+        let ipv4: cidr::Ipv4Inet = ipv4.parse().unwrap();
+        global_ctx.set_ipv4_inet(ipv4);
+        let (s, r) = mpsc::channel(100);
+        let peer_mgr = Arc::new(PeerManager::new(RouteAlgoType::Ospf, global_ctx, s));
+        peer_mgr.run().await.unwrap();
+
+        let netstack = Netstack::new(ipv4);
+        tokio::spawn(netstack.clone().run(peer_mgr.clone(), r));
+        (peer_mgr, netstack)
+    }
+
+    #[tokio::test]
+    async fn udp_between_netstacks() {
+        let (peer_mgr_a, netstack_a) = create_netstack_peer_manager("10.144.145.1/24").await;
+        let (peer_mgr_b, netstack_b) = create_netstack_peer_manager("10.144.145.2/24").await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_b.clone())
+            .await
+            .unwrap();
+
+        let socket_a = netstack_a.udp_bind().unwrap();
+        let socket_b = netstack_b.udp_bind().unwrap();
+        let addr_b = SocketAddrV4::new(netstack_b.ipv4(), EPHEMERAL_PORT_START);
+
+        // the first packet may be dropped before the route to b is resolved, so retry
+        let mut buf = [0u8; 64];
+        let (len, from) = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                socket_a.send_to(b"hello", addr_b).await.unwrap();
+                if let Ok(ret) =
+                    tokio::time::timeout(Duration::from_secs(1), socket_b.recv_from(&mut buf)).await
+                {
+                    break ret.unwrap();
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(
+            from,
+            SocketAddrV4::new(netstack_a.ipv4(), EPHEMERAL_PORT_START)
+        );
+
+        socket_b.send_to(b"world", from).await.unwrap();
+        let (len, from) =
+            tokio::time::timeout(Duration::from_secs(5), socket_a.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(&buf[..len], b"world");
+        assert_eq!(from, addr_b);
+    }
+
+    #[test]
+    fn alloc_ephemeral_port() {
+        let netstack = Netstack::new("10.144.145.1/24".parse().unwrap());
+        let socket = netstack.udp_bind().unwrap();
+        let mut inner = netstack.inner.lock().unwrap();
+        assert_eq!(inner.alloc_port().unwrap(), EPHEMERAL_PORT_START + 1);
+        inner.next_port = u16::MAX;
+        assert_eq!(inner.alloc_port().unwrap(), u16::MAX);
+        // the port of the bound socket is skipped after wrapping
+        assert_eq!(inner.alloc_port().unwrap(), EPHEMERAL_PORT_START + 1);
+        drop(inner);
+        drop(socket);
+        let mut inner = netstack.inner.lock().unwrap();
+        inner.next_port = EPHEMERAL_PORT_START;
+        assert_eq!(inner.alloc_port().unwrap(), EPHEMERAL_PORT_START);
+    }
+
+    #[test]
+    fn rx_queue_is_bounded() {
+        let mut device = ChannelDevice::default();
+        for _ in 0..MAX_RX_QUEUE_LEN + 10 {
+            device.push_rx(vec![0; 20]);
+        }
+        assert_eq!(device.rx.len(), MAX_RX_QUEUE_LEN);
+    }
+}
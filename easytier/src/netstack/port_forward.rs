@@ -0,0 +1,185 @@
+// forward a local tcp or udp port to an address in the virtual network through the netstack.
+
+use std::{
+    net::{SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crossbeam::atomic::AtomicCell;
+use dashmap::DashMap;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use crate::common::{config::PortForwardConfig, error::Error};
+
+use super::{Netstack, NetstackUdpSocket};
+
+// udp sessions without packets in both directions are removed after this
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub enum PortForwardListener {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+struct UdpSession {
+    socket: NetstackUdpSocket,
+    last_active: AtomicCell<Instant>,
+}
+
+pub struct PortForwarder {
+    netstack: Arc<Netstack>,
+    config: PortForwardConfig,
+    dst: SocketAddrV4,
+    udp_sessions: DashMap<SocketAddr, Arc<UdpSession>>,
+}
+
+impl PortForwarder {
+    pub fn new(netstack: Arc<Netstack>, config: PortForwardConfig) -> Result<Self, Error> {
+        let SocketAddr::V4(dst) = config.dst_addr else {
+            return Err(anyhow::anyhow!(
+                "port forward destination must be ipv4: {}",
+                config.dst_addr
+            )
+            .into());
+        };
+        if config.proto != "tcp" && config.proto != "udp" {
+            return Err(anyhow::anyhow!("invalid port forward proto: {}", config.proto).into());
+        }
+        Ok(PortForwarder {
+            netstack,
+            config,
+            dst,
+            udp_sessions: DashMap::new(),
+        })
+    }
+
+    pub async fn bind(&self) -> Result<PortForwardListener, Error> {
+        let addr = self.config.bind_addr;
+        let listener = match self.config.proto.as_str() {
+            "tcp" => TcpListener::bind(addr).await.map(PortForwardListener::Tcp),
+            _ => UdpSocket::bind(addr).await.map(PortForwardListener::Udp),
+        };
+        Ok(listener
+            .map_err(|e| anyhow::anyhow!("bind port forward failed. addr: {}, err: {}", addr, e))?)
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: PortForwardListener) {
+        tracing::info!(config = ?self.config, "port forward started");
+        match listener {
+            PortForwardListener::Tcp(listener) => self.serve_tcp(listener).await,
+            PortForwardListener::Udp(socket) => self.serve_udp(Arc::new(socket)).await,
+        }
+    }
+
+    async fn forward_tcp_conn(&self, mut stream: TcpStream) -> Result<(), Error> {
+        let mut remote = self.netstack.tcp_connect(self.dst).await?;
+        tokio::io::copy_bidirectional(&mut stream, &mut remote).await?;
+        Ok(())
+    }
+
+    async fn serve_tcp(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(ret) => ret,
+                Err(e) => {
+                    tracing::warn!(?e, "port forward accept failed");
+                    continue;
+                }
+            };
+            let forwarder = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = forwarder.forward_tcp_conn(stream).await {
+                    tracing::debug!(?e, ?addr, dst = ?forwarder.dst, "port forward conn failed");
+                }
+            });
+        }
+    }
+
+    fn get_or_create_udp_session(
+        self: &Arc<Self>,
+        local: &Arc<UdpSocket>,
+        client: SocketAddr,
+    ) -> Result<Arc<UdpSession>, Error> {
+        if let Some(session) = self.udp_sessions.get(&client) {
+            return Ok(session.clone());
+        }
+        let session = Arc::new(UdpSession {
+            socket: self.netstack.udp_bind()?,
+            last_active: AtomicCell::new(Instant::now()),
+        });
+        self.udp_sessions.insert(client, session.clone());
+
+        // replies from the destination are sent back to the client
+        let forwarder = self.clone();
+        let local = local.clone();
+        let s = session.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            loop {
+                match tokio::time::timeout(UDP_SESSION_TIMEOUT, s.socket.recv_from(&mut buf)).await
+                {
+                    Ok(Ok((len, _))) => {
+                        s.last_active.store(Instant::now());
+                        if let Err(e) = local.send_to(&buf[..len], client).await {
+                            tracing::debug!(?e, ?client, "port forward udp send to client failed");
+                        }
+                    }
+                    Ok(Err(e)) => {
+                        tracing::debug!(?e, ?client, "port forward udp recv failed");
+                        break;
+                    }
+                    Err(_) if s.last_active.load().elapsed() < UDP_SESSION_TIMEOUT => {}
+                    Err(_) => break,
+                }
+            }
+            forwarder.udp_sessions.remove(&client);
+        });
+
+        Ok(session)
+    }
+
+    async fn serve_udp(self: Arc<Self>, local: Arc<UdpSocket>) {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (len, client) = match local.recv_from(&mut buf).await {
+                Ok(ret) => ret,
+                Err(e) => {
+                    tracing::warn!(?e, "port forward udp recv failed");
+                    continue;
+                }
+            };
+            let session = match self.get_or_create_udp_session(&local, client) {
+                Ok(session) => session,
+                Err(e) => {
+                    tracing::warn!(?e, ?client, "create port forward udp session failed");
+                    continue;
+                }
+            };
+            session.last_active.store(Instant::now());
+            if let Err(e) = session.socket.send_to(&buf[..len], self.dst).await {
+                tracing::debug!(?e, dst = ?self.dst, "port forward udp send failed");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_invalid_forward() {
+        let netstack = Netstack::new("10.144.145.1/24".parse().unwrap());
+        let config: PortForwardConfig = "tcp://127.0.0.1:8080/10.144.145.2:80".parse().unwrap();
+        assert!(PortForwarder::new(netstack.clone(), config.clone()).is_ok());
+
+        let mut ipv6 = config.clone();
+        ipv6.dst_addr = "[fd00::1]:80".parse().unwrap();
+        assert!(PortForwarder::new(netstack.clone(), ipv6).is_err());
+
+        let mut icmp = config;
+        icmp.proto = "icmp".to_string();
+        assert!(PortForwarder::new(netstack, icmp).is_err());
+    }
+}
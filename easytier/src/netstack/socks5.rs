@@ -0,0 +1,399 @@
+// socks5 server (rfc 1928) backed by the netstack. only CONNECT is supported, clients must
+// authenticate with username and password (rfc 1929) if socks5 auth is set. domain names are
+// resolved to the virtual ip of the peer with the same hostname, e.g. nas or nas.<network_name>.et
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    common::{config::Socks5AuthConfig, error::Error},
+    peers::peer_manager::PeerManager,
+    rpc::Route,
+};
+
+use super::Netstack;
+
+const SOCKS5_VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_USERNAME_PASSWORD: u8 = 2;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+const AUTH_VERSION: u8 = 1;
+const AUTH_SUCCEEDED: u8 = 0;
+const AUTH_FAILED: u8 = 1;
+
+const REP_SUCCEEDED: u8 = 0;
+const REP_HOST_UNREACHABLE: u8 = 4;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    Ipv4(SocketAddrV4),
+    Domain(String, u16),
+    Ipv6,
+}
+
+async fn read_request<R: AsyncRead + Unpin>(r: &mut R) -> Result<(u8, Target), Error> {
+    let mut hdr = [0u8; 4];
+    r.read_exact(&mut hdr).await?;
+    if hdr[0] != SOCKS5_VERSION {
+        return Err(anyhow::anyhow!("invalid socks version: {}", hdr[0]).into());
+    }
+    let target = match hdr[3] {
+        ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            r.read_exact(&mut addr).await?;
+            Target::Ipv4(SocketAddrV4::new(Ipv4Addr::from(addr), r.read_u16().await?))
+        }
+        ATYP_DOMAIN => {
+            let len = r.read_u8().await? as usize;
+            let mut name = vec![0u8; len];
+            r.read_exact(&mut name).await?;
+            let name = String::from_utf8(name)
+                .map_err(|_| anyhow::anyhow!("invalid domain name in socks request"))?;
+            Target::Domain(name, r.read_u16().await?)
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0u8; 18];
+            r.read_exact(&mut addr).await?;
+            Target::Ipv6
+        }
+        atyp => return Err(anyhow::anyhow!("invalid socks address type: {}", atyp).into()),
+    };
+    Ok((hdr[1], target))
+}
+
+async fn read_auth<R: AsyncRead + Unpin>(r: &mut R) -> Result<(String, String), Error> {
+    if r.read_u8().await? != AUTH_VERSION {
+        return Err(anyhow::anyhow!("invalid socks auth version").into());
+    }
+    // username and password, each with a one byte length
+    let mut fields = [String::new(), String::new()];
+    for field in fields.iter_mut() {
+        let mut buf = vec![0u8; r.read_u8().await? as usize];
+        r.read_exact(&mut buf).await?;
+        *field = String::from_utf8_lossy(&buf).into_owned();
+    }
+    let [username, password] = fields;
+    Ok((username, password))
+}
+
+fn build_reply(rep: u8, bind_addr: SocketAddrV4) -> [u8; 10] {
+    let mut buf = [SOCKS5_VERSION, rep, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0];
+    buf[4..8].copy_from_slice(&bind_addr.ip().octets());
+    buf[8..10].copy_from_slice(&bind_addr.port().to_be_bytes());
+    buf
+}
+
+fn resolve_hostname(routes: &[Route], name: &str) -> Option<Ipv4Addr> {
+    if let Ok(ip) = name.parse::<Ipv4Addr>() {
+        return Some(ip);
+    }
+    // nas.<network_name>.et -> nas
+    let name = name.trim_end_matches('.');
+    let host = match name.strip_suffix(".et") {
+        Some(rest) => rest.split('.').next().unwrap_or(rest),
+        None => name,
+    };
+    routes
+        .iter()
+        .find(|r| r.hostname.eq_ignore_ascii_case(host))
+        .and_then(|r| r.ipv4_addr.parse().ok())
+}
+
+pub struct Socks5Server {
+    netstack: Arc<Netstack>,
+    peer_mgr: Arc<PeerManager>,
+    auth: Option<Socks5AuthConfig>,
+}
+
+impl Socks5Server {
+    pub fn new(
+        netstack: Arc<Netstack>,
+        peer_mgr: Arc<PeerManager>,
+        auth: Option<Socks5AuthConfig>,
+    ) -> Self {
+        Socks5Server {
+            netstack,
+            peer_mgr,
+            auth,
+        }
+    }
+
+    pub async fn bind(addr: SocketAddr) -> Result<TcpListener, Error> {
+        let listener = TcpListener::bind(addr).await.map_err(|e| {
+            anyhow::anyhow!("create socks5 server failed. addr: {}, err: {}", addr, e)
+        })?;
+        Ok(listener)
+    }
+
+    async fn resolve(&self, target: &Target) -> Option<SocketAddrV4> {
+        match target {
+            Target::Ipv4(addr) => Some(*addr),
+            Target::Domain(name, port) => {
+                let routes = self.peer_mgr.list_routes().await;
+                resolve_hostname(&routes, name).map(|ip| SocketAddrV4::new(ip, *port))
+            }
+            Target::Ipv6 => None,
+        }
+    }
+
+    async fn handle_conn(&self, mut stream: TcpStream) -> Result<(), Error> {
+        let mut hdr = [0u8; 2];
+        stream.read_exact(&mut hdr).await?;
+        if hdr[0] != SOCKS5_VERSION {
+            return Err(anyhow::anyhow!("invalid socks version: {}", hdr[0]).into());
+        }
+        let mut methods = vec![0u8; hdr[1] as usize];
+        stream.read_exact(&mut methods).await?;
+        let method = match self.auth {
+            Some(_) => METHOD_USERNAME_PASSWORD,
+            None => METHOD_NO_AUTH,
+        };
+        if !methods.contains(&method) {
+            stream
+                .write_all(&[SOCKS5_VERSION, METHOD_NOT_ACCEPTABLE])
+                .await?;
+            return Err(anyhow::anyhow!("socks client does not support method {}", method).into());
+        }
+        stream.write_all(&[SOCKS5_VERSION, method]).await?;
+
+        if let Some(auth) = &self.auth {
+            let (username, password) = read_auth(&mut stream).await?;
+            if username != auth.username || password != auth.password {
+                stream.write_all(&[AUTH_VERSION, AUTH_FAILED]).await?;
+                return Err(anyhow::anyhow!("socks auth failed, username: {}", username).into());
+            }
+            stream.write_all(&[AUTH_VERSION, AUTH_SUCCEEDED]).await?;
+        }
+
+        let (cmd, target) = read_request(&mut stream).await?;
+        let unspecified = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+        if cmd != CMD_CONNECT {
+            stream
+                .write_all(&build_reply(REP_COMMAND_NOT_SUPPORTED, unspecified))
+                .await?;
+            return Err(anyhow::anyhow!("unsupported socks command: {}", cmd).into());
+        }
+        let Some(dst) = self.resolve(&target).await else {
+            let rep = match target {
+                Target::Ipv6 => REP_ADDRESS_NOT_SUPPORTED,
+                _ => REP_HOST_UNREACHABLE,
+            };
+            stream.write_all(&build_reply(rep, unspecified)).await?;
+            return Err(anyhow::anyhow!("resolve socks target failed: {:?}", target).into());
+        };
+
+        let mut remote = match self.netstack.tcp_connect(dst).await {
+            Ok(remote) => remote,
+            Err(e) => {
+                stream
+                    .write_all(&build_reply(REP_HOST_UNREACHABLE, unspecified))
+                    .await?;
+                return Err(e);
+            }
+        };
+        stream
+            .write_all(&build_reply(
+                REP_SUCCEEDED,
+                SocketAddrV4::new(self.netstack.ipv4(), 0),
+            ))
+            .await?;
+        tracing::debug!(?dst, "socks5 connection established");
+
+        tokio::io::copy_bidirectional(&mut stream, &mut remote).await?;
+        Ok(())
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(ret) => ret,
+                Err(e) => {
+                    tracing::warn!(?e, "socks5 server accept failed");
+                    continue;
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_conn(stream).await {
+                    tracing::debug!(?e, ?addr, "socks5 connection failed");
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use smoltcp::socket::tcp;
+
+    use crate::{
+        netstack::{tests::create_netstack_peer_manager, NetstackTcpStream, TCP_BUFFER_SIZE},
+        peers::tests::{connect_peer_manager, wait_for_condition, wait_route_appear},
+    };
+
+    use super::*;
+
+    fn tcp_listen(netstack: &Arc<Netstack>, port: u16) -> NetstackTcpStream {
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        );
+        socket.listen(port).unwrap();
+        let handle = netstack.inner.lock().unwrap().sockets.add(socket);
+        NetstackTcpStream {
+            stack: netstack.clone(),
+            handle,
+        }
+    }
+
+    async fn socks5_handshake(
+        addr: SocketAddr,
+        auth: Option<(&str, &str)>,
+        target: SocketAddrV4,
+    ) -> Result<TcpStream, Error> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let method = match auth {
+            Some(_) => METHOD_USERNAME_PASSWORD,
+            None => METHOD_NO_AUTH,
+        };
+        stream.write_all(&[SOCKS5_VERSION, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply != [SOCKS5_VERSION, method] {
+            return Err(anyhow::anyhow!("method not accepted: {:?}", reply).into());
+        }
+
+        if let Some((username, password)) = auth {
+            let mut req = vec![AUTH_VERSION, username.len() as u8];
+            req.extend_from_slice(username.as_bytes());
+            req.push(password.len() as u8);
+            req.extend_from_slice(password.as_bytes());
+            stream.write_all(&req).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply != [AUTH_VERSION, AUTH_SUCCEEDED] {
+                return Err(anyhow::anyhow!("auth failed: {:?}", reply).into());
+            }
+        }
+
+        let mut req = vec![SOCKS5_VERSION, CMD_CONNECT, 0, ATYP_IPV4];
+        req.extend_from_slice(&target.ip().octets());
+        req.extend_from_slice(&target.port().to_be_bytes());
+        stream.write_all(&req).await?;
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != REP_SUCCEEDED {
+            return Err(anyhow::anyhow!("connect failed: {}", reply[1]).into());
+        }
+        Ok(stream)
+    }
+
+    #[tokio::test]
+    async fn socks5_connect() {
+        let (peer_mgr_a, netstack_a) = create_netstack_peer_manager("10.144.146.1/24").await;
+        let (peer_mgr_b, netstack_b) = create_netstack_peer_manager("10.144.146.2/24").await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_b.clone())
+            .await
+            .unwrap();
+
+        let auth: Socks5AuthConfig = "alice:secret".parse().unwrap();
+        let server = Arc::new(Socks5Server::new(netstack_a, peer_mgr_a, Some(auth)));
+        let listener = Socks5Server::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let socks_addr = listener.local_addr().unwrap();
+        tokio::spawn(server.serve(listener));
+
+        let target = SocketAddrV4::new(netstack_b.ipv4(), 80);
+        assert!(socks5_handshake(socks_addr, None, target).await.is_err());
+        assert!(
+            socks5_handshake(socks_addr, Some(("alice", "wrong")), target)
+                .await
+                .is_err()
+        );
+
+        let mut remote = tcp_listen(&netstack_b, 80);
+        let mut client = socks5_handshake(socks_addr, Some(("alice", "secret")), target)
+            .await
+            .unwrap();
+        wait_for_condition(
+            || async { remote.with_socket(|s| s.state()) == tcp::State::Established },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tokio::time::timeout(Duration::from_secs(5), remote.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+
+        remote.write_all(b"world").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn parse_socks_request() {
+        let req: &[u8] = &[5, 1, 0, ATYP_IPV4, 10, 144, 144, 2, 0, 80];
+        assert_eq!(
+            (
+                CMD_CONNECT,
+                Target::Ipv4("10.144.144.2:80".parse().unwrap())
+            ),
+            read_request(&mut &req[..]).await.unwrap()
+        );
+
+        let mut req = vec![5, 1, 0, ATYP_DOMAIN, 3];
+        req.extend_from_slice(b"nas");
+        req.extend_from_slice(&22u16.to_be_bytes());
+        assert_eq!(
+            (CMD_CONNECT, Target::Domain("nas".to_string(), 22)),
+            read_request(&mut &req[..]).await.unwrap()
+        );
+
+        let req: &[u8] = &[4, 1, 0, ATYP_IPV4, 10, 144, 144, 2, 0, 80];
+        assert!(read_request(&mut &req[..]).await.is_err());
+        // truncated
+        let req: &[u8] = &[5, 1, 0, ATYP_IPV4, 10, 144];
+        assert!(read_request(&mut &req[..]).await.is_err());
+    }
+
+    #[test]
+    fn resolve_peer_hostname() {
+        let routes = vec![Route {
+            hostname: "NAS".to_string(),
+            ipv4_addr: "10.144.144.100".to_string(),
+            ..Default::default()
+        }];
+        let nas = Some(Ipv4Addr::new(10, 144, 144, 100));
+        assert_eq!(nas, resolve_hostname(&routes, "nas"));
+        assert_eq!(nas, resolve_hostname(&routes, "nas.default.et."));
+        assert_eq!(
+            Some(Ipv4Addr::new(10, 1, 1, 1)),
+            resolve_hostname(&routes, "10.1.1.1")
+        );
+        assert_eq!(None, resolve_hostname(&routes, "example.com"));
+    }
+}
@@ -0,0 +1,1032 @@
+// access control for data packets exchanged with peers. rules come from the [[acl]] section
+// of config and are evaluated in order, the first matched rule decides whether the packet is
+// delivered (to the nic, proxies or the peer) or dropped. each rule has a hit counter for the cli.
+// while any rule is set, packets received from a peer must carry a source address the peer
+// advertises (its virtual ip or proxy cidrs), so src rules can not be bypassed by spoofing.
+// in tap mode the ip packet inside each ethernet frame is checked the same way.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use pnet::packet::{
+    ethernet::{EtherType, EtherTypes},
+    ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+    ipv4::Ipv4Packet,
+    ipv6::Ipv6Packet,
+    Packet,
+};
+
+use crate::{
+    common::{
+        config::AclRuleConfig,
+        error::Error,
+        noise::{decode_key, PublicKey},
+        PeerId,
+    },
+    rpc::{
+        acl_manage_rpc_server::AclManageRpc, AclRuleInfo, ListAclRuleRequest, ListAclRuleResponse,
+    },
+    tunnel::packet_def::{PacketType, ZCPacket},
+};
+
+use super::{peer_manager::PeerManager, NicPacketFilter, PeerPacketFilter};
+
+// avoid listing routes for every packet from a peer which is not in route table yet
+const PEER_IDENTITY_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    Allow,
+    Drop,
+}
+
+impl FromStr for AclAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "allow" | "accept" => Ok(AclAction::Allow),
+            "drop" | "deny" => Ok(AclAction::Drop),
+            _ => Err(anyhow::anyhow!("invalid acl action: {}", s)),
+        }
+    }
+}
+
+impl AclAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AclAction::Allow => "allow",
+            AclAction::Drop => "drop",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AclDirection {
+    In,
+    Out,
+    Any,
+}
+
+impl FromStr for AclDirection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "in" | "inbound" => Ok(AclDirection::In),
+            "out" | "outbound" => Ok(AclDirection::Out),
+            "any" | "both" => Ok(AclDirection::Any),
+            _ => Err(anyhow::anyhow!("invalid direction in acl rule: {}", s)),
+        }
+    }
+}
+
+impl AclDirection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AclDirection::In => "in",
+            AclDirection::Out => "out",
+            AclDirection::Any => "any",
+        }
+    }
+
+    fn matches(&self, direction: AclDirection) -> bool {
+        *self == AclDirection::Any || *self == direction
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AclProtocol {
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+impl AclProtocol {
+    fn matches(&self, protocol: IpNextHeaderProtocol) -> bool {
+        match self {
+            AclProtocol::Tcp => protocol == IpNextHeaderProtocols::Tcp,
+            AclProtocol::Udp => protocol == IpNextHeaderProtocols::Udp,
+            AclProtocol::Icmp => {
+                protocol == IpNextHeaderProtocols::Icmp || protocol == IpNextHeaderProtocols::Icmpv6
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct PacketInfo {
+    src: IpAddr,
+    dst: IpAddr,
+    protocol: IpNextHeaderProtocol,
+    dst_port: Option<u16>,
+}
+
+fn parse_dst_port(protocol: IpNextHeaderProtocol, payload: &[u8]) -> Option<u16> {
+    // tcp and udp both put dst port at offset 2
+    if (protocol == IpNextHeaderProtocols::Tcp || protocol == IpNextHeaderProtocols::Udp)
+        && payload.len() >= 4
+    {
+        Some(u16::from_be_bytes([payload[2], payload[3]]))
+    } else {
+        None
+    }
+}
+
+fn parse_packet(data: &[u8]) -> Option<PacketInfo> {
+    match data.first()? >> 4 {
+        4 => {
+            let ipv4 = Ipv4Packet::new(data)?;
+            let protocol = ipv4.get_next_level_protocol();
+            // only the first fragment carries l4 header
+            let dst_port = if ipv4.get_fragment_offset() == 0 {
+                parse_dst_port(protocol, ipv4.payload())
+            } else {
+                None
+            };
+            Some(PacketInfo {
+                src: ipv4.get_source().into(),
+                dst: ipv4.get_destination().into(),
+                protocol,
+                dst_port,
+            })
+        }
+        6 => {
+            let ipv6 = Ipv6Packet::new(data)?;
+            let protocol = ipv6.get_next_header();
+            Some(PacketInfo {
+                src: ipv6.get_source().into(),
+                dst: ipv6.get_destination().into(),
+                protocol,
+                dst_port: parse_dst_port(protocol, ipv6.payload()),
+            })
+        }
+        _ => None,
+    }
+}
+
+// the part of a packet the rules apply to, None if acl does not apply to it. tap mode carries
+// ethernet frames, the ip packet inside them is checked. arp is needed to reach any ip address
+// and is never filtered, other frames which are not ip only match the default action.
+fn acl_payload(packet_type: u8, payload: &[u8]) -> Option<&[u8]> {
+    if packet_type == PacketType::Data as u8 {
+        return Some(payload);
+    }
+    if packet_type != PacketType::Ethernet as u8 {
+        return None;
+    }
+    if payload.len() < 14 {
+        return Some(&[]);
+    }
+    let mut ether_type = EtherType(u16::from_be_bytes([payload[12], payload[13]]));
+    let mut offset = 14;
+    // skip vlan tags
+    while [EtherTypes::Vlan, EtherTypes::PBridge, EtherTypes::QinQ].contains(&ether_type) {
+        if payload.len() < offset + 4 {
+            return Some(&[]);
+        }
+        ether_type = EtherType(u16::from_be_bytes([
+            payload[offset + 2],
+            payload[offset + 3],
+        ]));
+        offset += 4;
+    }
+    if ether_type == EtherTypes::Arp {
+        None
+    } else if ether_type == EtherTypes::Ipv4 || ether_type == EtherTypes::Ipv6 {
+        Some(&payload[offset..])
+    } else {
+        Some(&[])
+    }
+}
+
+fn parse_cidr(s: &str) -> Result<cidr::IpCidr, anyhow::Error> {
+    if s.contains('/') {
+        // host bits are allowed, e.g. 10.144.144.1/24
+        let inet: cidr::IpInet = s
+            .parse()
+            .with_context(|| format!("invalid cidr in acl rule: {}", s))?;
+        Ok(inet.network())
+    } else {
+        let addr: IpAddr = s
+            .parse()
+            .with_context(|| format!("invalid ip in acl rule: {}", s))?;
+        Ok(cidr::IpCidr::new_host(addr))
+    }
+}
+
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, anyhow::Error> {
+    let parse = |p: &str| {
+        p.trim()
+            .parse::<u16>()
+            .with_context(|| format!("invalid port in acl rule: {}", s))
+    };
+    let range = match s.split_once('-') {
+        Some((start, end)) => parse(start)?..=parse(end)?,
+        None => {
+            let port = parse(s)?;
+            port..=port
+        }
+    };
+    if range.is_empty() {
+        return Err(anyhow::anyhow!("invalid port range in acl rule: {}", s));
+    }
+    Ok(range)
+}
+
+#[derive(Debug, Clone)]
+struct PeerIdentity {
+    hostname: String,
+    inst_id: String,
+    // static noise key the peer proved in handshake or with pairwise encryption
+    public_key: Option<PublicKey>,
+    // addresses the peer may send from
+    addrs: Vec<cidr::IpCidr>,
+}
+
+impl PeerIdentity {
+    fn owns(&self, addr: &IpAddr) -> bool {
+        self.addrs.iter().any(|cidr| cidr.contains(addr))
+    }
+
+    fn claims(&self, name: &str) -> bool {
+        self.hostname == name || self.inst_id == name
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum AclPeer {
+    PublicKey(PublicKey),
+    // hostname or instance id
+    Name(String),
+}
+
+impl FromStr for AclPeer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(anyhow::anyhow!("empty peer in acl rule"));
+        }
+        Ok(match decode_key(s) {
+            Ok(key) => AclPeer::PublicKey(key),
+            Err(_) => AclPeer::Name(s.to_string()),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct AclRule {
+    config: AclRuleConfig,
+    action: AclAction,
+    direction: AclDirection,
+    src: Option<cidr::IpCidr>,
+    dst: Option<cidr::IpCidr>,
+    protocol: Option<AclProtocol>,
+    port: Option<RangeInclusive<u16>>,
+    peer: Option<AclPeer>,
+    hit_count: AtomicU64,
+}
+
+impl TryFrom<&AclRuleConfig> for AclRule {
+    type Error = anyhow::Error;
+
+    fn try_from(config: &AclRuleConfig) -> Result<Self, Self::Error> {
+        let protocol = match config.protocol.as_deref().map(|p| p.to_lowercase()) {
+            None => None,
+            Some(p) => match p.as_str() {
+                "any" | "all" => None,
+                "tcp" => Some(AclProtocol::Tcp),
+                "udp" => Some(AclProtocol::Udp),
+                "icmp" => Some(AclProtocol::Icmp),
+                _ => return Err(anyhow::anyhow!("invalid protocol in acl rule: {}", p)),
+            },
+        };
+        let port = config.port.as_deref().map(parse_port_range).transpose()?;
+        if port.is_some()
+            && protocol != Some(AclProtocol::Tcp)
+            && protocol != Some(AclProtocol::Udp)
+        {
+            return Err(anyhow::anyhow!(
+                "port in acl rule requires protocol tcp or udp"
+            ));
+        }
+
+        Ok(AclRule {
+            config: config.clone(),
+            action: config.action.parse()?,
+            direction: config
+                .direction
+                .as_deref()
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or(AclDirection::In),
+            src: config.src.as_deref().map(parse_cidr).transpose()?,
+            dst: config.dst.as_deref().map(parse_cidr).transpose()?,
+            protocol,
+            port,
+            peer: config.peer.as_deref().map(str::parse).transpose()?,
+            hit_count: AtomicU64::new(0),
+        })
+    }
+}
+
+impl AclRule {
+    // everything except the peer, which needs an async lookup
+    fn matches_packet(&self, info: &PacketInfo) -> bool {
+        if let Some(src) = &self.src {
+            if !src.contains(&info.src) {
+                return false;
+            }
+        }
+        if let Some(dst) = &self.dst {
+            if !dst.contains(&info.dst) {
+                return false;
+            }
+        }
+        if let Some(protocol) = &self.protocol {
+            if !protocol.matches(info.protocol) {
+                return false;
+            }
+        }
+        if let Some(port) = &self.port {
+            if !info.dst_port.map(|p| port.contains(&p)).unwrap_or(false) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // names are not authenticated, only keys are. a drop rule matches every peer claiming the
+    // name, an allow rule only matches if all peers claiming it share one key.
+    fn matches_peer(
+        &self,
+        identity: Option<&PeerIdentity>,
+        identities: &HashMap<PeerId, PeerIdentity>,
+    ) -> bool {
+        let Some(peer) = &self.peer else {
+            return true;
+        };
+        let Some(identity) = identity else {
+            return false;
+        };
+        match peer {
+            AclPeer::PublicKey(key) => identity.public_key.as_ref() == Some(key),
+            AclPeer::Name(name) => {
+                if !identity.claims(name) {
+                    return false;
+                }
+                if self.action == AclAction::Drop {
+                    return true;
+                }
+                identity.public_key.is_some()
+                    && identities
+                        .values()
+                        .filter(|i| i.claims(name))
+                        .all(|i| i.public_key == identity.public_key)
+            }
+        }
+    }
+}
+
+fn parse_rules(
+    rules: &[AclRuleConfig],
+    default_action: &str,
+) -> Result<(Vec<AclRule>, AclAction), Error> {
+    let rules = rules
+        .iter()
+        .enumerate()
+        .map(|(i, r)| AclRule::try_from(r).with_context(|| format!("acl rule {}", i)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let default_action = default_action.parse::<AclAction>()?;
+    Ok((rules, default_action))
+}
+
+struct AclRuleSet {
+    rules: Vec<AclRule>,
+    default_action: AclAction,
+    default_hit_count: AtomicU64,
+}
+
+pub struct AclFilter {
+    rule_set: Mutex<Arc<AclRuleSet>>,
+
+    peer_manager: Weak<PeerManager>,
+    // replaced as a whole on refresh, so lookups never see a partly filled map
+    peer_identities: Mutex<Arc<HashMap<PeerId, PeerIdentity>>>,
+    last_refresh: Mutex<Option<Instant>>,
+}
+
+impl AclFilter {
+    pub fn new(peer_manager: Weak<PeerManager>) -> Self {
+        AclFilter {
+            rule_set: Mutex::new(Arc::new(AclRuleSet {
+                rules: vec![],
+                default_action: AclAction::Allow,
+                default_hit_count: AtomicU64::new(0),
+            })),
+            peer_manager,
+            peer_identities: Mutex::new(Arc::new(HashMap::new())),
+            last_refresh: Mutex::new(None),
+        }
+    }
+
+    pub fn check_rules(rules: &[AclRuleConfig], default_action: &str) -> Result<(), Error> {
+        parse_rules(rules, default_action).map(|_| ())
+    }
+
+    // replace all rules, hit counters are reset. nothing is changed if any rule is invalid.
+    pub fn set_rules(&self, rules: &[AclRuleConfig], default_action: &str) -> Result<(), Error> {
+        let (rules, default_action) = parse_rules(rules, default_action)?;
+        tracing::info!(?rules, ?default_action, "acl rules updated");
+        *self.rule_set.lock().unwrap() = Arc::new(AclRuleSet {
+            rules,
+            default_action,
+            default_hit_count: AtomicU64::new(0),
+        });
+        Ok(())
+    }
+
+    pub fn list_rules(&self) -> ListAclRuleResponse {
+        let rule_set = self.rule_set.lock().unwrap().clone();
+        ListAclRuleResponse {
+            rules: rule_set
+                .rules
+                .iter()
+                .enumerate()
+                .map(|(i, r)| AclRuleInfo {
+                    index: i as u32,
+                    action: r.action.as_str().to_string(),
+                    src: r.config.src.clone().unwrap_or_default(),
+                    dst: r.config.dst.clone().unwrap_or_default(),
+                    protocol: r.config.protocol.clone().unwrap_or_default(),
+                    port: r.config.port.clone().unwrap_or_default(),
+                    peer: r.config.peer.clone().unwrap_or_default(),
+                    direction: r.direction.as_str().to_string(),
+                    hit_count: r.hit_count.load(Ordering::Relaxed),
+                })
+                .collect(),
+            default_action: rule_set.default_action.as_str().to_string(),
+            default_hit_count: rule_set.default_hit_count.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn refresh_peer_identities(&self) {
+        {
+            let mut last_refresh = self.last_refresh.lock().unwrap();
+            if last_refresh
+                .map(|t| t.elapsed() < PEER_IDENTITY_REFRESH_INTERVAL)
+                .unwrap_or(false)
+            {
+                return;
+            }
+            *last_refresh = Some(Instant::now());
+        }
+
+        let Some(peer_manager) = self.peer_manager.upgrade() else {
+            return;
+        };
+        let routes = peer_manager.list_routes().await;
+        let peer_map = peer_manager.get_peer_map();
+        let mut peer_identities = HashMap::new();
+        for route in routes {
+            let addrs = route
+                .proxy_cidrs
+                .iter()
+                .filter_map(|c| c.parse::<cidr::IpCidr>().ok())
+                .chain(
+                    [&route.ipv4_addr, &route.ipv6_addr]
+                        .into_iter()
+                        .filter_map(|a| a.parse::<IpAddr>().ok())
+                        .map(cidr::IpCidr::new_host),
+                )
+                .collect();
+            let public_key = peer_map
+                .get_peer_public_keys(route.peer_id)
+                .await
+                .map(|k| k.static_key);
+            peer_identities.insert(
+                route.peer_id,
+                PeerIdentity {
+                    hostname: route.hostname,
+                    inst_id: route.inst_id,
+                    public_key,
+                    addrs,
+                },
+            );
+        }
+        *self.peer_identities.lock().unwrap() = Arc::new(peer_identities);
+    }
+
+    fn peer_identities(&self) -> Arc<HashMap<PeerId, PeerIdentity>> {
+        self.peer_identities.lock().unwrap().clone()
+    }
+
+    async fn get_peer_identity(&self, peer_id: PeerId) -> Option<PeerIdentity> {
+        if let Some(identity) = self.peer_identities().get(&peer_id) {
+            return Some(identity.clone());
+        }
+        self.refresh_peer_identities().await;
+        self.peer_identities().get(&peer_id).cloned()
+    }
+
+    // the cached identity may be older than a new address of the peer, refresh once on miss
+    async fn peer_owns_addr(&self, peer_id: PeerId, addr: &IpAddr) -> bool {
+        if let Some(identity) = self.peer_identities().get(&peer_id) {
+            if identity.owns(addr) {
+                return true;
+            }
+        }
+        self.refresh_peer_identities().await;
+        self.peer_identities()
+            .get(&peer_id)
+            .map(|i| i.owns(addr))
+            .unwrap_or(false)
+    }
+
+    // peer_id is the sender of inbound packets and the receiver of outbound packets
+    async fn evaluate(&self, direction: AclDirection, peer_id: PeerId, data: &[u8]) -> AclAction {
+        let rule_set = self.rule_set.lock().unwrap().clone();
+        // packets which are not ip can only be handled by the default action
+        if let Some(info) = parse_packet(data) {
+            if direction == AclDirection::In
+                && rule_set
+                    .rules
+                    .iter()
+                    .any(|r| r.direction.matches(AclDirection::In))
+                && !self.peer_owns_addr(peer_id, &info.src).await
+            {
+                tracing::trace!(?peer_id, ?info, "source not owned by peer, dropped by acl");
+                return AclAction::Drop;
+            }
+
+            let mut identity = None;
+            for rule in rule_set.rules.iter() {
+                if !rule.direction.matches(direction) || !rule.matches_packet(&info) {
+                    continue;
+                }
+                if rule.peer.is_some() && identity.is_none() {
+                    identity = Some(self.get_peer_identity(peer_id).await);
+                }
+                if !rule.matches_peer(
+                    identity.as_ref().and_then(|i| i.as_ref()),
+                    &self.peer_identities(),
+                ) {
+                    continue;
+                }
+                rule.hit_count.fetch_add(1, Ordering::Relaxed);
+                return rule.action;
+            }
+        }
+
+        // default action only applies to received packets, sending is restricted by out rules
+        if direction == AclDirection::Out {
+            return AclAction::Allow;
+        }
+        rule_set.default_hit_count.fetch_add(1, Ordering::Relaxed);
+        rule_set.default_action
+    }
+}
+
+#[async_trait::async_trait]
+impl PeerPacketFilter for AclFilter {
+    async fn try_process_packet_from_peer(&self, packet: ZCPacket) -> Option<ZCPacket> {
+        let hdr = packet.peer_manager_header().unwrap();
+        let from_peer_id = hdr.from_peer_id.get();
+        let Some(data) = acl_payload(hdr.packet_type, packet.payload()) else {
+            return Some(packet);
+        };
+
+        match self.evaluate(AclDirection::In, from_peer_id, data).await {
+            AclAction::Allow => Some(packet),
+            AclAction::Drop => {
+                tracing::trace!(?from_peer_id, ?packet, "packet dropped by acl");
+                None
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl NicPacketFilter for AclFilter {
+    async fn try_process_packet_from_nic(&self, _data: &mut ZCPacket) {}
+
+    async fn check_packet_to_peer(&self, packet: &ZCPacket, dst_peer_id: PeerId) -> bool {
+        let hdr = packet.peer_manager_header().unwrap();
+        let Some(data) = acl_payload(hdr.packet_type, packet.payload()) else {
+            return true;
+        };
+
+        match self.evaluate(AclDirection::Out, dst_peer_id, data).await {
+            AclAction::Allow => true,
+            AclAction::Drop => {
+                tracing::trace!(?dst_peer_id, ?packet, "packet to peer dropped by acl");
+                false
+            }
+        }
+    }
+}
+
+pub struct AclManagerRpcService(pub Arc<AclFilter>);
+
+#[tonic::async_trait]
+impl AclManageRpc for AclManagerRpcService {
+    async fn list_acl_rule(
+        &self,
+        _request: tonic::Request<ListAclRuleRequest>,
+    ) -> Result<tonic::Response<ListAclRuleResponse>, tonic::Status> {
+        Ok(tonic::Response::new(self.0.list_rules()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use pnet::packet::{ipv4::MutableIpv4Packet, ipv6::MutableIpv6Packet};
+
+    use super::*;
+
+    fn build_ipv4_packet(
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        protocol: IpNextHeaderProtocol,
+        dst_port: u16,
+    ) -> Vec<u8> {
+        let mut buf = vec![0u8; 28];
+        buf[22..24].copy_from_slice(&dst_port.to_be_bytes());
+        let mut ipv4 = MutableIpv4Packet::new(&mut buf[..]).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length(28);
+        ipv4.set_next_level_protocol(protocol);
+        ipv4.set_source(src);
+        ipv4.set_destination(dst);
+        buf
+    }
+
+    fn rule(action: &str) -> AclRuleConfig {
+        AclRuleConfig {
+            action: action.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn new_filter(rules: &[AclRuleConfig], default_action: &str) -> AclFilter {
+        let filter = AclFilter::new(Weak::new());
+        filter.set_rules(rules, default_action).unwrap();
+        filter
+    }
+
+    fn insert_identity(filter: &AclFilter, peer_id: PeerId, identity: PeerIdentity) {
+        let mut identities = filter.peer_identities.lock().unwrap();
+        Arc::make_mut(&mut identities).insert(peer_id, identity);
+    }
+
+    fn identity(hostname: &str, key: Option<u8>, addrs: &[&str]) -> PeerIdentity {
+        PeerIdentity {
+            hostname: hostname.to_string(),
+            inst_id: format!("{}-inst", hostname),
+            public_key: key.map(|k| [k; 32]),
+            addrs: addrs.iter().map(|a| a.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_acl_packet() {
+        let src = Ipv4Addr::new(10, 144, 144, 1);
+        let dst = Ipv4Addr::new(10, 144, 144, 2);
+        let data = build_ipv4_packet(src, dst, IpNextHeaderProtocols::Tcp, 22);
+        assert_eq!(
+            parse_packet(&data),
+            Some(PacketInfo {
+                src: src.into(),
+                dst: dst.into(),
+                protocol: IpNextHeaderProtocols::Tcp,
+                dst_port: Some(22),
+            })
+        );
+
+        let mut buf = vec![0u8; 48];
+        buf[42..44].copy_from_slice(&53u16.to_be_bytes());
+        let mut ipv6 = MutableIpv6Packet::new(&mut buf[..]).unwrap();
+        ipv6.set_version(6);
+        ipv6.set_payload_length(8);
+        ipv6.set_next_header(IpNextHeaderProtocols::Udp);
+        ipv6.set_source(Ipv6Addr::LOCALHOST);
+        ipv6.set_destination("fd00::1".parse().unwrap());
+        let info = parse_packet(&buf).unwrap();
+        assert_eq!(info.dst, "fd00::1".parse::<IpAddr>().unwrap());
+        assert_eq!(info.dst_port, Some(53));
+
+        assert_eq!(parse_packet(&[]), None);
+        assert_eq!(parse_packet(&[0x45, 0, 0]), None);
+    }
+
+    #[test]
+    fn invalid_acl_rules() {
+        let filter = AclFilter::new(Weak::new());
+        let mut r = rule("reject");
+        assert!(filter.set_rules(&[r.clone()], "allow").is_err());
+
+        r.action = "drop".to_string();
+        r.src = Some("10.0.0.0/33".to_string());
+        assert!(filter.set_rules(&[r.clone()], "allow").is_err());
+
+        r.src = None;
+        r.port = Some("22".to_string());
+        assert!(filter.set_rules(&[r.clone()], "allow").is_err());
+
+        r.protocol = Some("tcp".to_string());
+        r.port = Some("9000-8000".to_string());
+        assert!(filter.set_rules(&[r.clone()], "allow").is_err());
+
+        r.port = Some("8000-9000".to_string());
+        filter.set_rules(&[r.clone()], "drop").unwrap();
+        assert!(filter.set_rules(&[r], "unknown").is_err());
+        // failed update keeps the old rules
+        assert_eq!(filter.list_rules().default_action, "drop");
+    }
+
+    #[tokio::test]
+    async fn acl_rules_first_match_wins() {
+        let mut ssh = rule("drop");
+        ssh.src = Some("10.144.144.0/24".to_string());
+        ssh.protocol = Some("tcp".to_string());
+        ssh.port = Some("22".to_string());
+
+        let mut web = rule("allow");
+        web.dst = Some("10.144.144.2".to_string());
+        web.protocol = Some("tcp".to_string());
+        web.port = Some("80-443".to_string());
+
+        let filter = new_filter(&[ssh, web], "drop");
+        insert_identity(&filter, 1, identity("laptop", None, &["10.0.0.0/8"]));
+
+        let src = Ipv4Addr::new(10, 144, 144, 1);
+        let dst = Ipv4Addr::new(10, 144, 144, 2);
+        let tcp = IpNextHeaderProtocols::Tcp;
+        let cases = [
+            (build_ipv4_packet(src, dst, tcp, 22), AclAction::Drop),
+            (build_ipv4_packet(src, dst, tcp, 443), AclAction::Allow),
+            (build_ipv4_packet(src, dst, tcp, 8080), AclAction::Drop),
+            (
+                build_ipv4_packet(src, dst, IpNextHeaderProtocols::Udp, 80),
+                AclAction::Drop,
+            ),
+            (
+                build_ipv4_packet(Ipv4Addr::new(10, 1, 1, 1), dst, tcp, 22),
+                AclAction::Drop,
+            ),
+        ];
+        for (data, action) in cases.iter() {
+            assert_eq!(filter.evaluate(AclDirection::In, 1, data).await, *action);
+        }
+
+        let resp = filter.list_rules();
+        assert_eq!(resp.rules[0].hit_count, 1);
+        assert_eq!(resp.rules[1].hit_count, 1);
+        assert_eq!(resp.default_hit_count, 3);
+        assert_eq!(resp.rules[1].port, "80-443");
+        assert_eq!(resp.rules[1].direction, "in");
+    }
+
+    #[tokio::test]
+    async fn acl_drop_spoofed_source() {
+        let mut web = rule("allow");
+        web.src = Some("10.144.144.2".to_string());
+        let filter = new_filter(&[web], "drop");
+        insert_identity(&filter, 1, identity("laptop", None, &["10.144.144.1"]));
+        insert_identity(
+            &filter,
+            2,
+            identity("build-server", None, &["10.144.144.2", "192.168.1.0/24"]),
+        );
+
+        let dst = Ipv4Addr::new(10, 144, 144, 3);
+        let icmp = IpNextHeaderProtocols::Icmp;
+        let from_build_server = build_ipv4_packet(Ipv4Addr::new(10, 144, 144, 2), dst, icmp, 0);
+        assert_eq!(
+            filter
+                .evaluate(AclDirection::In, 2, &from_build_server)
+                .await,
+            AclAction::Allow
+        );
+        // laptop pretends to be the build server
+        assert_eq!(
+            filter
+                .evaluate(AclDirection::In, 1, &from_build_server)
+                .await,
+            AclAction::Drop
+        );
+        // proxied lan of the build server
+        let from_lan = build_ipv4_packet(Ipv4Addr::new(192, 168, 1, 9), dst, icmp, 0);
+        assert_eq!(
+            filter.evaluate(AclDirection::In, 2, &from_lan).await,
+            AclAction::Drop
+        );
+        assert_eq!(
+            filter
+                .evaluate(AclDirection::In, 3, &from_build_server)
+                .await,
+            AclAction::Drop
+        );
+        assert_eq!(filter.list_rules().rules[0].hit_count, 1);
+        assert_eq!(filter.list_rules().default_hit_count, 1);
+
+        // no acl configured, nothing is checked
+        let filter = new_filter(&[], "allow");
+        assert_eq!(
+            filter
+                .evaluate(AclDirection::In, 1, &from_build_server)
+                .await,
+            AclAction::Allow
+        );
+    }
+
+    #[tokio::test]
+    async fn acl_rules_match_peer() {
+        let mut contractor = rule("drop");
+        contractor.peer = Some("contractor".to_string());
+        let mut build_server = rule("allow");
+        build_server.peer = Some("build-server".to_string());
+        let mut by_key = rule("allow");
+        by_key.peer = Some(crate::common::noise::encode_key(&[3; 32]));
+        let filter = new_filter(&[contractor, build_server, by_key], "drop");
+
+        let data = build_ipv4_packet(
+            Ipv4Addr::new(10, 144, 144, 1),
+            Ipv4Addr::new(10, 144, 144, 2),
+            IpNextHeaderProtocols::Icmp,
+            0,
+        );
+        let all = ["0.0.0.0/0"];
+        insert_identity(&filter, 1, identity("contractor", Some(1), &all));
+        insert_identity(&filter, 2, identity("build-server", Some(2), &all));
+        insert_identity(&filter, 3, identity("laptop", Some(3), &all));
+        insert_identity(&filter, 4, identity("laptop", None, &all));
+
+        assert_eq!(
+            filter.evaluate(AclDirection::In, 1, &data).await,
+            AclAction::Drop
+        );
+        assert_eq!(
+            filter.evaluate(AclDirection::In, 2, &data).await,
+            AclAction::Allow
+        );
+        // matched by key, the hostname is not used
+        assert_eq!(
+            filter.evaluate(AclDirection::In, 3, &data).await,
+            AclAction::Allow
+        );
+        assert_eq!(
+            filter.evaluate(AclDirection::In, 4, &data).await,
+            AclAction::Drop
+        );
+
+        // another key claims the name of the build server, the allow rule no longer matches
+        insert_identity(&filter, 5, identity("build-server", Some(5), &all));
+        assert_eq!(
+            filter.evaluate(AclDirection::In, 2, &data).await,
+            AclAction::Drop
+        );
+        assert_eq!(
+            filter.evaluate(AclDirection::In, 5, &data).await,
+            AclAction::Drop
+        );
+        // a claimed name still matches drop rules
+        insert_identity(&filter, 6, identity("contractor", Some(6), &all));
+        assert_eq!(filter.list_rules().rules[0].hit_count, 1);
+        assert_eq!(
+            filter.evaluate(AclDirection::In, 6, &data).await,
+            AclAction::Drop
+        );
+        assert_eq!(filter.list_rules().rules[0].hit_count, 2);
+
+        let mut packet = ZCPacket::new_with_payload(&data);
+        packet.fill_peer_manager_hdr(1, 3, PacketType::Data as u8);
+        assert!(filter.try_process_packet_from_peer(packet).await.is_none());
+
+        // non data packets are never filtered
+        let mut packet = ZCPacket::new_with_payload(&data);
+        packet.fill_peer_manager_hdr(1, 3, PacketType::TaRpc as u8);
+        assert!(filter.try_process_packet_from_peer(packet).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn acl_rules_direction() {
+        let mut no_ssh_out = rule("drop");
+        no_ssh_out.direction = Some("out".to_string());
+        no_ssh_out.peer = Some("build-server".to_string());
+        let mut no_icmp = rule("drop");
+        no_icmp.direction = Some("any".to_string());
+        no_icmp.protocol = Some("icmp".to_string());
+        let filter = new_filter(&[no_ssh_out, no_icmp], "drop");
+        insert_identity(
+            &filter,
+            2,
+            identity("build-server", Some(2), &["10.144.144.2"]),
+        );
+        insert_identity(&filter, 3, identity("laptop", Some(3), &["10.144.144.3"]));
+
+        let src = Ipv4Addr::new(10, 144, 144, 1);
+        let tcp = build_ipv4_packet(
+            src,
+            Ipv4Addr::new(10, 144, 144, 2),
+            IpNextHeaderProtocols::Tcp,
+            22,
+        );
+        let icmp = build_ipv4_packet(
+            src,
+            Ipv4Addr::new(10, 144, 144, 3),
+            IpNextHeaderProtocols::Icmp,
+            0,
+        );
+
+        let mut packet = ZCPacket::new_with_payload(&tcp);
+        packet.fill_peer_manager_hdr(1, 2, PacketType::Data as u8);
+        assert!(!filter.check_packet_to_peer(&packet, 2).await);
+        // default action does not apply to sent packets
+        assert!(filter.check_packet_to_peer(&packet, 3).await);
+        let mut packet = ZCPacket::new_with_payload(&icmp);
+        packet.fill_peer_manager_hdr(1, 3, PacketType::Data as u8);
+        assert!(!filter.check_packet_to_peer(&packet, 3).await);
+
+        let resp = filter.list_rules();
+        assert_eq!(resp.rules[0].direction, "out");
+        assert_eq!(resp.rules[0].hit_count, 1);
+        assert_eq!(resp.rules[1].hit_count, 1);
+        assert_eq!(resp.default_hit_count, 0);
+
+        let mut r = rule("drop");
+        r.direction = Some("sideways".to_string());
+        assert!(filter.set_rules(&[r], "allow").is_err());
+    }
+
+    fn build_ethernet_frame(ether_types: &[EtherType], payload: &[u8]) -> ZCPacket {
+        // mac addresses, then the ether type of each vlan tag followed by its tag
+        let mut buf = vec![0u8; 12];
+        for (i, ether_type) in ether_types.iter().enumerate() {
+            buf.extend_from_slice(&ether_type.0.to_be_bytes());
+            if i + 1 < ether_types.len() {
+                buf.extend_from_slice(&[0, 1]);
+            }
+        }
+        buf.extend_from_slice(payload);
+        let mut packet = ZCPacket::new_with_payload(&buf);
+        packet.fill_peer_manager_hdr(2, 1, PacketType::Ethernet as u8);
+        packet
+    }
+
+    #[tokio::test]
+    async fn acl_ethernet_frames() {
+        let mut no_ssh = rule("drop");
+        no_ssh.protocol = Some("tcp".to_string());
+        no_ssh.port = Some("22".to_string());
+        no_ssh.direction = Some("any".to_string());
+        let filter = new_filter(&[no_ssh, rule("allow")], "drop");
+        insert_identity(&filter, 2, identity("laptop", None, &["10.144.144.2"]));
+
+        let src = Ipv4Addr::new(10, 144, 144, 2);
+        let dst = Ipv4Addr::new(10, 144, 144, 1);
+        let ssh = build_ipv4_packet(src, dst, IpNextHeaderProtocols::Tcp, 22);
+        let http = build_ipv4_packet(src, dst, IpNextHeaderProtocols::Tcp, 80);
+
+        let frame = build_ethernet_frame(&[EtherTypes::Ipv4], &ssh);
+        assert!(filter.try_process_packet_from_peer(frame).await.is_none());
+        let frame = build_ethernet_frame(&[EtherTypes::Vlan, EtherTypes::Ipv4], &ssh);
+        assert!(filter.try_process_packet_from_peer(frame).await.is_none());
+        let frame = build_ethernet_frame(&[EtherTypes::Ipv4], &http);
+        assert!(filter.try_process_packet_from_peer(frame).await.is_some());
+        let frame = build_ethernet_frame(&[EtherTypes::Ipv4], &ssh);
+        assert!(!filter.check_packet_to_peer(&frame, 2).await);
+        assert_eq!(filter.list_rules().rules[0].hit_count, 3);
+
+        // spoofed source inside a frame
+        let spoofed = build_ipv4_packet(
+            Ipv4Addr::new(10, 144, 144, 3),
+            dst,
+            IpNextHeaderProtocols::Tcp,
+            80,
+        );
+        let frame = build_ethernet_frame(&[EtherTypes::Ipv4], &spoofed);
+        assert!(filter.try_process_packet_from_peer(frame).await.is_none());
+
+        // arp is never filtered, other frames get the default action
+        let frame = build_ethernet_frame(&[EtherTypes::Arp], &[0u8; 28]);
+        assert!(filter.try_process_packet_from_peer(frame).await.is_some());
+        let frame = build_ethernet_frame(&[EtherTypes::Lldp], &[0u8; 28]);
+        assert!(filter.try_process_packet_from_peer(frame).await.is_none());
+        assert_eq!(filter.list_rules().default_hit_count, 1);
+    }
+}
@@ -0,0 +1,123 @@
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::{AeadCore, AeadInPlace, ChaCha20Poly1305, Key, KeyInit, Nonce, Tag};
+use rand::rngs::OsRng;
+use zerocopy::{AsBytes, FromBytes};
+
+use crate::tunnel::packet_def::{AesGcmTail, ZCPacket, AES_GCM_ENCRYPTION_RESERVED};
+
+use super::{Encryptor, Error};
+
+#[derive(Clone)]
+pub struct ChaCha20Poly1305Cipher {
+    pub(crate) cipher: ChaCha20Poly1305,
+}
+
+impl ChaCha20Poly1305Cipher {
+    pub fn new(key: [u8; 32]) -> Self {
+        let key: &Key = &key.into();
+        Self {
+            cipher: ChaCha20Poly1305::new(key),
+        }
+    }
+}
+
+impl Encryptor for ChaCha20Poly1305Cipher {
+    fn decrypt(&self, zc_packet: &mut ZCPacket) -> Result<(), Error> {
+        let pm_header = zc_packet.peer_manager_header().unwrap();
+        if !pm_header.is_encrypted() {
+            return Err(Error::NotEcrypted);
+        }
+
+        let payload_len = zc_packet.payload().len();
+        if payload_len < AES_GCM_ENCRYPTION_RESERVED {
+            return Err(Error::PacketTooShort(zc_packet.payload().len()));
+        }
+
+        let text_len = payload_len - AES_GCM_ENCRYPTION_RESERVED;
+
+        let tail = AesGcmTail::ref_from_suffix(zc_packet.payload())
+            .unwrap()
+            .clone();
+        let nonce = Nonce::from_slice(&tail.nonce);
+        let tag: Tag = GenericArray::clone_from_slice(tail.tag.as_slice());
+
+        let rs = self.cipher.decrypt_in_place_detached(
+            nonce,
+            &[],
+            &mut zc_packet.mut_payload()[..text_len],
+            &tag,
+        );
+        if let Err(_) = rs {
+            return Err(Error::DecryptionFailed);
+        }
+
+        let pm_header = zc_packet.mut_peer_manager_header().unwrap();
+        pm_header.set_encrypted(false);
+        let old_len = zc_packet.buf_len();
+        zc_packet
+            .mut_inner()
+            .truncate(old_len - AES_GCM_ENCRYPTION_RESERVED);
+        return Ok(());
+    }
+
+    fn encrypt(&self, zc_packet: &mut ZCPacket) -> Result<(), Error> {
+        let mut nonce = [0u8; 12];
+        nonce.copy_from_slice(ChaCha20Poly1305::generate_nonce(&mut OsRng).as_slice());
+        self.encrypt_with_nonce(zc_packet, nonce)
+    }
+
+    fn encrypt_with_nonce(&self, zc_packet: &mut ZCPacket, nonce: [u8; 12]) -> Result<(), Error> {
+        let pm_header = zc_packet.peer_manager_header().unwrap();
+        if pm_header.is_encrypted() {
+            tracing::warn!(?zc_packet, "packet is already encrypted");
+            return Ok(());
+        }
+
+        let mut tail = AesGcmTail::default();
+        tail.nonce = nonce;
+
+        let rs = self.cipher.encrypt_in_place_detached(
+            Nonce::from_slice(&nonce),
+            &[],
+            zc_packet.mut_payload(),
+        );
+        return match rs {
+            Ok(tag) => {
+                tail.tag.copy_from_slice(tag.as_slice());
+
+                let pm_header = zc_packet.mut_peer_manager_header().unwrap();
+                pm_header.set_encrypted(true);
+                zc_packet.mut_inner().extend_from_slice(tail.as_bytes());
+                Ok(())
+            }
+            Err(_) => Err(Error::EncryptionFailed),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        peers::encrypt::{chacha20::ChaCha20Poly1305Cipher, Encryptor},
+        tunnel::packet_def::{ZCPacket, AES_GCM_ENCRYPTION_RESERVED},
+    };
+
+    #[test]
+    fn test_chacha20_poly1305_cipher() {
+        let key = [0u8; 32];
+        let cipher = ChaCha20Poly1305Cipher::new(key);
+        let text = b"1234567";
+        let mut packet = ZCPacket::new_with_payload(text);
+        packet.fill_peer_manager_hdr(0, 0, 0);
+        cipher.encrypt(&mut packet).unwrap();
+        assert_eq!(
+            packet.payload().len(),
+            text.len() + AES_GCM_ENCRYPTION_RESERVED
+        );
+        assert_eq!(packet.peer_manager_header().unwrap().is_encrypted(), true);
+
+        cipher.decrypt(&mut packet).unwrap();
+        assert_eq!(packet.payload(), text);
+        assert_eq!(packet.peer_manager_header().unwrap().is_encrypted(), false);
+    }
+}
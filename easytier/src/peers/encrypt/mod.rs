@@ -6,6 +6,12 @@ pub mod ring_aes_gcm;
 #[cfg(feature = "aes-gcm")]
 pub mod aes_gcm;
 
+#[cfg(feature = "chacha20")]
+pub mod chacha20;
+
+pub mod pairwise;
+pub mod session;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("packet is not encrypted")]
@@ -18,11 +24,31 @@ pub enum Error {
     EncryptionFailed,
     #[error("invalid tag. tag: {0:?}")]
     InvalidTag(Vec<u8>),
+    #[error("unsupported cipher suite. id: {0}")]
+    UnsupportedCipher(u8),
+    #[error("unexpected key generation: {0}")]
+    UnexpectedKeyGeneration(u16),
+    #[error("replayed packet. counter: {0}")]
+    Replayed(u64),
 }
 
 pub trait Encryptor: Send + Sync + 'static {
     fn encrypt(&self, zc_packet: &mut ZCPacket) -> Result<(), Error>;
     fn decrypt(&self, zc_packet: &mut ZCPacket) -> Result<(), Error>;
+
+    // aead ciphers use a random nonce in encrypt, session cipher puts a counter in it
+    fn encrypt_with_nonce(&self, _zc_packet: &mut ZCPacket, _nonce: [u8; 12]) -> Result<(), Error> {
+        Err(Error::EncryptionFailed)
+    }
+
+    // encryptors with only one cipher suite just ignore the suite
+    fn encrypt_with_suite(
+        &self,
+        zc_packet: &mut ZCPacket,
+        _suite: CipherSuite,
+    ) -> Result<(), Error> {
+        self.encrypt(zc_packet)
+    }
 }
 
 pub struct NullCipher;
@@ -36,3 +62,327 @@ impl Encryptor for NullCipher {
         Ok(())
     }
 }
+
+const CIPHER_FEATURE_PREFIX: &str = "cipher:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CipherSuite {
+    // id 0 is used by peers without cipher negotiation, so it must be aes-gcm.
+    #[default]
+    AesGcm,
+    ChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    pub fn id(&self) -> u8 {
+        match self {
+            CipherSuite::AesGcm => 0,
+            CipherSuite::ChaCha20Poly1305 => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(CipherSuite::AesGcm),
+            1 => Some(CipherSuite::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CipherSuite::AesGcm => "aes-gcm",
+            CipherSuite::ChaCha20Poly1305 => "chacha20-poly1305",
+        }
+    }
+
+    // all suites compiled in this binary
+    pub fn supported() -> Vec<CipherSuite> {
+        let mut ret = vec![CipherSuite::AesGcm];
+        if cfg!(feature = "chacha20") {
+            ret.push(CipherSuite::ChaCha20Poly1305);
+        }
+        ret
+    }
+
+    // chacha20 is fast on all cpus, so it wins when both sides are equally happy.
+    fn tie_break_rank(&self) -> u8 {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => 0,
+            CipherSuite::AesGcm => 1,
+        }
+    }
+}
+
+impl std::str::FromStr for CipherSuite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-gcm" => Ok(CipherSuite::AesGcm),
+            "chacha20-poly1305" => Ok(CipherSuite::ChaCha20Poly1305),
+            _ => Err(anyhow::anyhow!("invalid cipher suite: {}", s)),
+        }
+    }
+}
+
+#[allow(unreachable_code)]
+fn has_aes_hardware() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        return std::arch::is_x86_feature_detected!("aes");
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        return std::arch::is_aarch64_feature_detected!("aes");
+    }
+
+    false
+}
+
+/// Returns supported suites in preference order. `config` can be "auto" or a suite name,
+/// with "auto", aes-gcm is preferred only if the cpu has aes instructions.
+pub fn preferred_cipher_suites(config: &str) -> Vec<CipherSuite> {
+    let mut ret = CipherSuite::supported();
+    let first = match config.parse::<CipherSuite>() {
+        Ok(suite) => suite,
+        Err(e) => {
+            if config != "auto" {
+                tracing::warn!(?e, "invalid cipher suite config, use auto");
+            }
+            if has_aes_hardware() {
+                CipherSuite::AesGcm
+            } else {
+                CipherSuite::ChaCha20Poly1305
+            }
+        }
+    };
+    if let Some(pos) = ret.iter().position(|s| *s == first) {
+        ret.remove(pos);
+        ret.insert(0, first);
+    }
+    ret
+}
+
+pub fn cipher_suites_to_features(suites: &[CipherSuite]) -> Vec<String> {
+    suites
+        .iter()
+        .map(|s| format!("{}{}", CIPHER_FEATURE_PREFIX, s.as_str()))
+        .collect()
+}
+
+// peers not announcing any suite only know aes-gcm
+pub fn cipher_suites_from_features(features: &[String]) -> Vec<CipherSuite> {
+    let ret = features
+        .iter()
+        .filter_map(|f| f.strip_prefix(CIPHER_FEATURE_PREFIX))
+        .filter_map(|s| s.parse::<CipherSuite>().ok())
+        .collect::<Vec<_>>();
+    if ret.is_empty() {
+        vec![CipherSuite::AesGcm]
+    } else {
+        ret
+    }
+}
+
+/// Picks the common suite with the best worst-case rank of both sides, so the result
+/// is the same no matter which side runs the negotiation.
+pub fn negotiate_cipher_suite(mine: &[CipherSuite], theirs: &[CipherSuite]) -> Option<CipherSuite> {
+    mine.iter()
+        .filter(|s| theirs.contains(s))
+        .min_by_key(|s| {
+            let my_rank = mine.iter().position(|x| x == *s).unwrap();
+            let their_rank = theirs.iter().position(|x| x == *s).unwrap();
+            (my_rank.max(their_rank), s.tie_break_rank())
+        })
+        .cloned()
+}
+
+/// Creates a cipher of the given suite with 256 bits key.
+#[allow(unreachable_code, unused_variables)]
+pub fn create_cipher_256(suite: CipherSuite, key: [u8; 32]) -> Option<Box<dyn Encryptor>> {
+    match suite {
+        CipherSuite::AesGcm => {
+            #[cfg(feature = "wireguard")]
+            {
+                use self::ring_aes_gcm::AesGcmCipher;
+                return Some(Box::new(AesGcmCipher::new_256(key)));
+            }
+
+            #[cfg(all(feature = "aes-gcm", not(feature = "wireguard")))]
+            {
+                use self::aes_gcm::AesGcmCipher;
+                return Some(Box::new(AesGcmCipher::new_256(key)));
+            }
+
+            None
+        }
+        CipherSuite::ChaCha20Poly1305 => {
+            #[cfg(feature = "chacha20")]
+            {
+                use self::chacha20::ChaCha20Poly1305Cipher;
+                return Some(Box::new(ChaCha20Poly1305Cipher::new(key)));
+            }
+
+            None
+        }
+    }
+}
+
+/// Holds all supported ciphers, encrypts with the suite chosen for the dst peer and
+/// decrypts according to the cipher id in peer manager header.
+pub struct CipherSet {
+    ciphers: Vec<(CipherSuite, Box<dyn Encryptor>)>,
+}
+
+impl CipherSet {
+    pub fn new(key_128: [u8; 16], key_256: [u8; 32]) -> Self {
+        let mut ciphers: Vec<(CipherSuite, Box<dyn Encryptor>)> = Vec::new();
+
+        #[cfg(feature = "wireguard")]
+        {
+            use self::ring_aes_gcm::AesGcmCipher;
+            ciphers.push((
+                CipherSuite::AesGcm,
+                Box::new(AesGcmCipher::new_128(key_128)),
+            ));
+        }
+
+        #[cfg(all(feature = "aes-gcm", not(feature = "wireguard")))]
+        {
+            use self::aes_gcm::AesGcmCipher;
+            ciphers.push((
+                CipherSuite::AesGcm,
+                Box::new(AesGcmCipher::new_128(key_128)),
+            ));
+        }
+
+        #[cfg(all(not(feature = "wireguard"), not(feature = "aes-gcm")))]
+        {
+            compile_error!("wireguard or aes-gcm feature must be enabled for encryption");
+        }
+
+        #[cfg(feature = "chacha20")]
+        {
+            use self::chacha20::ChaCha20Poly1305Cipher;
+            ciphers.push((
+                CipherSuite::ChaCha20Poly1305,
+                Box::new(ChaCha20Poly1305Cipher::new(key_256)),
+            ));
+        }
+
+        #[cfg(not(feature = "chacha20"))]
+        let _ = key_256;
+
+        Self { ciphers }
+    }
+
+    fn get_cipher(&self, suite: CipherSuite) -> Option<&dyn Encryptor> {
+        self.ciphers
+            .iter()
+            .find(|(s, _)| *s == suite)
+            .map(|(_, c)| c.as_ref())
+    }
+}
+
+impl Encryptor for CipherSet {
+    fn encrypt(&self, zc_packet: &mut ZCPacket) -> Result<(), Error> {
+        self.encrypt_with_suite(zc_packet, CipherSuite::default())
+    }
+
+    fn decrypt(&self, zc_packet: &mut ZCPacket) -> Result<(), Error> {
+        let pm_header = zc_packet.peer_manager_header().unwrap();
+        if !pm_header.is_encrypted() {
+            return Err(Error::NotEcrypted);
+        }
+
+        let id = pm_header.get_cipher_id();
+        let Some(cipher) = CipherSuite::from_id(id).and_then(|s| self.get_cipher(s)) else {
+            return Err(Error::UnsupportedCipher(id));
+        };
+        cipher.decrypt(zc_packet)?;
+        zc_packet
+            .mut_peer_manager_header()
+            .unwrap()
+            .set_cipher_id(0);
+        Ok(())
+    }
+
+    fn encrypt_with_suite(
+        &self,
+        zc_packet: &mut ZCPacket,
+        suite: CipherSuite,
+    ) -> Result<(), Error> {
+        let Some(cipher) = self.get_cipher(suite) else {
+            return Err(Error::UnsupportedCipher(suite.id()));
+        };
+        cipher.encrypt(zc_packet)?;
+        zc_packet
+            .mut_peer_manager_header()
+            .unwrap()
+            .set_cipher_id(suite.id());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_cipher() {
+        let aes_first = vec![CipherSuite::AesGcm, CipherSuite::ChaCha20Poly1305];
+        let chacha_first = vec![CipherSuite::ChaCha20Poly1305, CipherSuite::AesGcm];
+        let aes_only = vec![CipherSuite::AesGcm];
+
+        let check = |a: &[CipherSuite], b: &[CipherSuite], expected: CipherSuite| {
+            assert_eq!(negotiate_cipher_suite(a, b), Some(expected));
+            assert_eq!(negotiate_cipher_suite(b, a), Some(expected));
+        };
+
+        check(&aes_first, &aes_first, CipherSuite::AesGcm);
+        check(&chacha_first, &chacha_first, CipherSuite::ChaCha20Poly1305);
+        check(&aes_first, &chacha_first, CipherSuite::ChaCha20Poly1305);
+        check(&chacha_first, &aes_only, CipherSuite::AesGcm);
+
+        assert_eq!(
+            negotiate_cipher_suite(&[CipherSuite::ChaCha20Poly1305], &aes_only),
+            None
+        );
+    }
+
+    #[test]
+    fn cipher_features() {
+        let suites = vec![CipherSuite::ChaCha20Poly1305, CipherSuite::AesGcm];
+        let mut features = cipher_suites_to_features(&suites);
+        features.push("other".to_owned());
+        assert_eq!(cipher_suites_from_features(&features), suites);
+        assert_eq!(cipher_suites_from_features(&[]), vec![CipherSuite::AesGcm]);
+
+        let suites = preferred_cipher_suites("chacha20-poly1305");
+        assert_eq!(suites.len(), CipherSuite::supported().len());
+        if cfg!(feature = "chacha20") {
+            assert_eq!(suites[0], CipherSuite::ChaCha20Poly1305);
+        }
+        assert_eq!(preferred_cipher_suites("aes-gcm")[0], CipherSuite::AesGcm);
+    }
+
+    #[test]
+    fn cipher_set_decrypt_by_id() {
+        let cipher_set = CipherSet::new([1u8; 16], [2u8; 32]);
+        let text = b"1234567";
+        for suite in CipherSuite::supported() {
+            let mut packet = ZCPacket::new_with_payload(text);
+            packet.fill_peer_manager_hdr(0, 0, 0);
+            cipher_set.encrypt_with_suite(&mut packet, suite).unwrap();
+            let hdr = packet.peer_manager_header().unwrap();
+            assert!(hdr.is_encrypted());
+            assert_eq!(hdr.get_cipher_id(), suite.id());
+
+            cipher_set.decrypt(&mut packet).unwrap();
+            assert_eq!(packet.payload(), text);
+            assert!(!packet.peer_manager_header().unwrap().is_encrypted());
+        }
+    }
+}
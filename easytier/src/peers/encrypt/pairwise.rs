@@ -0,0 +1,138 @@
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+use crate::{
+    common::{global_ctx::ArcGlobalCtx, noise::PeerPublicKeys, PeerId},
+    tunnel::packet_def::ZCPacket,
+};
+
+use super::{CipherSet, CipherSuite, Encryptor, Error};
+
+/// Ciphers for traffic relayed by other peers. Unlike the network key, the pairwise key
+/// can only be derived by the two ends, so it proves the sender holds the private key of
+/// the static key we know for its peer id. Those keys come from route info; they are pinned
+/// to the peer id on first sight and checked against the handshake of direct peers, but a
+/// peer advertising keys of a new peer id before it does is not detected.
+pub struct PairwiseCiphers {
+    global_ctx: ArcGlobalCtx,
+    enable_encryption: bool,
+    ciphers: DashMap<PeerId, (PeerPublicKeys, Arc<CipherSet>)>,
+}
+
+impl PairwiseCiphers {
+    pub fn new(global_ctx: ArcGlobalCtx) -> Self {
+        let enable_encryption = global_ctx.get_flags().enable_encryption;
+        Self {
+            global_ctx,
+            enable_encryption,
+            ciphers: DashMap::new(),
+        }
+    }
+
+    // peers enforcing trust drop relayed traffic without a pairwise key, even if
+    // encryption is disabled.
+    pub fn should_encrypt(&self, peer_keys: &PeerPublicKeys) -> bool {
+        self.enable_encryption || peer_keys.trust_enforced
+    }
+
+    fn get_cipher(
+        &self,
+        peer_id: PeerId,
+        peer_keys: &PeerPublicKeys,
+    ) -> Result<Arc<CipherSet>, Error> {
+        if let Some(item) = self.ciphers.get(&peer_id) {
+            // keys change when the peer restarts
+            if item.0 == *peer_keys {
+                return Ok(item.1.clone());
+            }
+        }
+
+        let key = self
+            .global_ctx
+            .get_pairwise_key(peer_keys)
+            .map_err(|_| Error::EncryptionFailed)?;
+        let mut key_128 = [0u8; 16];
+        key_128.copy_from_slice(&key[32..48]);
+        let mut key_256 = [0u8; 32];
+        key_256.copy_from_slice(&key[0..32]);
+        let cipher = Arc::new(CipherSet::new(key_128, key_256));
+        self.ciphers.insert(peer_id, (*peer_keys, cipher.clone()));
+        Ok(cipher)
+    }
+
+    pub fn encrypt(
+        &self,
+        peer_id: PeerId,
+        peer_keys: &PeerPublicKeys,
+        zc_packet: &mut ZCPacket,
+        suite: CipherSuite,
+    ) -> Result<(), Error> {
+        self.get_cipher(peer_id, peer_keys)?
+            .encrypt_with_suite(zc_packet, suite)?;
+        zc_packet
+            .mut_peer_manager_header()
+            .unwrap()
+            .set_pairwise_encrypted(true);
+        Ok(())
+    }
+
+    pub fn decrypt(
+        &self,
+        peer_id: PeerId,
+        peer_keys: &PeerPublicKeys,
+        zc_packet: &mut ZCPacket,
+    ) -> Result<(), Error> {
+        self.get_cipher(peer_id, peer_keys)?.decrypt(zc_packet)?;
+        zc_packet
+            .mut_peer_manager_header()
+            .unwrap()
+            .set_pairwise_encrypted(false);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{config::TomlConfigLoader, global_ctx::GlobalCtx};
+
+    use super::*;
+
+    #[test]
+    fn pairwise_encrypt_decrypt() {
+        let a = Arc::new(GlobalCtx::new(TomlConfigLoader::default()));
+        let b = Arc::new(GlobalCtx::new(TomlConfigLoader::default()));
+        let a_ciphers = PairwiseCiphers::new(a.clone());
+        let b_ciphers = PairwiseCiphers::new(b.clone());
+
+        let text = b"relayed";
+        let mut packet = ZCPacket::new_with_payload(text);
+        packet.fill_peer_manager_hdr(1, 2, 0);
+        a_ciphers
+            .encrypt(
+                2,
+                &b.get_my_public_keys(),
+                &mut packet,
+                CipherSuite::default(),
+            )
+            .unwrap();
+        let hdr = packet.peer_manager_header().unwrap();
+        assert!(hdr.is_encrypted());
+        assert!(hdr.is_pairwise_encrypted());
+
+        // a peer claiming the static key of a can not decrypt
+        let c = Arc::new(GlobalCtx::new(TomlConfigLoader::default()));
+        let c_ciphers = PairwiseCiphers::new(c.clone());
+        let mut fake = a.get_my_public_keys();
+        fake.ephemeral_key = c.get_my_public_keys().ephemeral_key;
+        assert!(c_ciphers.decrypt(1, &fake, &mut packet.clone()).is_err());
+
+        b_ciphers
+            .decrypt(1, &a.get_my_public_keys(), &mut packet)
+            .unwrap();
+        assert_eq!(packet.payload(), text);
+        let hdr = packet.peer_manager_header().unwrap();
+        assert!(!hdr.is_encrypted());
+        assert!(!hdr.is_pairwise_encrypted());
+    }
+}
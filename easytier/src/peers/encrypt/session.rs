@@ -0,0 +1,428 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use blake2::{Blake2s256, Digest};
+
+use zerocopy::FromBytes;
+
+use crate::tunnel::packet_def::{AesGcmTail, ZCPacket, AES_GCM_ENCRYPTION_RESERVED};
+
+use super::{create_cipher_256, CipherSuite, Encryptor, Error};
+
+// the receiver can catch up if all packets of several generations are lost
+const MAX_GENERATION_SKIP: u16 = 16;
+
+// packets reordered further than this are dropped as replays
+const REPLAY_WINDOW_SIZE: u64 = 2048;
+
+#[derive(Debug, Clone)]
+pub struct RekeyConfig {
+    pub max_bytes: u64,
+    pub max_packets: u64,
+    pub max_age: Duration,
+    // how long the previous recv key is kept for in-flight packets
+    pub grace: Duration,
+}
+
+// one way function, so a leaked key cannot decrypt packets of previous generations
+fn next_key(key: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Blake2s256::new();
+    hasher.update(b"easytier-rekey");
+    hasher.update(key);
+    hasher.finalize().into()
+}
+
+// counters of received packets, packets older than the window or seen before are rejected.
+struct ReplayWindow {
+    // highest counter seen plus one
+    next: u64,
+    bitmap: [u64; (REPLAY_WINDOW_SIZE / 64) as usize],
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        Self {
+            next: 0,
+            bitmap: [0; (REPLAY_WINDOW_SIZE / 64) as usize],
+        }
+    }
+
+    fn bit(counter: u64) -> (usize, u64) {
+        let idx = counter % REPLAY_WINDOW_SIZE;
+        ((idx / 64) as usize, 1 << (idx % 64))
+    }
+
+    fn check_and_update(&mut self, counter: u64) -> bool {
+        if counter.saturating_add(REPLAY_WINDOW_SIZE) < self.next {
+            return false;
+        }
+
+        if counter >= self.next {
+            if counter - self.next >= REPLAY_WINDOW_SIZE {
+                self.bitmap = [0; (REPLAY_WINDOW_SIZE / 64) as usize];
+            } else {
+                for c in self.next..=counter {
+                    let (word, mask) = Self::bit(c);
+                    self.bitmap[word] &= !mask;
+                }
+            }
+            self.next = counter + 1;
+        }
+
+        let (word, mask) = Self::bit(counter);
+        if self.bitmap[word] & mask != 0 {
+            return false;
+        }
+        self.bitmap[word] |= mask;
+        true
+    }
+}
+
+// nonce of session encrypted packets is the packet counter of the key generation,
+// which never repeats for a key and lets the receiver detect replays.
+fn counter_to_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+fn nonce_counter(zc_packet: &ZCPacket) -> Result<u64, Error> {
+    let payload = zc_packet.payload();
+    if payload.len() < AES_GCM_ENCRYPTION_RESERVED {
+        return Err(Error::PacketTooShort(payload.len()));
+    }
+    let tail = AesGcmTail::ref_from_suffix(payload).unwrap();
+    let nonce = tail.nonce;
+    Ok(u64::from_be_bytes(nonce[4..].try_into().unwrap()))
+}
+
+struct SessionKey {
+    generation: u16,
+    key: [u8; 32],
+    cipher: Arc<dyn Encryptor>,
+    // only used for recv keys
+    replay_window: ReplayWindow,
+}
+
+impl SessionKey {
+    // authenticate the packet before its counter is recorded, so forged packets can not
+    // move the window.
+    fn decrypt(&mut self, zc_packet: &mut ZCPacket, counter: u64) -> Result<(), Error> {
+        self.cipher.decrypt(zc_packet)?;
+        if !self.replay_window.check_and_update(counter) {
+            return Err(Error::Replayed(counter));
+        }
+        Ok(())
+    }
+}
+
+impl SessionKey {
+    fn new(suite: CipherSuite, generation: u16, key: [u8; 32]) -> Result<Self, Error> {
+        let cipher = create_cipher_256(suite, key).ok_or(Error::UnsupportedCipher(suite.id()))?;
+        Ok(Self {
+            generation,
+            key,
+            cipher: cipher.into(),
+            replay_window: ReplayWindow::new(),
+        })
+    }
+
+    fn next(&self, suite: CipherSuite) -> Result<Self, Error> {
+        Self::new(suite, self.generation.wrapping_add(1), next_key(&self.key))
+    }
+}
+
+struct SendState {
+    key: SessionKey,
+    bytes: u64,
+    packets: u64,
+    created_at: Instant,
+}
+
+impl SendState {
+    fn need_rekey(&self, config: &RekeyConfig) -> bool {
+        self.bytes >= config.max_bytes
+            || self.packets >= config.max_packets
+            || self.created_at.elapsed() >= config.max_age
+    }
+}
+
+struct RecvState {
+    current: SessionKey,
+    // previous key and its expire time
+    previous: Option<(SessionKey, Instant)>,
+}
+
+/// Encrypts packets between two directly connected peers with keys derived from the
+/// handshake of peer conn, keys are rotated according to the rekey config.
+pub struct SessionCipher {
+    suite: CipherSuite,
+    config: RekeyConfig,
+    send: Mutex<SendState>,
+    recv: Mutex<RecvState>,
+}
+
+impl SessionCipher {
+    pub fn new(
+        suite: CipherSuite,
+        send_key: [u8; 32],
+        recv_key: [u8; 32],
+        config: RekeyConfig,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            suite,
+            config,
+            send: Mutex::new(SendState {
+                key: SessionKey::new(suite, 0, send_key)?,
+                bytes: 0,
+                packets: 0,
+                created_at: Instant::now(),
+            }),
+            recv: Mutex::new(RecvState {
+                current: SessionKey::new(suite, 0, recv_key)?,
+                previous: None,
+            }),
+        })
+    }
+
+    pub fn encrypt(&self, zc_packet: &mut ZCPacket) -> Result<(), Error> {
+        let (cipher, generation, counter) = {
+            let mut send = self.send.lock().unwrap();
+            if send.need_rekey(&self.config) {
+                send.key = send.key.next(self.suite)?;
+                send.bytes = 0;
+                send.packets = 0;
+                send.created_at = Instant::now();
+                tracing::debug!(generation = send.key.generation, "session send key rotated");
+            }
+            // packets resets with the key, so the counter is unique for each key
+            let counter = send.packets;
+            send.bytes += zc_packet.payload_len() as u64;
+            send.packets += 1;
+            (send.key.cipher.clone(), send.key.generation, counter)
+        };
+
+        cipher.encrypt_with_nonce(zc_packet, counter_to_nonce(counter))?;
+        let pm_header = zc_packet.mut_peer_manager_header().unwrap();
+        pm_header.set_session_encrypted(true);
+        pm_header.set_key_generation(generation);
+        Ok(())
+    }
+
+    pub fn decrypt(&self, zc_packet: &mut ZCPacket) -> Result<(), Error> {
+        let pm_header = zc_packet.peer_manager_header().unwrap();
+        if !pm_header.is_session_encrypted() {
+            return Err(Error::NotEcrypted);
+        }
+        let generation = pm_header.get_key_generation();
+        let counter = nonce_counter(zc_packet)?;
+
+        let mut recv = self.recv.lock().unwrap();
+        if recv
+            .previous
+            .as_ref()
+            .map(|(_, expire)| *expire <= Instant::now())
+            .unwrap_or(false)
+        {
+            recv.previous = None;
+        }
+
+        if generation == recv.current.generation {
+            recv.current.decrypt(zc_packet, counter)?;
+        } else if let Some((prev, _)) = recv
+            .previous
+            .as_mut()
+            .filter(|(k, _)| k.generation == generation)
+        {
+            prev.decrypt(zc_packet, counter)?;
+        } else {
+            let skip = generation.wrapping_sub(recv.current.generation);
+            if skip > MAX_GENERATION_SKIP {
+                return Err(Error::UnexpectedKeyGeneration(generation));
+            }
+
+            let mut candidate = recv.current.next(self.suite)?;
+            for _ in 1..skip {
+                candidate = candidate.next(self.suite)?;
+            }
+            // only move forward after the packet is authenticated with the new key
+            candidate.decrypt(zc_packet, counter)?;
+            tracing::debug!(generation, "session recv key rotated");
+
+            let old = std::mem::replace(&mut recv.current, candidate);
+            recv.previous = Some((old, Instant::now() + self.config.grace));
+        }
+
+        let pm_header = zc_packet.mut_peer_manager_header().unwrap();
+        pm_header.set_session_encrypted(false);
+        pm_header.set_key_generation(0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_pair(config: RekeyConfig) -> (SessionCipher, SessionCipher) {
+        let (k1, k2) = ([1u8; 32], [2u8; 32]);
+        let suite = CipherSuite::default();
+        (
+            SessionCipher::new(suite, k1, k2, config.clone()).unwrap(),
+            SessionCipher::new(suite, k2, k1, config).unwrap(),
+        )
+    }
+
+    fn new_packet() -> ZCPacket {
+        let mut packet = ZCPacket::new_with_payload(b"1234567");
+        packet.fill_peer_manager_hdr(0, 0, 0);
+        packet
+    }
+
+    #[test]
+    fn session_rekey_by_packets() {
+        let (a, b) = new_pair(RekeyConfig {
+            max_bytes: u64::MAX,
+            max_packets: 2,
+            max_age: Duration::from_secs(3600),
+            grace: Duration::from_secs(10),
+        });
+
+        let mut packets = vec![];
+        for i in 0..6 {
+            let mut packet = new_packet();
+            a.encrypt(&mut packet).unwrap();
+            let hdr = packet.peer_manager_header().unwrap();
+            assert!(hdr.is_session_encrypted());
+            assert_eq!(hdr.get_key_generation(), i / 2);
+            packets.push(packet);
+        }
+
+        // generation 1 is skipped, the one of generation 0 arrives late
+        for idx in [4, 0, 5] {
+            let mut packet = packets[idx].clone();
+            b.decrypt(&mut packet).unwrap();
+            assert_eq!(packet.payload(), b"1234567");
+            assert!(!packet.peer_manager_header().unwrap().is_session_encrypted());
+        }
+
+        // key of generation 0 is dropped after recv key is rotated again
+        for _ in 0..2 {
+            let mut packet = new_packet();
+            a.encrypt(&mut packet).unwrap();
+            assert_eq!(
+                packet.peer_manager_header().unwrap().get_key_generation(),
+                3
+            );
+            b.decrypt(&mut packet).unwrap();
+        }
+        let mut packet = packets[1].clone();
+        b.decrypt(&mut packet).unwrap_err();
+
+        // keys of two directions are independent
+        let mut packet = new_packet();
+        b.encrypt(&mut packet).unwrap();
+        assert_eq!(
+            packet.peer_manager_header().unwrap().get_key_generation(),
+            0
+        );
+        a.decrypt(&mut packet).unwrap();
+    }
+
+    #[test]
+    fn session_grace_window() {
+        let (a, b) = new_pair(RekeyConfig {
+            max_bytes: u64::MAX,
+            max_packets: 1,
+            max_age: Duration::from_secs(3600),
+            grace: Duration::from_millis(100),
+        });
+
+        let mut p0 = new_packet();
+        a.encrypt(&mut p0).unwrap();
+        let mut p1 = new_packet();
+        a.encrypt(&mut p1).unwrap();
+
+        b.decrypt(&mut p1).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        b.decrypt(&mut p0).unwrap_err();
+    }
+
+    #[test]
+    fn session_reject_replay() {
+        let (a, b) = new_pair(RekeyConfig {
+            max_bytes: u64::MAX,
+            max_packets: u64::MAX,
+            max_age: Duration::from_secs(3600),
+            grace: Duration::from_secs(10),
+        });
+
+        let mut packets = vec![];
+        for _ in 0..3 {
+            let mut packet = new_packet();
+            a.encrypt(&mut packet).unwrap();
+            packets.push(packet);
+        }
+
+        // reordered packets are accepted once
+        for idx in [1, 0, 2] {
+            b.decrypt(&mut packets[idx].clone()).unwrap();
+        }
+        for idx in [0, 1, 2] {
+            assert!(matches!(
+                b.decrypt(&mut packets[idx].clone()),
+                Err(Error::Replayed(_))
+            ));
+        }
+
+        // packets older than the window are rejected
+        let mut old = new_packet();
+        a.encrypt(&mut old).unwrap();
+        for _ in 0..REPLAY_WINDOW_SIZE + 1 {
+            let mut packet = new_packet();
+            a.encrypt(&mut packet).unwrap();
+            b.decrypt(&mut packet).unwrap();
+        }
+        b.decrypt(&mut old).unwrap_err();
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::new();
+        assert!(window.check_and_update(5));
+        assert!(!window.check_and_update(5));
+        assert!(window.check_and_update(0));
+        assert!(window.check_and_update(5 + REPLAY_WINDOW_SIZE));
+        // slots of old counters are reused for new ones
+        assert!(!window.check_and_update(5));
+        assert!(window.check_and_update(6 + REPLAY_WINDOW_SIZE));
+        assert!(!window.check_and_update(6 + REPLAY_WINDOW_SIZE));
+        assert!(window.check_and_update(u64::MAX - 1));
+        assert!(!window.check_and_update(7 + REPLAY_WINDOW_SIZE));
+    }
+
+    #[test]
+    fn session_reject_forged_generation() {
+        let (a, b) = new_pair(RekeyConfig {
+            max_bytes: u64::MAX,
+            max_packets: u64::MAX,
+            max_age: Duration::from_secs(3600),
+            grace: Duration::from_secs(10),
+        });
+
+        let mut packet = new_packet();
+        a.encrypt(&mut packet).unwrap();
+        packet
+            .mut_peer_manager_header()
+            .unwrap()
+            .set_key_generation(3);
+        b.decrypt(&mut packet).unwrap_err();
+
+        // recv key is not moved forward by the forged packet
+        let mut packet = new_packet();
+        a.encrypt(&mut packet).unwrap();
+        b.decrypt(&mut packet).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+// learning switch of tap mode. source macs of frames from peers are learned, so unicast frames
+// from the tap device are sent to one peer; broadcast, multicast and unknown frames are flooded.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use pnet::{packet::ethernet::EthernetPacket, util::MacAddr};
+use tokio::sync::mpsc;
+
+use crate::{
+    common::{error::Error, PeerId},
+    tunnel::{
+        packet_def::{PacketType, ZCPacket},
+        SinkItem,
+    },
+};
+
+use super::{peer_manager::PeerManager, PeerPacketFilter};
+
+// same as the default ageing time of linux bridge
+const MAC_AGEING_TIME: Duration = Duration::from_secs(300);
+// peers can send frames with any source mac, keep the table from growing without limit
+const MAX_MAC_TABLE_SIZE: usize = 4096;
+
+fn is_flood_mac(mac: &MacAddr) -> bool {
+    // the group bit also covers the broadcast address
+    mac.0 & 0x01 != 0
+}
+
+pub struct EthernetSwitch {
+    peer_mgr: Arc<PeerManager>,
+    nic_channel: mpsc::Sender<SinkItem>,
+    mac_table: DashMap<MacAddr, (PeerId, Instant)>,
+}
+
+impl EthernetSwitch {
+    pub fn new(peer_mgr: Arc<PeerManager>) -> Self {
+        let nic_channel = peer_mgr.get_nic_channel();
+        EthernetSwitch {
+            peer_mgr,
+            nic_channel,
+            mac_table: DashMap::new(),
+        }
+    }
+
+    fn learn(&self, mac: MacAddr, peer_id: PeerId) {
+        if is_flood_mac(&mac) {
+            return;
+        }
+        if self.mac_table.len() >= MAX_MAC_TABLE_SIZE && !self.mac_table.contains_key(&mac) {
+            self.mac_table
+                .retain(|_, v| v.1.elapsed() <= MAC_AGEING_TIME);
+            while self.mac_table.len() >= MAX_MAC_TABLE_SIZE {
+                let Some(oldest) = self
+                    .mac_table
+                    .iter()
+                    .min_by_key(|x| x.value().1)
+                    .map(|x| *x.key())
+                else {
+                    break;
+                };
+                self.mac_table.remove(&oldest);
+            }
+        }
+        self.mac_table.insert(mac, (peer_id, Instant::now()));
+    }
+
+    fn lookup(&self, mac: &MacAddr) -> Option<PeerId> {
+        let peer_id = {
+            let entry = self.mac_table.get(mac)?;
+            if entry.1.elapsed() <= MAC_AGEING_TIME {
+                return Some(entry.0);
+            }
+            entry.0
+        };
+        self.mac_table.remove_if(mac, |_, v| {
+            v.0 == peer_id && v.1.elapsed() > MAC_AGEING_TIME
+        });
+        None
+    }
+
+    pub fn list_mac_table(&self) -> Vec<(MacAddr, PeerId)> {
+        self.mac_table
+            .iter()
+            .filter(|x| x.value().1.elapsed() <= MAC_AGEING_TIME)
+            .map(|x| (*x.key(), x.value().0))
+            .collect()
+    }
+
+    // send a frame read from the tap device to peers
+    pub async fn forward_frame(&self, packet: ZCPacket) -> Result<(), Error> {
+        let Some(eth) = EthernetPacket::new(packet.payload()) else {
+            tracing::trace!(?packet, "[USER_PACKET] not ethernet frame, drop it");
+            return Ok(());
+        };
+        let dst = eth.get_destination();
+        let dst_peer = if is_flood_mac(&dst) {
+            None
+        } else {
+            self.lookup(&dst)
+        };
+        tracing::trace!(?dst, ?dst_peer, "[USER_PACKET] forward frame to peers");
+        self.peer_mgr.send_ethernet_frame(packet, dst_peer).await
+    }
+}
+
+#[async_trait::async_trait]
+impl PeerPacketFilter for EthernetSwitch {
+    async fn try_process_packet_from_peer(&self, packet: ZCPacket) -> Option<ZCPacket> {
+        let hdr = packet.peer_manager_header().unwrap();
+        let packet_type = hdr.packet_type;
+        let from_peer_id = hdr.from_peer_id.get();
+
+        if packet_type == PacketType::Data as u8 {
+            // peers in tun mode send ip packets, they can not be put on the tap device
+            tracing::trace!(?from_peer_id, "drop ip packet in tap mode");
+            return None;
+        } else if packet_type != PacketType::Ethernet as u8 {
+            return Some(packet);
+        }
+
+        let Some(eth) = EthernetPacket::new(packet.payload()) else {
+            return None;
+        };
+        self.learn(eth.get_source(), from_peer_id);
+
+        if let Err(e) = self.nic_channel.send(packet).await {
+            tracing::error!(?e, "send frame to nic channel failed");
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+
+    use crate::{
+        common::global_ctx::tests::get_mock_global_ctx,
+        peers::{
+            peer_manager::RouteAlgoType,
+            tests::{connect_peer_manager, wait_for_condition, wait_route_appear},
+        },
+    };
+
+    use super::*;
+
+    fn build_frame(src: MacAddr, dst: MacAddr) -> ZCPacket {
+        let mut buf = [0u8; 64];
+        let mut eth = MutableEthernetPacket::new(&mut buf[..]).unwrap();
+        eth.set_source(src);
+        eth.set_destination(dst);
+        eth.set_ethertype(EtherTypes::Ipv4);
+        ZCPacket::new_with_payload(&buf)
+    }
+
+    async fn create_tap_peer_manager() -> (Arc<PeerManager>, mpsc::Receiver<SinkItem>) {
+        let (s, r) = mpsc::channel(100);
+        let peer_mgr = Arc::new(PeerManager::new(
+            RouteAlgoType::Ospf,
+            get_mock_global_ctx(),
+            s,
+        ));
+        peer_mgr.run().await.unwrap();
+        (peer_mgr, r)
+    }
+
+    #[tokio::test]
+    async fn flood_and_learn() {
+        let (peer_mgr_a, _recv_a) = create_tap_peer_manager().await;
+        let (peer_mgr_b, mut recv_b) = create_tap_peer_manager().await;
+        let (peer_mgr_c, mut recv_c) = create_tap_peer_manager().await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_c.clone()).await;
+        wait_route_appear(peer_mgr_b.clone(), peer_mgr_c.clone())
+            .await
+            .unwrap();
+
+        let switch_a = Arc::new(EthernetSwitch::new(peer_mgr_a.clone()));
+        let switch_b = Arc::new(EthernetSwitch::new(peer_mgr_b.clone()));
+        let switch_c = Arc::new(EthernetSwitch::new(peer_mgr_c.clone()));
+        peer_mgr_a
+            .add_packet_process_pipeline(Box::new(switch_a.clone()))
+            .await;
+        peer_mgr_b
+            .add_packet_process_pipeline(Box::new(switch_b.clone()))
+            .await;
+        peer_mgr_c
+            .add_packet_process_pipeline(Box::new(switch_c.clone()))
+            .await;
+
+        let mac_a = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let mac_b = MacAddr::new(2, 0, 0, 0, 0, 2);
+
+        // broadcast from a reaches both peers, and they learn mac of a
+        switch_a
+            .forward_frame(build_frame(mac_a, MacAddr::broadcast()))
+            .await
+            .unwrap();
+        for recv in [&mut recv_b, &mut recv_c] {
+            let frame = tokio::time::timeout(Duration::from_secs(5), recv.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            let eth = EthernetPacket::new(frame.payload()).unwrap();
+            assert_eq!(eth.get_source(), mac_a);
+        }
+        assert_eq!(
+            switch_b.list_mac_table(),
+            vec![(mac_a, peer_mgr_a.my_peer_id())]
+        );
+
+        // reply from b is unicast to a, so c learns nothing
+        switch_b
+            .forward_frame(build_frame(mac_b, mac_a))
+            .await
+            .unwrap();
+        wait_for_condition(
+            || async { switch_a.list_mac_table().len() == 1 },
+            Duration::from_secs(5),
+        )
+        .await;
+        assert_eq!(switch_a.lookup(&mac_b), Some(peer_mgr_b.my_peer_id()));
+        assert_eq!(switch_c.list_mac_table().len(), 1);
+        assert!(recv_c.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn mac_table_ageing() {
+        let (peer_mgr, _recv) = create_tap_peer_manager().await;
+        let switch = EthernetSwitch::new(peer_mgr);
+        let mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+
+        switch.learn(MacAddr::broadcast(), 2);
+        assert!(switch.mac_table.is_empty());
+
+        switch.learn(mac, 2);
+        assert_eq!(switch.lookup(&mac), Some(2));
+
+        switch
+            .mac_table
+            .insert(mac, (2, Instant::now() - MAC_AGEING_TIME * 2));
+        assert_eq!(switch.lookup(&mac), None);
+        assert!(switch.mac_table.is_empty());
+    }
+
+    #[tokio::test]
+    async fn mac_table_size_limit() {
+        let (peer_mgr, _recv) = create_tap_peer_manager().await;
+        let switch = EthernetSwitch::new(peer_mgr);
+        let mac = |i: usize| MacAddr::new(2, 0, 0, 0, (i >> 8) as u8, i as u8);
+
+        let now = Instant::now();
+        for i in 0..MAX_MAC_TABLE_SIZE {
+            let learned = if i < 10 {
+                now - MAC_AGEING_TIME * 2
+            } else {
+                now - Duration::from_millis(i as u64)
+            };
+            switch.mac_table.insert(mac(i), (2, learned));
+        }
+
+        // stale entries are evicted first
+        switch.learn(mac(MAX_MAC_TABLE_SIZE), 3);
+        assert_eq!(switch.mac_table.len(), MAX_MAC_TABLE_SIZE - 9);
+        assert_eq!(switch.lookup(&mac(MAX_MAC_TABLE_SIZE)), Some(3));
+
+        // then the oldest ones
+        while switch.mac_table.len() < MAX_MAC_TABLE_SIZE {
+            switch.learn(mac(MAX_MAC_TABLE_SIZE + switch.mac_table.len()), 3);
+        }
+        switch.learn(mac(MAX_MAC_TABLE_SIZE * 2), 3);
+        assert_eq!(switch.mac_table.len(), MAX_MAC_TABLE_SIZE);
+        assert_eq!(switch.lookup(&mac(MAX_MAC_TABLE_SIZE * 2)), Some(3));
+        assert!(!switch.mac_table.contains_key(&mac(MAX_MAC_TABLE_SIZE - 1)));
+
+        // known macs are refreshed without eviction
+        switch.learn(mac(MAX_MAC_TABLE_SIZE), 4);
+        assert_eq!(switch.mac_table.len(), MAX_MAC_TABLE_SIZE);
+        assert_eq!(switch.lookup(&mac(MAX_MAC_TABLE_SIZE)), Some(4));
+    }
+}
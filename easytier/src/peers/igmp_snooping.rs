@@ -0,0 +1,377 @@
+// igmp snooping, only forward multicast packets to peers which joined the group.
+// membership is learned from igmp reports sent by peers. we also act as a querier
+// for the local host, so reports are refreshed periodically and spread to other peers.
+
+use std::{
+    net::Ipv4Addr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use pnet::packet::{
+    ip::IpNextHeaderProtocols,
+    ipv4::{self, Ipv4Packet, MutableIpv4Packet},
+    Packet,
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc, Mutex},
+    task::JoinSet,
+};
+
+use crate::{
+    common::{
+        global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
+        PeerId,
+    },
+    tunnel::{
+        packet_def::{PacketType, ZCPacket},
+        SinkItem,
+    },
+};
+
+use super::PeerPacketFilter;
+
+// same as the default of igmpv2 (robustness * query interval + max response time)
+const GROUP_MEMBERSHIP_TIMEOUT: Duration = Duration::from_secs(260);
+const QUERY_INTERVAL: Duration = Duration::from_secs(125);
+
+const IGMP_MEMBERSHIP_QUERY: u8 = 0x11;
+const IGMP_V1_MEMBERSHIP_REPORT: u8 = 0x12;
+const IGMP_V2_MEMBERSHIP_REPORT: u8 = 0x16;
+const IGMP_V2_LEAVE_GROUP: u8 = 0x17;
+const IGMP_V3_MEMBERSHIP_REPORT: u8 = 0x22;
+
+const IGMP_V3_MODE_IS_INCLUDE: u8 = 1;
+const IGMP_V3_MODE_IS_EXCLUDE: u8 = 2;
+const IGMP_V3_CHANGE_TO_INCLUDE: u8 = 3;
+const IGMP_V3_CHANGE_TO_EXCLUDE: u8 = 4;
+const IGMP_V3_ALLOW_NEW_SOURCES: u8 = 5;
+
+const IGMP_ALL_HOSTS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GroupOp {
+    Join(Ipv4Addr),
+    Leave(Ipv4Addr),
+}
+
+fn parse_igmp_packet(igmp: &[u8]) -> Vec<GroupOp> {
+    let mut ret = vec![];
+    if igmp.len() < 8 {
+        return ret;
+    }
+
+    let group = Ipv4Addr::new(igmp[4], igmp[5], igmp[6], igmp[7]);
+    match igmp[0] {
+        IGMP_V1_MEMBERSHIP_REPORT | IGMP_V2_MEMBERSHIP_REPORT => ret.push(GroupOp::Join(group)),
+        IGMP_V2_LEAVE_GROUP => ret.push(GroupOp::Leave(group)),
+        IGMP_V3_MEMBERSHIP_REPORT => {
+            let num_records = u16::from_be_bytes([igmp[6], igmp[7]]) as usize;
+            let mut offset = 8;
+            for _ in 0..num_records {
+                if igmp.len() < offset + 8 {
+                    break;
+                }
+                let record_type = igmp[offset];
+                let aux_len = igmp[offset + 1] as usize * 4;
+                let num_sources = u16::from_be_bytes([igmp[offset + 2], igmp[offset + 3]]);
+                let group = Ipv4Addr::new(
+                    igmp[offset + 4],
+                    igmp[offset + 5],
+                    igmp[offset + 6],
+                    igmp[offset + 7],
+                );
+                offset += 8 + num_sources as usize * 4 + aux_len;
+
+                match record_type {
+                    // exclude mode means the host wants traffic from (almost) all sources
+                    IGMP_V3_MODE_IS_EXCLUDE | IGMP_V3_CHANGE_TO_EXCLUDE => {
+                        ret.push(GroupOp::Join(group))
+                    }
+                    // include mode with empty source list means leave
+                    IGMP_V3_MODE_IS_INCLUDE | IGMP_V3_CHANGE_TO_INCLUDE if num_sources == 0 => {
+                        ret.push(GroupOp::Leave(group))
+                    }
+                    IGMP_V3_MODE_IS_INCLUDE
+                    | IGMP_V3_CHANGE_TO_INCLUDE
+                    | IGMP_V3_ALLOW_NEW_SOURCES => ret.push(GroupOp::Join(group)),
+                    _ => {}
+                }
+            }
+        }
+        _ => {}
+    }
+
+    ret
+}
+
+fn build_general_query() -> ZCPacket {
+    let mut buf = [0u8; 28];
+
+    let igmp = &mut buf[20..];
+    igmp[0] = IGMP_MEMBERSHIP_QUERY;
+    // max response time in 1/10 second
+    igmp[1] = 100;
+    let checksum = pnet::util::checksum(igmp, 1);
+    igmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut ipv4_packet = MutableIpv4Packet::new(&mut buf[..]).unwrap();
+    ipv4_packet.set_version(4);
+    ipv4_packet.set_header_length(5);
+    ipv4_packet.set_total_length(28);
+    ipv4_packet.set_ttl(1);
+    ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Igmp);
+    // use 0.0.0.0 as source, os will drop the packet if source is the local address.
+    ipv4_packet.set_source(Ipv4Addr::UNSPECIFIED);
+    ipv4_packet.set_destination(IGMP_ALL_HOSTS);
+    ipv4_packet.set_checksum(ipv4::checksum(&ipv4_packet.to_immutable()));
+
+    ZCPacket::new_with_payload(&buf)
+}
+
+pub struct IgmpSnooping {
+    my_peer_id: PeerId,
+    global_ctx: ArcGlobalCtx,
+    nic_channel: mpsc::Sender<SinkItem>,
+
+    // group -> (peer id -> last report time)
+    groups: DashMap<Ipv4Addr, DashMap<PeerId, Instant>>,
+
+    tasks: Mutex<JoinSet<()>>,
+}
+
+impl IgmpSnooping {
+    pub fn new(
+        my_peer_id: PeerId,
+        global_ctx: ArcGlobalCtx,
+        nic_channel: mpsc::Sender<SinkItem>,
+    ) -> Self {
+        IgmpSnooping {
+            my_peer_id,
+            global_ctx,
+            nic_channel,
+            groups: DashMap::new(),
+            tasks: Mutex::new(JoinSet::new()),
+        }
+    }
+
+    // as rfc 4541 suggests, packets to 224.0.0.x are always flooded. igmp packets themselves
+    // must also reach every peer, so snooping can work.
+    pub fn need_flood(&self, packet: &ZCPacket, group: &Ipv4Addr) -> bool {
+        if group.octets()[..3] == [224, 0, 0] {
+            return true;
+        }
+
+        Ipv4Packet::new(packet.payload())
+            .map(|ip| ip.get_next_level_protocol() == IpNextHeaderProtocols::Igmp)
+            .unwrap_or(false)
+    }
+
+    pub fn list_group_members(&self, group: &Ipv4Addr) -> Vec<PeerId> {
+        let Some(members) = self.groups.get(group) else {
+            return vec![];
+        };
+        members
+            .iter()
+            .filter(|x| x.value().elapsed() < GROUP_MEMBERSHIP_TIMEOUT)
+            .map(|x| *x.key())
+            .collect()
+    }
+
+    fn handle_igmp_packet(&self, from_peer_id: PeerId, igmp: &[u8]) {
+        for op in parse_igmp_packet(igmp) {
+            tracing::debug!(?op, ?from_peer_id, "igmp snooping got group op");
+            match op {
+                GroupOp::Join(group) if group.is_multicast() => {
+                    self.groups
+                        .entry(group)
+                        .or_default()
+                        .insert(from_peer_id, Instant::now());
+                }
+                GroupOp::Leave(group) => {
+                    if let Some(members) = self.groups.get(&group) {
+                        members.remove(&from_peer_id);
+                    }
+                    self.groups.remove_if(&group, |_, v| v.is_empty());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn remove_peer(&self, peer_id: PeerId) {
+        for group in self.groups.iter() {
+            group.value().remove(&peer_id);
+        }
+        self.groups.retain(|_, v| !v.is_empty());
+    }
+
+    fn clean_expired(&self) {
+        for group in self.groups.iter() {
+            group
+                .value()
+                .retain(|_, v| v.elapsed() < GROUP_MEMBERSHIP_TIMEOUT);
+        }
+        self.groups.retain(|_, v| !v.is_empty());
+    }
+
+    fn send_query_to_nic(&self) {
+        // no tun device if we have no ipv4 address
+        if self.global_ctx.get_ipv4().is_none() {
+            return;
+        }
+
+        let mut packet = build_general_query();
+        packet.fill_peer_manager_hdr(self.my_peer_id, self.my_peer_id, PacketType::Data as u8);
+        if let Err(e) = self.nic_channel.try_send(packet) {
+            tracing::warn!(?e, "send igmp query to nic failed");
+        }
+    }
+
+    pub async fn run(self: &Arc<Self>) {
+        let this = Arc::downgrade(self);
+        let mut event_recv = self.global_ctx.subscribe();
+        self.tasks.lock().await.spawn(async move {
+            loop {
+                let Some(this) = this.upgrade() else {
+                    break;
+                };
+                this.clean_expired();
+                this.send_query_to_nic();
+                drop(this);
+
+                // also query when a new peer appears, so it can learn our groups quickly.
+                let timeout = tokio::time::sleep(QUERY_INTERVAL);
+                tokio::pin!(timeout);
+                loop {
+                    tokio::select! {
+                        _ = &mut timeout => break,
+                        ev = event_recv.recv() => match ev {
+                            Ok(GlobalCtxEvent::PeerAdded(_)) => break,
+                            Ok(GlobalCtxEvent::PeerRemoved(peer_id)) => {
+                                if let Some(this) = this.upgrade() {
+                                    this.remove_peer(peer_id);
+                                }
+                            }
+                            Err(RecvError::Closed) => return,
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl PeerPacketFilter for IgmpSnooping {
+    async fn try_process_packet_from_peer(&self, packet: ZCPacket) -> Option<ZCPacket> {
+        let hdr = packet.peer_manager_header().unwrap();
+        if hdr.packet_type != PacketType::Data as u8 {
+            return Some(packet);
+        }
+        let from_peer_id = hdr.from_peer_id.get();
+
+        if let Some(ipv4) = Ipv4Packet::new(packet.payload()) {
+            if ipv4.get_version() == 4
+                && ipv4.get_next_level_protocol() == IpNextHeaderProtocols::Igmp
+            {
+                self.handle_igmp_packet(from_peer_id, ipv4.payload());
+            }
+        }
+
+        Some(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::global_ctx::tests::get_mock_global_ctx;
+
+    use super::*;
+
+    fn build_v3_report(records: &[(u8, Ipv4Addr, u16)]) -> Vec<u8> {
+        let mut buf = vec![IGMP_V3_MEMBERSHIP_REPORT, 0, 0, 0, 0, 0];
+        buf.extend_from_slice(&(records.len() as u16).to_be_bytes());
+        for (record_type, group, num_sources) in records {
+            buf.extend_from_slice(&[*record_type, 0]);
+            buf.extend_from_slice(&num_sources.to_be_bytes());
+            buf.extend_from_slice(&group.octets());
+            for i in 0..*num_sources {
+                buf.extend_from_slice(&[10, 0, 0, i as u8]);
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn parse_igmp_report() {
+        let group = Ipv4Addr::new(239, 255, 255, 250);
+        let mut v2 = vec![IGMP_V2_MEMBERSHIP_REPORT, 0, 0, 0];
+        v2.extend_from_slice(&group.octets());
+        assert_eq!(parse_igmp_packet(&v2), vec![GroupOp::Join(group)]);
+
+        v2[0] = IGMP_V2_LEAVE_GROUP;
+        assert_eq!(parse_igmp_packet(&v2), vec![GroupOp::Leave(group)]);
+
+        let group2 = Ipv4Addr::new(224, 0, 0, 251);
+        let v3 = build_v3_report(&[
+            (IGMP_V3_CHANGE_TO_EXCLUDE, group, 0),
+            (IGMP_V3_CHANGE_TO_INCLUDE, group2, 0),
+            (IGMP_V3_ALLOW_NEW_SOURCES, group2, 2),
+        ]);
+        assert_eq!(
+            parse_igmp_packet(&v3),
+            vec![
+                GroupOp::Join(group),
+                GroupOp::Leave(group2),
+                GroupOp::Join(group2)
+            ]
+        );
+
+        // truncated packet should not panic
+        assert_eq!(parse_igmp_packet(&v3[..20]), vec![GroupOp::Join(group)]);
+        assert!(parse_igmp_packet(&v3[..4]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn snooping_membership() {
+        let (nic_channel, _nic_recv) = mpsc::channel(10);
+        let snooping = IgmpSnooping::new(1, get_mock_global_ctx(), nic_channel);
+        let group = Ipv4Addr::new(239, 1, 2, 3);
+
+        let mut report = vec![IGMP_V2_MEMBERSHIP_REPORT, 0, 0, 0];
+        report.extend_from_slice(&group.octets());
+        snooping.handle_igmp_packet(2, &report);
+        snooping.handle_igmp_packet(3, &report);
+
+        let mut members = snooping.list_group_members(&group);
+        members.sort();
+        assert_eq!(members, vec![2, 3]);
+
+        report[0] = IGMP_V2_LEAVE_GROUP;
+        snooping.handle_igmp_packet(2, &report);
+        assert_eq!(snooping.list_group_members(&group), vec![3]);
+
+        snooping.remove_peer(3);
+        assert!(snooping.list_group_members(&group).is_empty());
+        assert!(snooping.groups.is_empty());
+    }
+
+    #[test]
+    fn general_query_is_valid() {
+        let packet = build_general_query();
+        let ipv4 = Ipv4Packet::new(packet.payload()).unwrap();
+        assert_eq!(ipv4.get_destination(), IGMP_ALL_HOSTS);
+        assert_eq!(ipv4.get_next_level_protocol(), IpNextHeaderProtocols::Igmp);
+        assert_eq!(ipv4.get_checksum(), ipv4::checksum(&ipv4));
+
+        let igmp = ipv4.payload();
+        assert_eq!(igmp[0], IGMP_MEMBERSHIP_QUERY);
+        assert_eq!(
+            u16::from_be_bytes([igmp[2], igmp[3]]),
+            pnet::util::checksum(igmp, 1)
+        );
+    }
+}
@@ -2,6 +2,7 @@ pub mod peer;
 // pub mod peer_conn;
 pub mod peer_conn;
 pub mod peer_conn_ping;
+pub mod peer_conn_selector;
 pub mod peer_manager;
 pub mod peer_map;
 pub mod peer_ospf_route;
@@ -13,12 +14,15 @@ pub mod rpc_service;
 pub mod foreign_network_client;
 pub mod foreign_network_manager;
 
+pub mod acl_filter;
 pub mod encrypt;
+pub mod ethernet_switch;
+pub mod igmp_snooping;
 
 #[cfg(test)]
 pub mod tests;
 
-use crate::tunnel::packet_def::ZCPacket;
+use crate::{common::PeerId, tunnel::packet_def::ZCPacket};
 
 #[async_trait::async_trait]
 #[auto_impl::auto_impl(Arc)]
@@ -32,6 +36,12 @@ pub trait PeerPacketFilter {
 #[auto_impl::auto_impl(Arc)]
 pub trait NicPacketFilter {
     async fn try_process_packet_from_nic(&self, data: &mut ZCPacket);
+
+    // called for every destination peer of an ip packet or ethernet frame from nic, return false
+    // to drop it
+    async fn check_packet_to_peer(&self, _data: &ZCPacket, _dst_peer_id: PeerId) -> bool {
+        true
+    }
 }
 
 type BoxPeerPacketFilter = Box<dyn PeerPacketFilter + Send + Sync>;
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Instant};
 
 use crossbeam::atomic::AtomicCell;
 use dashmap::DashMap;
@@ -8,7 +8,9 @@ use tokio::{select, sync::mpsc, task::JoinHandle};
 use tracing::Instrument;
 
 use super::{
+    encrypt::CipherSuite,
     peer_conn::{PeerConn, PeerConnId},
+    peer_conn_selector::{create_conn_selector, ConnMetric, PeerConnSelector},
     PacketRecvChan,
 };
 use crate::rpc::PeerConnInfo;
@@ -16,6 +18,7 @@ use crate::{
     common::{
         error::Error,
         global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
+        noise::PublicKey,
         PeerId,
     },
     tunnel::packet_def::ZCPacket,
@@ -24,6 +27,9 @@ use crate::{
 type ArcPeerConn = Arc<PeerConn>;
 type ConnMap = Arc<DashMap<PeerConnId, ArcPeerConn>>;
 
+// how often the conn selector is fed with latest latency and loss rate
+const CONN_SELECT_UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 pub struct Peer {
     pub peer_node_id: PeerId,
     conns: ConnMap,
@@ -36,7 +42,8 @@ pub struct Peer {
 
     shutdown_notifier: Arc<tokio::sync::Notify>,
 
-    default_conn_id: AtomicCell<PeerConnId>,
+    conn_selector: Box<dyn PeerConnSelector>,
+    last_conn_select_update: AtomicCell<Option<Instant>>,
 }
 
 impl Peer {
@@ -88,6 +95,10 @@ impl Peer {
             )),
         );
 
+        let flags = global_ctx.get_flags();
+        let conn_selector =
+            create_conn_selector(flags.conn_select_policy, flags.conn_failover_loss_rate);
+
         Peer {
             peer_node_id,
             conns: conns.clone(),
@@ -98,7 +109,8 @@ impl Peer {
             close_event_listener,
 
             shutdown_notifier,
-            default_conn_id: AtomicCell::new(PeerConnId::default()),
+            conn_selector,
+            last_conn_select_update: AtomicCell::new(None),
         }
     }
 
@@ -109,22 +121,45 @@ impl Peer {
         self.global_ctx
             .issue_event(GlobalCtxEvent::PeerConnAdded(conn.get_conn_info()));
         self.conns.insert(conn.get_conn_id(), Arc::new(conn));
+        self.update_conn_selector();
+    }
+
+    fn update_conn_selector(&self) {
+        let mut metrics = self
+            .conns
+            .iter()
+            .map(|conn| ConnMetric {
+                conn_id: conn.get_conn_id(),
+                latency_us: conn.get_stats().latency_us,
+                loss_rate: conn.get_loss_rate(),
+            })
+            .collect::<Vec<_>>();
+        // make the result not depend on the iteration order of dashmap
+        metrics.sort_by_key(|m| m.conn_id);
+        self.conn_selector.update(&metrics);
+        self.last_conn_select_update.store(Some(Instant::now()));
     }
 
     async fn select_conn(&self) -> Option<ArcPeerConn> {
-        let default_conn_id = self.default_conn_id.load();
-        if let Some(conn) = self.conns.get(&default_conn_id) {
-            return Some(conn.clone());
+        let need_update = self
+            .last_conn_select_update
+            .load()
+            .map(|t| t.elapsed() > CONN_SELECT_UPDATE_INTERVAL)
+            .unwrap_or(true);
+        if need_update {
+            self.update_conn_selector();
         }
 
-        let conn = self.conns.iter().next();
-        if conn.is_none() {
-            return None;
+        if let Some(conn_id) = self.conn_selector.select() {
+            if let Some(conn) = self.conns.get(&conn_id) {
+                return Some(conn.clone());
+            }
         }
 
-        let conn = conn.unwrap().clone();
-        self.default_conn_id.store(conn.get_conn_id());
-        Some(conn)
+        // selected conn is closed, select again with remaining conns
+        self.update_conn_selector();
+        let conn_id = self.conn_selector.select()?;
+        self.conns.get(&conn_id).map(|conn| conn.clone())
     }
 
     pub async fn send_msg(&self, msg: ZCPacket) -> Result<(), Error> {
@@ -145,6 +180,42 @@ impl Peer {
         Ok(())
     }
 
+    // trusted keys may be changed after handshake, returns ids of the closed conns
+    pub async fn close_untrusted_conns(&self) -> Vec<PeerConnId> {
+        let untrusted = self
+            .conns
+            .iter()
+            .filter(|conn| {
+                !self
+                    .global_ctx
+                    .is_public_key_trusted(&conn.get_peer_public_key())
+            })
+            .map(|conn| conn.get_conn_id())
+            .collect::<Vec<_>>();
+        for conn_id in untrusted.iter() {
+            let _ = self.close_peer_conn(conn_id).await;
+        }
+        untrusted
+    }
+
+    // static keys proven by the handshake of the conns, more than one if another node
+    // claims the same peer id
+    pub fn list_public_keys(&self) -> Vec<PublicKey> {
+        let mut keys = self
+            .conns
+            .iter()
+            .map(|conn| conn.get_peer_public_key())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    // all conns of one peer are negotiated with the same configs, any of them is ok
+    pub fn get_cipher_suite(&self) -> Option<CipherSuite> {
+        self.conns.iter().next().map(|conn| conn.get_cipher_suite())
+    }
+
     pub async fn list_peer_conns(&self) -> Vec<PeerConnInfo> {
         let mut conns = vec![];
         for conn in self.conns.iter() {
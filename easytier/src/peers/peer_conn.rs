@@ -8,6 +8,7 @@ use std::{
     },
 };
 
+use anyhow::Context;
 use futures::{SinkExt, StreamExt, TryFutureExt};
 
 use prost::Message;
@@ -20,13 +21,13 @@ use tokio::{
 
 use tokio_util::sync::PollSender;
 use tracing::Instrument;
-use zerocopy::AsBytes;
 
 use crate::{
     common::{
         config::{NetworkIdentity, NetworkSecretDigest},
         error::Error,
         global_ctx::ArcGlobalCtx,
+        noise::{PublicKey, NOISE_MAX_MSG_LEN},
         PeerId,
     },
     rpc::{HandshakeRequest, PeerConnInfo, PeerConnStats, TunnelInfo},
@@ -40,12 +41,30 @@ use crate::{
     },
 };
 
-use super::{peer_conn_ping::PeerConnPinger, PacketRecvChan};
+use super::{
+    encrypt::{
+        cipher_suites_from_features, cipher_suites_to_features, negotiate_cipher_suite,
+        preferred_cipher_suites,
+        session::{RekeyConfig, SessionCipher},
+        CipherSuite,
+    },
+    peer_conn_ping::PeerConnPinger,
+    PacketRecvChan,
+};
 
 pub type PeerConnId = uuid::Uuid;
 
 const MAGIC: u32 = 0xd1e1a5e1;
-const VERSION: u32 = 1;
+const VERSION: u32 = 2;
+// announced by peers encrypting traffic with the session key
+const SESSION_ENCRYPTION_FEATURE: &str = "session-encryption";
+
+// keys derived from the noise handshake, unique for each conn
+#[derive(Clone)]
+pub struct PeerConnSessionKeys {
+    pub send_key: [u8; 32],
+    pub recv_key: [u8; 32],
+}
 
 pub struct PeerConn {
     conn_id: PeerConnId,
@@ -61,6 +80,13 @@ pub struct PeerConn {
     tasks: JoinSet<Result<(), TunnelError>>,
 
     info: Option<HandshakeRequest>,
+    peer_public_key: Option<PublicKey>,
+    session_keys: Option<PeerConnSessionKeys>,
+    cipher_suite: CipherSuite,
+    session_cipher: Option<Arc<SessionCipher>>,
+    enable_session_encryption: bool,
+    // both sides encrypt, so plaintext from the peer is rejected
+    require_session_encryption: bool,
 
     close_event_sender: Option<mpsc::Sender<PeerConnId>>,
 
@@ -107,6 +133,12 @@ impl PeerConn {
             tasks: JoinSet::new(),
 
             info: None,
+            peer_public_key: None,
+            session_keys: None,
+            cipher_suite: CipherSuite::default(),
+            session_cipher: None,
+            enable_session_encryption: false,
+            require_session_encryption: false,
             close_event_sender: None,
 
             ctrl_resp_sender: ctrl_sender,
@@ -121,7 +153,7 @@ impl PeerConn {
         self.conn_id
     }
 
-    async fn wait_handshake(&mut self, need_retry: &mut bool) -> Result<HandshakeRequest, Error> {
+    async fn wait_handshake(&mut self, need_retry: &mut bool) -> Result<Vec<u8>, Error> {
         *need_retry = false;
 
         let mut locked = self.recv.lock().await;
@@ -149,20 +181,10 @@ impl PeerConn {
             )));
         }
 
-        let rsp = HandshakeRequest::decode(rsp.payload()).map_err(|e| {
-            Error::WaitRespError(format!("decode handshake response error: {:?}", e))
-        })?;
-
-        if rsp.network_secret_digrest.len() != std::mem::size_of::<NetworkSecretDigest>() {
-            return Err(Error::WaitRespError(
-                "invalid network secret digest".to_owned(),
-            ));
-        }
-
-        return Ok(rsp);
+        return Ok(rsp.payload().to_vec());
     }
 
-    async fn wait_handshake_loop(&mut self) -> Result<HandshakeRequest, Error> {
+    async fn wait_handshake_loop(&mut self) -> Result<Vec<u8>, Error> {
         timeout(Duration::from_secs(5), async move {
             loop {
                 let mut need_retry = true;
@@ -181,52 +203,168 @@ impl PeerConn {
         .await?
     }
 
-    async fn send_handshake(&mut self) -> Result<(), Error> {
+    async fn send_handshake(&mut self, msg: &[u8]) -> Result<(), Error> {
+        let mut zc_packet = ZCPacket::new_with_payload(msg);
+        zc_packet.fill_peer_manager_hdr(
+            self.my_peer_id,
+            PeerId::default(),
+            PacketType::HandShake as u8,
+        );
+
+        self.sink.send(zc_packet).await.map_err(|e| {
+            tracing::warn!("send handshake request error: {:?}", e);
+            Error::WaitRespError("send handshake request error".to_owned())
+        })?;
+
+        Ok(())
+    }
+
+    fn build_handshake_req(&self) -> Vec<u8> {
         let network = self.global_ctx.get_network_identity();
+        let mut features = cipher_suites_to_features(&self.my_cipher_suites());
+        if self.global_ctx.get_flags().enable_encryption {
+            features.push(SESSION_ENCRYPTION_FEATURE.to_owned());
+        }
         let mut req = HandshakeRequest {
             magic: MAGIC,
             my_peer_id: self.my_peer_id,
             version: VERSION,
-            features: Vec::new(),
+            features,
             network_name: network.network_name.clone(),
             ..Default::default()
         };
         req.network_secret_digrest
             .extend_from_slice(&network.network_secret_digest.unwrap_or_default());
 
-        let hs_req = req.encode_to_vec();
-        let mut zc_packet = ZCPacket::new_with_payload(hs_req.as_bytes());
-        zc_packet.fill_peer_manager_hdr(
-            self.my_peer_id,
-            PeerId::default(),
-            PacketType::HandShake as u8,
-        );
+        req.encode_to_vec()
+    }
 
-        self.sink.send(zc_packet).await.map_err(|e| {
-            tracing::warn!("send handshake request error: {:?}", e);
-            Error::WaitRespError("send handshake request error".to_owned())
+    fn my_cipher_suites(&self) -> Vec<CipherSuite> {
+        preferred_cipher_suites(&self.global_ctx.get_flags().cipher_suite)
+    }
+
+    fn decode_handshake_req(payload: &[u8]) -> Result<HandshakeRequest, Error> {
+        let rsp = HandshakeRequest::decode(payload).map_err(|e| {
+            Error::WaitRespError(format!("decode handshake response error: {:?}", e))
         })?;
 
+        if rsp.magic != MAGIC || rsp.version != VERSION {
+            return Err(Error::WaitRespError(format!(
+                "handshake magic or version not match, magic: {:x}, version: {}",
+                rsp.magic, rsp.version
+            )));
+        }
+
+        if rsp.network_secret_digrest.len() != std::mem::size_of::<NetworkSecretDigest>() {
+            return Err(Error::WaitRespError(
+                "invalid network secret digest".to_owned(),
+            ));
+        }
+
+        Ok(rsp)
+    }
+
+    fn finish_handshake(
+        &mut self,
+        mut hs: snow::HandshakeState,
+        info: HandshakeRequest,
+    ) -> Result<(), Error> {
+        let Some(remote_static) = hs.get_remote_static() else {
+            return Err(Error::WaitRespError(
+                "no remote static key after handshake".to_owned(),
+            ));
+        };
+        let mut peer_public_key = PublicKey::default();
+        peer_public_key.copy_from_slice(remote_static);
+
+        // initiator sends with the first key, responder with the second one
+        let (k1, k2) = hs.dangerously_get_raw_split();
+        let session_keys = if hs.is_initiator() {
+            PeerConnSessionKeys {
+                send_key: k1,
+                recv_key: k2,
+            }
+        } else {
+            PeerConnSessionKeys {
+                send_key: k2,
+                recv_key: k1,
+            }
+        };
+
+        let peer_cipher_suites = cipher_suites_from_features(&info.features);
+        self.cipher_suite = negotiate_cipher_suite(&self.my_cipher_suites(), &peer_cipher_suites)
+            .unwrap_or_default();
+        tracing::info!(cipher_suite = ?self.cipher_suite, "cipher suite negotiated");
+
+        let flags = self.global_ctx.get_flags();
+        let rekey_config = RekeyConfig {
+            max_bytes: flags.rekey_after_bytes,
+            max_packets: flags.rekey_after_packets,
+            max_age: Duration::from_secs(flags.rekey_after_secs),
+            grace: Duration::from_secs(flags.rekey_grace_secs),
+        };
+        let session_cipher = SessionCipher::new(
+            self.cipher_suite,
+            session_keys.send_key,
+            session_keys.recv_key,
+            rekey_config,
+        )
+        .with_context(|| "create session cipher failed")?;
+        self.session_cipher = Some(Arc::new(session_cipher));
+        self.enable_session_encryption = flags.enable_encryption;
+        self.require_session_encryption = flags.enable_encryption
+            && info
+                .features
+                .iter()
+                .any(|f| f == SESSION_ENCRYPTION_FEATURE);
+
+        self.peer_public_key = Some(peer_public_key);
+        self.session_keys = Some(session_keys);
+        self.info = Some(info);
         Ok(())
     }
 
+    // noise xx handshake:
+    // -> e
+    // <- e, ee, s, es, HandshakeRequest of server
+    // -> s, se, HandshakeRequest of client
     #[tracing::instrument]
     pub async fn do_handshake_as_server(&mut self) -> Result<(), Error> {
-        let rsp = self.wait_handshake_loop().await?;
+        let mut hs = self.global_ctx.get_static_keypair().build_responder()?;
+        let mut buf = vec![0u8; NOISE_MAX_MSG_LEN];
+
+        let msg = self.wait_handshake_loop().await?;
+        hs.read_message(&msg, &mut buf)?;
+
+        let len = hs.write_message(&self.build_handshake_req(), &mut buf)?;
+        self.send_handshake(&buf[..len]).await?;
+
+        let msg = self.wait_handshake_loop().await?;
+        let len = hs.read_message(&msg, &mut buf)?;
+        let rsp = Self::decode_handshake_req(&buf[..len])?;
         tracing::info!("handshake request: {:?}", rsp);
-        self.info = Some(rsp);
-        self.send_handshake().await?;
-        Ok(())
+
+        self.finish_handshake(hs, rsp)
     }
 
     #[tracing::instrument]
     pub async fn do_handshake_as_client(&mut self) -> Result<(), Error> {
-        self.send_handshake().await?;
+        let mut hs = self.global_ctx.get_static_keypair().build_initiator()?;
+        let mut buf = vec![0u8; NOISE_MAX_MSG_LEN];
+
+        let len = hs.write_message(&[], &mut buf)?;
+        self.send_handshake(&buf[..len]).await?;
+
         tracing::info!("waiting for handshake request from server");
-        let rsp = self.wait_handshake_loop().await?;
+        let msg = self.wait_handshake_loop().await?;
+        let len = hs.read_message(&msg, &mut buf)?;
+        let rsp = Self::decode_handshake_req(&buf[..len])?;
         tracing::info!("handshake response: {:?}", rsp);
-        self.info = Some(rsp);
-        Ok(())
+
+        let len = hs.write_message(&self.build_handshake_req(), &mut buf)?;
+        self.send_handshake(&buf[..len]).await?;
+
+        self.finish_handshake(hs, rsp)
     }
 
     pub fn handshake_done(&self) -> bool {
@@ -240,6 +378,12 @@ impl PeerConn {
         let close_event_sender = self.close_event_sender.clone().unwrap();
         let conn_id = self.conn_id;
         let ctrl_sender = self.ctrl_resp_sender.clone();
+        let session_cipher = self.session_cipher.clone();
+        let pong_cipher = self.get_send_session_cipher();
+        let require_session_encryption = self.require_session_encryption;
+        let global_ctx = self.global_ctx.clone();
+        let my_peer_id = self.my_peer_id;
+        let peer_id = self.get_peer_id();
         let _conn_info = self.get_conn_info();
         let conn_info_for_instrument = self.get_conn_info();
 
@@ -255,6 +399,32 @@ impl PeerConn {
                     }
 
                     let mut zc_packet = ret.unwrap();
+
+                    let is_session_encrypted = zc_packet
+                        .peer_manager_header()
+                        .map(|hdr| hdr.is_session_encrypted())
+                        .unwrap_or(false);
+                    if is_session_encrypted {
+                        let Some(session_cipher) = &session_cipher else {
+                            tracing::error!("no session cipher for session encrypted packet");
+                            continue;
+                        };
+                        if let Err(e) = session_cipher.decrypt(&mut zc_packet) {
+                            tracing::warn!(?e, "session decrypt failed");
+                            continue;
+                        }
+                    } else if require_session_encryption
+                        && !zc_packet
+                            .peer_manager_header()
+                            .map(|hdr| hdr.is_encrypted())
+                            .unwrap_or(false)
+                    {
+                        // packets not for the peer are encrypted end to end, anything else
+                        // in plaintext may be injected by an attacker on the path.
+                        tracing::warn!("drop plaintext packet on session encrypted conn");
+                        continue;
+                    }
+
                     let Some(peer_mgr_hdr) = zc_packet.mut_peer_manager_header() else {
                         tracing::error!(
                             "unexpected packet: {:?}, cannot decode peer manager hdr",
@@ -263,8 +433,30 @@ impl PeerConn {
                         continue;
                     };
 
+                    // the session only proves the identity of the direct peer, relayed packets
+                    // prove the sender with the pairwise key when trusted keys are configured.
+                    if peer_mgr_hdr.to_peer_id.get() == my_peer_id
+                        && peer_mgr_hdr.from_peer_id.get() != peer_id
+                        && !peer_mgr_hdr.is_pairwise_encrypted()
+                        && global_ctx.is_trust_enforced()
+                    {
+                        tracing::warn!(
+                            from_peer_id = peer_mgr_hdr.from_peer_id.get(),
+                            "drop relayed packet without pairwise encryption"
+                        );
+                        continue;
+                    }
+                    let from_direct_peer = peer_mgr_hdr.from_peer_id.get() == peer_id;
+                    peer_mgr_hdr.set_sender_authenticated(from_direct_peer);
+
                     if peer_mgr_hdr.packet_type == PacketType::Ping as u8 {
                         peer_mgr_hdr.packet_type = PacketType::Pong as u8;
+                        if let Some(cipher) = &pong_cipher {
+                            if let Err(e) = cipher.encrypt(&mut zc_packet) {
+                                tracing::error!(?e, "session encrypt pong error");
+                                continue;
+                            }
+                        }
                         if let Err(e) = sink.send(zc_packet).await {
                             tracing::error!(?e, "peer conn send req error");
                         }
@@ -302,6 +494,7 @@ impl PeerConn {
             self.ctrl_resp_sender.clone(),
             self.latency_stats.clone(),
             self.loss_rate_stats.clone(),
+            self.get_send_session_cipher(),
         );
 
         let close_event_sender = self.close_event_sender.clone().unwrap();
@@ -320,7 +513,25 @@ impl PeerConn {
         });
     }
 
-    pub async fn send_msg(&self, msg: ZCPacket) -> Result<(), Error> {
+    fn get_send_session_cipher(&self) -> Option<Arc<SessionCipher>> {
+        self.session_cipher
+            .clone()
+            .filter(|_| self.enable_session_encryption)
+    }
+
+    pub async fn send_msg(&self, mut msg: ZCPacket) -> Result<(), Error> {
+        if let Some(session_cipher) = &self.session_cipher {
+            // packets to other peers are encrypted end to end by peer manager
+            let need_encrypt = msg
+                .peer_manager_header()
+                .map(|hdr| !hdr.is_encrypted() && hdr.to_peer_id.get() == self.get_peer_id())
+                .unwrap_or(false);
+            if self.enable_session_encryption && need_encrypt {
+                session_cipher
+                    .encrypt(&mut msg)
+                    .with_context(|| "session encrypt failed")?;
+            }
+        }
         Ok(self.sink.send(msg).await?)
     }
 
@@ -328,6 +539,18 @@ impl PeerConn {
         self.info.as_ref().unwrap().my_peer_id
     }
 
+    pub fn get_peer_public_key(&self) -> PublicKey {
+        self.peer_public_key.unwrap()
+    }
+
+    pub fn get_session_keys(&self) -> PeerConnSessionKeys {
+        self.session_keys.clone().unwrap()
+    }
+
+    pub fn get_cipher_suite(&self) -> CipherSuite {
+        self.cipher_suite
+    }
+
     pub fn get_network_identity(&self) -> NetworkIdentity {
         let info = self.info.as_ref().unwrap();
         let mut ret = NetworkIdentity {
@@ -358,6 +581,10 @@ impl PeerConn {
         }
     }
 
+    pub fn get_loss_rate(&self) -> f32 {
+        (f64::from(self.loss_rate_stats.load(Ordering::Relaxed)) / 100.0) as f32
+    }
+
     pub fn get_conn_info(&self) -> PeerConnInfo {
         PeerConnInfo {
             conn_id: self.conn_id.to_string(),
@@ -366,7 +593,7 @@ impl PeerConn {
             features: self.info.as_ref().unwrap().features.clone(),
             tunnel: self.tunnel_info.clone(),
             stats: Some(self.get_stats()),
-            loss_rate: (f64::from(self.loss_rate_stats.load(Ordering::Relaxed)) / 100.0) as f32,
+            loss_rate: self.get_loss_rate(),
         }
     }
 }
@@ -395,9 +622,12 @@ mod tests {
         let c_peer_id = new_peer_id();
         let s_peer_id = new_peer_id();
 
-        let mut c_peer = PeerConn::new(c_peer_id, get_mock_global_ctx(), Box::new(c));
+        let c_ctx = get_mock_global_ctx();
+        let s_ctx = get_mock_global_ctx();
 
-        let mut s_peer = PeerConn::new(s_peer_id, get_mock_global_ctx(), Box::new(s));
+        let mut c_peer = PeerConn::new(c_peer_id, c_ctx.clone(), Box::new(c));
+
+        let mut s_peer = PeerConn::new(s_peer_id, s_ctx.clone(), Box::new(s));
 
         let (c_ret, s_ret) = tokio::join!(
             c_peer.do_handshake_as_client(),
@@ -407,16 +637,168 @@ mod tests {
         c_ret.unwrap();
         s_ret.unwrap();
 
-        assert_eq!(c_recorder.sent.lock().unwrap().len(), 1);
+        assert_eq!(c_recorder.sent.lock().unwrap().len(), 2);
         assert_eq!(c_recorder.received.lock().unwrap().len(), 1);
 
         assert_eq!(s_recorder.sent.lock().unwrap().len(), 1);
-        assert_eq!(s_recorder.received.lock().unwrap().len(), 1);
+        assert_eq!(s_recorder.received.lock().unwrap().len(), 2);
 
         assert_eq!(c_peer.get_peer_id(), s_peer_id);
         assert_eq!(s_peer.get_peer_id(), c_peer_id);
         assert_eq!(c_peer.get_network_identity(), s_peer.get_network_identity());
         assert_eq!(c_peer.get_network_identity(), NetworkIdentity::default());
+
+        assert_eq!(
+            &c_peer.get_peer_public_key(),
+            s_ctx.get_static_keypair().public_key()
+        );
+        assert_eq!(
+            &s_peer.get_peer_public_key(),
+            c_ctx.get_static_keypair().public_key()
+        );
+
+        let c_keys = c_peer.get_session_keys();
+        let s_keys = s_peer.get_session_keys();
+        assert_eq!(c_keys.send_key, s_keys.recv_key);
+        assert_eq!(c_keys.recv_key, s_keys.send_key);
+        assert_ne!(c_keys.send_key, c_keys.recv_key);
+
+        assert_eq!(c_peer.get_cipher_suite(), s_peer.get_cipher_suite());
+    }
+
+    #[tokio::test]
+    async fn peer_conn_negotiate_cipher_suite() {
+        let (c, s) = create_ring_tunnel_pair();
+
+        let set_cipher_suite = |ctx: &ArcGlobalCtx, suite: &str| {
+            let mut flags = ctx.get_flags();
+            flags.cipher_suite = suite.to_owned();
+            ctx.config.set_flags(flags);
+        };
+
+        let c_ctx = get_mock_global_ctx();
+        set_cipher_suite(&c_ctx, "chacha20-poly1305");
+        let s_ctx = get_mock_global_ctx();
+        set_cipher_suite(&s_ctx, "aes-gcm");
+
+        let mut c_peer = PeerConn::new(new_peer_id(), c_ctx, Box::new(c));
+        let mut s_peer = PeerConn::new(new_peer_id(), s_ctx, Box::new(s));
+
+        let (c_ret, s_ret) = tokio::join!(
+            c_peer.do_handshake_as_client(),
+            s_peer.do_handshake_as_server()
+        );
+        c_ret.unwrap();
+        s_ret.unwrap();
+
+        let expected = if cfg!(feature = "chacha20") {
+            CipherSuite::ChaCha20Poly1305
+        } else {
+            CipherSuite::AesGcm
+        };
+        assert_eq!(c_peer.get_cipher_suite(), expected);
+        assert_eq!(s_peer.get_cipher_suite(), expected);
+    }
+
+    #[tokio::test]
+    async fn peer_conn_session_encryption() {
+        let (c, s) = create_ring_tunnel_pair();
+
+        let c_recorder = Arc::new(PacketRecorderTunnelFilter::new());
+        let c = TunnelWithFilter::new(c, c_recorder.clone());
+
+        let c_peer_id = new_peer_id();
+        let s_peer_id = new_peer_id();
+        let mut c_peer = PeerConn::new(c_peer_id, get_mock_global_ctx(), Box::new(c));
+        let mut s_peer = PeerConn::new(s_peer_id, get_mock_global_ctx(), Box::new(s));
+
+        let (c_ret, s_ret) = tokio::join!(
+            c_peer.do_handshake_as_client(),
+            s_peer.do_handshake_as_server()
+        );
+        c_ret.unwrap();
+        s_ret.unwrap();
+
+        let (s_sender, mut s_receiver) = tokio::sync::mpsc::channel(200);
+        s_peer.set_close_event_sender(tokio::sync::mpsc::channel(1).0);
+        s_peer.start_recv_loop(s_sender).await;
+
+        let text = b"hello session";
+        let mut packet = ZCPacket::new_with_payload(text);
+        packet.fill_peer_manager_hdr(c_peer_id, s_peer_id, PacketType::Data as u8);
+        c_peer.send_msg(packet).await.unwrap();
+
+        let sent = c_recorder.received.lock().unwrap().last().unwrap().clone();
+        let hdr = sent.peer_manager_header().unwrap();
+        assert!(hdr.is_session_encrypted());
+        assert!(hdr.is_encrypted());
+        assert_ne!(sent.payload(), text);
+
+        let recved = timeout(Duration::from_secs(1), s_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let hdr = recved.peer_manager_header().unwrap();
+        assert!(!hdr.is_session_encrypted());
+        assert!(!hdr.is_encrypted());
+        assert_eq!(recved.payload(), text);
+
+        // replayed and plaintext packets are dropped
+        c_peer.sink.send(sent).await.unwrap();
+        let mut packet = ZCPacket::new_with_payload(text);
+        packet.fill_peer_manager_hdr(c_peer_id, s_peer_id, PacketType::Data as u8);
+        c_peer.sink.send(packet).await.unwrap();
+        assert!(timeout(Duration::from_millis(500), s_receiver.recv())
+            .await
+            .is_err());
+    }
+
+    struct TamperSendTunnelFilter {
+        tamper_at: u32,
+        cur: AtomicU32,
+    }
+
+    impl TamperSendTunnelFilter {
+        fn new(tamper_at: u32) -> Self {
+            Self {
+                tamper_at,
+                cur: AtomicU32::new(0),
+            }
+        }
+    }
+
+    impl TunnelFilter for TamperSendTunnelFilter {
+        type FilterOutput = ();
+
+        fn before_send(&self, mut data: ZCPacket) -> Option<ZCPacket> {
+            if self.cur.fetch_add(1, Ordering::SeqCst) + 1 == self.tamper_at {
+                if let Some(b) = data.mut_payload().last_mut() {
+                    *b ^= 0xff;
+                }
+            }
+            Some(data)
+        }
+
+        fn filter_output(&self) {}
+    }
+
+    #[tokio::test]
+    async fn peer_conn_handshake_with_tampered_msg() {
+        let (c, s) = create_ring_tunnel_pair();
+
+        // flip the last byte of the final handshake msg sent by client
+        let c = TunnelWithFilter::new(c, TamperSendTunnelFilter::new(2));
+
+        let mut c_peer = PeerConn::new(new_peer_id(), get_mock_global_ctx(), Box::new(c));
+        let mut s_peer = PeerConn::new(new_peer_id(), get_mock_global_ctx(), Box::new(s));
+
+        let (_c_ret, s_ret) = tokio::join!(
+            c_peer.do_handshake_as_client(),
+            s_peer.do_handshake_as_server()
+        );
+
+        assert!(s_ret.is_err());
+        assert!(!s_peer.handshake_done());
     }
 
     async fn peer_conn_pingpong_test_common(drop_start: u32, drop_end: u32, conn_closed: bool) {
@@ -10,6 +10,7 @@ use tokio::{sync::broadcast, task::JoinSet, time::timeout};
 
 use crate::{
     common::{error::Error, PeerId},
+    peers::encrypt::session::SessionCipher,
     tunnel::{
         mpsc::MpscTunnelSender,
         packet_def::{PacketType, ZCPacket},
@@ -25,6 +26,8 @@ pub struct PeerConnPinger {
     ctrl_sender: broadcast::Sender<ZCPacket>,
     latency_stats: Arc<WindowLatency>,
     loss_rate_stats: Arc<AtomicU32>,
+    // pings are encrypted like other packets to the peer
+    session_cipher: Option<Arc<SessionCipher>>,
     tasks: JoinSet<Result<(), TunnelError>>,
 }
 
@@ -45,6 +48,7 @@ impl PeerConnPinger {
         ctrl_sender: broadcast::Sender<ZCPacket>,
         latency_stats: Arc<WindowLatency>,
         loss_rate_stats: Arc<AtomicU32>,
+        session_cipher: Option<Arc<SessionCipher>>,
     ) -> Self {
         Self {
             my_peer_id,
@@ -54,6 +58,7 @@ impl PeerConnPinger {
             latency_stats,
             ctrl_sender,
             loss_rate_stats,
+            session_cipher,
         }
     }
 
@@ -69,9 +74,15 @@ impl PeerConnPinger {
         sink: &mut MpscTunnelSender,
         receiver: &mut broadcast::Receiver<ZCPacket>,
         seq: u32,
+        session_cipher: Option<&SessionCipher>,
     ) -> Result<u128, Error> {
         // should add seq here. so latency can be calculated more accurately
-        let req = Self::new_ping_packet(my_node_id, peer_id, seq);
+        let mut req = Self::new_ping_packet(my_node_id, peer_id, seq);
+        if let Some(cipher) = session_cipher {
+            cipher
+                .encrypt(&mut req)
+                .map_err(|e| Error::WaitRespError(format!("encrypt ping error: {:?}", e)))?;
+        }
         sink.send(req).await?;
 
         let now = std::time::Instant::now();
@@ -122,6 +133,7 @@ impl PeerConnPinger {
         let my_node_id = self.my_peer_id;
         let peer_id = self.peer_id;
         let latency_stats = self.latency_stats.clone();
+        let session_cipher = self.session_cipher.clone();
 
         let (ping_res_sender, mut ping_res_receiver) = tokio::sync::mpsc::channel(100);
 
@@ -146,6 +158,7 @@ impl PeerConnPinger {
                 }
 
                 let mut sink = sink.clone();
+                let session_cipher = session_cipher.clone();
                 pingpong_tasks.spawn(async move {
                     let mut receiver = receiver.resubscribe();
                     let pingpong_once_ret = Self::do_pingpong_once(
@@ -154,6 +167,7 @@ impl PeerConnPinger {
                         &mut sink,
                         &mut receiver,
                         req_seq,
+                        session_cipher.as_deref(),
                     )
                     .await;
 
@@ -0,0 +1,400 @@
+// policies to choose which peer conn is used to send packets when we have multiple
+// conns (e.g. udp and tcp) to the same peer.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+use crossbeam::atomic::AtomicCell;
+
+use crate::common::config::ConnSelectPolicy;
+
+use super::peer_conn::PeerConnId;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnMetric {
+    pub conn_id: PeerConnId,
+    // 0 means no latency sample yet
+    pub latency_us: u64,
+    // 0.0 ~ 1.0
+    pub loss_rate: f32,
+}
+
+impl ConnMetric {
+    fn latency_for_compare(&self) -> u64 {
+        if self.latency_us == 0 {
+            u64::MAX
+        } else {
+            self.latency_us
+        }
+    }
+}
+
+pub trait PeerConnSelector: Send + Sync {
+    // called periodically and when conns are added or removed, with metrics of all conns.
+    fn update(&self, metrics: &[ConnMetric]);
+    // called for every packet, so it must be cheap.
+    fn select(&self) -> Option<PeerConnId>;
+}
+
+pub fn create_conn_selector(
+    policy: ConnSelectPolicy,
+    failover_loss_rate: f32,
+) -> Box<dyn PeerConnSelector> {
+    match policy {
+        ConnSelectPolicy::Sticky => Box::new(StickySelector::new(failover_loss_rate)),
+        ConnSelectPolicy::LowestLatency => Box::new(LowestLatencySelector::new(failover_loss_rate)),
+        ConnSelectPolicy::LowestLoss => Box::new(LowestLossSelector::new(failover_loss_rate)),
+        ConnSelectPolicy::WeightedRoundRobin => {
+            Box::new(WeightedRoundRobinSelector::new(failover_loss_rate))
+        }
+    }
+}
+
+// conns with loss rate above the threshold are not used, unless all of them are.
+fn usable_conns(metrics: &[ConnMetric], failover_loss_rate: f32) -> Vec<&ConnMetric> {
+    let ret: Vec<_> = metrics
+        .iter()
+        .filter(|m| m.loss_rate <= failover_loss_rate)
+        .collect();
+    if ret.is_empty() {
+        metrics.iter().collect()
+    } else {
+        ret
+    }
+}
+
+fn find_lowest_loss(metrics: &[&ConnMetric]) -> Option<PeerConnId> {
+    metrics
+        .iter()
+        .min_by(|a, b| {
+            a.loss_rate
+                .total_cmp(&b.loss_rate)
+                .then(a.latency_for_compare().cmp(&b.latency_for_compare()))
+        })
+        .map(|m| m.conn_id)
+}
+
+pub struct StickySelector {
+    failover_loss_rate: f32,
+    current: AtomicCell<Option<PeerConnId>>,
+}
+
+impl StickySelector {
+    pub fn new(failover_loss_rate: f32) -> Self {
+        Self {
+            failover_loss_rate,
+            current: AtomicCell::new(None),
+        }
+    }
+}
+
+impl PeerConnSelector for StickySelector {
+    fn update(&self, metrics: &[ConnMetric]) {
+        let current = self.current.load();
+        let keep = metrics
+            .iter()
+            .any(|m| Some(m.conn_id) == current && m.loss_rate <= self.failover_loss_rate);
+        if keep {
+            return;
+        }
+
+        let usable = usable_conns(metrics, self.failover_loss_rate);
+        // first time, just use the first conn, like what we did before
+        let next = if current.is_none() {
+            usable.first().map(|m| m.conn_id)
+        } else {
+            find_lowest_loss(&usable)
+        };
+        if next != current {
+            tracing::info!(?current, ?next, "sticky selector switch conn");
+        }
+        self.current.store(next);
+    }
+
+    fn select(&self) -> Option<PeerConnId> {
+        self.current.load()
+    }
+}
+
+// only switch to a new conn if it's better than current one by this ratio, avoid flapping.
+const LATENCY_SWITCH_RATIO: f64 = 0.9;
+
+pub struct LowestLatencySelector {
+    failover_loss_rate: f32,
+    current: AtomicCell<Option<PeerConnId>>,
+}
+
+impl LowestLatencySelector {
+    pub fn new(failover_loss_rate: f32) -> Self {
+        Self {
+            failover_loss_rate,
+            current: AtomicCell::new(None),
+        }
+    }
+}
+
+impl PeerConnSelector for LowestLatencySelector {
+    fn update(&self, metrics: &[ConnMetric]) {
+        let usable = usable_conns(metrics, self.failover_loss_rate);
+        let Some(best) = usable.iter().min_by_key(|m| m.latency_for_compare()) else {
+            self.current.store(None);
+            return;
+        };
+
+        let current = self.current.load();
+        if let Some(cur) = usable.iter().find(|m| Some(m.conn_id) == current) {
+            let best_lat = best.latency_for_compare() as f64;
+            let cur_lat = cur.latency_for_compare() as f64;
+            if best_lat >= cur_lat * LATENCY_SWITCH_RATIO {
+                return;
+            }
+        }
+
+        if Some(best.conn_id) != current {
+            tracing::debug!(?current, next = ?best, "lowest latency selector switch conn");
+        }
+        self.current.store(Some(best.conn_id));
+    }
+
+    fn select(&self) -> Option<PeerConnId> {
+        self.current.load()
+    }
+}
+
+// same as above, tolerate small loss difference unless current conn exceeds the threshold.
+const LOSS_SWITCH_DIFF: f32 = 0.05;
+
+pub struct LowestLossSelector {
+    failover_loss_rate: f32,
+    current: AtomicCell<Option<PeerConnId>>,
+}
+
+impl LowestLossSelector {
+    pub fn new(failover_loss_rate: f32) -> Self {
+        Self {
+            failover_loss_rate,
+            current: AtomicCell::new(None),
+        }
+    }
+}
+
+impl PeerConnSelector for LowestLossSelector {
+    fn update(&self, metrics: &[ConnMetric]) {
+        let all = metrics.iter().collect::<Vec<_>>();
+        let Some(best) = find_lowest_loss(&all) else {
+            self.current.store(None);
+            return;
+        };
+        let best_loss = metrics
+            .iter()
+            .find(|m| m.conn_id == best)
+            .unwrap()
+            .loss_rate;
+
+        let current = self.current.load();
+        if let Some(cur) = metrics.iter().find(|m| Some(m.conn_id) == current) {
+            if cur.loss_rate <= self.failover_loss_rate
+                && cur.loss_rate <= best_loss + LOSS_SWITCH_DIFF
+            {
+                return;
+            }
+        }
+
+        if Some(best) != current {
+            tracing::debug!(?current, next = ?best, "lowest loss selector switch conn");
+        }
+        self.current.store(Some(best));
+    }
+
+    fn select(&self) -> Option<PeerConnId> {
+        self.current.load()
+    }
+}
+
+const MAX_CONN_WEIGHT: u64 = 10;
+
+// distribute packets across all usable conns, weight is inversely proportional to latency.
+pub struct WeightedRoundRobinSelector {
+    failover_loss_rate: f32,
+    schedule: Mutex<Vec<PeerConnId>>,
+    counter: AtomicUsize,
+}
+
+impl WeightedRoundRobinSelector {
+    pub fn new(failover_loss_rate: f32) -> Self {
+        Self {
+            failover_loss_rate,
+            schedule: Mutex::new(Vec::new()),
+            counter: AtomicUsize::new(0),
+        }
+    }
+
+    fn calc_weights(metrics: &[&ConnMetric]) -> Vec<(PeerConnId, u64)> {
+        let min_latency = metrics
+            .iter()
+            .filter(|m| m.latency_us > 0)
+            .map(|m| m.latency_us)
+            .min();
+
+        metrics
+            .iter()
+            .map(|m| {
+                let weight = match min_latency {
+                    Some(min) if m.latency_us > 0 => {
+                        (MAX_CONN_WEIGHT * min / m.latency_us).clamp(1, MAX_CONN_WEIGHT)
+                    }
+                    _ => 1,
+                };
+                (m.conn_id, weight)
+            })
+            .collect()
+    }
+
+    // smooth weighted round robin, so conns are interleaved instead of used in bursts.
+    fn build_schedule(weights: &[(PeerConnId, u64)]) -> Vec<PeerConnId> {
+        let total: i64 = weights.iter().map(|(_, w)| *w as i64).sum();
+        let mut current = vec![0i64; weights.len()];
+        let mut ret = Vec::with_capacity(total as usize);
+        for _ in 0..total {
+            let mut best = 0;
+            for (i, (_, w)) in weights.iter().enumerate() {
+                current[i] += *w as i64;
+                if current[i] > current[best] {
+                    best = i;
+                }
+            }
+            current[best] -= total;
+            ret.push(weights[best].0);
+        }
+        ret
+    }
+}
+
+impl PeerConnSelector for WeightedRoundRobinSelector {
+    fn update(&self, metrics: &[ConnMetric]) {
+        let usable = usable_conns(metrics, self.failover_loss_rate);
+        let schedule = Self::build_schedule(&Self::calc_weights(&usable));
+        *self.schedule.lock().unwrap() = schedule;
+    }
+
+    fn select(&self) -> Option<PeerConnId> {
+        let schedule = self.schedule.lock().unwrap();
+        if schedule.is_empty() {
+            return None;
+        }
+        let idx = self.counter.fetch_add(1, Ordering::Relaxed) % schedule.len();
+        Some(schedule[idx])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(latency_us: u64, loss_rate: f32) -> ConnMetric {
+        ConnMetric {
+            conn_id: PeerConnId::new_v4(),
+            latency_us,
+            loss_rate,
+        }
+    }
+
+    #[test]
+    fn sticky_failover() {
+        let selector = StickySelector::new(0.2);
+        let mut metrics = vec![metric(5000, 0.0), metric(1000, 0.0)];
+        selector.update(&metrics);
+        assert_eq!(selector.select(), Some(metrics[0].conn_id));
+
+        // better latency does not make sticky selector switch
+        selector.update(&metrics);
+        assert_eq!(selector.select(), Some(metrics[0].conn_id));
+
+        metrics[0].loss_rate = 0.5;
+        selector.update(&metrics);
+        assert_eq!(selector.select(), Some(metrics[1].conn_id));
+    }
+
+    #[test]
+    fn lowest_latency() {
+        let selector = LowestLatencySelector::new(0.2);
+        let mut metrics = vec![metric(5000, 0.0), metric(1000, 0.0), metric(0, 0.0)];
+        selector.update(&metrics);
+        assert_eq!(selector.select(), Some(metrics[1].conn_id));
+
+        // not better enough, keep current
+        metrics[0].latency_us = 950;
+        selector.update(&metrics);
+        assert_eq!(selector.select(), Some(metrics[1].conn_id));
+
+        metrics[0].latency_us = 500;
+        selector.update(&metrics);
+        assert_eq!(selector.select(), Some(metrics[0].conn_id));
+
+        // failover when loss exceeds threshold
+        metrics[0].loss_rate = 0.3;
+        selector.update(&metrics);
+        assert_eq!(selector.select(), Some(metrics[1].conn_id));
+
+        selector.update(&[]);
+        assert_eq!(selector.select(), None);
+    }
+
+    #[test]
+    fn lowest_loss() {
+        let selector = LowestLossSelector::new(0.2);
+        let mut metrics = vec![metric(5000, 0.1), metric(1000, 0.03)];
+        selector.update(&metrics);
+        assert_eq!(selector.select(), Some(metrics[1].conn_id));
+
+        metrics[0].loss_rate = 0.0;
+        selector.update(&metrics);
+        assert_eq!(selector.select(), Some(metrics[1].conn_id));
+
+        metrics[1].loss_rate = 0.2;
+        selector.update(&metrics);
+        assert_eq!(selector.select(), Some(metrics[0].conn_id));
+
+        // small difference, but current conn exceeds the threshold
+        let selector = LowestLossSelector::new(0.05);
+        let mut metrics = vec![metric(1000, 0.0), metric(1000, 0.01)];
+        selector.update(&metrics);
+        assert_eq!(selector.select(), Some(metrics[0].conn_id));
+        metrics[0].loss_rate = 0.08;
+        metrics[1].loss_rate = 0.04;
+        selector.update(&metrics);
+        assert_eq!(selector.select(), Some(metrics[1].conn_id));
+    }
+
+    #[test]
+    fn weighted_round_robin() {
+        let selector = WeightedRoundRobinSelector::new(0.2);
+        let metrics = vec![
+            metric(1000, 0.0),
+            metric(2000, 0.0),
+            metric(0, 0.0),
+            metric(1000, 0.5),
+        ];
+        selector.update(&metrics);
+
+        let mut counts = std::collections::HashMap::new();
+        let mut last = None;
+        let mut max_burst = 0;
+        let mut burst = 0;
+        for _ in 0..160 {
+            let id = selector.select().unwrap();
+            *counts.entry(id).or_insert(0) += 1;
+            burst = if Some(id) == last { burst + 1 } else { 1 };
+            max_burst = std::cmp::max(max_burst, burst);
+            last = Some(id);
+        }
+
+        assert_eq!(counts[&metrics[0].conn_id], 100);
+        assert_eq!(counts[&metrics[1].conn_id], 50);
+        assert_eq!(counts[&metrics[2].conn_id], 10);
+        assert!(!counts.contains_key(&metrics[3].conn_id));
+        assert!(max_burst <= 2);
+    }
+}
@@ -1,11 +1,12 @@
 use std::{
     fmt::Debug,
-    net::Ipv4Addr,
+    net::{Ipv4Addr, Ipv6Addr},
     sync::{Arc, Weak},
 };
 
 use anyhow::Context;
 use async_trait::async_trait;
+use crossbeam::atomic::AtomicCell;
 
 use futures::StreamExt;
 
@@ -20,7 +21,7 @@ use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::bytes::Bytes;
 
 use crate::{
-    common::{error::Error, global_ctx::ArcGlobalCtx, PeerId},
+    common::{error::Error, global_ctx::ArcGlobalCtx, noise::encode_key, PeerId},
     peers::{
         peer_conn::PeerConn, peer_rpc::PeerRpcManagerTransport, route_trait::RouteInterface,
         PeerPacketFilter,
@@ -33,9 +34,10 @@ use crate::{
 };
 
 use super::{
-    encrypt::{Encryptor, NullCipher},
+    encrypt::{pairwise::PairwiseCiphers, CipherSet, Encryptor, NullCipher},
     foreign_network_client::ForeignNetworkClient,
     foreign_network_manager::ForeignNetworkManager,
+    igmp_snooping::IgmpSnooping,
     peer_conn::PeerConnId,
     peer_map::PeerMap,
     peer_ospf_route::PeerRoute,
@@ -54,6 +56,37 @@ struct RpcTransport {
     peer_rpc_tspt_sender: UnboundedSender<ZCPacket>,
 
     encryptor: Arc<Box<dyn Encryptor>>,
+    pairwise_ciphers: Arc<PairwiseCiphers>,
+}
+
+// packets sent to a direct peer are encrypted by peer conn with its session key, others are
+// encrypted end to end with the pairwise key if the dst advertises its public keys, or with
+// the network key. only direct peers have negotiated cipher suite with us, the default one
+// is used for other peers because it is known by everyone.
+async fn encrypt_for_peer(
+    encryptor: &dyn Encryptor,
+    pairwise_ciphers: &PairwiseCiphers,
+    peers: &PeerMap,
+    dst_peer_id: PeerId,
+    gateway_id: Option<PeerId>,
+    msg: &mut ZCPacket,
+) -> Result<(), Error> {
+    if gateway_id == Some(dst_peer_id) {
+        return Ok(());
+    }
+    let suite = peers.get_peer_cipher_suite(dst_peer_id).unwrap_or_default();
+    if let Some(peer_keys) = peers.get_peer_public_keys(dst_peer_id).await {
+        if pairwise_ciphers.should_encrypt(&peer_keys) {
+            pairwise_ciphers
+                .encrypt(dst_peer_id, &peer_keys, msg, suite)
+                .with_context(|| "pairwise encrypt failed")?;
+            return Ok(());
+        }
+    }
+    encryptor
+        .encrypt_with_suite(msg, suite)
+        .with_context(|| "encrypt failed")?;
+    Ok(())
 }
 
 #[async_trait::async_trait]
@@ -80,16 +113,28 @@ impl PeerRpcManagerTransport for RpcTransport {
                 ?self.my_peer_id,
                 "send msg to peer via gateway",
             );
-            self.encryptor
-                .encrypt(&mut msg)
-                .with_context(|| "encrypt failed")?;
+            encrypt_for_peer(
+                &**self.encryptor,
+                &self.pairwise_ciphers,
+                &peers,
+                dst_peer_id,
+                Some(gateway_id),
+                &mut msg,
+            )
+            .await?;
             peers.send_msg_directly(msg, gateway_id).await
         } else if foreign_peers.has_next_hop(dst_peer_id) {
             if !foreign_peers.is_peer_public_node(&dst_peer_id) {
                 // do not encrypt for msg sending to public node
-                self.encryptor
-                    .encrypt(&mut msg)
-                    .with_context(|| "encrypt failed")?;
+                encrypt_for_peer(
+                    &**self.encryptor,
+                    &self.pairwise_ciphers,
+                    &peers,
+                    dst_peer_id,
+                    None,
+                    &mut msg,
+                )
+                .await?;
             }
             tracing::debug!(
                 ?dst_peer_id,
@@ -143,13 +188,22 @@ pub struct PeerManager {
 
     peer_packet_process_pipeline: Arc<RwLock<Vec<BoxPeerPacketFilter>>>,
     nic_packet_process_pipeline: Arc<RwLock<Vec<BoxNicPacketFilter>>>,
+    // executed before all normal pipelines no matter when they are added, e.g. acl
+    priority_peer_packet_process_pipeline: Arc<RwLock<Vec<BoxPeerPacketFilter>>>,
+    priority_nic_packet_process_pipeline: Arc<RwLock<Vec<BoxNicPacketFilter>>>,
 
     route_algo_inst: RouteAlgoInst,
 
     foreign_network_manager: Arc<ForeignNetworkManager>,
     foreign_network_client: Arc<ForeignNetworkClient>,
 
+    igmp_snooping: Arc<IgmpSnooping>,
+
     encryptor: Arc<Box<dyn Encryptor>>,
+    pairwise_ciphers: Arc<PairwiseCiphers>,
+
+    // peer to send ipv4 traffic without a route to, set by the exit node manager of instance
+    exit_node: AtomicCell<Option<PeerId>>,
 }
 
 impl Debug for PeerManager {
@@ -179,24 +233,14 @@ impl PeerManager {
 
         let mut encryptor: Arc<Box<dyn Encryptor>> = Arc::new(Box::new(NullCipher));
         if global_ctx.get_flags().enable_encryption {
-            #[cfg(feature = "wireguard")]
-            {
-                use super::encrypt::ring_aes_gcm::AesGcmCipher;
-                encryptor = Arc::new(Box::new(AesGcmCipher::new_128(global_ctx.get_128_key())));
-            }
-
-            #[cfg(all(feature = "aes-gcm", not(feature = "wireguard")))]
-            {
-                use super::encrypt::aes_gcm::AesGcmCipher;
-                encryptor = Arc::new(Box::new(AesGcmCipher::new_128(global_ctx.get_128_key())));
-            }
-
-            #[cfg(all(not(feature = "wireguard"), not(feature = "aes-gcm")))]
-            {
-                compile_error!("wireguard or aes-gcm feature must be enabled for encryption");
-            }
+            encryptor = Arc::new(Box::new(CipherSet::new(
+                global_ctx.get_128_key(),
+                global_ctx.get_256_key(),
+            )));
         }
 
+        let pairwise_ciphers = Arc::new(PairwiseCiphers::new(global_ctx.clone()));
+
         // TODO: remove these because we have impl pipeline processor.
         let (peer_rpc_tspt_sender, peer_rpc_tspt_recv) = mpsc::unbounded_channel();
         let rpc_tspt = Arc::new(RpcTransport {
@@ -206,6 +250,7 @@ impl PeerManager {
             packet_recv: Mutex::new(peer_rpc_tspt_recv),
             peer_rpc_tspt_sender,
             encryptor: encryptor.clone(),
+            pairwise_ciphers: pairwise_ciphers.clone(),
         });
         let peer_rpc_mgr = Arc::new(PeerRpcManager::new(rpc_tspt.clone()));
 
@@ -233,6 +278,12 @@ impl PeerManager {
             my_peer_id,
         ));
 
+        let igmp_snooping = Arc::new(IgmpSnooping::new(
+            my_peer_id,
+            global_ctx.clone(),
+            nic_channel.clone(),
+        ));
+
         PeerManager {
             my_peer_id,
 
@@ -250,13 +301,20 @@ impl PeerManager {
 
             peer_packet_process_pipeline: Arc::new(RwLock::new(Vec::new())),
             nic_packet_process_pipeline: Arc::new(RwLock::new(Vec::new())),
+            priority_peer_packet_process_pipeline: Arc::new(RwLock::new(Vec::new())),
+            priority_nic_packet_process_pipeline: Arc::new(RwLock::new(Vec::new())),
 
             route_algo_inst,
 
             foreign_network_manager,
             foreign_network_client,
 
+            igmp_snooping,
+
             encryptor,
+            pairwise_ciphers,
+
+            exit_node: AtomicCell::new(None),
         }
     }
 
@@ -266,9 +324,18 @@ impl PeerManager {
                 "network identity not match".to_string(),
             ));
         }
+        let peer_public_key = peer_conn.get_peer_public_key();
+        if !self.global_ctx.is_public_key_trusted(&peer_public_key) {
+            return Err(Error::UntrustedPublicKey(encode_key(&peer_public_key)));
+        }
         Ok(self.peers.add_new_peer_conn(peer_conn).await)
     }
 
+    // close conns whose peer key is no longer trusted, returns the number of closed conns
+    pub async fn close_untrusted_peer_conns(&self) -> usize {
+        self.peers.close_untrusted_peer_conns().await
+    }
+
     pub async fn add_client_tunnel(
         &self,
         tunnel: Box<dyn Tunnel>,
@@ -320,7 +387,10 @@ impl PeerManager {
         let my_peer_id = self.my_peer_id;
         let peers = self.peers.clone();
         let pipe_line = self.peer_packet_process_pipeline.clone();
+        let priority_pipe_line = self.priority_peer_packet_process_pipeline.clone();
         let encryptor = self.encryptor.clone();
+        let pairwise_ciphers = self.pairwise_ciphers.clone();
+        let global_ctx = self.global_ctx.clone();
         self.tasks.lock().await.spawn(async move {
             log::trace!("start_peer_recv");
             while let Some(mut ret) = recv.next().await {
@@ -332,23 +402,62 @@ impl PeerManager {
                 let from_peer_id = hdr.from_peer_id.get();
                 let to_peer_id = hdr.to_peer_id.get();
                 if to_peer_id != my_peer_id {
+                    // the previous hop claims to be us, the next hop would take it as sent
+                    // over our direct session
+                    if from_peer_id == my_peer_id {
+                        tracing::warn!(?to_peer_id, "drop forwarded packet from my peer id");
+                        continue;
+                    }
                     tracing::trace!(?to_peer_id, ?my_peer_id, "need forward");
                     let ret = peers.send_msg(ret, to_peer_id).await;
                     if ret.is_err() {
                         tracing::error!(?ret, ?to_peer_id, ?from_peer_id, "forward packet error");
                     }
                 } else {
-                    if let Err(e) = encryptor
-                        .decrypt(&mut ret)
-                        .with_context(|| "decrypt failed")
-                    {
-                        tracing::error!(?e, "decrypt failed");
+                    // packets from direct peers are already decrypted by peer conn
+                    if hdr.is_pairwise_encrypted() {
+                        let Some(peer_keys) = peers.get_peer_public_keys(from_peer_id).await else {
+                            tracing::warn!(
+                                ?from_peer_id,
+                                "no public keys for pairwise encrypted packet"
+                            );
+                            continue;
+                        };
+                        if !global_ctx.is_public_key_trusted(&peer_keys.static_key) {
+                            tracing::warn!(
+                                ?from_peer_id,
+                                public_key = %encode_key(&peer_keys.static_key),
+                                "drop relayed packet from untrusted peer"
+                            );
+                            continue;
+                        }
+                        if let Err(e) = pairwise_ciphers.decrypt(from_peer_id, &peer_keys, &mut ret)
+                        {
+                            tracing::error!(?e, ?from_peer_id, "pairwise decrypt failed");
+                            continue;
+                        }
+                        ret.mut_peer_manager_header()
+                            .unwrap()
+                            .set_sender_authenticated(true);
+                    } else if hdr.is_encrypted() {
+                        if let Err(e) = encryptor
+                            .decrypt(&mut ret)
+                            .with_context(|| "decrypt failed")
+                        {
+                            tracing::error!(?e, "decrypt failed");
+                        }
                     }
 
                     let mut processed = false;
                     let mut zc_packet = Some(ret);
                     let mut idx = 0;
-                    for pipeline in pipe_line.read().await.iter().rev() {
+                    let priority_pipelines = priority_pipe_line.read().await;
+                    let pipelines = pipe_line.read().await;
+                    for pipeline in priority_pipelines
+                        .iter()
+                        .rev()
+                        .chain(pipelines.iter().rev())
+                    {
                         tracing::debug!(?zc_packet, ?idx, "try_process_packet_from_peer");
                         idx += 1;
                         zc_packet = pipeline
@@ -384,6 +493,22 @@ impl PeerManager {
             .push(pipeline);
     }
 
+    pub async fn add_priority_packet_process_pipeline(&self, pipeline: BoxPeerPacketFilter) {
+        // newest pipeline will be executed first
+        self.priority_peer_packet_process_pipeline
+            .write()
+            .await
+            .push(pipeline);
+    }
+
+    pub async fn add_priority_nic_packet_process_pipeline(&self, pipeline: BoxNicPacketFilter) {
+        // newest pipeline will be executed first
+        self.priority_nic_packet_process_pipeline
+            .write()
+            .await
+            .push(pipeline);
+    }
+
     async fn init_packet_process_pipeline(&self) {
         // for tun/tap ip/eth packet.
         struct NicPacketProcessor {
@@ -408,6 +533,10 @@ impl PeerManager {
         }))
         .await;
 
+        // learn multicast group membership before the packet is consumed by nic processor.
+        self.add_packet_process_pipeline(Box::new(self.igmp_snooping.clone()))
+            .await;
+
         // for peer rpc packet
         struct PeerRpcPacketProcessor {
             peer_rpc_tspt_sender: UnboundedSender<ZCPacket>,
@@ -486,6 +615,12 @@ impl PeerManager {
             fn my_peer_id(&self) -> PeerId {
                 self.my_peer_id
             }
+            async fn list_peer_conns(&self, peer_id: PeerId) -> Vec<crate::rpc::PeerConnInfo> {
+                let Some(peer_map) = self.peers.upgrade() else {
+                    return vec![];
+                };
+                peer_map.list_peer_conns(peer_id).await.unwrap_or_default()
+            }
         }
 
         let my_peer_id = self.my_peer_id;
@@ -515,25 +650,54 @@ impl PeerManager {
     }
 
     async fn run_nic_packet_process_pipeline(&self, data: &mut ZCPacket) {
-        for pipeline in self.nic_packet_process_pipeline.read().await.iter().rev() {
+        let priority_pipelines = self.priority_nic_packet_process_pipeline.read().await;
+        let pipelines = self.nic_packet_process_pipeline.read().await;
+        for pipeline in priority_pipelines
+            .iter()
+            .rev()
+            .chain(pipelines.iter().rev())
+        {
             pipeline.try_process_packet_from_nic(data).await;
         }
     }
 
+    async fn check_nic_packet_to_peer(&self, data: &ZCPacket, dst_peer_id: PeerId) -> bool {
+        let priority_pipelines = self.priority_nic_packet_process_pipeline.read().await;
+        let pipelines = self.nic_packet_process_pipeline.read().await;
+        for pipeline in priority_pipelines
+            .iter()
+            .rev()
+            .chain(pipelines.iter().rev())
+        {
+            if !pipeline.check_packet_to_peer(data, dst_peer_id).await {
+                return false;
+            }
+        }
+        true
+    }
+
     pub async fn send_msg(&self, msg: ZCPacket, dst_peer_id: PeerId) -> Result<(), Error> {
         self.peers.send_msg(msg, dst_peer_id).await
     }
 
-    pub async fn send_msg_ipv4(&self, mut msg: ZCPacket, ipv4_addr: Ipv4Addr) -> Result<(), Error> {
+    pub async fn send_msg_ipv4(&self, msg: ZCPacket, ipv4_addr: Ipv4Addr) -> Result<(), Error> {
         log::trace!(
             "do send_msg in peer manager, msg: {:?}, ipv4_addr: {}",
             msg,
             ipv4_addr
         );
 
+        let is_directed_broadcast = self
+            .global_ctx
+            .get_ipv4_inet()
+            .map(|inet| inet.network_length() < 31 && inet.last_address() == ipv4_addr)
+            .unwrap_or(false);
+
         let mut dst_peers = vec![];
-        // NOTE: currently we only support ipv4 and cidr is 24
-        if ipv4_addr.is_broadcast() || ipv4_addr.is_multicast() || ipv4_addr.octets()[3] == 255 {
+        if ipv4_addr.is_broadcast()
+            || is_directed_broadcast
+            || (ipv4_addr.is_multicast() && self.igmp_snooping.need_flood(&msg, &ipv4_addr))
+        {
             dst_peers.extend(
                 self.peers
                     .list_routes()
@@ -541,8 +705,25 @@ impl PeerManager {
                     .iter()
                     .map(|x| x.key().clone()),
             );
+        } else if ipv4_addr.is_multicast() {
+            let routes = self.peers.list_routes().await;
+            dst_peers.extend(
+                self.igmp_snooping
+                    .list_group_members(&ipv4_addr)
+                    .into_iter()
+                    .filter(|peer_id| routes.contains_key(peer_id)),
+            );
         } else if let Some(peer_id) = self.peers.get_peer_id_by_ipv4(&ipv4_addr).await {
             dst_peers.push(peer_id);
+        } else if let Some(peer_id) = self.exit_node.load() {
+            let in_virtual_network = self
+                .global_ctx
+                .get_ipv4_inet()
+                .map(|inet| inet.network().contains(&ipv4_addr))
+                .unwrap_or(false);
+            if !in_virtual_network {
+                dst_peers.push(peer_id);
+            }
         }
 
         if dst_peers.is_empty() {
@@ -550,15 +731,74 @@ impl PeerManager {
             return Ok(());
         }
 
-        msg.fill_peer_manager_hdr(
-            self.my_peer_id,
-            0,
-            tunnel::packet_def::PacketType::Data as u8,
+        self.send_msg_to_peers(msg, dst_peers, PacketType::Data)
+            .await
+    }
+
+    pub async fn send_msg_ipv6(&self, msg: ZCPacket, ipv6_addr: Ipv6Addr) -> Result<(), Error> {
+        log::trace!(
+            "do send_msg in peer manager, msg: {:?}, ipv6_addr: {}",
+            msg,
+            ipv6_addr
         );
-        self.run_nic_packet_process_pipeline(&mut msg).await;
-        self.encryptor
-            .encrypt(&mut msg)
-            .with_context(|| "encrypt failed")?;
+
+        let mut dst_peers = vec![];
+        if ipv6_addr.is_multicast() {
+            dst_peers.extend(
+                self.peers
+                    .list_routes()
+                    .await
+                    .iter()
+                    .map(|x| x.key().clone()),
+            );
+        } else if let Some(peer_id) = self.peers.get_peer_id_by_ipv6(&ipv6_addr).await {
+            dst_peers.push(peer_id);
+        }
+
+        if dst_peers.is_empty() {
+            tracing::info!("no peer id for ipv6: {}", ipv6_addr);
+            return Ok(());
+        }
+
+        self.send_msg_to_peers(msg, dst_peers, PacketType::Data)
+            .await
+    }
+
+    // ethernet frame from tap device, flooded to all reachable peers if dst_peer is not known
+    pub async fn send_ethernet_frame(
+        &self,
+        msg: ZCPacket,
+        dst_peer: Option<PeerId>,
+    ) -> Result<(), Error> {
+        let dst_peers = match dst_peer {
+            Some(peer_id) => vec![peer_id],
+            None => self
+                .peers
+                .list_routes()
+                .await
+                .iter()
+                .map(|x| x.key().clone())
+                .collect(),
+        };
+        if dst_peers.is_empty() {
+            return Ok(());
+        }
+        self.send_msg_to_peers(msg, dst_peers, PacketType::Ethernet)
+            .await
+    }
+
+    async fn send_msg_to_peers(
+        &self,
+        mut msg: ZCPacket,
+        dst_peers: Vec<PeerId>,
+        packet_type: PacketType,
+    ) -> Result<(), Error> {
+        let packet_type = packet_type as u8;
+        msg.fill_peer_manager_hdr(self.my_peer_id, 0, packet_type);
+        // nic filters only understand ip packets
+        if packet_type == PacketType::Data as u8 {
+            self.run_nic_packet_process_pipeline(&mut msg).await;
+        }
 
         let mut errs: Vec<Error> = vec![];
 
@@ -572,12 +812,32 @@ impl PeerManager {
             };
 
             let peer_id = &dst_peers[i];
+            if (packet_type == PacketType::Data as u8 || packet_type == PacketType::Ethernet as u8)
+                && !self.check_nic_packet_to_peer(&msg, *peer_id).await
+            {
+                continue;
+            }
             msg.mut_peer_manager_header()
                 .unwrap()
                 .to_peer_id
                 .set(*peer_id);
 
-            if let Some(gateway) = self.peers.get_gateway_peer_id(*peer_id).await {
+            let gateway = self.peers.get_gateway_peer_id(*peer_id).await;
+            if let Err(e) = encrypt_for_peer(
+                &**self.encryptor,
+                &self.pairwise_ciphers,
+                &self.peers,
+                *peer_id,
+                gateway,
+                &mut msg,
+            )
+            .await
+            {
+                errs.push(e);
+                continue;
+            }
+
+            if let Some(gateway) = gateway {
                 if let Err(e) = self.peers.send_msg_directly(msg, gateway).await {
                     errs.push(e);
                 }
@@ -628,6 +888,7 @@ impl PeerManager {
 
         self.init_packet_process_pipeline().await;
         self.peer_rpc_mgr.run();
+        self.igmp_snooping.run().await;
 
         self.start_peer_recv().await;
         self.run_clean_peer_without_conn_routine().await;
@@ -675,6 +936,14 @@ impl PeerManager {
     pub fn get_foreign_network_client(&self) -> Arc<ForeignNetworkClient> {
         self.foreign_network_client.clone()
     }
+
+    pub fn set_exit_node(&self, peer_id: Option<PeerId>) {
+        self.exit_node.store(peer_id);
+    }
+
+    pub fn get_exit_node(&self) -> Option<PeerId> {
+        self.exit_node.load()
+    }
 }
 
 #[cfg(test)]
@@ -683,16 +952,19 @@ mod tests {
     use std::{fmt::Debug, sync::Arc};
 
     use crate::{
+        common::{error::Error, noise::StaticKeypair},
         connector::{
             create_connector_by_url, udp_hole_punch::tests::create_mock_peer_manager_with_mock_stun,
         },
         instance::listeners::get_listener_by_url,
         peers::{
             peer_rpc::tests::{MockService, TestRpcService, TestRpcServiceClient},
-            tests::{connect_peer_manager, wait_for_condition, wait_route_appear},
+            tests::{
+                connect_peer_manager, wait_direct_peer, wait_for_condition, wait_route_appear,
+            },
         },
         rpc::NatType,
-        tunnel::{TunnelConnector, TunnelListener},
+        tunnel::{ring::create_ring_tunnel_pair, TunnelConnector, TunnelListener},
     };
 
     use super::PeerManager;
@@ -752,8 +1024,8 @@ mod tests {
     #[tokio::test]
     #[serial_test::serial(forward_packet_test)]
     async fn forward_packet(
-        #[values("tcp", "udp", "wg", "quic")] proto1: &str,
-        #[values("tcp", "udp", "wg", "quic")] proto2: &str,
+        #[values("tcp", "udp", "wg", "quic", "ws", "wss")] proto1: &str,
+        #[values("tcp", "udp", "wg", "quic", "ws", "wss")] proto2: &str,
     ) {
         let peer_mgr_a = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
         peer_mgr_a.get_peer_rpc_mgr().run_service(
@@ -822,4 +1094,126 @@ mod tests {
             .unwrap();
         assert_eq!(ret, "hello c abc");
     }
+
+    #[tokio::test]
+    async fn untrusted_public_key() {
+        let peer_mgr_a = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        let peer_mgr_b = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+
+        // b only trusts a random key
+        peer_mgr_b
+            .get_global_ctx()
+            .config
+            .set_trusted_public_keys(Some(vec![StaticKeypair::generate().public_key_str()]));
+
+        let (a_ring, b_ring) = create_ring_tunnel_pair();
+        let (a_ret, b_ret) = tokio::join!(
+            peer_mgr_a.add_client_tunnel(a_ring),
+            peer_mgr_b.add_tunnel_as_server(b_ring)
+        );
+        assert!(a_ret.is_ok());
+        assert!(matches!(b_ret, Err(Error::UntrustedPublicKey(_))));
+
+        // trust a, then the conn should be accepted
+        let a_key = peer_mgr_a
+            .get_global_ctx()
+            .get_static_keypair()
+            .public_key_str();
+        peer_mgr_b
+            .get_global_ctx()
+            .config
+            .set_trusted_public_keys(Some(vec![a_key]));
+
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_b.clone())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn untrusted_relayed_peer() {
+        let peer_mgr_a = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        let peer_mgr_b = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        let peer_mgr_c = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        peer_mgr_c.get_peer_rpc_mgr().run_service(
+            100,
+            MockService {
+                prefix: "hello c".to_owned(),
+            }
+            .serve(),
+        );
+
+        // c only trusts b, a reaches c through b which trusts everyone
+        let key_of =
+            |mgr: &Arc<PeerManager>| mgr.get_global_ctx().get_static_keypair().public_key_str();
+        peer_mgr_c
+            .get_global_ctx()
+            .config
+            .set_trusted_public_keys(Some(vec![key_of(&peer_mgr_b)]));
+
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        connect_peer_manager(peer_mgr_b.clone(), peer_mgr_c.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_c.clone())
+            .await
+            .unwrap();
+
+        let call_c = || async {
+            tokio::time::timeout(
+                std::time::Duration::from_secs(2),
+                peer_mgr_a.get_peer_rpc_mgr().do_client_rpc_scoped(
+                    100,
+                    peer_mgr_c.my_peer_id(),
+                    |c| async {
+                        let c =
+                            TestRpcServiceClient::new(tarpc::client::Config::default(), c).spawn();
+                        c.hello(tarpc::context::current(), "abc".to_owned()).await
+                    },
+                ),
+            )
+            .await
+        };
+        assert!(!matches!(call_c().await, Ok(Ok(_))));
+
+        peer_mgr_c
+            .get_global_ctx()
+            .config
+            .set_trusted_public_keys(Some(vec![key_of(&peer_mgr_b), key_of(&peer_mgr_a)]));
+        assert_eq!(call_c().await.unwrap().unwrap(), "hello c abc");
+    }
+
+    #[tokio::test]
+    async fn close_untrusted_peer_conns() {
+        let peer_mgr_a = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        let peer_mgr_b = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        wait_direct_peer(peer_mgr_b.clone(), peer_mgr_a.my_peer_id())
+            .await
+            .unwrap();
+
+        // removing a key does not affect other peers
+        let key_of_a = peer_mgr_a
+            .get_global_ctx()
+            .get_static_keypair()
+            .public_key_str();
+        let global_ctx_b = peer_mgr_b.get_global_ctx();
+        global_ctx_b
+            .config
+            .set_trusted_public_keys(Some(vec![key_of_a]));
+        assert_eq!(peer_mgr_b.close_untrusted_peer_conns().await, 0);
+
+        global_ctx_b.config.set_trusted_public_keys(Some(vec![]));
+        assert_eq!(peer_mgr_b.close_untrusted_peer_conns().await, 1);
+        wait_for_condition(
+            || async {
+                peer_mgr_b
+                    .get_peer_map()
+                    .list_peer_conns(peer_mgr_a.my_peer_id())
+                    .await
+                    .map(|conns| conns.is_empty())
+                    .unwrap_or(true)
+            },
+            std::time::Duration::from_secs(5),
+        )
+        .await;
+    }
 }
@@ -1,4 +1,7 @@
-use std::{net::Ipv4Addr, sync::Arc};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
 
 use anyhow::Context;
 use dashmap::DashMap;
@@ -8,6 +11,7 @@ use crate::{
     common::{
         error::Error,
         global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
+        noise::PeerPublicKeys,
         PeerId,
     },
     rpc::PeerConnInfo,
@@ -16,6 +20,7 @@ use crate::{
 };
 
 use super::{
+    encrypt::CipherSuite,
     peer::Peer,
     peer_conn::{PeerConn, PeerConnId},
     route_trait::ArcRoute,
@@ -99,11 +104,8 @@ impl PeerMap {
             return Some(dst_peer_id);
         }
 
-        if self.has_peer(dst_peer_id) {
-            return Some(dst_peer_id);
-        }
-
-        // get route info
+        // get route info, the next hop may be a relay even if dst is connected
+        // directly when the direct link costs more.
         for route in self.routes.read().await.iter() {
             if let Some(gateway_peer_id) = route.get_next_hop(dst_peer_id).await {
                 // for foreign network, gateway_peer_id may not connect to me
@@ -113,6 +115,10 @@ impl PeerMap {
             }
         }
 
+        if self.has_peer(dst_peer_id) {
+            return Some(dst_peer_id);
+        }
+
         None
     }
 
@@ -128,6 +134,12 @@ impl PeerMap {
         return Ok(());
     }
 
+    pub fn get_peer_cipher_suite(&self, peer_id: PeerId) -> Option<CipherSuite> {
+        self.peer_map
+            .get(&peer_id)
+            .and_then(|peer| peer.get_cipher_suite())
+    }
+
     pub async fn get_peer_id_by_ipv4(&self, ipv4: &Ipv4Addr) -> Option<PeerId> {
         for route in self.routes.read().await.iter() {
             let peer_id = route.get_peer_id_by_ipv4(ipv4).await;
@@ -138,6 +150,40 @@ impl PeerMap {
         None
     }
 
+    pub async fn get_peer_id_by_ipv6(&self, ipv6: &Ipv6Addr) -> Option<PeerId> {
+        for route in self.routes.read().await.iter() {
+            let peer_id = route.get_peer_id_by_ipv6(ipv6).await;
+            if peer_id.is_some() {
+                return peer_id;
+            }
+        }
+        None
+    }
+
+    // keys advertised in route info may be forwarded by any peer, those of a direct peer must
+    // match the static key proven by the handshake.
+    pub async fn get_peer_public_keys(&self, peer_id: PeerId) -> Option<PeerPublicKeys> {
+        let mut keys = None;
+        for route in self.routes.read().await.iter() {
+            keys = route.get_peer_public_keys(peer_id).await;
+            if keys.is_some() {
+                break;
+            }
+        }
+        let keys = keys?;
+        if let Some(peer) = self.get_peer_by_id(peer_id) {
+            if peer
+                .list_public_keys()
+                .iter()
+                .any(|public_key| *public_key != keys.static_key)
+            {
+                tracing::warn!(?peer_id, "advertised public key differs from the handshake");
+                return None;
+            }
+        }
+        Some(keys)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.peer_map.is_empty()
     }
@@ -185,6 +231,19 @@ impl PeerMap {
         }
     }
 
+    pub async fn close_untrusted_peer_conns(&self) -> usize {
+        let peers = self
+            .peer_map
+            .iter()
+            .map(|p| p.value().clone())
+            .collect::<Vec<_>>();
+        let mut closed = 0;
+        for peer in peers {
+            closed += peer.close_untrusted_conns().await.len();
+        }
+        closed
+    }
+
     pub async fn close_peer(&self, peer_id: PeerId) -> Result<(), TunnelError> {
         let remove_ret = self.peer_map.remove(&peer_id);
         self.global_ctx
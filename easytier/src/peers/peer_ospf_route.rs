@@ -1,7 +1,7 @@
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
     fmt::Debug,
-    net::Ipv4Addr,
+    net::{Ipv4Addr, Ipv6Addr},
     sync::{
         atomic::{AtomicBool, AtomicU32, Ordering},
         Arc, Weak,
@@ -10,11 +10,16 @@ use std::{
 };
 
 use dashmap::DashMap;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tokio::{select, sync::Mutex, task::JoinSet};
 
 use crate::{
-    common::{global_ctx::ArcGlobalCtx, stun::StunInfoCollectorTrait, PeerId},
+    common::{
+        global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
+        noise::PeerPublicKeys,
+        stun::StunInfoCollectorTrait,
+        PeerId,
+    },
     peers::route_trait::{Route, RouteInterfaceBox},
     rpc::{NatType, StunInfo},
 };
@@ -26,6 +31,41 @@ static UPDATE_PEER_INFO_PERIOD: Duration = Duration::from_secs(3600);
 static REMOVE_DEAD_PEER_INFO_AFTER: Duration = Duration::from_secs(3660);
 
 type Version = u32;
+type LinkCost = u32;
+
+// a link costs at least 1, so cost equals hop count when latency and loss are low.
+static LATENCY_MS_PER_COST: u64 = 10;
+static LOSS_PERCENT_PER_COST: f32 = 1.0;
+static MAX_LINK_COST: LinkCost = 1000;
+// ignore small changes of link cost, avoid route flapping and syncing conn bitmap too often.
+static LINK_COST_HYSTERESIS_PERCENT: LinkCost = 20;
+static LINK_COST_HYSTERESIS_MIN: LinkCost = 2;
+
+fn calc_link_cost(conns: &[crate::rpc::PeerConnInfo]) -> LinkCost {
+    conns
+        .iter()
+        .map(|conn| {
+            let latency_ms = conn
+                .stats
+                .as_ref()
+                .map(|s| s.latency_us / 1000)
+                .unwrap_or(0);
+            let loss_cost = (conn.loss_rate * 100.0 / LOSS_PERCENT_PER_COST) as u64;
+            let cost = 1 + latency_ms / LATENCY_MS_PER_COST + loss_cost;
+            std::cmp::min(cost, MAX_LINK_COST as u64) as LinkCost
+        })
+        .min()
+        .unwrap_or(1)
+}
+
+fn apply_link_cost_hysteresis(old: LinkCost, new: LinkCost) -> LinkCost {
+    let diff = old.abs_diff(new);
+    if diff < LINK_COST_HYSTERESIS_MIN || diff * 100 < old * LINK_COST_HYSTERESIS_PERCENT {
+        old
+    } else {
+        new
+    }
+}
 
 #[derive(Debug, Clone)]
 struct AtomicVersion(Arc<AtomicU32>);
@@ -60,6 +100,27 @@ impl From<Version> for AtomicVersion {
     }
 }
 
+// an optional value appended to the end of a postcard message. postcard is positional, fields
+// can't be added to existing structs without breaking nodes of older versions. but older nodes
+// ignore trailing bytes of a message, and a message of an older node decodes to None here.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Trailing<T>(Option<T>);
+
+impl<T: Serialize> Serialize for Trailing<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Trailing<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // the message ends before this value if it's from an older node
+        Ok(Trailing(
+            Option::<T>::deserialize(deserializer).unwrap_or(None),
+        ))
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
 struct RoutePeerInfo {
     // means next hop in route table.
@@ -72,6 +133,23 @@ struct RoutePeerInfo {
     udp_stun_info: i8,
     last_update: SystemTime,
     version: Version,
+    // synced with RoutePeerInfoExt
+    #[serde(skip)]
+    ipv6_addr: Option<Ipv6Addr>,
+    #[serde(skip)]
+    public_keys: Option<PeerPublicKeys>,
+    // dhcp addresses give way to static ones on conflict
+    #[serde(skip)]
+    ipv4_dhcp: bool,
+}
+
+// fields of RoutePeerInfo added after the first release, sync_route_info carries one for each
+// item of peer_infos in a trailing argument.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+struct RoutePeerInfoExt {
+    ipv6_addr: Option<Ipv6Addr>,
+    public_keys: Option<PeerPublicKeys>,
+    ipv4_dhcp: bool,
 }
 
 impl RoutePeerInfo {
@@ -86,9 +164,26 @@ impl RoutePeerInfo {
             udp_stun_info: 0,
             last_update: SystemTime::now(),
             version: 0,
+            ipv6_addr: None,
+            public_keys: None,
+            ipv4_dhcp: false,
         }
     }
 
+    fn get_ext(&self) -> RoutePeerInfoExt {
+        RoutePeerInfoExt {
+            ipv6_addr: self.ipv6_addr,
+            public_keys: self.public_keys,
+            ipv4_dhcp: self.ipv4_dhcp,
+        }
+    }
+
+    fn set_ext(&mut self, ext: RoutePeerInfoExt) {
+        self.ipv6_addr = ext.ipv6_addr;
+        self.public_keys = ext.public_keys;
+        self.ipv4_dhcp = ext.ipv4_dhcp;
+    }
+
     pub fn update_self(&self, my_peer_id: PeerId, global_ctx: &ArcGlobalCtx) -> Self {
         let mut new = Self {
             peer_id: my_peer_id,
@@ -96,16 +191,20 @@ impl RoutePeerInfo {
             cost: 0,
             ipv4_addr: global_ctx.get_ipv4(),
             proxy_cidrs: global_ctx
-                .get_proxy_cidrs()
+                .get_advertised_proxy_cidrs()
                 .iter()
                 .map(|x| x.to_string())
                 .chain(global_ctx.get_vpn_portal_cidr().map(|x| x.to_string()))
+                .chain(global_ctx.get_exit_node_cidr().map(|x| x.to_string()))
                 .collect(),
             hostname: global_ctx.get_hostname(),
             udp_stun_info: global_ctx
                 .get_stun_info_collector()
                 .get_stun_info()
                 .udp_nat_type as i8,
+            ipv6_addr: global_ctx.get_ipv6().map(|x| x.address()),
+            public_keys: Some(global_ctx.get_my_public_keys()),
+            ipv4_dhcp: global_ctx.is_ipv4_from_dhcp(),
             // following fields do not participate in comparison.
             last_update: self.last_update,
             version: self.version,
@@ -151,6 +250,15 @@ impl Into<crate::rpc::Route> for RoutePeerInfo {
                 Some(stun_info)
             },
             inst_id: self.inst_id.to_string(),
+            ipv6_addr: if let Some(ipv6_addr) = self.ipv6_addr {
+                ipv6_addr.to_string()
+            } else {
+                "".to_string()
+            },
+            ipv4_dhcp: self.ipv4_dhcp,
+            // filled by route table
+            ipv4_conflict: false,
+            ipv4_owner: false,
         }
     }
 }
@@ -159,6 +267,10 @@ impl Into<crate::rpc::Route> for RoutePeerInfo {
 struct RouteConnBitmap {
     peer_ids: Vec<(PeerId, Version)>,
     bitmap: Vec<u8>,
+    // one cost for each set bit, in the order of bits.
+    // synced in a trailing rpc argument to keep the bitmap wire format.
+    #[serde(skip)]
+    link_costs: Vec<LinkCost>,
 }
 
 impl RouteConnBitmap {
@@ -166,6 +278,7 @@ impl RouteConnBitmap {
         RouteConnBitmap {
             peer_ids: Vec::new(),
             bitmap: Vec::new(),
+            link_costs: Vec::new(),
         }
     }
 
@@ -176,14 +289,21 @@ impl RouteConnBitmap {
         (byte >> bit_idx) & 1 == 1
     }
 
-    fn get_connected_peers(&self, peer_idx: usize) -> BTreeSet<PeerId> {
-        let mut connected_peers = BTreeSet::new();
-        for (idx, (peer_id, _)) in self.peer_ids.iter().enumerate() {
-            if self.get_bit(peer_idx * self.peer_ids.len() + idx) {
-                connected_peers.insert(*peer_id);
+    // returns connected peers with link cost for each peer in peer_ids.
+    fn get_all_connected_peers(&self) -> Vec<BTreeMap<PeerId, LinkCost>> {
+        let peer_count = self.peer_ids.len();
+        let mut ret = vec![BTreeMap::new(); peer_count];
+        let mut cost_idx = 0;
+        for peer_idx in 0..peer_count {
+            for (idx, (peer_id, _)) in self.peer_ids.iter().enumerate() {
+                if self.get_bit(peer_idx * peer_count + idx) {
+                    let cost = self.link_costs.get(cost_idx).copied().unwrap_or(1);
+                    ret[peer_idx].insert(*peer_id, cost);
+                    cost_idx += 1;
+                }
             }
         }
-        connected_peers
+        ret
     }
 }
 
@@ -207,6 +327,9 @@ trait RouteService {
         is_initiator: bool,
         peer_infos: Option<Vec<RoutePeerInfo>>,
         conn_bitmap: Option<RouteConnBitmap>,
+        // arguments added after the first release must be appended as Trailing
+        peer_info_exts: Trailing<Vec<RoutePeerInfoExt>>,
+        link_costs: Trailing<Vec<LinkCost>>,
     ) -> Result<SyncRouteInfoResponse, Error>;
 }
 
@@ -214,14 +337,15 @@ trait RouteService {
 #[derive(Debug)]
 struct SyncedRouteInfo {
     peer_infos: DashMap<PeerId, RoutePeerInfo>,
-    conn_map: DashMap<PeerId, (BTreeSet<PeerId>, AtomicVersion)>,
+    conn_map: DashMap<PeerId, (BTreeMap<PeerId, LinkCost>, AtomicVersion)>,
 }
 
 impl SyncedRouteInfo {
-    fn get_connected_peers<T: FromIterator<PeerId>>(&self, peer_id: PeerId) -> Option<T> {
+    fn get_connected_peers_with_cost(&self, peer_id: PeerId) -> Vec<(PeerId, LinkCost)> {
         self.conn_map
             .get(&peer_id)
-            .map(|x| x.0.clone().iter().map(|x| *x).collect())
+            .map(|x| x.0.iter().map(|(k, v)| (*k, *v)).collect())
+            .unwrap_or_default()
     }
 
     fn remove_peer(&self, peer_id: PeerId) {
@@ -230,15 +354,15 @@ impl SyncedRouteInfo {
         self.conn_map.remove(&peer_id);
     }
 
-    fn fill_empty_peer_info(&self, peer_ids: &BTreeSet<PeerId>) {
+    fn fill_empty_peer_info(&self, peer_ids: impl Iterator<Item = PeerId>) {
         for peer_id in peer_ids {
             self.peer_infos
-                .entry(*peer_id)
+                .entry(peer_id)
                 .or_insert_with(|| RoutePeerInfo::new());
 
             self.conn_map
-                .entry(*peer_id)
-                .or_insert_with(|| (BTreeSet::new(), AtomicVersion::new()));
+                .entry(peer_id)
+                .or_insert_with(|| (BTreeMap::new(), AtomicVersion::new()));
         }
     }
 
@@ -288,7 +412,22 @@ impl SyncedRouteInfo {
                 .entry(route_info.peer_id)
                 .and_modify(|old_entry| {
                     if route_info.version > old_entry.version {
+                        let pinned_keys = old_entry.public_keys;
                         *old_entry = route_info.clone();
+                        // keys are regenerated with the peer id, they never change for one peer
+                        // id. any peer can forward an info with a higher version, so only the
+                        // peer itself may replace the keys we have seen first.
+                        if pinned_keys.is_some()
+                            && pinned_keys != route_info.public_keys
+                            && route_info.peer_id != dst_peer_id
+                        {
+                            tracing::warn!(
+                                peer_id = route_info.peer_id,
+                                ?dst_peer_id,
+                                "ignore public keys of peer changed by another peer"
+                            );
+                            old_entry.public_keys = pinned_keys;
+                        }
                     }
                 })
                 .or_insert_with(|| route_info.clone());
@@ -297,27 +436,24 @@ impl SyncedRouteInfo {
     }
 
     fn update_conn_map(&self, conn_bitmap: &RouteConnBitmap) {
-        self.fill_empty_peer_info(&conn_bitmap.peer_ids.iter().map(|x| x.0).collect());
+        self.fill_empty_peer_info(conn_bitmap.peer_ids.iter().map(|x| x.0));
 
-        for (peer_idx, (peer_id, version)) in conn_bitmap.peer_ids.iter().enumerate() {
+        let all_connected_peers = conn_bitmap.get_all_connected_peers();
+        for ((peer_id, version), connceted_peers) in
+            conn_bitmap.peer_ids.iter().zip(all_connected_peers)
+        {
             assert!(self.peer_infos.contains_key(peer_id));
-            let connceted_peers = conn_bitmap.get_connected_peers(peer_idx);
-            self.fill_empty_peer_info(&connceted_peers);
+            self.fill_empty_peer_info(connceted_peers.keys().copied());
 
             self.conn_map
                 .entry(*peer_id)
                 .and_modify(|(old_conn_bitmap, old_version)| {
                     if *version > old_version.get() {
-                        *old_conn_bitmap = conn_bitmap.get_connected_peers(peer_idx);
+                        *old_conn_bitmap = connceted_peers.clone();
                         old_version.set(*version);
                     }
                 })
-                .or_insert_with(|| {
-                    (
-                        conn_bitmap.get_connected_peers(peer_idx),
-                        version.clone().into(),
-                    )
-                });
+                .or_insert_with(|| (connceted_peers.clone(), version.clone().into()));
         }
     }
 
@@ -334,13 +470,27 @@ impl SyncedRouteInfo {
         new_version != old_version
     }
 
-    fn update_my_conn_info(&self, my_peer_id: PeerId, connected_peers: BTreeSet<PeerId>) -> bool {
-        self.fill_empty_peer_info(&connected_peers);
+    fn update_my_conn_info(
+        &self,
+        my_peer_id: PeerId,
+        connected_peers: BTreeMap<PeerId, LinkCost>,
+    ) -> bool {
+        self.fill_empty_peer_info(connected_peers.keys().copied());
 
         let mut my_conn_info = self
             .conn_map
             .entry(my_peer_id)
-            .or_insert((BTreeSet::new(), AtomicVersion::new()));
+            .or_insert((BTreeMap::new(), AtomicVersion::new()));
+
+        let connected_peers = connected_peers
+            .into_iter()
+            .map(
+                |(peer_id, cost)| match my_conn_info.value().0.get(&peer_id) {
+                    Some(old_cost) => (peer_id, apply_link_cost_hysteresis(*old_cost, cost)),
+                    None => (peer_id, cost),
+                },
+            )
+            .collect::<BTreeMap<_, _>>();
 
         if connected_peers == my_conn_info.value().0 {
             false
@@ -354,7 +504,7 @@ impl SyncedRouteInfo {
     fn is_peer_bidirectly_connected(&self, src_peer_id: PeerId, dst_peer_id: PeerId) -> bool {
         self.conn_map
             .get(&src_peer_id)
-            .map(|x| x.0.contains(&dst_peer_id))
+            .map(|x| x.0.contains_key(&dst_peer_id))
             .unwrap_or(false)
     }
 
@@ -370,7 +520,10 @@ struct RouteTable {
     peer_infos: DashMap<PeerId, RoutePeerInfo>,
     next_hop_map: DashMap<PeerId, (PeerId, i32)>,
     ipv4_peer_id_map: DashMap<Ipv4Addr, PeerId>,
+    ipv6_peer_id_map: DashMap<Ipv6Addr, PeerId>,
     cidr_peer_id_map: DashMap<cidr::IpCidr, PeerId>,
+    // peers claiming the same ipv4, the first one owns the address.
+    ipv4_conflicts: DashMap<Ipv4Addr, Vec<PeerId>>,
 }
 
 impl RouteTable {
@@ -379,7 +532,9 @@ impl RouteTable {
             peer_infos: DashMap::new(),
             next_hop_map: DashMap::new(),
             ipv4_peer_id_map: DashMap::new(),
+            ipv6_peer_id_map: DashMap::new(),
             cidr_peer_id_map: DashMap::new(),
+            ipv4_conflicts: DashMap::new(),
         }
     }
 
@@ -414,34 +569,31 @@ impl RouteTable {
         // build next hop map
         self.next_hop_map.clear();
         self.next_hop_map.insert(my_peer_id, (my_peer_id, 0));
+        let parents = pathfinding::prelude::dijkstra_all(&my_peer_id, |p| {
+            synced_info.get_connected_peers_with_cost(*p)
+        });
         for item in self.peer_infos.iter() {
             let peer_id = *item.key();
             if peer_id == my_peer_id {
                 continue;
             }
-            let Some(path) = pathfinding::prelude::bfs(
-                &my_peer_id,
-                |p| {
-                    synced_info
-                        .get_connected_peers(*p)
-                        .unwrap_or_else(|| BTreeSet::new())
-                },
-                |x| *x == peer_id,
-            ) else {
+            let Some((_, cost)) = parents.get(&peer_id) else {
                 continue;
             };
-            if !path.is_empty() {
-                assert!(path.len() >= 2);
-                self.next_hop_map
-                    .insert(peer_id, (path[1], (path.len() - 1) as i32));
-            }
+            let path = pathfinding::prelude::build_path(&peer_id, &parents);
+            assert!(path.len() >= 2);
+            self.next_hop_map.insert(peer_id, (path[1], *cost as i32));
         }
 
-        // build ipv4_peer_id_map, cidr_peer_id_map
+        // build ipv4_peer_id_map, ipv6_peer_id_map, cidr_peer_id_map
         self.ipv4_peer_id_map.clear();
+        self.ipv6_peer_id_map.clear();
         self.cidr_peer_id_map.clear();
+        // when multiple peers claim the same ipv4, a static address wins over dhcp ones, then the
+        // smallest (inst_id, peer_id) owns it, so all nodes agree on where the traffic goes.
+        let mut ipv4_claims: BTreeMap<Ipv4Addr, Vec<(bool, uuid::Uuid, PeerId)>> = BTreeMap::new();
         for item in self.peer_infos.iter() {
-            // only set ipv4 map for peers we can reach.
+            // only set ip map for peers we can reach.
             if !self.next_hop_map.contains_key(item.key()) {
                 continue;
             }
@@ -450,21 +602,42 @@ impl RouteTable {
             let info = item.value();
 
             if let Some(ipv4_addr) = info.ipv4_addr {
-                self.ipv4_peer_id_map.insert(ipv4_addr, *peer_id);
+                ipv4_claims.entry(ipv4_addr).or_default().push((
+                    info.ipv4_dhcp,
+                    info.inst_id,
+                    *peer_id,
+                ));
+            }
+
+            if let Some(ipv6_addr) = info.ipv6_addr {
+                self.ipv6_peer_id_map.insert(ipv6_addr, *peer_id);
             }
 
             for cidr in info.proxy_cidrs.iter() {
-                self.cidr_peer_id_map
-                    .insert(cidr.parse().unwrap(), *peer_id);
+                let cidr: cidr::IpCidr = cidr.parse().unwrap();
+                // exit nodes are only used when selected
+                if cidr.network_length() == 0 {
+                    continue;
+                }
+                self.cidr_peer_id_map.insert(cidr, *peer_id);
+            }
+        }
+
+        self.ipv4_conflicts.clear();
+        for (ipv4_addr, mut claims) in ipv4_claims {
+            claims.sort();
+            self.ipv4_peer_id_map.insert(ipv4_addr, claims[0].2);
+            if claims.len() > 1 {
+                self.ipv4_conflicts
+                    .insert(ipv4_addr, claims.into_iter().map(|x| x.2).collect());
             }
         }
     }
 
-    fn get_peer_id_for_proxy(&self, ipv4: &Ipv4Addr) -> Option<PeerId> {
-        let ipv4 = std::net::IpAddr::V4(*ipv4);
+    fn get_peer_id_for_proxy(&self, ip: &std::net::IpAddr) -> Option<PeerId> {
         for item in self.cidr_peer_id_map.iter() {
             let (k, v) = item.pair();
-            if k.contains(&ipv4) {
+            if k.contains(ip) {
                 return Some(*v);
             }
         }
@@ -570,6 +743,8 @@ struct PeerRouteServiceImpl {
     route_table: RouteTable,
     synced_route_info: Arc<SyncedRouteInfo>,
     cached_local_conn_map: std::sync::Mutex<RouteConnBitmap>,
+    // ipv4 conflicts already reported by event, to avoid reporting the same conflict repeatedly.
+    reported_ipv4_conflicts: std::sync::Mutex<BTreeMap<Ipv4Addr, Vec<PeerId>>>,
 }
 
 impl Debug for PeerRouteServiceImpl {
@@ -597,6 +772,7 @@ impl PeerRouteServiceImpl {
                 conn_map: DashMap::new(),
             }),
             cached_local_conn_map: std::sync::Mutex::new(RouteConnBitmap::new()),
+            reported_ipv4_conflicts: std::sync::Mutex::new(BTreeMap::new()),
         }
     }
 
@@ -639,8 +815,26 @@ impl PeerRouteServiceImpl {
         false
     }
 
+    async fn list_peer_conns_from_interface(
+        &self,
+        peer_id: PeerId,
+    ) -> Vec<crate::rpc::PeerConnInfo> {
+        self.interface
+            .lock()
+            .await
+            .as_ref()
+            .unwrap()
+            .list_peer_conns(peer_id)
+            .await
+    }
+
     async fn update_my_conn_info(&self) -> bool {
-        let connected_peers: BTreeSet<PeerId> = self.list_peers_from_interface().await;
+        let peers: BTreeSet<PeerId> = self.list_peers_from_interface().await;
+        let mut connected_peers = BTreeMap::new();
+        for peer_id in peers {
+            let conns = self.list_peer_conns_from_interface(peer_id).await;
+            connected_peers.insert(peer_id, calc_link_cost(&conns));
+        }
         let updated = self
             .synced_route_info
             .update_my_conn_info(self.my_peer_id, connected_peers);
@@ -655,6 +849,39 @@ impl PeerRouteServiceImpl {
     fn update_route_table(&self) {
         self.route_table
             .build_from_synced_info(self.my_peer_id, &self.synced_route_info);
+        self.report_ipv4_conflicts();
+    }
+
+    fn report_ipv4_conflicts(&self) {
+        let conflicts: BTreeMap<_, _> = self
+            .route_table
+            .ipv4_conflicts
+            .iter()
+            .map(|x| (*x.key(), x.value().clone()))
+            .collect();
+
+        let mut reported = self.reported_ipv4_conflicts.lock().unwrap();
+        for (ipv4_addr, peer_ids) in conflicts.iter() {
+            if reported.get(ipv4_addr) == Some(peer_ids) {
+                continue;
+            }
+
+            let peers = peer_ids
+                .iter()
+                .filter_map(|peer_id| {
+                    let info = self.route_table.peer_infos.get(peer_id)?;
+                    Some(format!(
+                        "{}({})",
+                        info.hostname.clone().unwrap_or_default(),
+                        info.inst_id
+                    ))
+                })
+                .collect::<Vec<_>>();
+            tracing::warn!(?ipv4_addr, ?peers, "duplicate ipv4 detected");
+            self.global_ctx
+                .issue_event(GlobalCtxEvent::DuplicateIpv4(ipv4_addr.to_string(), peers));
+        }
+        *reported = conflicts;
     }
 
     fn update_route_table_and_cached_local_conn_bitmap(&self) {
@@ -667,7 +894,7 @@ impl PeerRouteServiceImpl {
             .synced_route_info
             .conn_map
             .iter()
-            .map(|x| x.value().clone().0.into_iter())
+            .map(|x| x.value().0.keys().copied().collect::<Vec<_>>())
             .flatten()
             .collect::<BTreeSet<_>>();
 
@@ -691,9 +918,10 @@ impl PeerRouteServiceImpl {
             };
 
             for (idx, (other_peer_id, _)) in all_peer_ids.iter().enumerate() {
-                if connected.0.contains(other_peer_id) {
+                if let Some(cost) = connected.0.get(other_peer_id) {
                     let bit_idx = peer_idx * all_peer_ids.len() + idx;
                     conn_bitmap.bitmap[bit_idx / 8] |= 1 << (bit_idx % 8);
+                    conn_bitmap.link_costs.push(*cost);
                 }
             }
         }
@@ -786,6 +1014,12 @@ impl PeerRouteServiceImpl {
         let my_peer_id = self.my_peer_id;
 
         let (peer_infos, conn_bitmap) = self.build_sync_request(&session);
+        let peer_info_exts = Trailing(
+            peer_infos
+                .as_ref()
+                .map(|infos| infos.iter().map(|info| info.get_ext()).collect()),
+        );
+        let link_costs = Trailing(conn_bitmap.as_ref().map(|b| b.link_costs.clone()));
         tracing::trace!("my_id {:?}, pper_id: {:?}, peer_infos: {:?}, conn_bitmap: {:?}, synced_route_info: {:?} session: {:?}",
                        my_peer_id, dst_peer_id, peer_infos, conn_bitmap, self.synced_route_info, session);
 
@@ -809,6 +1043,8 @@ impl PeerRouteServiceImpl {
                         session.we_are_initiator.load(Ordering::Relaxed),
                         peer_infos.clone(),
                         conn_bitmap.clone(),
+                        peer_info_exts.clone(),
+                        link_costs.clone(),
                     )
                     .await
             })
@@ -868,11 +1104,26 @@ impl RouteService for RouteSessionManager {
         is_initiator: bool,
         peer_infos: Option<Vec<RoutePeerInfo>>,
         conn_bitmap: Option<RouteConnBitmap>,
+        peer_info_exts: Trailing<Vec<RoutePeerInfoExt>>,
+        link_costs: Trailing<Vec<LinkCost>>,
     ) -> Result<SyncRouteInfoResponse, Error> {
         let Some(service_impl) = self.service_impl.upgrade() else {
             return Err(Error::Stopped);
         };
 
+        let mut peer_infos = peer_infos;
+        if let (Some(infos), Some(exts)) = (&mut peer_infos, peer_info_exts.0) {
+            for (info, ext) in infos.iter_mut().zip(exts) {
+                info.set_ext(ext);
+            }
+        }
+
+        // older peers do not send link costs, every link then costs 1.
+        let mut conn_bitmap = conn_bitmap;
+        if let (Some(bitmap), Some(costs)) = (&mut conn_bitmap, link_costs.0) {
+            bitmap.link_costs = costs;
+        }
+
         let my_peer_id = service_impl.my_peer_id;
         let session = self.get_or_start_session(from_peer_id)?;
 
@@ -1251,6 +1502,11 @@ impl Route for PeerRoute {
             let mut route: crate::rpc::Route = item.value().clone().into();
             route.next_hop_peer_id = next_hop_peer.0;
             route.cost = next_hop_peer.1;
+            if let Some(ipv4_addr) = item.value().ipv4_addr {
+                route.ipv4_conflict = route_table.ipv4_conflicts.contains_key(&ipv4_addr);
+                route.ipv4_owner =
+                    route_table.ipv4_peer_id_map.get(&ipv4_addr).map(|x| *x) == Some(*item.key());
+            }
             routes.push(route);
         }
         routes
@@ -1262,13 +1518,35 @@ impl Route for PeerRoute {
             return Some(*peer_id);
         }
 
-        if let Some(peer_id) = route_table.get_peer_id_for_proxy(ipv4_addr) {
+        if let Some(peer_id) = route_table.get_peer_id_for_proxy(&(*ipv4_addr).into()) {
             return Some(peer_id);
         }
 
         tracing::info!(?ipv4_addr, "no peer id for ipv4");
         None
     }
+
+    async fn get_peer_public_keys(&self, peer_id: PeerId) -> Option<PeerPublicKeys> {
+        let route_table = &self.service_impl.route_table;
+        route_table
+            .peer_infos
+            .get(&peer_id)
+            .and_then(|info| info.public_keys)
+    }
+
+    async fn get_peer_id_by_ipv6(&self, ipv6_addr: &Ipv6Addr) -> Option<PeerId> {
+        let route_table = &self.service_impl.route_table;
+        if let Some(peer_id) = route_table.ipv6_peer_id_map.get(ipv6_addr) {
+            return Some(*peer_id);
+        }
+
+        if let Some(peer_id) = route_table.get_peer_id_for_proxy(&(*ipv6_addr).into()) {
+            return Some(peer_id);
+        }
+
+        tracing::info!(?ipv6_addr, "no peer id for ipv6");
+        None
+    }
 }
 
 impl PeerPacketFilter for Arc<PeerRoute> {}
@@ -1276,23 +1554,30 @@ impl PeerPacketFilter for Arc<PeerRoute> {}
 #[cfg(test)]
 mod tests {
     use std::{
-        collections::BTreeSet,
+        collections::{BTreeMap, BTreeSet},
         sync::{atomic::Ordering, Arc},
         time::Duration,
     };
 
+    use dashmap::DashMap;
+
     use crate::{
-        common::{global_ctx::tests::get_mock_global_ctx, PeerId},
+        common::{global_ctx::tests::get_mock_global_ctx, noise::PeerPublicKeys, PeerId},
         connector::udp_hole_punch::tests::replace_stun_info_collector,
         peers::{
             peer_manager::{PeerManager, RouteAlgoType},
             route_trait::Route,
             tests::{connect_peer_manager, wait_for_condition},
         },
-        rpc::NatType,
+        rpc::{NatType, PeerConnInfo, PeerConnStats},
     };
 
-    use super::PeerRoute;
+    use serde::{Deserialize, Serialize};
+
+    use super::{
+        apply_link_cost_hysteresis, calc_link_cost, AtomicVersion, PeerRoute, RouteConnBitmap,
+        RoutePeerInfo, RoutePeerInfoExt, RouteTable, SyncedRouteInfo, Trailing, Version,
+    };
 
     async fn create_mock_route(peer_mgr: Arc<PeerManager>) -> Arc<PeerRoute> {
         let peer_route = PeerRoute::new(
@@ -1503,7 +1788,7 @@ mod tests {
                 .unwrap();
 
             assert_eq!(
-                conns.0,
+                conns.0.keys().copied().collect::<BTreeSet<PeerId>>(),
                 routable_peer
                     .get_peer_map()
                     .list_peers()
@@ -1613,4 +1898,246 @@ mod tests {
         println!("session: {:?}", r_a.session_mgr.dump_sessions());
         check_rpc_counter(&r_a, p_b.my_peer_id(), 2, 2);
     }
+
+    #[tokio::test]
+    async fn ospf_route_ipv6() {
+        let p_a = create_mock_pmgr().await;
+        let p_b = create_mock_pmgr().await;
+        p_b.get_global_ctx()
+            .config
+            .set_ipv6("fd00::2/64".parse().unwrap());
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+
+        let r_a = create_mock_route(p_a.clone()).await;
+        let _r_b = create_mock_route(p_b.clone()).await;
+
+        wait_for_condition(
+            || async {
+                r_a.get_peer_id_by_ipv6(&"fd00::2".parse().unwrap()).await == Some(p_b.my_peer_id())
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        let routes = r_a.list_routes().await;
+        assert_eq!(1, routes.len());
+        assert_eq!("fd00::2", routes[0].ipv6_addr);
+    }
+
+    #[test]
+    fn link_cost_calc() {
+        let conn = |latency_ms: u64, loss_rate: f32| PeerConnInfo {
+            stats: Some(PeerConnStats {
+                latency_us: latency_ms * 1000,
+                ..Default::default()
+            }),
+            loss_rate,
+            ..Default::default()
+        };
+
+        assert_eq!(1, calc_link_cost(&[]));
+        assert_eq!(1, calc_link_cost(&[conn(5, 0.0)]));
+        assert_eq!(16, calc_link_cost(&[conn(150, 0.0)]));
+        assert_eq!(6, calc_link_cost(&[conn(50, 0.05), conn(50, 0.0)]));
+        assert_eq!(1000, calc_link_cost(&[conn(100000, 0.0)]));
+
+        assert_eq!(10, apply_link_cost_hysteresis(10, 11));
+        assert_eq!(12, apply_link_cost_hysteresis(10, 12));
+        assert_eq!(100, apply_link_cost_hysteresis(100, 115));
+        assert_eq!(130, apply_link_cost_hysteresis(100, 130));
+        assert_eq!(3, apply_link_cost_hysteresis(1, 3));
+    }
+
+    #[test]
+    fn trailing_args_compatible() {
+        #[derive(Serialize, Deserialize)]
+        struct OldRequest {
+            peer_infos: Option<Vec<RoutePeerInfo>>,
+        }
+        #[derive(Serialize, Deserialize)]
+        struct NewRequest {
+            peer_infos: Option<Vec<RoutePeerInfo>>,
+            peer_info_exts: Trailing<Vec<RoutePeerInfoExt>>,
+        }
+
+        let mut info = RoutePeerInfo::new();
+        info.peer_id = 10;
+        info.ipv6_addr = Some("fd00::1".parse().unwrap());
+
+        // older nodes ignore the trailing exts
+        let new = NewRequest {
+            peer_infos: Some(vec![info.clone()]),
+            peer_info_exts: Trailing(Some(vec![info.get_ext()])),
+        };
+        let buf = postcard::to_allocvec(&new).unwrap();
+        let old: OldRequest = postcard::from_bytes(&buf).unwrap();
+        assert_eq!(10, old.peer_infos.unwrap()[0].peer_id);
+        let new: NewRequest = postcard::from_bytes(&buf).unwrap();
+        assert_eq!(Trailing(Some(vec![info.get_ext()])), new.peer_info_exts);
+
+        // requests of older nodes have no exts
+        let old = OldRequest {
+            peer_infos: Some(vec![info.clone()]),
+        };
+        let buf = postcard::to_allocvec(&old).unwrap();
+        let new: NewRequest = postcard::from_bytes(&buf).unwrap();
+        assert_eq!(Trailing(None), new.peer_info_exts);
+        assert_eq!(None, new.peer_infos.unwrap()[0].ipv6_addr);
+    }
+
+    #[test]
+    fn conn_bitmap_with_cost() {
+        let mut conn_bitmap = RouteConnBitmap::new();
+        conn_bitmap.peer_ids = vec![(1, 1), (2, 1), (3, 1)];
+        // 1 -> 2, 1 -> 3, 3 -> 1
+        conn_bitmap.bitmap = vec![0b0100_0110, 0];
+        conn_bitmap.link_costs = vec![5, 7, 9];
+
+        let all = conn_bitmap.get_all_connected_peers();
+        assert_eq!(all[0], BTreeMap::from([(2, 5), (3, 7)]));
+        assert!(all[1].is_empty());
+        assert_eq!(all[2], BTreeMap::from([(1, 9)]));
+
+        // costs are not part of the bitmap wire format
+        let buf = postcard::to_allocvec(&conn_bitmap).unwrap();
+        let decoded: RouteConnBitmap = postcard::from_bytes(&buf).unwrap();
+        assert!(decoded.link_costs.is_empty());
+        let all = decoded.get_all_connected_peers();
+        assert_eq!(all[0], BTreeMap::from([(2, 1), (3, 1)]));
+    }
+
+    #[test]
+    fn public_keys_pinned() {
+        let synced_info = SyncedRouteInfo {
+            peer_infos: DashMap::new(),
+            conn_map: DashMap::new(),
+        };
+        let (me, a, b) = (1, 2, 3);
+        let keys = |k: u8| PeerPublicKeys {
+            static_key: [k; 32],
+            ephemeral_key: [k; 32],
+            trust_enforced: false,
+        };
+        let info = |version: Version, k: Option<u8>| {
+            let mut info = RoutePeerInfo::new();
+            info.peer_id = a;
+            info.version = version;
+            info.public_keys = k.map(keys);
+            info
+        };
+        let public_keys_of_a = || synced_info.peer_infos.get(&a).unwrap().public_keys;
+
+        synced_info
+            .update_peer_infos(me, b, &vec![info(1, Some(1))])
+            .unwrap();
+        assert_eq!(Some(keys(1)), public_keys_of_a());
+
+        // b forwards a newer info of a with other keys, the other fields are still updated
+        let mut forged = info(2, Some(2));
+        forged.hostname = Some("a".to_string());
+        synced_info.update_peer_infos(me, b, &vec![forged]).unwrap();
+        assert_eq!(Some(keys(1)), public_keys_of_a());
+        assert_eq!(
+            Some("a".to_string()),
+            synced_info.peer_infos.get(&a).unwrap().hostname
+        );
+
+        // a itself is the origin of its keys
+        synced_info
+            .update_peer_infos(me, a, &vec![info(3, Some(3))])
+            .unwrap();
+        assert_eq!(Some(keys(3)), public_keys_of_a());
+    }
+
+    #[test]
+    fn weighted_shortest_path() {
+        let synced_info = SyncedRouteInfo {
+            peer_infos: DashMap::new(),
+            conn_map: DashMap::new(),
+        };
+        let (a, b, c) = (1, 2, 3);
+        for peer_id in [a, b, c] {
+            let mut info = RoutePeerInfo::new();
+            info.peer_id = peer_id;
+            info.version = 1;
+            synced_info.peer_infos.insert(peer_id, info);
+        }
+
+        // direct link between a and c is much slower than relaying through b
+        let links = [
+            (a, b, 2),
+            (b, a, 2),
+            (b, c, 3),
+            (c, b, 3),
+            (a, c, 16),
+            (c, a, 16),
+        ];
+        for (src, dst, cost) in links {
+            synced_info
+                .conn_map
+                .entry(src)
+                .or_insert((BTreeMap::new(), AtomicVersion::new()))
+                .0
+                .insert(dst, cost);
+        }
+
+        let route_table = RouteTable::new();
+        route_table.build_from_synced_info(a, &synced_info);
+        assert_eq!(Some((b, 2)), route_table.get_next_hop(b));
+        assert_eq!(Some((b, 5)), route_table.get_next_hop(c));
+
+        synced_info.conn_map.get_mut(&a).unwrap().0.insert(c, 4);
+        route_table.build_from_synced_info(a, &synced_info);
+        assert_eq!(Some((c, 4)), route_table.get_next_hop(c));
+    }
+
+    #[test]
+    fn duplicate_ipv4_owned_by_smallest_inst_id() {
+        let synced_info = SyncedRouteInfo {
+            peer_infos: DashMap::new(),
+            conn_map: DashMap::new(),
+        };
+        let (a, b, c) = (1, 2, 3);
+        let dup_addr: Ipv4Addr = "10.144.144.2".parse().unwrap();
+        for (peer_id, inst_id, ipv4_addr) in [
+            (a, 3, "10.144.144.1"),
+            (b, 2, "10.144.144.2"),
+            (c, 1, "10.144.144.2"),
+        ] {
+            let mut info = RoutePeerInfo::new();
+            info.peer_id = peer_id;
+            info.inst_id = uuid::Uuid::from_u128(inst_id);
+            info.ipv4_addr = Some(ipv4_addr.parse().unwrap());
+            info.version = 1;
+            synced_info.peer_infos.insert(peer_id, info);
+        }
+        for (src, dst) in [(a, b), (b, a), (a, c), (c, a)] {
+            synced_info
+                .conn_map
+                .entry(src)
+                .or_insert((BTreeMap::new(), AtomicVersion::new()))
+                .0
+                .insert(dst, 1);
+        }
+
+        let route_table = RouteTable::new();
+        route_table.build_from_synced_info(a, &synced_info);
+        assert_eq!(
+            Some(c),
+            route_table.ipv4_peer_id_map.get(&dup_addr).map(|x| *x)
+        );
+        assert_eq!(
+            Some(vec![c, b]),
+            route_table.ipv4_conflicts.get(&dup_addr).map(|x| x.clone())
+        );
+        assert_eq!(1, route_table.ipv4_conflicts.len());
+
+        // a static address wins over a dhcp one
+        synced_info.peer_infos.get_mut(&c).unwrap().ipv4_dhcp = true;
+        route_table.build_from_synced_info(a, &synced_info);
+        assert_eq!(
+            Some(b),
+            route_table.ipv4_peer_id_map.get(&dup_addr).map(|x| *x)
+        );
+    }
 }
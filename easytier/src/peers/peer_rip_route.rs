@@ -47,10 +47,11 @@ impl SyncPeerInfo {
             cost: 0,
             ipv4_addr: global_ctx.get_ipv4(),
             proxy_cidrs: global_ctx
-                .get_proxy_cidrs()
+                .get_advertised_proxy_cidrs()
                 .iter()
                 .map(|x| x.to_string())
                 .chain(global_ctx.get_vpn_portal_cidr().map(|x| x.to_string()))
+                .chain(global_ctx.get_exit_node_cidr().map(|x| x.to_string()))
                 .collect(),
             hostname: global_ctx.get_hostname(),
             udp_stun_info: global_ctx
@@ -281,6 +282,10 @@ impl BasicRoute {
 
             for cidr in peer_info.proxy_cidrs.iter() {
                 let cidr: cidr::IpCidr = cidr.parse().unwrap();
+                // exit nodes are only used when selected
+                if cidr.network_length() == 0 {
+                    continue;
+                }
                 route_table
                     .cidr_peer_id_map
                     .insert(cidr, node_id.clone().into());
@@ -86,6 +86,26 @@ impl PeerRpcManager {
         S::Resp:
             Send + std::fmt::Debug + 'static + serde::Serialize + for<'a> serde::Deserialize<'a>,
         S::Fut: Send + 'static,
+    {
+        self.run_service_for_peer(service_id, false, move |_| s.clone())
+    }
+
+    // create_service is called with the peer id of each calling peer, so the service knows
+    // who it serves. the peer id comes from the packet header, not from the request. any
+    // peer relaying a request can forge it, so services trusting it should set
+    // authenticated_only to serve only senders proven by the direct session or pairwise key.
+    pub fn run_service_for_peer<F, S, Req>(
+        self: &Self,
+        service_id: PeerRpcServiceId,
+        authenticated_only: bool,
+        create_service: F,
+    ) where
+        F: Fn(PeerId) -> S + Send + Sync + 'static,
+        S: tarpc::server::Serve<Req> + Clone + Send + Sync + 'static,
+        Req: Send + 'static + serde::Serialize + for<'a> serde::Deserialize<'a>,
+        S::Resp:
+            Send + std::fmt::Debug + 'static + serde::Serialize + for<'a> serde::Deserialize<'a>,
+        S::Fut: Send + 'static,
     {
         let tspt = self.tspt.clone();
         let creator = Box::new(move |peer_id: PeerId| {
@@ -97,7 +117,7 @@ impl PeerRpcManager {
             let my_peer_id_clone = tspt.my_peer_id();
             let peer_id_clone = peer_id.clone();
 
-            let o = server.execute(s.clone());
+            let o = server.execute(create_service(peer_id));
             tasks.spawn(o);
 
             let tspt = tspt.clone();
@@ -153,6 +173,20 @@ impl PeerRpcManager {
                                 continue;
                             }
 
+                            // the flag proves the sender in the header, the request must be
+                            // from the same peer
+                            let authenticated = packet
+                                .peer_manager_header()
+                                .map(|hdr| {
+                                    hdr.is_sender_authenticated()
+                                        && hdr.from_peer_id.get() == info.from_peer
+                                })
+                                .unwrap_or(false);
+                            if authenticated_only && !authenticated {
+                                tracing::warn!("recv packet from unauthenticated peer, ignore it");
+                                continue;
+                            }
+
                             if cur_req_peer_id.is_some() {
                                 tracing::warn!("cur_req_peer_id is not none, ignore this packet");
                                 continue;
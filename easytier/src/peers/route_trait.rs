@@ -1,9 +1,12 @@
-use std::{net::Ipv4Addr, sync::Arc};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
 
 use async_trait::async_trait;
 use tokio_util::bytes::Bytes;
 
-use crate::common::{error::Error, PeerId};
+use crate::common::{error::Error, noise::PeerPublicKeys, PeerId};
 
 #[async_trait]
 pub trait RouteInterface {
@@ -15,6 +18,11 @@ pub trait RouteInterface {
         dst_peer_id: PeerId,
     ) -> Result<(), Error>;
     fn my_peer_id(&self) -> PeerId;
+
+    // conns of a directly connected peer, route can use their latency and loss rate as link cost.
+    async fn list_peer_conns(&self, _peer_id: PeerId) -> Vec<crate::rpc::PeerConnInfo> {
+        vec![]
+    }
 }
 
 pub type RouteInterfaceBox = Box<dyn RouteInterface + Send + Sync>;
@@ -31,6 +39,14 @@ pub trait Route {
     async fn get_peer_id_by_ipv4(&self, _ipv4: &Ipv4Addr) -> Option<PeerId> {
         None
     }
+
+    async fn get_peer_id_by_ipv6(&self, _ipv6: &Ipv6Addr) -> Option<PeerId> {
+        None
+    }
+
+    async fn get_peer_public_keys(&self, _peer_id: PeerId) -> Option<PeerPublicKeys> {
+        None
+    }
 }
 
 pub type ArcRoute = Arc<Box<dyn Route + Send + Sync>>;
@@ -3,7 +3,10 @@ use std::sync::Arc;
 use crate::rpc::{
     cli::PeerInfo,
     peer_manage_rpc_server::PeerManageRpc,
-    {ListPeerRequest, ListPeerResponse, ListRouteRequest, ListRouteResponse},
+    {
+        ClosePeerRequest, ClosePeerResponse, ListPeerRequest, ListPeerResponse, ListRouteRequest,
+        ListRouteResponse,
+    },
 };
 use tonic::{Request, Response, Status};
 
@@ -60,4 +63,52 @@ impl PeerManageRpc for PeerManagerRpcService {
         reply.routes = self.peer_manager.list_routes().await;
         Ok(Response::new(reply))
     }
+
+    // closes all conns of a directly connected peer. manual connectors to the peer will
+    // reconnect unless they are removed first.
+    async fn close_peer(
+        &self,
+        request: Request<ClosePeerRequest>,
+    ) -> Result<Response<ClosePeerResponse>, Status> {
+        let peer_id = request.into_inner().peer_id;
+        let peer_map = self.peer_manager.get_peer_map();
+        if !peer_map.has_peer(peer_id) {
+            return Err(Status::not_found(format!("peer {} not found", peer_id)));
+        }
+        peer_map
+            .close_peer(peer_id)
+            .await
+            .map_err(|e| Status::internal(format!("close peer failed: {:?}", e)))?;
+        Ok(Response::new(ClosePeerResponse::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::peers::tests::{connect_peer_manager, create_mock_peer_manager, wait_route_appear};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn close_peer() {
+        let peer_mgr_a = create_mock_peer_manager().await;
+        let peer_mgr_b = create_mock_peer_manager().await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_b.clone())
+            .await
+            .unwrap();
+
+        let service = PeerManagerRpcService::new(peer_mgr_a.clone());
+        let peer_id = peer_mgr_b.my_peer_id();
+        service
+            .close_peer(Request::new(ClosePeerRequest { peer_id }))
+            .await
+            .unwrap();
+        assert!(!peer_mgr_a.get_peer_map().has_peer(peer_id));
+
+        let ret = service
+            .close_peer(Request::new(ClosePeerRequest { peer_id }))
+            .await;
+        assert_eq!(tonic::Code::NotFound, ret.unwrap_err().code());
+    }
 }
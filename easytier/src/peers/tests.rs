@@ -51,6 +51,19 @@ pub async fn wait_route_appear_with_cost(
     return Err(Error::NotFound);
 }
 
+// link costs depend on measured latency, so wait for a direct connection
+// instead of a route with a specific cost.
+pub async fn wait_direct_peer(peer_mgr: Arc<PeerManager>, node_id: PeerId) -> Result<(), Error> {
+    let now = std::time::Instant::now();
+    while now.elapsed().as_secs() < 5 {
+        if peer_mgr.get_peer_map().has_peer(node_id) {
+            return Ok(());
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    return Err(Error::NotFound);
+}
+
 pub async fn wait_route_appear(
     peer_mgr: Arc<PeerManager>,
     target_peer: Arc<PeerManager>,
@@ -0,0 +1,79 @@
+use clap::CommandFactory;
+
+use crate::{common::config::ConfigLoader, Cli};
+
+fn parse_cli(args: &[&str]) -> Cli {
+    let args = std::iter::once("easytier-core").chain(args.iter().copied());
+    Cli::from_matches(&Cli::command().get_matches_from(args))
+}
+
+#[test]
+fn flags_override_config_file() {
+    let path = std::env::temp_dir().join(format!("easytier-test-{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        r#"
+instance_name = "from_file"
+listeners = ["tcp://0.0.0.0:12010"]
+rpc_portal = "127.0.0.1:15999"
+
+[network_identity]
+network_name = "file_net"
+network_secret = "file_secret"
+
+[[peer]]
+uri = "tcp://10.0.0.1:11010"
+
+[[proxy_network]]
+cidr = "192.168.1.0/24"
+
+[flags]
+enable_encryption = false
+"#,
+    )
+    .unwrap();
+
+    let cli = parse_cli(&[
+        "--config-file",
+        path.to_str().unwrap(),
+        "--network-secret",
+        "flag_secret",
+        "-p",
+        "tcp://10.0.0.2:11010",
+    ]);
+    let cfg = cli.load_config().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // values not given on command line come from the file, not from flag defaults
+    assert_eq!(cfg.get_inst_name(), "from_file");
+    assert_eq!(
+        cfg.get_listeners(),
+        vec!["tcp://0.0.0.0:12010".parse().unwrap()]
+    );
+    assert_eq!(
+        cfg.get_rpc_portal(),
+        Some("127.0.0.1:15999".parse().unwrap())
+    );
+    assert_eq!(cfg.get_proxy_cidrs().len(), 1);
+    assert!(!cfg.get_flags().enable_encryption);
+
+    let identity = cfg.get_network_identity();
+    assert_eq!(identity.network_name, "file_net");
+    assert_eq!(identity.network_secret, Some("flag_secret".to_string()));
+
+    // flags replace the lists in the file
+    let peers = cfg.get_peers();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].uri.to_string(), "tcp://10.0.0.2:11010");
+}
+
+#[test]
+fn flag_defaults_without_config_file() {
+    let cfg = parse_cli(&[]).load_config().unwrap();
+    assert_eq!(cfg.get_inst_name(), "default");
+    assert_eq!(cfg.get_listeners().len(), 3);
+    assert_eq!(
+        cfg.get_rpc_portal(),
+        Some("127.0.0.1:15888".parse().unwrap())
+    );
+}
@@ -332,6 +332,102 @@ pub async fn udp_proxy_three_node_test(#[values("tcp", "udp", "wg")] proto: &str
     .await;
 }
 
+#[tokio::test]
+#[serial_test::serial]
+pub async fn mapped_proxy_network_three_node_test() {
+    use crate::tunnel::{common::tests::_tunnel_pingpong_netns, tcp::TcpTunnelListener};
+
+    let insts = init_three_node("tcp").await;
+
+    let cidr = "10.1.2.0/24".parse().unwrap();
+    let global_ctx = insts[2].get_global_ctx();
+    global_ctx.add_proxy_cidr(cidr).unwrap();
+    global_ctx
+        .set_proxy_cidr_mapping(cidr, Some("10.200.2.0/24".parse().unwrap()))
+        .unwrap();
+
+    // peers only see the mapped cidr
+    wait_proxy_route_appear(
+        &insts[0].get_peer_manager(),
+        "10.144.144.3",
+        insts[2].peer_id(),
+        "10.200.2.0/24",
+    )
+    .await;
+
+    wait_for_condition(
+        || async { ping_test("net_a", "10.200.2.4").await },
+        Duration::from_secs(5),
+    )
+    .await;
+
+    // responses come from the mapped cidr advertised by inst3, so acl sees them as owned by it
+    let allow_mapped = crate::common::config::AclRuleConfig {
+        action: "allow".to_string(),
+        src: Some("10.200.2.0/24".to_string()),
+        ..Default::default()
+    };
+    insts[0]
+        .get_acl_filter()
+        .set_rules(&[allow_mapped], "drop")
+        .unwrap();
+
+    _tunnel_pingpong_netns(
+        TcpTunnelListener::new("tcp://10.1.2.4:22225".parse().unwrap()),
+        TcpTunnelConnector::new("tcp://10.200.2.4:22225".parse().unwrap()),
+        NetNS::new(Some("net_d".into())),
+        NetNS::new(Some("net_a".into())),
+    )
+    .await;
+}
+
+#[rstest::rstest]
+#[tokio::test]
+#[serial_test::serial]
+pub async fn dnat_three_node_test(#[values("tcp", "udp")] dnat_proto: &str) {
+    use crate::{
+        common::config::DnatRuleConfig,
+        tunnel::{
+            common::tests::_tunnel_pingpong_netns, tcp::TcpTunnelListener, udp::UdpTunnelListener,
+        },
+    };
+
+    let insts = init_three_node("tcp").await;
+
+    // only the forwarded port of inst3 is reachable, its lan is not proxied
+    let rule: DnatRuleConfig = format!("{}:22224/10.1.2.4:22223", dnat_proto)
+        .parse()
+        .unwrap();
+    insts[2].get_dnat_rules().add_rule(&rule).unwrap();
+    assert!(insts[2].get_global_ctx().get_proxy_cidrs().is_empty());
+
+    let listen_url = format!("{}://10.1.2.4:22223", dnat_proto).parse().unwrap();
+    let connect_url = format!("{}://10.144.144.3:22224", dnat_proto)
+        .parse()
+        .unwrap();
+    if dnat_proto == "tcp" {
+        _tunnel_pingpong_netns(
+            TcpTunnelListener::new(listen_url),
+            TcpTunnelConnector::new(connect_url),
+            NetNS::new(Some("net_d".into())),
+            NetNS::new(Some("net_a".into())),
+        )
+        .await;
+    } else {
+        _tunnel_pingpong_netns(
+            UdpTunnelListener::new(listen_url),
+            UdpTunnelConnector::new(connect_url),
+            NetNS::new(Some("net_d".into())),
+            NetNS::new(Some("net_a".into())),
+        )
+        .await;
+    }
+
+    let rules = insts[2].get_dnat_rules().list_rules().rules;
+    assert_eq!(1, rules.len());
+    assert!(rules[0].hit_count > 0);
+}
+
 #[tokio::test]
 #[serial_test::serial]
 pub async fn udp_broadcast_test() {
@@ -528,3 +624,32 @@ pub async fn wireguard_vpn_portal() {
     )
     .await;
 }
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn connector_peer_id_test() {
+    let insts = init_three_node("tcp").await;
+    let conn_manager = insts[1].get_conn_manager();
+
+    let tcp_url = "tcp://10.1.1.1:11010";
+    let ring_url = format!("ring://{}", insts[2].id());
+    wait_for_condition(
+        || async {
+            conn_manager.get_connector_peer_id(tcp_url) == Some(insts[0].peer_id())
+                && conn_manager.get_connector_peer_id(&ring_url) == Some(insts[2].peer_id())
+        },
+        Duration::from_secs(5),
+    )
+    .await;
+
+    let connectors = conn_manager.list_connectors().await;
+    let connector = connectors.iter().find(|c| c.url == ring_url).unwrap();
+    assert_eq!(insts[2].peer_id(), connector.peer_id);
+
+    conn_manager.remove_connector(&ring_url).await.unwrap();
+    wait_for_condition(
+        || async { conn_manager.get_connector_peer_id(&ring_url).is_none() },
+        Duration::from_secs(5),
+    )
+    .await;
+}
@@ -28,6 +28,12 @@ pub mod wireguard;
 #[cfg(feature = "quic")]
 pub mod quic;
 
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+#[cfg(any(feature = "quic", feature = "websocket"))]
+pub mod insecure_tls;
+
 #[derive(thiserror::Error, Debug)]
 pub enum TunnelError {
     #[error("io error")]
@@ -64,6 +70,10 @@ pub enum TunnelError {
 
     #[error("tunnel error: {0}")]
     TunError(String),
+
+    #[cfg(feature = "websocket")]
+    #[error("websocket error: {0}")]
+    WebSocketError(#[from] tokio_tungstenite::tungstenite::Error),
 }
 
 pub type StreamT = packet_def::ZCPacket;
@@ -54,11 +54,22 @@ pub enum PacketType {
     Pong = 5,
     TaRpc = 6,
     Route = 7,
+    // ethernet frame of tap mode
+    Ethernet = 8,
 }
 
 bitflags::bitflags! {
     struct PeerManagerHeaderFlags: u8 {
         const ENCRYPTED = 0b0000_0001;
+        // id of cipher suite used by encrypted packet
+        const CIPHER_MASK = 0b0000_1110;
+        // encrypted with session key of peer conn, instead of the network key
+        const SESSION_ENCRYPTED = 0b0001_0000;
+        // encrypted with the pairwise key of both ends, instead of the network key
+        const PAIRWISE_ENCRYPTED = 0b0010_0000;
+        // set by the receiver when the sender is proven by the direct session or the pairwise
+        // key, the value on the wire is never trusted
+        const SENDER_AUTHENTICATED = 0b0100_0000;
     }
 }
 
@@ -69,20 +80,20 @@ pub struct PeerManagerHeader {
     pub to_peer_id: U32<DefaultEndian>,
     pub packet_type: u8,
     pub flags: u8,
-    reserved: U16<DefaultEndian>,
+    // generation of session key, only valid for session encrypted packet
+    key_generation: U16<DefaultEndian>,
     pub len: U32<DefaultEndian>,
 }
 pub const PEER_MANAGER_HEADER_SIZE: usize = std::mem::size_of::<PeerManagerHeader>();
 
 impl PeerManagerHeader {
     pub fn is_encrypted(&self) -> bool {
-        PeerManagerHeaderFlags::from_bits(self.flags)
-            .unwrap()
+        PeerManagerHeaderFlags::from_bits_retain(self.flags)
             .contains(PeerManagerHeaderFlags::ENCRYPTED)
     }
 
     pub fn set_encrypted(&mut self, encrypted: bool) {
-        let mut flags = PeerManagerHeaderFlags::from_bits(self.flags).unwrap();
+        let mut flags = PeerManagerHeaderFlags::from_bits_retain(self.flags);
         if encrypted {
             flags.insert(PeerManagerHeaderFlags::ENCRYPTED);
         } else {
@@ -90,9 +101,71 @@ impl PeerManagerHeader {
         }
         self.flags = flags.bits();
     }
+
+    pub fn get_cipher_id(&self) -> u8 {
+        (self.flags & PeerManagerHeaderFlags::CIPHER_MASK.bits()) >> 1
+    }
+
+    pub fn set_cipher_id(&mut self, id: u8) {
+        let mask = PeerManagerHeaderFlags::CIPHER_MASK.bits();
+        self.flags = (self.flags & !mask) | ((id << 1) & mask);
+    }
+
+    pub fn is_session_encrypted(&self) -> bool {
+        PeerManagerHeaderFlags::from_bits_retain(self.flags)
+            .contains(PeerManagerHeaderFlags::SESSION_ENCRYPTED)
+    }
+
+    pub fn set_session_encrypted(&mut self, encrypted: bool) {
+        let mut flags = PeerManagerHeaderFlags::from_bits_retain(self.flags);
+        if encrypted {
+            flags.insert(PeerManagerHeaderFlags::SESSION_ENCRYPTED);
+        } else {
+            flags.remove(PeerManagerHeaderFlags::SESSION_ENCRYPTED);
+        }
+        self.flags = flags.bits();
+    }
+
+    pub fn is_pairwise_encrypted(&self) -> bool {
+        PeerManagerHeaderFlags::from_bits_retain(self.flags)
+            .contains(PeerManagerHeaderFlags::PAIRWISE_ENCRYPTED)
+    }
+
+    pub fn set_pairwise_encrypted(&mut self, encrypted: bool) {
+        let mut flags = PeerManagerHeaderFlags::from_bits_retain(self.flags);
+        if encrypted {
+            flags.insert(PeerManagerHeaderFlags::PAIRWISE_ENCRYPTED);
+        } else {
+            flags.remove(PeerManagerHeaderFlags::PAIRWISE_ENCRYPTED);
+        }
+        self.flags = flags.bits();
+    }
+
+    pub fn is_sender_authenticated(&self) -> bool {
+        PeerManagerHeaderFlags::from_bits_retain(self.flags)
+            .contains(PeerManagerHeaderFlags::SENDER_AUTHENTICATED)
+    }
+
+    pub fn set_sender_authenticated(&mut self, authenticated: bool) {
+        let mut flags = PeerManagerHeaderFlags::from_bits_retain(self.flags);
+        if authenticated {
+            flags.insert(PeerManagerHeaderFlags::SENDER_AUTHENTICATED);
+        } else {
+            flags.remove(PeerManagerHeaderFlags::SENDER_AUTHENTICATED);
+        }
+        self.flags = flags.bits();
+    }
+
+    pub fn get_key_generation(&self) -> u16 {
+        self.key_generation.get()
+    }
+
+    pub fn set_key_generation(&mut self, generation: u16) {
+        self.key_generation.set(generation);
+    }
 }
 
-// reserve the space for aes tag and nonce
+// reserve the space for aes tag and nonce, chacha20-poly1305 uses the same layout
 #[repr(C, packed)]
 #[derive(AsBytes, FromBytes, FromZeroes, Clone, Debug, Default)]
 pub struct AesGcmTail {
@@ -346,6 +419,7 @@ impl ZCPacket {
         hdr.to_peer_id.set(to_peer_id);
         hdr.packet_type = packet_type;
         hdr.flags = 0;
+        hdr.key_generation.set(0);
         hdr.len.set(payload_len as u32);
     }
 
@@ -438,4 +512,16 @@ mod tests {
         assert_eq!(&tcp_packet[..1], b"\x0b");
         println!("{:?}", tcp_packet);
     }
+
+    #[test]
+    fn unknown_header_flags() {
+        // flags added by newer versions must not panic and are kept when forwarding
+        let mut hdr = PeerManagerHeader::default();
+        hdr.flags = 0b1000_0001;
+        assert!(hdr.is_encrypted());
+        assert!(!hdr.is_session_encrypted());
+        hdr.set_session_encrypted(true);
+        hdr.set_encrypted(false);
+        assert_eq!(hdr.flags, 0b1001_0000);
+    }
 }
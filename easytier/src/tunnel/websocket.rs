@@ -0,0 +1,514 @@
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::Context as _;
+use bytes::BytesMut;
+use futures::{Sink, SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpSocket, TcpStream},
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::StatusCode,
+        Message,
+    },
+    WebSocketStream,
+};
+
+use crate::{rpc::TunnelInfo, tunnel::common::setup_sokcet2};
+
+use super::{
+    check_scheme_and_get_socket_addr_ext,
+    common::{TcpZCPacketToBytes, TunnelWrapper, ZCPacketToBytes},
+    insecure_tls::{get_insecure_tls_cert, get_insecure_tls_client_config},
+    packet_def::{ZCPacket, ZCPacketType, TCP_TUNNEL_HEADER_SIZE},
+    IpVersion, Tunnel, TunnelConnector, TunnelError, TunnelListener, ZCPacketSink,
+};
+
+fn is_wss(addr: &url::Url) -> Result<bool, TunnelError> {
+    match addr.scheme() {
+        "ws" => Ok(false),
+        "wss" => Ok(true),
+        _ => Err(TunnelError::InvalidProtocol(addr.scheme().to_string())),
+    }
+}
+
+// certificate chain and private key of a wss listener from pem files
+fn load_tls_cert(
+    cert: &str,
+    key: &str,
+) -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey), TunnelError> {
+    let read_pem =
+        |path: &str| std::fs::read(path).with_context(|| format!("read pem file failed: {}", path));
+
+    let cert_chain = rustls_pemfile::certs(&mut read_pem(cert)?.as_slice())
+        .with_context(|| format!("parse certificate failed: {}", cert))?;
+    if cert_chain.is_empty() {
+        return Err(anyhow::anyhow!("no certificate found in {}", cert).into());
+    }
+
+    let priv_key = rustls_pemfile::read_all(&mut read_pem(key)?.as_slice())
+        .with_context(|| format!("parse private key failed: {}", key))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(k)
+            | rustls_pemfile::Item::PKCS8Key(k)
+            | rustls_pemfile::Item::ECKey(k) => Some(k),
+            _ => None,
+        })
+        .with_context(|| format!("no private key found in {}", key))?;
+
+    Ok((
+        cert_chain.into_iter().map(rustls::Certificate).collect(),
+        rustls::PrivateKey(priv_key),
+    ))
+}
+
+// server certificate is verified against webpki roots unless insecure is set
+fn get_tls_client_config(insecure: bool) -> rustls::ClientConfig {
+    if insecure {
+        return get_insecure_tls_client_config();
+    }
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+// name in the certificate must match the host of the url, it's also sent as sni
+fn get_tls_server_name(addr: &url::Url) -> Result<rustls::ServerName, TunnelError> {
+    let server_name = match addr.host() {
+        Some(url::Host::Domain(domain)) => rustls::ServerName::try_from(domain).ok(),
+        Some(url::Host::Ipv4(ip)) => Some(rustls::ServerName::IpAddress(ip.into())),
+        Some(url::Host::Ipv6(ip)) => Some(rustls::ServerName::IpAddress(ip.into())),
+        None => None,
+    };
+    server_name.ok_or_else(|| TunnelError::InvalidAddr(addr.to_string()))
+}
+
+// the message comes from the remote, check it like FramedReader does for tcp
+fn get_zcpacket_from_ws_data(data: Vec<u8>) -> Result<ZCPacket, TunnelError> {
+    if data.len() < TCP_TUNNEL_HEADER_SIZE {
+        return Err(TunnelError::InvalidPacket(format!(
+            "ws packet size too small: {}",
+            data.len()
+        )));
+    }
+
+    let zc_packet = ZCPacket::new_from_buf(BytesMut::from(data.as_slice()), ZCPacketType::TCP);
+    let payload_len = zc_packet.tcp_tunnel_header().unwrap().len.get() as usize;
+    if payload_len != data.len() - TCP_TUNNEL_HEADER_SIZE {
+        return Err(TunnelError::InvalidPacket(format!(
+            "ws packet payload len not match: header len: {}, real len: {}",
+            payload_len,
+            data.len()
+        )));
+    }
+
+    Ok(zc_packet)
+}
+
+// every binary message carries exactly one packet in tcp tunnel format
+fn get_tunnel_with_ws_stream<S>(stream: WebSocketStream<S>, info: TunnelInfo) -> Box<dyn Tunnel>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (write, read) = stream.split();
+
+    let read = read.filter_map(|msg| async move {
+        match msg {
+            Ok(Message::Binary(data)) => Some(get_zcpacket_from_ws_data(data)),
+            Ok(_) => None,
+            Err(e) => Some(Err(TunnelError::from(e))),
+        }
+    });
+
+    let write = write.with(|packet: ZCPacket| async move {
+        let bytes = TcpZCPacketToBytes.into_bytes(packet)?;
+        Ok::<_, TunnelError>(Message::Binary(bytes.to_vec()))
+    });
+
+    Box::new(TunnelWrapper::new(
+        read,
+        WsSinkWrapper {
+            inner: Some(Box::pin(write)),
+        },
+        Some(info),
+    ))
+}
+
+struct WsSinkWrapper {
+    inner: Option<Pin<Box<dyn ZCPacketSink>>>,
+}
+
+impl Sink<ZCPacket> for WsSinkWrapper {
+    type Error = TunnelError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.as_mut().unwrap().as_mut().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: ZCPacket) -> Result<(), Self::Error> {
+        self.inner.as_mut().unwrap().as_mut().start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.as_mut().unwrap().as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.as_mut().unwrap().as_mut().poll_close(cx)
+    }
+}
+
+impl Drop for WsSinkWrapper {
+    fn drop(&mut self) {
+        // send a close frame like dropping the write half of a tcp stream, so peer can see eof
+        let Some(mut inner) = self.inner.take() else {
+            return;
+        };
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                let _ = inner.close().await;
+            });
+        }
+    }
+}
+
+pub struct WsTunnelListener {
+    addr: url::Url,
+    listener: Option<TcpListener>,
+    tls_acceptor: Option<TlsAcceptor>,
+    // pem files of certificate chain and private key used by wss
+    tls_cert: Option<(String, String)>,
+}
+
+impl WsTunnelListener {
+    pub fn new(addr: url::Url) -> Self {
+        WsTunnelListener {
+            addr,
+            listener: None,
+            tls_acceptor: None,
+            tls_cert: None,
+        }
+    }
+
+    pub fn set_tls_cert(&mut self, cert: String, key: String) {
+        self.tls_cert = Some((cert, key));
+    }
+
+    async fn try_accept(&self, stream: TcpStream) -> Result<Box<dyn Tunnel>, TunnelError> {
+        let info = TunnelInfo {
+            tunnel_type: self.addr.scheme().to_owned(),
+            local_addr: self.local_url().into(),
+            remote_addr: super::build_url_from_socket_addr(
+                &stream.peer_addr()?.to_string(),
+                self.addr.scheme(),
+            )
+            .into(),
+        };
+
+        let path = self.addr.path().to_owned();
+        let check_path = move |req: &Request, resp: Response| -> Result<Response, ErrorResponse> {
+            if req.uri().path() != path {
+                let mut err_resp = ErrorResponse::new(None);
+                *err_resp.status_mut() = StatusCode::NOT_FOUND;
+                return Err(err_resp);
+            }
+            Ok(resp)
+        };
+
+        if let Some(acceptor) = &self.tls_acceptor {
+            let stream = acceptor.accept(stream).await?;
+            let stream = tokio_tungstenite::accept_hdr_async(stream, check_path).await?;
+            Ok(get_tunnel_with_ws_stream(stream, info))
+        } else {
+            let stream = tokio_tungstenite::accept_hdr_async(stream, check_path).await?;
+            Ok(get_tunnel_with_ws_stream(stream, info))
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TunnelListener for WsTunnelListener {
+    async fn listen(&mut self) -> Result<(), TunnelError> {
+        let addr = check_scheme_and_get_socket_addr_ext::<SocketAddr>(
+            &self.addr,
+            self.addr.scheme(),
+            IpVersion::Both,
+        )?;
+
+        if is_wss(&self.addr)? {
+            let (cert_chain, priv_key) = match &self.tls_cert {
+                Some((cert, key)) => load_tls_cert(cert, key)?,
+                None => {
+                    tracing::warn!(
+                        addr = ?self.addr,
+                        "no certificate set for wss listener, use a self signed one which is only accepted by clients skipping verification"
+                    );
+                    get_insecure_tls_cert()
+                }
+            };
+            let config = rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(cert_chain, priv_key)
+                .with_context(|| "build tls server config failed")?;
+            self.tls_acceptor = Some(TlsAcceptor::from(Arc::new(config)));
+        }
+
+        let socket2_socket = socket2::Socket::new(
+            socket2::Domain::for_address(addr),
+            socket2::Type::STREAM,
+            Some(socket2::Protocol::TCP),
+        )?;
+        setup_sokcet2(&socket2_socket, &addr)?;
+        let socket = TcpSocket::from_std_stream(socket2_socket.into());
+
+        self.addr
+            .set_port(Some(socket.local_addr()?.port()))
+            .unwrap();
+
+        self.listener = Some(socket.listen(1024)?);
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> Result<Box<dyn Tunnel>, TunnelError> {
+        loop {
+            let listener = self.listener.as_ref().unwrap();
+            let (stream, _) = listener.accept().await?;
+            stream.set_nodelay(true).unwrap();
+
+            // a failed handshake should not stop the listener
+            match self.try_accept(stream).await {
+                Ok(tunnel) => return Ok(tunnel),
+                Err(e) => {
+                    tracing::warn!(?e, "websocket handshake failed");
+                    continue;
+                }
+            }
+        }
+    }
+
+    fn local_url(&self) -> url::Url {
+        self.addr.clone()
+    }
+}
+
+pub struct WsTunnelConnector {
+    addr: url::Url,
+    ip_version: IpVersion,
+    tls_insecure: bool,
+}
+
+impl WsTunnelConnector {
+    pub fn new(addr: url::Url) -> Self {
+        WsTunnelConnector {
+            addr,
+            ip_version: IpVersion::Both,
+            tls_insecure: false,
+        }
+    }
+
+    // accept any server certificate, vulnerable to mitm. only for servers with self signed certs
+    pub fn set_tls_insecure(&mut self, insecure: bool) {
+        self.tls_insecure = insecure;
+    }
+}
+
+#[async_trait::async_trait]
+impl TunnelConnector for WsTunnelConnector {
+    async fn connect(&mut self) -> Result<Box<dyn Tunnel>, TunnelError> {
+        let server_name = if is_wss(&self.addr)? {
+            Some(get_tls_server_name(&self.addr)?)
+        } else {
+            None
+        };
+        let addr = check_scheme_and_get_socket_addr_ext::<SocketAddr>(
+            &self.addr,
+            self.addr.scheme(),
+            self.ip_version,
+        )?;
+
+        tracing::info!(addr = ?self.addr, "connect websocket start");
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true).unwrap();
+
+        let info = TunnelInfo {
+            tunnel_type: self.addr.scheme().to_owned(),
+            local_addr: super::build_url_from_socket_addr(
+                &stream.local_addr()?.to_string(),
+                self.addr.scheme(),
+            )
+            .into(),
+            remote_addr: self.addr.to_string(),
+        };
+
+        let ret = if let Some(server_name) = server_name {
+            let connector = TlsConnector::from(Arc::new(get_tls_client_config(self.tls_insecure)));
+            let stream = connector.connect(server_name, stream).await?;
+            let (stream, _) = tokio_tungstenite::client_async(self.addr.as_str(), stream).await?;
+            get_tunnel_with_ws_stream(stream, info)
+        } else {
+            let (stream, _) = tokio_tungstenite::client_async(self.addr.as_str(), stream).await?;
+            get_tunnel_with_ws_stream(stream, info)
+        };
+        tracing::info!(addr = ?self.addr, "connect websocket succ");
+
+        Ok(ret)
+    }
+
+    fn remote_url(&self) -> url::Url {
+        self.addr.clone()
+    }
+
+    fn set_ip_version(&mut self, ip_version: IpVersion) {
+        self.ip_version = ip_version;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tunnel::common::tests::{_tunnel_bench, _tunnel_pingpong};
+
+    use super::*;
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn ws_pair(scheme: &str, path: &str) -> (WsTunnelListener, WsTunnelConnector) {
+        let port = free_port();
+        let listener = WsTunnelListener::new(
+            format!("{}://0.0.0.0:{}{}", scheme, port, path)
+                .parse()
+                .unwrap(),
+        );
+        let connector = WsTunnelConnector::new(
+            format!("{}://127.0.0.1:{}{}", scheme, port, path)
+                .parse()
+                .unwrap(),
+        );
+        (listener, connector)
+    }
+
+    #[test]
+    fn ws_invalid_packet() {
+        assert!(get_zcpacket_from_ws_data(vec![1, 2]).is_err());
+
+        let packet = ZCPacket::new_with_payload(b"hello");
+        let data = TcpZCPacketToBytes.into_bytes(packet).unwrap().to_vec();
+        let ret = get_zcpacket_from_ws_data(data.clone()).unwrap();
+        assert_eq!(b"hello", ret.payload());
+
+        assert!(get_zcpacket_from_ws_data(data[..data.len() - 1].to_vec()).is_err());
+        let mut longer = data;
+        longer.push(0);
+        assert!(get_zcpacket_from_ws_data(longer).is_err());
+    }
+
+    #[tokio::test]
+    async fn ws_pingpong() {
+        let (listener, connector) = ws_pair("ws", "");
+        _tunnel_pingpong(listener, connector).await
+    }
+
+    #[tokio::test]
+    async fn ws_bench() {
+        let (listener, connector) = ws_pair("ws", "");
+        _tunnel_bench(listener, connector).await
+    }
+
+    #[tokio::test]
+    async fn wss_pingpong() {
+        let (listener, mut connector) = ws_pair("wss", "/et");
+        connector.set_tls_insecure(true);
+        _tunnel_pingpong(listener, connector).await
+    }
+
+    #[tokio::test]
+    async fn wss_cert_from_pem_files() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("easytier-wss-{}.pem", std::process::id()));
+        let key_path = dir.join(format!("easytier-wss-{}.key", std::process::id()));
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        let (mut listener, mut connector) = ws_pair("wss", "/et");
+        listener.set_tls_cert(
+            cert_path.to_string_lossy().to_string(),
+            key_path.to_string_lossy().to_string(),
+        );
+        connector.set_tls_insecure(true);
+        _tunnel_pingpong(listener, connector).await;
+
+        let _ = std::fs::remove_file(cert_path);
+        let _ = std::fs::remove_file(key_path);
+    }
+
+    #[tokio::test]
+    async fn wss_reject_untrusted_cert() {
+        let (mut listener, mut connector) = ws_pair("wss", "/et");
+        listener.listen().await.unwrap();
+        tokio::spawn(async move {
+            let _ = listener.accept().await;
+        });
+        assert!(connector.connect().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn wss_missing_cert_file() {
+        let (mut listener, _) = ws_pair("wss", "/et");
+        listener.set_tls_cert(
+            "/nonexistent/cert.pem".to_string(),
+            "/nonexistent/key.pem".to_string(),
+        );
+        assert!(listener.listen().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ws_path_not_match() {
+        let (mut listener, _) = ws_pair("ws", "/et");
+        let mut connector = WsTunnelConnector::new(
+            format!(
+                "ws://127.0.0.1:{}/foo",
+                listener.local_url().port().unwrap()
+            )
+            .parse()
+            .unwrap(),
+        );
+        listener.listen().await.unwrap();
+        tokio::spawn(async move {
+            let _ = listener.accept().await;
+        });
+        assert!(connector.connect().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_alloc_port() {
+        let mut listener = WsTunnelListener::new("ws://0.0.0.0:0".parse().unwrap());
+        listener.listen().await.unwrap();
+        let port = listener.local_url().port().unwrap();
+        assert!(port > 0);
+    }
+}
@@ -117,8 +117,8 @@ pub fn list_peer_route_pair(peers: Vec<PeerInfo>, routes: Vec<Route>) -> Vec<Pee
     pairs
 }
 
-pub fn cost_to_str(cost: i32) -> String {
-    if cost == 1 {
+pub fn cost_to_str(cost: i32, is_direct: bool) -> String {
+    if is_direct {
         "p2p".to_string()
     } else {
         format!("relay({})", cost)
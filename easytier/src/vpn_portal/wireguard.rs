@@ -15,7 +15,7 @@ use tracing::Level;
 use crate::{
     common::{
         config::NetworkIdentity,
-        global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
+        global_ctx::{ArcGlobalCtx, GlobalCtxEvent, EXIT_NODE_CIDR},
         join_joinset_background,
     },
     peers::{peer_manager::PeerManager, PeerPacketFilter},
@@ -250,6 +250,7 @@ impl VpnPortal for WireGuard {
             .iter()
             .map(|x| x.proxy_cidrs.iter().map(String::to_string))
             .flatten()
+            .filter(|x| x != EXIT_NODE_CIDR)
             .collect::<Vec<_>>();
         for ipv4 in routes
             .iter()